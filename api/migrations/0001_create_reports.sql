@@ -0,0 +1,13 @@
+-- Abuse reports on uploaded images
+CREATE TABLE IF NOT EXISTS reports (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    hash TEXT NOT NULL,
+    reason TEXT NOT NULL,
+    contact TEXT,
+    -- SHA-256 of the reporter's IP address
+    reporter TEXT NOT NULL,
+    -- unix time in milliseconds
+    created_at INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_reports_reporter_created_at ON reports (reporter, created_at);
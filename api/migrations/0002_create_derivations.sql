@@ -0,0 +1,15 @@
+-- Parent→derived relationships of images made by transforms
+CREATE TABLE IF NOT EXISTS derivations (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    -- ID of the tenant, empty for the default tenant
+    tenant TEXT NOT NULL,
+    parent TEXT NOT NULL,
+    child TEXT NOT NULL,
+    -- transform spec (JSON)
+    transform TEXT NOT NULL,
+    -- unix time in milliseconds
+    created_at INTEGER NOT NULL,
+    UNIQUE (tenant, parent, child)
+);
+
+CREATE INDEX IF NOT EXISTS idx_derivations_tenant_parent ON derivations (tenant, parent);
@@ -0,0 +1,8 @@
+-- Nonces of redeemed one-time upload tokens
+CREATE TABLE IF NOT EXISTS used_upload_tokens (
+    nonce TEXT PRIMARY KEY,
+    -- unix time in seconds, after which the row can be deleted
+    expires_at INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_used_upload_tokens_expires_at ON used_upload_tokens (expires_at);
@@ -0,0 +1,24 @@
+-- Uploaded images, recorded at upload so that listings and metadata don't have to list the bucket
+CREATE TABLE IF NOT EXISTS uploads (
+    -- ID of the tenant, empty for the default tenant
+    tenant TEXT NOT NULL,
+    -- name of the namespace, empty for the root namespace
+    namespace TEXT NOT NULL,
+    hash TEXT NOT NULL,
+    -- prefix of keys the images are stored under
+    key_prefix TEXT NOT NULL,
+    -- format of the uploaded data (extension), NULL for frames reconstructed from delta patches
+    format TEXT,
+    width INTEGER NOT NULL,
+    height INTEGER NOT NULL,
+    animated INTEGER NOT NULL,
+    -- `key:{SHA-256 of the API key}` or `token:{nonce of the upload token}`
+    uploader TEXT,
+    -- stored images (JSON array of `UploadedImage`, with byte sizes)
+    variants TEXT NOT NULL,
+    -- unix time in milliseconds of the first upload
+    uploaded_at INTEGER NOT NULL,
+    PRIMARY KEY (tenant, namespace, hash)
+);
+
+CREATE INDEX IF NOT EXISTS idx_uploads_tenant_namespace_uploaded_at_hash ON uploads (tenant, namespace, uploaded_at, hash);
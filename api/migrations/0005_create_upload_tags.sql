@@ -0,0 +1,11 @@
+-- Tags of uploaded images (see lib/src/tags.rs), which images are searched by
+CREATE TABLE IF NOT EXISTS upload_tags (
+    tenant TEXT NOT NULL,
+    namespace TEXT NOT NULL,
+    hash TEXT NOT NULL,
+    -- in lowercase
+    tag TEXT NOT NULL,
+    PRIMARY KEY (tenant, namespace, hash, tag)
+);
+
+CREATE INDEX IF NOT EXISTS idx_upload_tags_tenant_namespace_tag ON upload_tags (tenant, namespace, tag);
@@ -0,0 +1,7 @@
+-- Clients uploads were sent from, for abuse investigations. Only exposed to admin routes
+-- HMAC-SHA256 of the IP address (CF-Connecting-IP) keyed with the IP_HASH_SECRET secret, so that
+-- raw addresses are never stored
+ALTER TABLE uploads ADD COLUMN client_ip_hash TEXT;
+-- country of the client as told by Cloudflare (ISO 3166-1 alpha-2)
+ALTER TABLE uploads ADD COLUMN client_country TEXT;
+ALTER TABLE uploads ADD COLUMN client_user_agent TEXT;
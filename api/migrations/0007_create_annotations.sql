@@ -0,0 +1,15 @@
+-- Named regions of uploaded images (see lib/src/annotation.rs), replaced as a whole on each update
+CREATE TABLE IF NOT EXISTS annotations (
+    tenant TEXT NOT NULL,
+    namespace TEXT NOT NULL,
+    hash TEXT NOT NULL,
+    name TEXT NOT NULL,
+    -- in pixels of the original image
+    x INTEGER NOT NULL,
+    y INTEGER NOT NULL,
+    width INTEGER NOT NULL,
+    height INTEGER NOT NULL,
+    -- order in the request, which outlines are colored by
+    position INTEGER NOT NULL,
+    PRIMARY KEY (tenant, namespace, hash, name)
+);
@@ -0,0 +1,11 @@
+-- Asset packs (see lib/src/pack.rs), which never change once stored
+CREATE TABLE IF NOT EXISTS packs (
+    tenant TEXT NOT NULL,
+    -- from the digest of the canonical form of the pack
+    id TEXT NOT NULL,
+    -- canonical form of the pack (JSON)
+    pack TEXT NOT NULL,
+    -- unix time in milliseconds of the first store
+    created_at INTEGER NOT NULL,
+    PRIMARY KEY (tenant, id)
+);
@@ -0,0 +1,6 @@
+-- Reports are filed on images in namespaces of tenants, and reporters are HMAC-SHA256 of IP
+-- addresses keyed with the IP_HASH_SECRET secret rather than plain SHA-256 of them
+
+-- empty for the default tenant and the root namespace, which all earlier reports were on
+ALTER TABLE reports ADD COLUMN tenant TEXT NOT NULL DEFAULT '';
+ALTER TABLE reports ADD COLUMN namespace TEXT NOT NULL DEFAULT '';
@@ -0,0 +1,24 @@
+-- Failures of jobs of the variant queue (see api/src/variant_queue.rs), by message
+CREATE TABLE IF NOT EXISTS failed_jobs (
+    -- ID of the message of the queue
+    id TEXT PRIMARY KEY,
+    -- `kind` of the message: `variants` or `delete`
+    kind TEXT NOT NULL,
+    -- ID of the tenant, empty for the default tenant
+    tenant TEXT NOT NULL,
+    hash TEXT NOT NULL,
+    -- the message as JSON, sent again on retries
+    message TEXT NOT NULL,
+    -- error of the last attempt
+    error TEXT NOT NULL,
+    attempts INTEGER NOT NULL,
+    -- `retrying` while the queue retries it, `dead` once given up, and `retried` once sent again
+    -- by an operator. jobs succeeding on retries by the queue are removed
+    status TEXT NOT NULL,
+    -- unix time in milliseconds of the last failure
+    failed_at INTEGER NOT NULL,
+    -- unix time in milliseconds when sent again by an operator
+    retried_at INTEGER
+);
+
+CREATE INDEX IF NOT EXISTS idx_failed_jobs_status_failed_at ON failed_jobs (status, failed_at);
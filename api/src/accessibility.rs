@@ -0,0 +1,43 @@
+use worker::{Context, Headers, Request, Response, Result as WorkerResult, RouteContext};
+
+use upix_lib::{
+    accessibility::AccessibilityReport,
+    cache_policy::CacheRoute,
+    extract::{path_param, Hash},
+    tenant::Tenant,
+    ApiResult,
+};
+
+use crate::{export::load_original_image, request_tenant};
+
+/// Contrast ratios between the colors of the image which are next to each other most often,
+/// flagging palettes hard to tell apart. Reports are of the original image and thus immutable, so
+/// they are cached like variants.
+pub async fn handle_get_accessibility(
+    req: Request,
+    ctx: RouteContext<Context>,
+) -> WorkerResult<Response> {
+    let tenant = match request_tenant(&req, &ctx.env).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e.to_response(),
+    };
+    match get_accessibility(&ctx, &tenant).await {
+        Ok(report) => {
+            let mut headers = Headers::new();
+            tenant
+                .cache_policy(CacheRoute::Variant, &ctx.env)
+                .apply(&mut headers)?;
+            Response::from_json(&report).map(|r| r.with_headers(headers))
+        }
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn get_accessibility(
+    ctx: &RouteContext<Context>,
+    tenant: &Tenant,
+) -> ApiResult<AccessibilityReport> {
+    let Hash(hash) = path_param(ctx, "hash")?;
+    let img = load_original_image(ctx, tenant, &hash).await?;
+    Ok(AccessibilityReport::of(&img.to_rgba8()))
+}
@@ -0,0 +1,348 @@
+use serde::Serialize;
+use worker::{
+    console_error, console_log, Context, Date, Env, Request, Response, Result as WorkerResult,
+    RouteContext,
+};
+
+use upix_lib::{
+    auth::constant_time_eq,
+    bulk_delete::{
+        confirmation_token, BulkDeleteRequest, DeleteJob, Selection, BULK_DELETE_SECRET,
+        MAX_BULK_DELETE,
+    },
+    cache_epoch::{bump_cache_epoch, bump_image_epoch, CACHE_EPOCH_BINDING},
+    extract::{path_param, Hash},
+    forecast::{forecast, Forecast, StoredBytes, DAY_MS},
+    job_summary::{count_jobs, WindowCounts, SUMMARY_WINDOWS},
+    panic::panic_count,
+    stats::{fetch_scale_views, COUNTER_BINDING},
+    tenant::Tenant,
+    ApiError, ApiResult,
+};
+
+use crate::{
+    db::{db_error, get_db},
+    images::delete_image,
+    jobs::list_job_statuses,
+    request_tenant,
+    uploads::{find_upload_hashes, find_upload_origins, UploadOriginRow},
+    variant_queue::{
+        enqueue_deletes, list_dead_jobs, list_failed_job_statuses, retry_dead_job, DeadJob,
+    },
+};
+
+#[derive(Serialize)]
+pub struct Metrics {
+    /// Panics in this isolate. Counts are per isolate, scrape them over time to see trends.
+    pub panics: u64,
+}
+
+pub fn handle_get_metrics(_: Request, _: RouteContext<Context>) -> WorkerResult<Response> {
+    Response::from_json(&Metrics {
+        panics: panic_count(),
+    })
+}
+
+#[derive(Serialize)]
+struct CacheEpoch {
+    epoch: u64,
+}
+
+/// Bump the cache epoch, which invalidates all variants cached by the dyn worker.
+pub async fn handle_post_cache_epoch(
+    _: Request,
+    ctx: RouteContext<Context>,
+) -> WorkerResult<Response> {
+    match post_cache_epoch(&ctx).await {
+        Ok(epoch) => Response::from_json(&epoch),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn post_cache_epoch(ctx: &RouteContext<Context>) -> ApiResult<CacheEpoch> {
+    let Ok(kv) = ctx.kv(CACHE_EPOCH_BINDING) else {
+        console_error!("failed to get bindings to the cache epoch KV");
+        return Err(ApiError::no_msg(500));
+    };
+    let epoch = bump_cache_epoch(&kv).await.map_err(|e| {
+        console_error!("failed to bump cache epoch: {:?}", e);
+        ApiError::no_msg(500)
+    })?;
+    console_log!("bumped cache epoch to {}", epoch);
+    Ok(CacheEpoch { epoch })
+}
+
+#[derive(Serialize)]
+struct PurgeResult {
+    hash: String,
+    /// new cache epoch of the image
+    epoch: u64,
+}
+
+/// Purge all cached variants of the image from the edge cache, like after replacing the image,
+/// instead of waiting for them to expire: all of them in every namespace, scale, format and query
+/// are keyed by the epoch of the image, which is bumped (see `upix_lib::cache_epoch`).
+pub async fn handle_post_purge(_: Request, ctx: RouteContext<Context>) -> WorkerResult<Response> {
+    match post_purge(&ctx).await {
+        Ok(res) => Response::from_json(&res),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn post_purge(ctx: &RouteContext<Context>) -> ApiResult<PurgeResult> {
+    let Hash(hash) = path_param(ctx, "hash")?;
+    let epoch = purge_image(&ctx.env, &hash).await?;
+    console_log!("bumped cache epoch of {} to {}", hash, epoch);
+    Ok(PurgeResult { hash, epoch })
+}
+
+/// Invalidate all cached variants of the image by bumping its cache epoch, returning the new one.
+pub(crate) async fn purge_image(env: &Env, hash: &str) -> ApiResult<u64> {
+    let Ok(kv) = env.kv(CACHE_EPOCH_BINDING) else {
+        console_error!("failed to get bindings to the cache epoch KV");
+        return Err(ApiError::no_msg(500));
+    };
+    bump_image_epoch(&kv, hash).await.map_err(|e| {
+        console_error!("failed to bump cache epoch of {}: {:?}", hash, e);
+        ApiError::no_msg(500)
+    })
+}
+
+#[derive(Serialize)]
+struct UploadOrigins {
+    hash: String,
+    /// oldest first
+    origins: Vec<UploadOriginRow>,
+}
+
+/// Where, by whom and from where the image was uploaded, for abuse investigations.
+pub async fn handle_get_upload_origins(
+    _: Request,
+    ctx: RouteContext<Context>,
+) -> WorkerResult<Response> {
+    match get_upload_origins(&ctx).await {
+        Ok(origins) => Response::from_json(&origins),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn get_upload_origins(ctx: &RouteContext<Context>) -> ApiResult<UploadOrigins> {
+    let Hash(hash) = path_param(ctx, "hash")?;
+    let origins = find_upload_origins(&get_db(ctx)?, &hash).await?;
+    if origins.is_empty() {
+        return Err(ApiError::new(404, "Upload not found"));
+    }
+    Ok(UploadOrigins {
+        hash: hash.clone(),
+        origins,
+    })
+}
+
+/// Projected growth and cost of the storage, with suggestions on which scales to stop
+/// pre-generating (see `upix_lib::forecast`).
+pub async fn handle_get_forecast(_: Request, ctx: RouteContext<Context>) -> WorkerResult<Response> {
+    match get_forecast(&ctx).await {
+        Ok(forecast) => Response::from_json(&forecast),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn get_forecast(ctx: &RouteContext<Context>) -> ApiResult<Forecast> {
+    // variants of all uploads summed up by day and scale, so that the rows don't grow with them
+    let stored = get_db(ctx)?
+        .prepare(format!(
+            "SELECT CAST(uploaded_at / {} AS INTEGER) AS day, \
+             json_extract(v.value, '$.scale') AS scale, \
+             SUM(json_extract(v.value, '$.size')) AS bytes, COUNT(*) AS variants \
+             FROM uploads, json_each(uploads.variants) AS v GROUP BY day, scale",
+            DAY_MS
+        ))
+        .all()
+        .await
+        .map_err(db_error)?
+        .results::<StoredBytes>()
+        .map_err(db_error)?;
+
+    let Ok(ns) = ctx.durable_object(COUNTER_BINDING) else {
+        console_error!("failed to get bindings to the counter");
+        return Err(ApiError::no_msg(500));
+    };
+    let scale_views = fetch_scale_views(&ns).await.map_err(|e| {
+        console_error!("failed to fetch views by scale: {:?}", e);
+        ApiError::no_msg(500)
+    })?;
+    let today = Date::now().as_millis() / DAY_MS;
+    Ok(forecast(&stored, &scale_views, today))
+}
+
+/// Images selected for a bulk deletion, as responded by its dry run.
+#[derive(Serialize)]
+struct BulkDeletePlan {
+    /// hashes of the images which would be deleted
+    hashes: Vec<String>,
+    /// whether more images match the filter, which are left to the next round
+    more: bool,
+    /// pass as `confirm` to delete exactly these images
+    confirm: String,
+}
+
+/// Images queued for deletion by the execution of a bulk deletion.
+#[derive(Serialize)]
+struct BulkDeleteQueued {
+    /// hashes of the images to be deleted, which a dry run of the same selection no longer lists
+    /// once deleted
+    queued: Vec<String>,
+}
+
+enum BulkDeleteResponse {
+    DryRun(BulkDeletePlan),
+    /// The images are deleted after responding by the consumer of the queue.
+    Accepted(BulkDeleteQueued),
+}
+
+/// Delete images in the root namespace by their hashes or by a filter, in two passes (see
+/// `upix_lib::bulk_delete`).
+pub async fn handle_post_bulk_delete(
+    mut req: Request,
+    ctx: RouteContext<Context>,
+) -> WorkerResult<Response> {
+    match post_bulk_delete(&mut req, &ctx).await {
+        Ok(BulkDeleteResponse::DryRun(plan)) => Response::from_json(&plan),
+        Ok(BulkDeleteResponse::Accepted(queued)) => {
+            Response::from_json(&queued).map(|r| r.with_status(202))
+        }
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn post_bulk_delete(
+    req: &mut Request,
+    ctx: &RouteContext<Context>,
+) -> ApiResult<BulkDeleteResponse> {
+    let tenant = request_tenant(req, &ctx.env).await?;
+    let Ok(request) = req.json::<BulkDeleteRequest>().await else {
+        return Err(ApiError::new(400, "Invalid bulk delete request"));
+    };
+    let selection = request
+        .selection()
+        .map_err(|e| ApiError::new(400, e.to_string()))?;
+    let Ok(secret) = ctx.secret(BULK_DELETE_SECRET) else {
+        console_error!("{} is not configured", BULK_DELETE_SECRET);
+        return Err(ApiError::no_msg(500));
+    };
+    let (hashes, more) = match selection {
+        Selection::Hashes(hashes) => (hashes, false),
+        Selection::Filter(filter) => {
+            let limit = MAX_BULK_DELETE as u32 + 1;
+            let mut hashes =
+                find_upload_hashes(&get_db(ctx)?, &tenant.id, "", &filter, limit).await?;
+            let more = hashes.len() > MAX_BULK_DELETE;
+            hashes.truncate(MAX_BULK_DELETE);
+            (hashes, more)
+        }
+    };
+    let confirm = confirmation_token(secret.to_string().as_bytes(), &tenant.id, &hashes);
+    if request.dry_run {
+        return Ok(BulkDeleteResponse::DryRun(BulkDeletePlan {
+            hashes,
+            more,
+            confirm,
+        }));
+    }
+    let confirmed = request
+        .confirm
+        .is_some_and(|c| constant_time_eq(c.as_bytes(), confirm.as_bytes()));
+    if !confirmed {
+        return Err(ApiError::new(
+            409,
+            "The selection has changed since the dry run, run it again",
+        ));
+    }
+
+    console_log!("queueing deletions of {} images in bulk", hashes.len());
+    enqueue_deletes(&ctx.env, &tenant.id, &hashes).await?;
+    Ok(BulkDeleteResponse::Accepted(BulkDeleteQueued {
+        queued: hashes,
+    }))
+}
+
+/// Delete an image of a bulk deletion, consumed from the queue. Images which are already gone
+/// are done with.
+pub(crate) async fn delete_queued_image(env: &Env, job: &DeleteJob) -> ApiResult<()> {
+    let tenant = Tenant {
+        id: job.tenant.clone(),
+        ..Tenant::default()
+    };
+    match delete_image(env, &tenant, job.hash.clone()).await {
+        Ok(_) => {
+            console_log!("deleted {} in bulk", job.hash);
+            Ok(())
+        }
+        Err(e) if e.status() == 404 => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// IDs of messages of the queue are 32 hex digits, which this is loose about.
+fn is_valid_job_id(id: &str) -> bool {
+    (1..=64).contains(&id.len()) && id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+}
+
+/// Send the dead-lettered job of the variant queue again (see `variant_queue`).
+pub async fn handle_post_job_retry(
+    _: Request,
+    ctx: RouteContext<Context>,
+) -> WorkerResult<Response> {
+    match post_job_retry(&ctx).await {
+        Ok(()) => Ok(Response::empty()?.with_status(202)),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn post_job_retry(ctx: &RouteContext<Context>) -> ApiResult<()> {
+    let Some(id) = ctx.param("id").filter(|id| is_valid_job_id(id)) else {
+        return Err(ApiError::new(400, "Invalid job ID"));
+    };
+    let db = get_db(ctx)?;
+    retry_dead_job(&ctx.env, &db, id).await
+}
+
+/// Dead-lettered jobs listed in summaries of jobs.
+const MAX_LISTED_DEAD_JOBS: u32 = 20;
+
+#[derive(Serialize)]
+struct JobsSummary {
+    /// counts of jobs by status in each window, by when their states last changed
+    windows: Vec<WindowCounts>,
+    /// whether there were more upload jobs than were counted
+    truncated: bool,
+    /// most recently dead-lettered jobs of the queue, newest first
+    dead_jobs: Vec<DeadJob>,
+}
+
+/// Summarize asynchronous uploads and jobs of the variant queue (see `upix_lib::job_summary`).
+pub async fn handle_get_jobs(_: Request, ctx: RouteContext<Context>) -> WorkerResult<Response> {
+    match get_jobs(&ctx).await {
+        Ok(summary) => Response::from_json(&summary),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn get_jobs(ctx: &RouteContext<Context>) -> ApiResult<JobsSummary> {
+    let Ok(bucket) = ctx.bucket("IMGS_BUCKET") else {
+        console_error!("failed to get bindings to the R2 bucket");
+        return Err(ApiError::no_msg(500));
+    };
+    let db = get_db(ctx)?;
+    let now = Date::now().as_millis();
+    let longest_window = SUMMARY_WINDOWS.iter().map(|&(_, len)| len).max();
+    let since = now.saturating_sub(longest_window.unwrap_or_default());
+
+    let (mut statuses, truncated) = list_job_statuses(&bucket).await?;
+    statuses.extend(list_failed_job_statuses(&db, since).await?);
+    Ok(JobsSummary {
+        windows: count_jobs(&statuses, now),
+        truncated,
+        dead_jobs: list_dead_jobs(&db, MAX_LISTED_DEAD_JOBS).await?,
+    })
+}
@@ -0,0 +1,163 @@
+//! Annotations of images (see lib/src/annotation.rs) in the `annotations` table of the D1
+//! database.
+//!
+//! Images are in the root namespace, or in the one given by the `namespace` query param.
+//!
+//! Responses of the dyn worker with annotations drawn are cached by the digest of them, so
+//! replacing annotations needs no purge.
+
+use serde::{Deserialize, Serialize};
+use worker::{
+    console_error, wasm_bindgen::JsValue, Context, D1Database, Request, Response,
+    Result as WorkerResult, RouteContext,
+};
+
+use upix_lib::{
+    annotation::{validate_annotations, Annotation},
+    dimensions::Dimensions,
+    extract::{path_param, Hash},
+    tenant::Tenant,
+    ApiError, ApiResult,
+};
+
+use crate::{
+    db::{db_error, get_db},
+    direct_upload::query_namespace,
+    export::load_png_image,
+    request_tenant,
+};
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Annotations {
+    annotations: Vec<Annotation>,
+}
+
+/// Annotations of the image, in the order they were put.
+pub async fn handle_get_annotations(
+    req: Request,
+    ctx: RouteContext<Context>,
+) -> WorkerResult<Response> {
+    let tenant = match request_tenant(&req, &ctx.env).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e.to_response(),
+    };
+    match get_annotations(&req, &ctx, &tenant).await {
+        Ok(annotations) => Response::from_json(&Annotations { annotations }),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn get_annotations(
+    req: &Request,
+    ctx: &RouteContext<Context>,
+    tenant: &Tenant,
+) -> ApiResult<Vec<Annotation>> {
+    let Hash(hash) = path_param(ctx, "hash")?;
+    let namespace = query_namespace(req, ctx)?;
+    let db = get_db(ctx)?;
+    find_annotations(&db, &tenant.id, &namespace.name, &hash).await
+}
+
+/// Replace the annotations of the image. An empty list removes all of them.
+pub async fn handle_put_annotations(
+    mut req: Request,
+    ctx: RouteContext<Context>,
+) -> WorkerResult<Response> {
+    let tenant = match request_tenant(&req, &ctx.env).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e.to_response(),
+    };
+    match put_annotations(&mut req, &ctx, &tenant).await {
+        Ok(annotations) => Response::from_json(&Annotations { annotations }),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn put_annotations(
+    req: &mut Request,
+    ctx: &RouteContext<Context>,
+    tenant: &Tenant,
+) -> ApiResult<Vec<Annotation>> {
+    let Hash(hash) = path_param(ctx, "hash")?;
+    let namespace = query_namespace(req, ctx)?;
+    let Ok(Annotations { annotations }) = req.json().await else {
+        return Err(ApiError::new(400, "Invalid annotations request"));
+    };
+
+    let Ok(bucket) = ctx.bucket("IMGS_BUCKET") else {
+        console_error!("failed to get bindings to the R2 bucket");
+        return Err(ApiError::no_msg(500));
+    };
+    // annotations lie within the original image, which must exist
+    let img = load_png_image(&bucket, tenant, &namespace, &format!("{}.png", hash)).await?;
+    validate_annotations(&annotations, Dimensions::of(&img))
+        .map_err(|e| ApiError::new(400, e.to_string()))?;
+
+    let db = get_db(ctx)?;
+    replace_annotations(&db, &tenant.id, &namespace.name, &hash, &annotations).await?;
+    Ok(annotations)
+}
+
+/// Annotations of the image in the namespace of the tenant, in the order they were put.
+async fn find_annotations(
+    db: &D1Database,
+    tenant: &str,
+    namespace: &str,
+    hash: &str,
+) -> ApiResult<Vec<Annotation>> {
+    db.prepare(
+        "SELECT name, x, y, width, height FROM annotations \
+         WHERE tenant = ?1 AND namespace = ?2 AND hash = ?3 ORDER BY position",
+    )
+    .bind(&[
+        JsValue::from(tenant),
+        JsValue::from(namespace),
+        JsValue::from(hash),
+    ])
+    .map_err(db_error)?
+    .all()
+    .await
+    .map_err(db_error)?
+    .results::<Annotation>()
+    .map_err(db_error)
+}
+
+/// Replace the annotations of the image in the namespace of the tenant at once.
+async fn replace_annotations(
+    db: &D1Database,
+    tenant: &str,
+    namespace: &str,
+    hash: &str,
+    annotations: &[Annotation],
+) -> ApiResult<()> {
+    let delete = db
+        .prepare("DELETE FROM annotations WHERE tenant = ?1 AND namespace = ?2 AND hash = ?3")
+        .bind(&[
+            JsValue::from(tenant),
+            JsValue::from(namespace),
+            JsValue::from(hash),
+        ]);
+    let inserts = annotations.iter().enumerate().map(|(i, a)| {
+        db.prepare(
+            "INSERT INTO annotations (tenant, namespace, hash, name, x, y, width, height, position) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        )
+        .bind(&[
+            JsValue::from(tenant),
+            JsValue::from(namespace),
+            JsValue::from(hash),
+            JsValue::from(a.name.as_str()),
+            JsValue::from(a.x),
+            JsValue::from(a.y),
+            JsValue::from(a.width),
+            JsValue::from(a.height),
+            JsValue::from(i as u32),
+        ])
+    });
+    let stmts = std::iter::once(delete)
+        .chain(inserts)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(db_error)?;
+    db.batch(stmts).await.map_err(db_error)?;
+    Ok(())
+}
@@ -0,0 +1,117 @@
+//! API keys and their scopes.
+//!
+//! Valid keys are listed in the `UPLOAD_API_KEYS` secret (comma-separated), or stored in the
+//! `API_KEYS` KV namespace under `key:{SHA-256 of the key}` so that keys can be issued and revoked
+//! without redeploying. Values in KV are free-form (e.g. who the key was issued to), except that
+//! JSON objects with `scopes` limit what the key may do (see `upix_lib::auth::scopes_of_key_entry`),
+//! like `{"scopes": ["write"]}` for a key of a public kiosk which can upload but neither list nor
+//! delete images. Other keys, including those in the secret, have the `write` and `list` scopes.
+//! The `ADMIN_TOKEN` secret is a credential with the `admin` scope.
+//!
+//! Scopes required by routes are declared in `routes.rs`, and checked before requests are routed.
+
+use worker::{console_error, Env, Request};
+
+use upix_lib::{
+    auth::{
+        bearer_token, constant_time_eq, grants, is_listed_key, scopes_of_key_entry, Scope,
+        DEFAULT_SCOPES,
+    },
+    route_guard::{find_rule, RouteRule},
+    sha256_hex,
+    upload_token::is_upload_token,
+    ApiError, ApiResult,
+};
+
+use crate::uploads::Uploader;
+
+const API_KEYS_SECRET: &str = "UPLOAD_API_KEYS";
+const API_KEYS_BINDING: &str = "API_KEYS";
+const ADMIN_TOKEN_SECRET: &str = "ADMIN_TOKEN";
+
+/// KV entries of keys are cached at the edge for this long, so revocations take up to this long.
+const API_KEY_CACHE_TTL: u64 = 60;
+
+/// Check that the request carries a credential with the scope its route requires by the table.
+/// Upload tokens are let through to routes accepting them (the upload routes), whose handlers
+/// verify them.
+pub(crate) async fn authorize_request(
+    rules: &[RouteRule],
+    req: &Request,
+    env: &Env,
+) -> ApiResult<()> {
+    let Some(rule) = find_rule(rules, &req.method(), &req.path()) else {
+        return Ok(());
+    };
+    let Some(scope) = rule.scope else {
+        return Ok(());
+    };
+    if rule.upload_tokens && bearer_token(req).is_some_and(|t| is_upload_token(&t)) {
+        return Ok(());
+    }
+    require_scope(req, env, scope).await.map(|_| ())
+}
+
+/// Check that the request carries a valid API key (or the admin token) with the scope, and
+/// identify the uploader by it.
+///
+/// Responds with 401 if the key is missing, and 403 if it is invalid or lacks the scope.
+pub(crate) async fn require_scope(req: &Request, env: &Env, scope: Scope) -> ApiResult<Uploader> {
+    let Some(token) = bearer_token(req) else {
+        return Err(ApiError::new(401, "Missing API key"));
+    };
+    let Some(scopes) = credential_scopes(&token, env).await? else {
+        return Err(ApiError::new(403, "Invalid API key"));
+    };
+    if !grants(&scopes, scope) {
+        return Err(ApiError::new(
+            403,
+            format!("API key lacks the '{}' scope", scope),
+        ));
+    }
+    Ok(Uploader::api_key(&token))
+}
+
+/// Scopes of the credential, if it is valid.
+async fn credential_scopes(token: &str, env: &Env) -> ApiResult<Option<Vec<Scope>>> {
+    let admin_token = env.secret(ADMIN_TOKEN_SECRET).ok().map(|s| s.to_string());
+    let secret = env.secret(API_KEYS_SECRET).ok().map(|s| s.to_string());
+    let kv = env.kv(API_KEYS_BINDING).ok();
+    if admin_token.is_none() && secret.is_none() && kv.is_none() {
+        // fail closed, so that a misconfigured deployment doesn't accept anonymous requests
+        console_error!(
+            "API keys ({}, {} or {}) are not configured",
+            ADMIN_TOKEN_SECRET,
+            API_KEYS_SECRET,
+            API_KEYS_BINDING
+        );
+        return Err(ApiError::no_msg(500));
+    }
+
+    if admin_token.is_some_and(|t| constant_time_eq(token.as_bytes(), t.as_bytes())) {
+        return Ok(Some(vec![Scope::Admin]));
+    }
+    if secret.is_some_and(|list| is_listed_key(token, &list)) {
+        return Ok(Some(DEFAULT_SCOPES.to_vec()));
+    }
+    let Some(kv) = kv else {
+        return Ok(None);
+    };
+    let entry = kv
+        .get(&format!("key:{}", sha256_hex(token.as_bytes())))
+        .cache_ttl(API_KEY_CACHE_TTL)
+        .text()
+        .await
+        .map_err(|e| {
+            console_error!("failed to look up API key: {:?}", e);
+            ApiError::no_msg(500)
+        })?;
+    entry
+        .map(|entry| {
+            scopes_of_key_entry(&entry).map_err(|e| {
+                console_error!("{}", e);
+                ApiError::no_msg(500)
+            })
+        })
+        .transpose()
+}
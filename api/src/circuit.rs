@@ -0,0 +1,46 @@
+//! Rejecting uploads while the bucket is failing (see lib/src/circuit.rs).
+//!
+//! Like rate limiting, the breaker is skipped if it is not bound or fails, so that it never takes
+//! uploads down by itself.
+
+use worker::{console_error, console_log, Context, Date, Env, RouteContext};
+
+use upix_lib::{
+    circuit::{check_circuit, report_failure, CIRCUIT_BREAKER_BINDING},
+    ApiError, ApiResult,
+};
+
+/// Reject the upload with 503 if the circuit is open, before any work is done for it.
+pub async fn guard_bucket(ctx: &RouteContext<Context>) -> ApiResult<()> {
+    let Ok(ns) = ctx.durable_object(CIRCUIT_BREAKER_BINDING) else {
+        return Ok(());
+    };
+    match check_circuit(&ns, Date::now().as_millis()).await {
+        Ok(decision) => match decision.retry_after {
+            Some(retry_after) => Err(ApiError::new(
+                503,
+                format!(
+                    "Storage is unavailable. Retry after {} seconds",
+                    retry_after
+                ),
+            )
+            .with_header("Retry-After", retry_after.to_string())),
+            None => Ok(()),
+        },
+        Err(e) => {
+            console_error!("failed to check circuit breaker: {:?}", e);
+            Ok(())
+        }
+    }
+}
+
+/// Count a failed bucket operation against the circuit.
+pub async fn report_bucket_failure(env: &Env) {
+    let Ok(ns) = env.durable_object(CIRCUIT_BREAKER_BINDING) else {
+        return;
+    };
+    match report_failure(&ns, Date::now().as_millis()).await {
+        Ok(()) => console_log!("reported bucket failure to circuit breaker"),
+        Err(e) => console_error!("failed to report bucket failure: {:?}", e),
+    }
+}
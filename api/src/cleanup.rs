@@ -0,0 +1,247 @@
+//! Scheduled cleanup of inconsistent variant sets in the bucket (see lib/src/cleanup.rs).
+//!
+//! Each run of the cron trigger lists up to `MAX_PAGES` pages of the bucket from where the last
+//! run stopped, and repairs up to `MAX_REPAIRS` images. Images over the limit are left to the
+//! next pass over the bucket. Progress is kept in the bucket under `_cleanup/state.json`.
+//!
+//! Orphaned images are deleted the way `DELETE /images/{hash}` deletes images, along with their
+//! records and cached responses. Regenerated variants are stored like deferred ones (see
+//! lib/src/variant_queue.rs), and added to the manifest, the record and the index entry of the
+//! image.
+
+use worker::{console_error, console_log, Bucket, Date, Env, Result as WorkerResult};
+
+use upix_lib::{
+    blob::{BlobError, BlobStore},
+    cleanup::{CleanupState, ImageObjects, ListedObject},
+    manifest::load_manifest,
+    namespace::{find_namespace, Namespace},
+    pipeline::{StoreError, VariantStores},
+    schema::{find_versioned, parse_key_prefix},
+    tenant::Tenant,
+    upload_index::{delete_index_entry, upload_index},
+    variant::Variant,
+    variant_queue::{merge_uploaded, store_deferred_variants},
+    ApiError,
+};
+
+use crate::{
+    cold_bucket,
+    images::delete_namespaced_image,
+    index_upload,
+    uploads::{find_upload, update_upload_variants},
+};
+
+const STATE_KEY: &str = "_cleanup/state.json";
+
+/// Pages of listings (of up to 1000 objects) per run.
+const MAX_PAGES: usize = 10;
+
+/// Images repaired per run, which is bounded by regenerating variants.
+const MAX_REPAIRS: usize = 20;
+
+#[derive(Debug, thiserror::Error)]
+enum CleanupError {
+    #[error(transparent)]
+    Blob(#[from] BlobError),
+    #[error(transparent)]
+    Worker(#[from] worker::Error),
+    #[error(transparent)]
+    Store(#[from] StoreError),
+    #[error("failed with status {}", .0.status())]
+    Api(ApiError),
+}
+
+/// Scan the next pages of the bucket, and repair images found inconsistent.
+pub(crate) async fn clean_up_bucket(env: &Env) -> WorkerResult<()> {
+    let bucket = env.bucket("IMGS_BUCKET")?;
+    let mut state = load_state(&bucket).await;
+    let now = Date::now().as_millis();
+    let mut repairs = 0;
+    for _ in 0..MAX_PAGES {
+        let mut list = bucket.list();
+        if let Some(cursor) = &state.cursor {
+            list = list.cursor(cursor);
+        }
+        let listing = list.execute().await?;
+        let mut images: Vec<_> = listing
+            .objects()
+            .into_iter()
+            .filter_map(|obj| {
+                state.grouper.push(ListedObject {
+                    key: obj.key(),
+                    uploaded_at: obj.uploaded().as_millis(),
+                })
+            })
+            .collect();
+        state.cursor = listing.cursor().filter(|_| listing.truncated());
+        if state.cursor.is_none() {
+            images.extend(state.grouper.finish());
+        }
+
+        for img in images.iter().filter(|img| !img.is_recent(now)) {
+            if repairs >= MAX_REPAIRS {
+                break;
+            }
+            match repair_image(env, &bucket, img).await {
+                Ok(true) => repairs += 1,
+                Ok(false) => {}
+                Err(e) => console_error!("failed to repair {}{}: {}", img.key_prefix, img.hash, e),
+            }
+        }
+        if state.cursor.is_none() {
+            console_log!("finished a cleanup pass over the bucket");
+            break;
+        }
+    }
+    store_state(&bucket, &state).await
+}
+
+/// Delete the objects of the image if they are orphans, or regenerate its missing variants.
+/// Returns whether it was inconsistent.
+async fn repair_image(
+    env: &Env,
+    bucket: &Bucket,
+    img: &ImageObjects,
+) -> Result<bool, CleanupError> {
+    let Some((tenant, namespace)) = parse_key_prefix(&img.key_prefix) else {
+        return Ok(false);
+    };
+    let tenant = Tenant {
+        id: tenant.to_string(),
+        ..Tenant::default()
+    };
+    let namespace = Namespace {
+        name: namespace.to_string(),
+        ..Namespace::default()
+    };
+    if !img.orphans().is_empty() {
+        return delete_orphans(env, bucket, img, &tenant, &namespace).await;
+    }
+
+    let manifest = match img.has_manifest() {
+        true => load_manifest(bucket, &img.key_prefix, &img.hash).await?,
+        false => None,
+    };
+    let mut missing = img.missing_scales(manifest.as_ref());
+    let cold_bucket = cold_bucket(env);
+    // without manifests, variants missing from the bucket may be in cold storage
+    if let (None, Some(cold_bucket)) = (&manifest, &cold_bucket) {
+        let mut in_bucket = Vec::new();
+        for &scale in &missing {
+            let key = format!(
+                "{}{}",
+                img.key_prefix,
+                Variant::Upscaled(scale).file_name(&img.hash)
+            );
+            if cold_bucket.head(key).await?.is_none() {
+                in_bucket.push(scale);
+            }
+        }
+        missing = in_bucket;
+    }
+    if missing.is_empty() {
+        return Ok(false);
+    }
+
+    let name = (!namespace.name.is_empty()).then_some(namespace.name.as_str());
+    let Some(namespace) = find_namespace(env, name) else {
+        console_error!(
+            "namespace of {}{} is no longer configured",
+            img.key_prefix,
+            img.hash
+        );
+        return Ok(false);
+    };
+    let stores = VariantStores {
+        store: bucket,
+        cold_store: cold_bucket.as_deref(),
+    };
+    let limits = &namespace.limits;
+    let stored =
+        store_deferred_variants(stores, &img.key_prefix, &img.hash, &missing, limits).await?;
+    let Some(stored) = stored else {
+        // deleted since listed
+        return Ok(false);
+    };
+    console_log!(
+        "regenerated variants of {}{} at scales {:?}",
+        img.key_prefix,
+        img.hash,
+        missing
+    );
+
+    // the record and the index entry list the regenerated variants again, unless the record is of
+    // the image in another key layout (or there is none, for images stored before records)
+    let record = find_upload(&env.d1("DB")?, &tenant.id, &namespace.name, &img.hash)
+        .await
+        .map_err(CleanupError::Api)?;
+    if let Some(record) = record.filter(|r| r.key_prefix == img.key_prefix) {
+        let mut images = record.variants();
+        merge_uploaded(&mut images, stored);
+        update_upload_variants(env, &tenant.id, &namespace.name, &img.hash, &images).await;
+        index_upload(env, &img.key_prefix, &img.hash, &images, limits).await;
+    }
+    Ok(true)
+}
+
+/// Delete the orphaned objects of the image as deletions of images do, along with its record and
+/// its cached responses. If the image is stored whole in another key layout, only the orphans are
+/// deleted.
+async fn delete_orphans(
+    env: &Env,
+    bucket: &Bucket,
+    img: &ImageObjects,
+    tenant: &Tenant,
+    namespace: &Namespace,
+) -> Result<bool, CleanupError> {
+    let orphans = img.orphans();
+    // unindexed first, so that uploads are never deduplicated against deleted objects
+    if let Some(kv) = upload_index(env) {
+        delete_index_entry(&kv, &img.key_prefix, &img.hash).await?;
+    }
+    let original = Variant::Original.file_name(&img.hash);
+    if find_versioned(bucket, tenant, namespace, &original)
+        .await?
+        .is_some()
+    {
+        for name in orphans {
+            bucket.delete(format!("{}{}", img.key_prefix, name)).await?;
+        }
+    } else {
+        match delete_namespaced_image(env, tenant, namespace, img.hash.clone()).await {
+            Ok(_) => {}
+            // deleted since listed
+            Err(e) if e.status() == 404 => return Ok(false),
+            Err(e) => return Err(CleanupError::Api(e)),
+        }
+    }
+    console_log!(
+        "deleted orphaned objects of {}{}: {:?}",
+        img.key_prefix,
+        img.hash,
+        orphans
+    );
+    Ok(true)
+}
+
+/// The state of the scan, or a new one if there is none (or it is unreadable).
+async fn load_state(bucket: &Bucket) -> CleanupState {
+    let data = match bucket.load(STATE_KEY).await {
+        Ok(data) => data,
+        Err(e) => {
+            console_error!("failed to load cleanup state: {}", e);
+            None
+        }
+    };
+    data.and_then(|data| serde_json::from_slice(&data).ok())
+        .unwrap_or_default()
+}
+
+async fn store_state(bucket: &Bucket, state: &CleanupState) -> WorkerResult<()> {
+    bucket
+        .put(STATE_KEY, serde_json::to_vec(state)?)
+        .execute()
+        .await?;
+    Ok(())
+}
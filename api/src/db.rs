@@ -0,0 +1,15 @@
+use worker::{console_error, Context, D1Database, RouteContext};
+
+use upix_lib::{ApiError, ApiResult};
+
+pub(crate) fn get_db(ctx: &RouteContext<Context>) -> ApiResult<D1Database> {
+    ctx.d1("DB").map_err(|_| {
+        console_error!("failed to get bindings to the D1 database");
+        ApiError::no_msg(500)
+    })
+}
+
+pub(crate) fn db_error(e: worker::Error) -> ApiError {
+    console_error!("failed to query the D1 database: {:?}", e);
+    ApiError::no_msg(500)
+}
@@ -0,0 +1,274 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use worker::{
+    console_error, console_log, wasm_bindgen::JsValue, Bucket, Context, D1Database, Date,
+    HttpMetadata, Request, Response, Result as WorkerResult, RouteContext,
+};
+
+use upix_lib::{
+    encode_png,
+    extract::{path_param, Hash},
+    namespace::{root_limits, Namespace},
+    pipeline::validate_img,
+    schema::KeySchema,
+    tenant::Tenant,
+    transform::{apply_transform, TransformOp},
+    ApiError, ApiResult,
+};
+
+use crate::{
+    db::{db_error, get_db},
+    export::load_png_image,
+    hash_algorithm, request_tenant,
+};
+
+/// Max number of transform specs in a request.
+const MAX_DERIVE_SPECS: usize = 8;
+
+/// Custom metadata of derived images, linking them to the parent.
+const PARENT_METADATA_KEY: &str = "parent";
+const TRANSFORM_METADATA_KEY: &str = "transform";
+
+#[derive(Debug, Deserialize)]
+struct DeriveRequest {
+    /// Each spec is a list of operations, and derives an image.
+    transforms: Vec<Vec<TransformOp>>,
+}
+
+#[derive(Debug, Serialize)]
+struct DerivedImage {
+    hash: String,
+    name: String,
+    width: u32,
+    height: u32,
+    transform: Vec<TransformOp>,
+}
+
+#[derive(Debug, Serialize)]
+struct DeriveResult {
+    parent: String,
+    derived: Vec<DerivedImage>,
+}
+
+pub async fn handle_post_derive(
+    mut req: Request,
+    ctx: RouteContext<Context>,
+) -> WorkerResult<Response> {
+    let tenant = match request_tenant(&req, &ctx.env).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e.to_response(),
+    };
+    match post_derive(&mut req, &ctx, &tenant).await {
+        Ok(res) => Response::from_json(&res),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn post_derive(
+    req: &mut Request,
+    ctx: &RouteContext<Context>,
+    tenant: &Tenant,
+) -> ApiResult<DeriveResult> {
+    let Hash(parent_hash) = path_param(ctx, "hash")?;
+    let Ok(DeriveRequest { transforms }) = req.json().await else {
+        return Err(ApiError::new(400, "Invalid derive request"));
+    };
+    if transforms.is_empty() || transforms.len() > MAX_DERIVE_SPECS {
+        return Err(ApiError::new(
+            400,
+            format!("'transforms' must have 1..={} specs", MAX_DERIVE_SPECS),
+        ));
+    }
+
+    let Ok(bucket) = ctx.bucket("IMGS_BUCKET") else {
+        console_error!("failed to get bindings to the R2 bucket");
+        return Err(ApiError::no_msg(500));
+    };
+    let db = get_db(ctx)?;
+    let parent = load_png_image(
+        &bucket,
+        tenant,
+        &Namespace::default(),
+        &format!("{}.png", parent_hash),
+    )
+    .await?;
+    let limits = root_limits(&ctx.env);
+
+    let mut derived = Vec::new();
+    for ops in transforms {
+        let img = apply_transform(&parent, &ops, &limits)
+            .map_err(|e| ApiError::new(400, e.to_string()))?;
+        validate_img(&img, &limits)?;
+
+        let mut img_data = Vec::new();
+        encode_png(&img, &mut img_data, true).map_err(|e| {
+            console_error!("failed to encode image: {:?}", e);
+            ApiError::no_msg(500)
+        })?;
+        let hash = hash_algorithm(&ctx.env).hash_hex(&img_data);
+        let name = format!("{}.png", hash);
+        store_derived_image(
+            &bucket,
+            &format!("{}{}", KeySchema::CURRENT.tenant_prefix(tenant), name),
+            img_data,
+            &parent_hash,
+            &ops,
+        )
+        .await?;
+        record_derivation(&db, tenant, &parent_hash, &hash, &ops).await?;
+
+        derived.push(DerivedImage {
+            hash,
+            name,
+            width: img.width(),
+            height: img.height(),
+            transform: ops,
+        });
+    }
+    Ok(DeriveResult {
+        parent: parent_hash,
+        derived,
+    })
+}
+
+/// Store the derived image unless it is already stored, as images are content-addressed.
+async fn store_derived_image(
+    bucket: &Bucket,
+    key: &str,
+    img_data: Vec<u8>,
+    parent_hash: &str,
+    ops: &[TransformOp],
+) -> ApiResult<()> {
+    let exists = bucket.head(key).await.map_err(|e| {
+        console_error!("failed to get object metadata from the bucket: {:?}", e);
+        ApiError::no_msg(500)
+    })?;
+    if exists.is_some() {
+        console_log!("derived image already exists: {}", key);
+        return Ok(());
+    }
+
+    let meta = HttpMetadata {
+        content_type: Some("image/png".to_string()),
+        ..HttpMetadata::default()
+    };
+    let custom_meta = HashMap::from([
+        (PARENT_METADATA_KEY.to_string(), parent_hash.to_string()),
+        (
+            TRANSFORM_METADATA_KEY.to_string(),
+            serde_json::to_string(ops).unwrap(),
+        ),
+    ]);
+    bucket
+        .put(key, img_data)
+        .http_metadata(meta)
+        .custom_metadata(custom_meta)
+        .execute()
+        .await
+        .map_err(|e| {
+            console_error!("failed to upload image to the bucket: {:?}", e);
+            ApiError::no_msg(500)
+        })?;
+    Ok(())
+}
+
+/// Record the parent→derived relationship. Recording the same one again is a no-op.
+async fn record_derivation(
+    db: &D1Database,
+    tenant: &Tenant,
+    parent_hash: &str,
+    hash: &str,
+    ops: &[TransformOp],
+) -> ApiResult<()> {
+    db.prepare(
+        "INSERT OR IGNORE INTO derivations (tenant, parent, child, transform, created_at) \
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+    )
+    .bind(&[
+        JsValue::from(tenant.id.as_str()),
+        JsValue::from(parent_hash),
+        JsValue::from(hash),
+        JsValue::from(serde_json::to_string(ops).unwrap()),
+        JsValue::from(Date::now().as_millis() as f64),
+    ])
+    .map_err(db_error)?
+    .run()
+    .await
+    .map_err(db_error)?;
+    Ok(())
+}
+
+/// Max number of derivatives listed.
+const MAX_DERIVATIVES: u32 = 500;
+
+#[derive(Debug, Deserialize)]
+struct DerivationRow {
+    child: String,
+    transform: String,
+    created_at: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct Derivative {
+    hash: String,
+    transform: serde_json::Value,
+    created_at: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct DerivativeList {
+    parent: String,
+    derivatives: Vec<Derivative>,
+}
+
+pub async fn handle_get_derivatives(
+    req: Request,
+    ctx: RouteContext<Context>,
+) -> WorkerResult<Response> {
+    let tenant = match request_tenant(&req, &ctx.env).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e.to_response(),
+    };
+    match get_derivatives(&ctx, &tenant).await {
+        Ok(list) => Response::from_json(&list),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn get_derivatives(
+    ctx: &RouteContext<Context>,
+    tenant: &Tenant,
+) -> ApiResult<DerivativeList> {
+    let Hash(parent_hash) = path_param(ctx, "hash")?;
+    let db = get_db(ctx)?;
+    let rows = db
+        .prepare(
+            "SELECT child, transform, created_at FROM derivations \
+             WHERE tenant = ?1 AND parent = ?2 ORDER BY id LIMIT ?3",
+        )
+        .bind(&[
+            JsValue::from(tenant.id.as_str()),
+            JsValue::from(parent_hash.as_str()),
+            JsValue::from(MAX_DERIVATIVES),
+        ])
+        .map_err(db_error)?
+        .all()
+        .await
+        .map_err(db_error)?
+        .results::<DerivationRow>()
+        .map_err(db_error)?;
+
+    let derivatives = rows
+        .into_iter()
+        .map(|r| Derivative {
+            hash: r.child,
+            transform: serde_json::from_str(&r.transform).unwrap_or_default(),
+            created_at: r.created_at,
+        })
+        .collect();
+    Ok(DerivativeList {
+        parent: parent_hash,
+        derivatives,
+    })
+}
@@ -0,0 +1,317 @@
+//! Direct uploads to the bucket, which bypass the request body limit of workers.
+//!
+//! 1. `POST /images/uploads` issues a pre-signed URL to `PUT` image data to a staging area.
+//! 2. The client uploads the data to the URL.
+//! 3. `POST /images/commit` promotes the staged image through the same pipeline as `POST /` with
+//!    the default mode (see `UploadTask`). The staged object is removed once committed.
+//!
+//! Objects that are staged but never committed stay under `_staging/`, so the bucket should have
+//! a lifecycle rule to expire objects with that prefix.
+
+use image::ImageFormat;
+use serde::{Deserialize, Serialize};
+use worker::{
+    console_error, send::SendWrapper, Bucket, Context, Date, Env, Request, Response,
+    Result as WorkerResult, RouteContext,
+};
+
+use upix_lib::{
+    auth::Scope,
+    error_code::ErrorCode,
+    namespace::{find_namespace, Limits, Namespace},
+    pipeline::{accepts_upload_result, parse_scales, UploadResult},
+    presign::R2Config,
+    schema::KeySchema,
+    tags::normalize_tags,
+    tenant::Tenant,
+    ApiError, ApiResult,
+};
+
+use crate::{
+    api_key::require_scope,
+    circuit::{guard_bucket, report_bucket_failure},
+    cold_bucket, context_handle,
+    rate_limit::limit_upload_rate,
+    request_tenant, upload_result_response,
+    uploads::{UploadClient, UploadOrigin},
+    validate_img_format, UploadMode, UploadSource, UploadTask,
+};
+
+/// Pre-signed URLs are valid for this many seconds.
+const UPLOAD_URL_TTL_SECS: u32 = 15 * 60;
+
+/// Prefix of keys of staged objects. Can't collide with namespaces or tenants, whose names
+/// never contain `_`.
+const STAGING_PREFIX: &str = "_staging/";
+
+#[derive(Debug, Deserialize)]
+struct UploadUrlRequest {
+    content_type: String,
+    /// size of the image data in bytes
+    size: usize,
+    #[serde(default)]
+    namespace: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct UploadUrl {
+    /// pass to `POST /images/commit` after uploading
+    upload_id: String,
+    url: String,
+    method: &'static str,
+    /// headers to send with the upload as is
+    headers: UploadHeaders,
+    /// unix time in milliseconds
+    expires_at: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct UploadHeaders {
+    #[serde(rename = "Content-Type")]
+    content_type: String,
+    #[serde(rename = "Content-Length")]
+    content_length: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct CommitRequest {
+    upload_id: String,
+    /// must be the same as the one the upload URL was issued for
+    #[serde(default)]
+    namespace: Option<String>,
+    /// scales of variants to generate, like `2,4`, instead of the default ladder
+    #[serde(default)]
+    scales: Option<String>,
+    #[serde(default)]
+    tags: Option<Vec<String>>,
+}
+
+fn staged_key(tenant: &Tenant, namespace: &Namespace, upload_id: &str) -> String {
+    format!(
+        "{}{}{}{}",
+        STAGING_PREFIX,
+        tenant.key_prefix(),
+        namespace.key_prefix(),
+        upload_id
+    )
+}
+
+pub(crate) fn is_valid_upload_id(s: &str) -> bool {
+    s.len() == 32 && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+pub(crate) fn new_upload_id() -> ApiResult<String> {
+    let mut id = [0u8; 16];
+    getrandom::getrandom(&mut id).map_err(|e| {
+        console_error!("failed to generate upload ID: {:?}", e);
+        ApiError::no_msg(500)
+    })?;
+    Ok(hex::encode(id))
+}
+
+pub(crate) fn namespace_by_name(
+    ctx: &RouteContext<Context>,
+    name: Option<&str>,
+) -> ApiResult<Namespace> {
+    find_namespace(&ctx.env, name).ok_or_else(|| {
+        ApiError::new(404, "Unknown namespace").with_code(ErrorCode::UnknownNamespace)
+    })
+}
+
+/// The namespace given by the `namespace` query param, or the root one.
+pub(crate) fn query_namespace(req: &Request, ctx: &RouteContext<Context>) -> ApiResult<Namespace> {
+    let url = req.url().map_err(|_| ApiError::no_msg(500))?;
+    let name = url
+        .query_pairs()
+        .find(|(k, _)| k == "namespace")
+        .map(|(_, v)| v.into_owned());
+    namespace_by_name(ctx, name.as_deref())
+}
+
+pub async fn handle_post_upload_url(
+    mut req: Request,
+    ctx: RouteContext<Context>,
+) -> WorkerResult<Response> {
+    let tenant = match request_tenant(&req, &ctx.env).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e.to_response(),
+    };
+    match post_upload_url(&mut req, &ctx, &tenant).await {
+        Ok(url) => Response::from_json(&url),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn post_upload_url(
+    req: &mut Request,
+    ctx: &RouteContext<Context>,
+    tenant: &Tenant,
+) -> ApiResult<UploadUrl> {
+    require_scope(req, &ctx.env, Scope::Write).await?;
+
+    let Ok(UploadUrlRequest {
+        content_type,
+        size,
+        namespace,
+    }) = req.json().await
+    else {
+        return Err(ApiError::new(400, "Invalid upload URL request"));
+    };
+    let namespace = namespace_by_name(ctx, namespace.as_deref())?;
+    let limits = &namespace.limits;
+    validate_img_format(&content_type, limits)?;
+    if size > limits.max_data_len {
+        return Err(ApiError::new(413, "Too large image data"));
+    }
+
+    let Some(r2) = R2Config::from_env(&ctx.env) else {
+        console_error!("S3 API access to the bucket is not configured");
+        return Err(ApiError::no_msg(500));
+    };
+    let upload_id = new_upload_id()?;
+    let now_ms = Date::now().as_millis();
+    let url = r2.presign_put_url(
+        &staged_key(tenant, &namespace, &upload_id),
+        &content_type,
+        size,
+        now_ms / 1000,
+        UPLOAD_URL_TTL_SECS,
+    );
+    Ok(UploadUrl {
+        upload_id,
+        url,
+        method: "PUT",
+        headers: UploadHeaders {
+            content_type,
+            content_length: size.to_string(),
+        },
+        expires_at: now_ms + u64::from(UPLOAD_URL_TTL_SECS) * 1000,
+    })
+}
+
+pub async fn handle_post_commit(
+    mut req: Request,
+    ctx: RouteContext<Context>,
+) -> WorkerResult<Response> {
+    let tenant = match request_tenant(&req, &ctx.env).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e.to_response(),
+    };
+    let full = accepts_upload_result(req.headers().get("Accept").ok().flatten().as_deref());
+    match post_commit(&mut req, &ctx, &tenant).await {
+        Ok(result) => upload_result_response(&result, full),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn post_commit(
+    req: &mut Request,
+    ctx: &RouteContext<Context>,
+    tenant: &Tenant,
+) -> ApiResult<UploadResult> {
+    let uploader = require_scope(req, &ctx.env, Scope::Write).await?;
+    limit_upload_rate(req, ctx).await?;
+    guard_bucket(ctx).await?;
+
+    let Ok(CommitRequest {
+        upload_id,
+        namespace,
+        scales,
+        tags,
+    }) = req.json().await
+    else {
+        return Err(ApiError::new(400, "Invalid commit request"));
+    };
+    if !is_valid_upload_id(&upload_id) {
+        return Err(ApiError::new(400, "Invalid upload ID"));
+    }
+    let namespace = namespace_by_name(ctx, namespace.as_deref())?;
+    let scales = scales
+        .map(|s| parse_scales(&s, &namespace.limits))
+        .transpose()?;
+    let tags =
+        normalize_tags(tags.unwrap_or_default()).map_err(|e| ApiError::new(400, e.to_string()))?;
+
+    let Ok(bucket) = ctx.bucket("IMGS_BUCKET") else {
+        console_error!("failed to get bindings to the R2 bucket");
+        return Err(ApiError::no_msg(500));
+    };
+    let key = staged_key(tenant, &namespace, &upload_id);
+    let res = async {
+        let (img_data, img_fmt) =
+            load_staged_image(&bucket, &key, &namespace.limits, &ctx.env).await?;
+        let task = UploadTask {
+            mode: UploadMode::Default,
+            quantize_colors: None,
+            color_key: None,
+            scales,
+            tags,
+            source: UploadSource::Data(img_data, img_fmt),
+            derivable: namespace.name.is_empty(),
+            origin: UploadOrigin {
+                tenant: tenant.id.clone(),
+                namespace: namespace.name.clone(),
+                key_prefix: KeySchema::CURRENT.key_prefix(tenant, &namespace),
+                uploader: Some(uploader),
+                client: UploadClient::of_request(req, &ctx.env),
+            },
+            token: None,
+            public_base_url: tenant.public_base_url(&ctx.env),
+            limits: namespace.limits.clone(),
+            bucket: SendWrapper::new(bucket.clone()),
+            cold_bucket: cold_bucket(&ctx.env),
+            env: ctx.env.clone(),
+            ctx: context_handle(&ctx.data),
+        };
+        task.run().await
+    }
+    .await;
+
+    // the staged image is consumed unless the commit can be retried
+    let consumed = match &res {
+        Ok(_) => true,
+        Err(e) => (400..500).contains(&e.status()),
+    };
+    if consumed {
+        if let Err(e) = bucket.delete(&key).await {
+            console_error!("failed to delete staged object: {:?}", e);
+        }
+    }
+    res
+}
+
+/// Data and format of the staged image, which are validated by the pipeline of `POST /` like
+/// uploaded ones.
+async fn load_staged_image(
+    bucket: &Bucket,
+    key: &str,
+    limits: &Limits,
+    env: &Env,
+) -> ApiResult<(Vec<u8>, ImageFormat)> {
+    let obj = match bucket.get(key).execute().await {
+        Ok(obj) => obj.ok_or_else(|| ApiError::new(404, "Upload not found"))?,
+        Err(e) => {
+            console_error!("failed to get object from the bucket: {:?}", e);
+            report_bucket_failure(env).await;
+            return Err(ApiError::no_msg(500));
+        }
+    };
+
+    // the signed Content-Length should prevent this, but the object is what it is
+    if obj.size() as usize > limits.max_data_len {
+        return Err(ApiError::new(413, "Too large image data"));
+    }
+    let Some(content_type) = obj.http_metadata().content_type else {
+        return Err(ApiError::new(400, "Missing Content-Type of the upload"));
+    };
+    let img_fmt = validate_img_format(&content_type, limits)?;
+    let Some(body) = obj.body() else {
+        console_error!("staged object has no body: {}", key);
+        return Err(ApiError::no_msg(500));
+    };
+    let img_data = body.bytes().await.map_err(|e| {
+        console_error!("failed to read object data: {:?}", e);
+        ApiError::no_msg(500)
+    })?;
+    Ok((img_data, img_fmt))
+}
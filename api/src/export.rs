@@ -0,0 +1,202 @@
+use image::{DynamicImage, ImageFormat};
+use serde_json::Value;
+use worker::{
+    console_error, console_log, Bucket, Cache, Context, Headers, Request, Response,
+    Result as WorkerResult, RouteContext,
+};
+
+use upix_lib::{
+    cache_policy::CacheRoute,
+    dimensions::Dimensions,
+    emoji::{render_emoji, MAX_EMOJI_DATA_LEN},
+    encode_image,
+    engine::{import_descriptor, Engine, EngineFile, DEFAULT_PIXELS_PER_UNIT, MAX_PIXELS_PER_UNIT},
+    extract::{path_param, Hash},
+    namespace::{root_limits, Namespace},
+    schema::load_versioned,
+    tenant::Tenant,
+    ApiError, ApiResult,
+};
+
+use crate::request_tenant;
+
+/// Load the original image with the hash in the root namespace of the tenant from the bucket.
+pub(crate) async fn load_original_image(
+    ctx: &RouteContext<Context>,
+    tenant: &Tenant,
+    hash: &str,
+) -> ApiResult<DynamicImage> {
+    let Ok(bucket) = ctx.bucket("IMGS_BUCKET") else {
+        console_error!("failed to get bindings to the R2 bucket");
+        return Err(ApiError::no_msg(500));
+    };
+    load_png_image(
+        &bucket,
+        tenant,
+        &Namespace::default(),
+        &format!("{}.png", hash),
+    )
+    .await
+}
+
+/// Load the PNG image with the file name in the tenant's namespace from the bucket, in the newest
+/// key layout it is stored in.
+pub(crate) async fn load_png_image(
+    bucket: &Bucket,
+    tenant: &Tenant,
+    namespace: &Namespace,
+    file_name: &str,
+) -> ApiResult<DynamicImage> {
+    let img_data = load_versioned(bucket, tenant, namespace, file_name)
+        .await
+        .map_err(|e| {
+            console_error!("failed to fetch image from the bucket: {}", e);
+            ApiError::no_msg(500)
+        })?
+        .ok_or_else(|| ApiError::new(404, "Image not found"))?;
+    image::load_from_memory_with_format(&img_data, ImageFormat::Png).map_err(|e| {
+        console_error!("failed to decode image from memory: {:?}", e);
+        ApiError::no_msg(500)
+    })
+}
+
+/// Load the data of the object at the key from the bucket.
+pub(crate) async fn load_object_data(bucket: &Bucket, key: &str) -> ApiResult<Vec<u8>> {
+    bucket
+        .get(key)
+        .execute()
+        .await
+        .map_err(|e| {
+            console_error!("failed to fetch image from the bucket: {:?}", e);
+            ApiError::no_msg(500)
+        })?
+        .ok_or_else(|| ApiError::new(404, "Image not found"))?
+        .body()
+        .ok_or_else(|| {
+            console_error!("object doesn't have body");
+            ApiError::no_msg(500)
+        })?
+        .bytes()
+        .await
+        .map_err(|e| {
+            console_error!("failed to read object body: {:?}", e);
+            ApiError::no_msg(500)
+        })
+}
+
+pub async fn handle_get_emoji(req: Request, ctx: RouteContext<Context>) -> WorkerResult<Response> {
+    let tenant = match request_tenant(&req, &ctx.env).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e.to_response(),
+    };
+    let cache = Cache::default();
+    match cache.get(&req, false).await {
+        Ok(Some(resp)) => {
+            console_log!("cache hit: {}", req.path());
+            return Ok(resp);
+        }
+        Ok(None) => {}
+        Err(e) => console_error!("failed to match request against cache: {:?}", e),
+    }
+
+    let cache_policy = tenant.cache_policy(CacheRoute::Variant, &ctx.env);
+    let resp = match get_emoji(&ctx, &tenant).await {
+        Ok(img_data) => {
+            let mut headers: Headers = [("Content-Type", "image/png")].iter().collect();
+            cache_policy.apply(&mut headers)?;
+            let mut resp = Response::from_bytes(img_data)?.with_headers(headers);
+            if let Err(e) = cache.put(&req, resp.cloned()?).await {
+                console_error!("failed to cache response: {:?}", e);
+            }
+            Ok(resp)
+        }
+        Err(e) => e.to_response(),
+    };
+    resp
+}
+
+async fn get_emoji(ctx: &RouteContext<Context>, tenant: &Tenant) -> ApiResult<Vec<u8>> {
+    let Hash(hash) = path_param(ctx, "hash")?;
+    let img = load_original_image(ctx, tenant, &hash).await?;
+
+    let emoji = render_emoji(&img);
+    let mut img_data = Vec::new();
+    encode_image(&emoji, ImageFormat::Png, &mut img_data).map_err(|e| {
+        console_error!("failed to encode image: {:?}", e);
+        ApiError::no_msg(500)
+    })?;
+    if img_data.len() > MAX_EMOJI_DATA_LEN {
+        return Err(ApiError::new(
+            422,
+            "Image can't be made into an emoji within the size limit",
+        ));
+    }
+    Ok(img_data)
+}
+
+pub async fn handle_get_engine_descriptor(
+    req: Request,
+    ctx: RouteContext<Context>,
+) -> WorkerResult<Response> {
+    let tenant = match request_tenant(&req, &ctx.env).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e.to_response(),
+    };
+    let resp = match get_engine_descriptor(&req, &ctx, &tenant).await {
+        Ok(descriptor) => Response::from_json(&descriptor),
+        Err(e) => e.to_response(),
+    };
+    resp
+}
+
+async fn get_engine_descriptor(
+    req: &Request,
+    ctx: &RouteContext<Context>,
+    tenant: &Tenant,
+) -> ApiResult<Value> {
+    let Hash(hash) = path_param(ctx, "hash")?;
+    let Some(engine) = ctx.param("engine").and_then(|e| Engine::from_name(e)) else {
+        return Err(ApiError::new(404, "Unsupported engine"));
+    };
+    let Ok(url) = req.url() else {
+        return Err(ApiError::no_msg(500));
+    };
+    let mut pixels_per_unit = DEFAULT_PIXELS_PER_UNIT;
+    if let Some((_, v)) = url.query_pairs().find(|(k, _)| k == "ppu") {
+        pixels_per_unit = v
+            .parse()
+            .ok()
+            .filter(|p| (1..=MAX_PIXELS_PER_UNIT).contains(p))
+            .ok_or_else(|| {
+                ApiError::new(400, format!("'ppu' must be in 1..={}", MAX_PIXELS_PER_UNIT))
+            })?;
+    }
+    let Some(base_url) = tenant.public_base_url(&ctx.env) else {
+        console_error!("PUBLIC_BASE_URL is not configured");
+        return Err(ApiError::no_msg(500));
+    };
+    let base_url = base_url.trim_end_matches('/');
+
+    let img = load_original_image(ctx, tenant, &hash).await?;
+    let dims = Dimensions::of(&img);
+    let files: Vec<_> = root_limits(&ctx.env)
+        .pregenerated_scales(dims.long_side())
+        .into_iter()
+        .map(|scale| {
+            let name = if scale == 1 {
+                format!("{}.png", hash)
+            } else {
+                format!("{}_{}x.png", hash, scale)
+            };
+            let scaled = dims.saturating_scale(scale);
+            EngineFile {
+                scale,
+                width: scaled.width,
+                height: scaled.height,
+                url: format!("{}/{}", base_url, name),
+            }
+        })
+        .collect();
+
+    Ok(import_descriptor(engine, pixels_per_unit, &files))
+}
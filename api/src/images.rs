@@ -0,0 +1,535 @@
+use std::io::Cursor;
+
+use image::{codecs::png::PngDecoder, ImageDecoder};
+use serde::{Deserialize, Serialize};
+use worker::{
+    console_error, Bucket, Context, Env, Include, Request, Response, Result as WorkerResult,
+    RouteContext,
+};
+
+use upix_lib::{
+    dimensions::Dimensions,
+    extract::{path_param, query, Hash},
+    is_valid_hash,
+    manifest::manifest_file_name,
+    namespace::Namespace,
+    schema::KeySchema,
+    tags::is_valid_tag,
+    tenant::Tenant,
+    upload_index::{delete_index_entry, upload_index},
+    variant::Variant,
+    ApiError, ApiResult,
+};
+
+use crate::{
+    admin::purge_image,
+    cold_bucket,
+    db::get_db,
+    export::load_object_data,
+    request_tenant,
+    uploads::{delete_upload, find_upload, find_upload_tags, list_uploads, UploadRow},
+};
+
+const DEFAULT_IMAGES_LIMIT: u32 = 50;
+const MAX_IMAGES_LIMIT: u32 = 500;
+
+/// Max number of bucket list calls per request. Listings skip variants (upscaled/avatar images),
+/// so it may take several calls to fill a page.
+const MAX_LIST_CALLS: usize = 5;
+
+#[derive(Debug, Serialize)]
+struct ImageEntry {
+    hash: String,
+    key: String,
+    size: u32,
+    /// unix time in milliseconds
+    uploaded_at: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct ImageList {
+    images: Vec<ImageEntry>,
+    /// pass as `cursor` to get the next page. absent if there are no more images
+    ///
+    /// with `source=db`, records are listed newest first, so the cursor is `{uploaded_at}.{hash}`
+    /// of the last one. with `source=bucket`, images are listed key layout by key layout, newest first, so the
+    /// cursor tells the layout too: `{index of the layout}.{cursor of the bucket listing}`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cursor: Option<String>,
+}
+
+/// Where images are listed from.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ListSource {
+    /// Records of uploads in the D1 database, which miss images uploaded before they were kept.
+    /// The default for listings by tags.
+    Db,
+    /// Objects in the bucket. The default otherwise.
+    Bucket,
+}
+
+#[derive(Debug, Deserialize)]
+struct ListQuery {
+    limit: Option<u32>,
+    cursor: Option<String>,
+    tag: Option<String>,
+    source: Option<ListSource>,
+}
+
+pub async fn handle_get_images(req: Request, ctx: RouteContext<Context>) -> WorkerResult<Response> {
+    let tenant = match request_tenant(&req, &ctx.env).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e.to_response(),
+    };
+    match get_images(&req, &ctx, &tenant).await {
+        Ok(images) => Response::from_json(&images),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn get_images(
+    req: &Request,
+    ctx: &RouteContext<Context>,
+    tenant: &Tenant,
+) -> ApiResult<ImageList> {
+    let ListQuery {
+        limit,
+        cursor,
+        tag,
+        source,
+    } = query(req)?;
+    let limit = limit.unwrap_or(DEFAULT_IMAGES_LIMIT);
+    if !(1..=MAX_IMAGES_LIMIT).contains(&limit) {
+        return Err(ApiError::new(
+            400,
+            format!("'limit' must be in 1..={}", MAX_IMAGES_LIMIT),
+        ));
+    }
+    let tag = tag.map(|t| t.trim().to_ascii_lowercase());
+    if tag.as_ref().is_some_and(|t| !is_valid_tag(t)) {
+        return Err(ApiError::new(400, "Invalid tag"));
+    }
+    let source = source.unwrap_or(match tag {
+        Some(_) => ListSource::Db,
+        None => ListSource::Bucket,
+    });
+    match source {
+        ListSource::Db => {
+            list_recorded_images(ctx, tenant, tag.as_deref(), limit, cursor.as_deref()).await
+        }
+        // objects in the bucket know nothing about tags
+        ListSource::Bucket if tag.is_some() => Err(ApiError::new(
+            400,
+            "'tag' can't be used with 'source=bucket'",
+        )),
+        ListSource::Bucket => list_stored_images(ctx, tenant, limit, cursor.as_deref()).await,
+    }
+}
+
+async fn list_recorded_images(
+    ctx: &RouteContext<Context>,
+    tenant: &Tenant,
+    tag: Option<&str>,
+    limit: u32,
+    cursor: Option<&str>,
+) -> ApiResult<ImageList> {
+    let cursor = cursor.map(parse_record_cursor).transpose()?;
+    let db = get_db(ctx)?;
+    let rows = list_uploads(&db, &tenant.id, "", tag, cursor.as_ref(), limit).await?;
+
+    // a full page may be followed by an empty one, which is cheaper than counting
+    let cursor = (rows.len() == limit as usize)
+        .then(|| rows.last())
+        .flatten()
+        .map(|row| format!("{}.{}", row.uploaded_at, row.hash));
+    let images = rows
+        .into_iter()
+        .map(|row| {
+            let file_name = Variant::Original.file_name(&row.hash);
+            let size = row
+                .variants()
+                .iter()
+                .find(|v| v.name == file_name)
+                .map_or(0, |v| v.size);
+            ImageEntry {
+                key: format!("{}{}", row.key_prefix, file_name),
+                size: size as u32,
+                uploaded_at: row.uploaded_at,
+                hash: row.hash,
+            }
+        })
+        .collect();
+    Ok(ImageList { images, cursor })
+}
+
+/// Parse the cursor of record listings into the upload time and the hash of the last record.
+fn parse_record_cursor(cursor: &str) -> ApiResult<(u64, String)> {
+    let invalid = || ApiError::new(400, "Invalid cursor");
+    let (uploaded_at, hash) = cursor.split_once('.').ok_or_else(invalid)?;
+    let uploaded_at = uploaded_at.parse().map_err(|_| invalid())?;
+    if !is_valid_hash(hash) {
+        return Err(invalid());
+    }
+    Ok((uploaded_at, hash.to_string()))
+}
+
+async fn list_stored_images(
+    ctx: &RouteContext<Context>,
+    tenant: &Tenant,
+    limit: u32,
+    cursor: Option<&str>,
+) -> ApiResult<ImageList> {
+    let cursor = cursor.map(parse_cursor).transpose()?;
+    let Ok(bucket) = ctx.bucket("IMGS_BUCKET") else {
+        console_error!("failed to get bindings to the R2 bucket");
+        return Err(ApiError::no_msg(500));
+    };
+
+    let mut images = Vec::new();
+    let (mut schema_idx, mut list_cursor) = cursor.unwrap_or((0, None));
+    let mut cursor = None;
+    for _ in 0..MAX_LIST_CALLS {
+        let prefix = KeySchema::READABLE[schema_idx].tenant_prefix(tenant);
+        // never list more than needed, so that the cursor doesn't skip any image
+        let mut list = bucket
+            .list()
+            .prefix(&prefix)
+            .delimiter("/")
+            .limit(limit - images.len() as u32);
+        if let Some(c) = list_cursor.take() {
+            list = list.cursor(c);
+        }
+        let objects = list.execute().await.map_err(|e| {
+            console_error!("failed to list objects in the bucket: {:?}", e);
+            ApiError::no_msg(500)
+        })?;
+
+        images.extend(objects.objects().into_iter().filter_map(|obj| {
+            let key = obj.key();
+            let hash = key.strip_prefix(&prefix)?.strip_suffix(".png")?.to_string();
+            is_valid_hash(&hash).then(|| ImageEntry {
+                hash,
+                size: obj.size(),
+                uploaded_at: obj.uploaded().as_millis(),
+                key,
+            })
+        }));
+        list_cursor = objects.truncated().then(|| objects.cursor()).flatten();
+        if list_cursor.is_none() {
+            // go on to the next older layout
+            schema_idx += 1;
+        }
+        cursor = (schema_idx < KeySchema::READABLE.len()).then(|| {
+            format!(
+                "{}.{}",
+                schema_idx,
+                list_cursor.as_deref().unwrap_or_default()
+            )
+        });
+        if cursor.is_none() || images.len() == limit as usize {
+            break;
+        }
+    }
+    Ok(ImageList { images, cursor })
+}
+
+/// Parse the cursor of image listings into the index of the key layout and the cursor of the
+/// bucket listing in it.
+fn parse_cursor(cursor: &str) -> ApiResult<(usize, Option<String>)> {
+    let invalid = || ApiError::new(400, "Invalid cursor");
+    let (idx, list_cursor) = cursor.split_once('.').ok_or_else(invalid)?;
+    let idx = idx
+        .parse()
+        .ok()
+        .filter(|&i| i < KeySchema::READABLE.len())
+        .ok_or_else(invalid)?;
+    Ok((
+        idx,
+        (!list_cursor.is_empty()).then(|| list_cursor.to_string()),
+    ))
+}
+
+#[derive(Debug, Serialize)]
+struct VariantInfo {
+    name: String,
+    /// absent for avatar images
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scale: Option<u32>,
+    width: u32,
+    height: u32,
+    size: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ImageMetadata {
+    hash: String,
+    width: u32,
+    height: u32,
+    animated: bool,
+    variants: Vec<VariantInfo>,
+    /// empty for images uploaded before records were kept
+    tags: Vec<String>,
+}
+
+pub async fn handle_get_image(req: Request, ctx: RouteContext<Context>) -> WorkerResult<Response> {
+    let tenant = match request_tenant(&req, &ctx.env).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e.to_response(),
+    };
+    match get_image_metadata(&ctx, &tenant).await {
+        Ok(meta) => Response::from_json(&meta),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn get_image_metadata(
+    ctx: &RouteContext<Context>,
+    tenant: &Tenant,
+) -> ApiResult<ImageMetadata> {
+    let Hash(hash) = path_param(ctx, "hash")?;
+    // images uploaded before records were kept are found in the bucket. so are those whose
+    // records can't be read for now, which is slower but not wrong (errors are logged already)
+    if let Ok(db) = get_db(ctx) {
+        if let Ok(Some(row)) = find_upload(&db, &tenant.id, "", &hash).await {
+            let tags = find_upload_tags(&db, &tenant.id, "", &hash).await?;
+            return Ok(recorded_metadata(row, tags));
+        }
+    }
+
+    let Ok(bucket) = ctx.bucket("IMGS_BUCKET") else {
+        console_error!("failed to get bindings to the R2 bucket");
+        return Err(ApiError::no_msg(500));
+    };
+
+    // the original and all its variants share the hash as the prefix of keys. they are stored
+    // together, so the newest layout with the original has them all
+    let mut found = None;
+    for schema in KeySchema::READABLE {
+        let tenant_prefix = schema.tenant_prefix(tenant);
+        let objects = bucket
+            .list()
+            .prefix(format!("{}{}", tenant_prefix, hash))
+            .include(vec![Include::HttpMetadata])
+            .execute()
+            .await
+            .map_err(|e| {
+                console_error!("failed to list objects in the bucket: {:?}", e);
+                ApiError::no_msg(500)
+            })?
+            .objects();
+        let has_original = objects.iter().any(|obj| {
+            obj.key().strip_prefix(&tenant_prefix) == Some(&Variant::Original.file_name(&hash))
+        });
+        if has_original {
+            found = Some((tenant_prefix, objects));
+            break;
+        }
+    }
+    let Some((tenant_prefix, objects)) = found else {
+        return Err(ApiError::new(404, "Image not found"));
+    };
+    let mut variants: Vec<_> = objects
+        .iter()
+        .filter_map(|obj| {
+            let name = obj.key().strip_prefix(&tenant_prefix)?.to_string();
+            let variant = Variant::parse(&hash, &name)?;
+            Some((variant, name, obj))
+        })
+        .collect();
+
+    // only the header of the original is needed, but R2 can't tell dimensions of images
+    let data = load_object_data(
+        &bucket,
+        &format!("{}{}", tenant_prefix, Variant::Original.file_name(&hash)),
+    )
+    .await?;
+    let header = PngDecoder::new(Cursor::new(&data)).and_then(|d| {
+        let animated = d.is_apng()?;
+        Ok((d.dimensions(), animated))
+    });
+    let Ok(((width, height), animated)) = header else {
+        console_error!("failed to decode header of the image: {}", hash);
+        return Err(ApiError::no_msg(500));
+    };
+
+    variants.sort_by_key(|(v, _, _)| match v {
+        Variant::Original => (0, 1),
+        Variant::Upscaled(scale) => (0, *scale),
+        Variant::Avatar(size) => (1, *size),
+    });
+    let variants = variants
+        .into_iter()
+        .map(|(variant, name, obj)| {
+            let dims = match variant {
+                Variant::Avatar(size) => Dimensions::new(size, size),
+                _ => Dimensions::new(width, height).saturating_scale(variant.scale().unwrap_or(1)),
+            };
+            VariantInfo {
+                name,
+                scale: variant.scale(),
+                width: dims.width,
+                height: dims.height,
+                size: obj.size(),
+                content_type: obj.http_metadata().content_type,
+            }
+        })
+        .collect();
+
+    Ok(ImageMetadata {
+        hash,
+        width,
+        height,
+        animated,
+        variants,
+        tags: Vec::new(),
+    })
+}
+
+fn recorded_metadata(row: UploadRow, tags: Vec<String>) -> ImageMetadata {
+    let variants = row
+        .variants()
+        .into_iter()
+        .map(|img| VariantInfo {
+            name: img.name,
+            scale: img.scale,
+            width: img.width,
+            height: img.height,
+            size: img.size as u32,
+            // all images are stored as PNG
+            content_type: Some("image/png".to_string()),
+        })
+        .collect();
+    ImageMetadata {
+        hash: row.hash,
+        width: row.width,
+        height: row.height,
+        animated: row.animated != 0,
+        variants,
+        tags,
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct DeleteResult {
+    hash: String,
+    /// names of the deleted objects: the original and its variants
+    deleted: Vec<String>,
+    /// whether the edge cache was purged of the image (see `POST /admin/purge/{hash}`)
+    purged: bool,
+}
+
+pub async fn handle_delete_image(
+    req: Request,
+    ctx: RouteContext<Context>,
+) -> WorkerResult<Response> {
+    let tenant = match request_tenant(&req, &ctx.env).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e.to_response(),
+    };
+
+    let res = match path_param(&ctx, "hash") {
+        Ok(Hash(hash)) => delete_image(&ctx.env, &tenant, hash).await,
+        Err(e) => Err(e),
+    };
+    match res {
+        Ok(res) => Response::from_json(&res),
+        Err(e) => e.to_response(),
+    }
+}
+
+/// Delete the image in the root namespace of the tenant, along with its record and its entry of
+/// the upload index, and purge it from the edge cache.
+pub(crate) async fn delete_image(
+    env: &Env,
+    tenant: &Tenant,
+    hash: String,
+) -> ApiResult<DeleteResult> {
+    delete_namespaced_image(env, tenant, &Namespace::default(), hash).await
+}
+
+/// Like `delete_image`, but in the namespace.
+pub(crate) async fn delete_namespaced_image(
+    env: &Env,
+    tenant: &Tenant,
+    namespace: &Namespace,
+    hash: String,
+) -> ApiResult<DeleteResult> {
+    let Ok(bucket) = env.bucket("IMGS_BUCKET") else {
+        console_error!("failed to get bindings to the R2 bucket");
+        return Err(ApiError::no_msg(500));
+    };
+    let cold_bucket = cold_bucket(env);
+    // unindexed first, so that uploads of the same data are never deduplicated against objects
+    // deleted below
+    if let Some(kv) = upload_index(env) {
+        let key_prefix = KeySchema::CURRENT.key_prefix(tenant, namespace);
+        delete_index_entry(&kv, &key_prefix, &hash)
+            .await
+            .map_err(|e| {
+                console_error!("failed to delete the entry from the upload index: {:?}", e);
+                ApiError::no_msg(500)
+            })?;
+    }
+    let deleted =
+        delete_image_objects(&bucket, cold_bucket.as_deref(), tenant, namespace, &hash).await?;
+    if deleted.is_empty() {
+        return Err(ApiError::new(404, "Image not found"));
+    }
+    delete_upload(env, &tenant.id, &namespace.name, &hash).await;
+
+    // cached responses of the image are invalidated everywhere, in every namespace, scale, format
+    // and query
+    let purged = purge_image(env, &hash).await.is_ok();
+    Ok(DeleteResult {
+        hash,
+        deleted,
+        purged,
+    })
+}
+
+/// Delete the original image in the namespace and all its variants from the bucket (and the cold
+/// bucket, if any), in all the key layouts, and return their names.
+pub(crate) async fn delete_image_objects(
+    bucket: &Bucket,
+    cold_bucket: Option<&Bucket>,
+    tenant: &Tenant,
+    namespace: &Namespace,
+    hash: &str,
+) -> ApiResult<Vec<String>> {
+    let mut deleted = Vec::new();
+    let buckets = std::iter::once(bucket).chain(cold_bucket);
+    for (bucket, schema) in buckets.flat_map(|b| KeySchema::READABLE.map(|s| (b, s))) {
+        let key_prefix = schema.key_prefix(tenant, namespace);
+        let objects = bucket
+            .list()
+            .prefix(format!("{}{}", key_prefix, hash))
+            .execute()
+            .await
+            .map_err(|e| {
+                console_error!("failed to list objects in the bucket: {:?}", e);
+                ApiError::no_msg(500)
+            })?
+            .objects();
+
+        for obj in objects {
+            let key = obj.key();
+            let Some(name) = key.strip_prefix(&key_prefix) else {
+                continue;
+            };
+            if Variant::parse(hash, name).is_none() && name != manifest_file_name(hash) {
+                continue;
+            }
+            bucket.delete(&key).await.map_err(|e| {
+                console_error!("failed to delete object from the bucket: {:?}", e);
+                ApiError::no_msg(500)
+            })?;
+            if !deleted.iter().any(|d| d == name) {
+                deleted.push(name.to_string());
+            }
+        }
+    }
+    Ok(deleted)
+}
@@ -0,0 +1,220 @@
+//! Asynchronous processing of large uploads.
+//!
+//! For namespaces setting `max_async_data_len` larger than `max_data_len`, image data between the
+//! two are accepted with `202 Accepted`, and processed after responding. The response points to
+//! `GET /jobs/{id}`, which tells the state of the job and its result once done.
+//!
+//! Job states are stored under `_jobs/` in the bucket, so the bucket should have a lifecycle rule
+//! to expire objects with that prefix, like staged objects of direct uploads. Names of the states
+//! are also in the custom metadata of them, so that they are summarized by listing them.
+
+use std::{collections::HashMap, future::Future};
+
+use serde::Serialize;
+use worker::{
+    console_error, console_log, Bucket, Context, Headers, HttpMetadata, Include, Request, Response,
+    Result as WorkerResult, RouteContext,
+};
+
+use upix_lib::{blob::BlobStore, job_summary::JobStatus, tenant::Tenant, ApiError, ApiResult};
+
+use crate::{
+    direct_upload::{is_valid_upload_id, new_upload_id},
+    request_tenant,
+};
+
+/// Prefix of keys of job states. Can't collide with namespaces or tenants, whose names never
+/// contain `_`.
+const JOBS_PREFIX: &str = "_jobs/";
+
+/// Key of the custom metadata of job states with the name of the state.
+const STATE_METADATA_KEY: &str = "state";
+
+/// Job states are summarized from at most this many pages of them.
+const MAX_SUMMARY_PAGES: usize = 10;
+
+#[derive(Debug, Serialize)]
+pub struct Job {
+    job_id: String,
+    /// poll this for the result of the job
+    status_url: String,
+}
+
+impl Job {
+    pub fn to_response(&self) -> WorkerResult<Response> {
+        let mut resp = Response::from_json(self)?.with_status(202);
+        resp.headers_mut().set("Location", &self.status_url)?;
+        Ok(resp)
+    }
+}
+
+/// State of a job, as responded by `GET /jobs/{id}`.
+#[derive(Debug, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+enum JobState<'a, T> {
+    Processing,
+    Done {
+        result: &'a T,
+    },
+    /// The job was rejected or failed, with the status and the message it would have had if
+    /// processed synchronously.
+    Failed {
+        status: u16,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        message: Option<&'a str>,
+    },
+}
+
+impl<T> JobState<'_, T> {
+    fn name(&self) -> &'static str {
+        match self {
+            JobState::Processing => "processing",
+            JobState::Done { .. } => "done",
+            JobState::Failed { .. } => "failed",
+        }
+    }
+}
+
+fn job_key(tenant: &Tenant, job_id: &str) -> String {
+    format!("{}{}{}.json", JOBS_PREFIX, tenant.key_prefix(), job_id)
+}
+
+async fn store_job_state<T: Serialize>(
+    bucket: &Bucket,
+    key: &str,
+    state: &JobState<'_, T>,
+) -> ApiResult<()> {
+    let data = serde_json::to_vec(state).map_err(|e| {
+        console_error!("failed to serialize job state: {:?}", e);
+        ApiError::no_msg(500)
+    })?;
+    let meta = HttpMetadata {
+        content_type: Some("application/json".to_string()),
+        ..HttpMetadata::default()
+    };
+    let custom_meta = HashMap::from([(STATE_METADATA_KEY.to_string(), state.name().to_string())]);
+    bucket
+        .put(key, data)
+        .http_metadata(meta)
+        .custom_metadata(custom_meta)
+        .execute()
+        .await
+        .map(|_| ())
+        .map_err(|e| {
+            console_error!("failed to store job state: {:?}", e);
+            ApiError::no_msg(500)
+        })
+}
+
+/// Statuses of jobs of all tenants with when they last changed, and whether there are more than
+/// listed. States stored without the metadata are skipped.
+pub(crate) async fn list_job_statuses(bucket: &Bucket) -> ApiResult<(Vec<(JobStatus, u64)>, bool)> {
+    let mut statuses = Vec::new();
+    let mut cursor = None;
+    for _ in 0..MAX_SUMMARY_PAGES {
+        let mut list = bucket
+            .list()
+            .prefix(JOBS_PREFIX)
+            .include(vec![Include::CustomMetadata]);
+        if let Some(cursor) = cursor {
+            list = list.cursor(cursor);
+        }
+        let listing = list.execute().await.map_err(|e| {
+            console_error!("failed to list job states: {:?}", e);
+            ApiError::no_msg(500)
+        })?;
+        for obj in listing.objects() {
+            let state = obj.custom_metadata().unwrap_or_default();
+            let status = state
+                .get(STATE_METADATA_KEY)
+                .and_then(|s| JobStatus::of_upload_state(s));
+            if let Some(status) = status {
+                statuses.push((status, obj.uploaded().as_millis()));
+            }
+        }
+        cursor = listing.cursor().filter(|_| listing.truncated());
+        if cursor.is_none() {
+            return Ok((statuses, false));
+        }
+    }
+    Ok((statuses, true))
+}
+
+/// Start a job running the task after responding, and return the job to respond with.
+pub async fn start_job<T, F>(
+    ctx: &RouteContext<Context>,
+    tenant: &Tenant,
+    task: F,
+) -> ApiResult<Job>
+where
+    T: Serialize,
+    F: Future<Output = ApiResult<T>> + 'static,
+{
+    let Ok(bucket) = ctx.bucket("IMGS_BUCKET") else {
+        console_error!("failed to get bindings to the R2 bucket");
+        return Err(ApiError::no_msg(500));
+    };
+    let job_id = new_upload_id()?;
+    let key = job_key(tenant, &job_id);
+    let processing: JobState<()> = JobState::Processing;
+    store_job_state(&bucket, &key, &processing).await?;
+
+    ctx.data.wait_until(async move {
+        let res = task.await;
+        let state = match &res {
+            Ok(result) => JobState::Done { result },
+            Err(e) => JobState::Failed {
+                status: e.status(),
+                message: e.message(),
+            },
+        };
+        console_log!("job {} finished: {}", key, state.name());
+        if store_job_state(&bucket, &key, &state).await.is_err() {
+            // keep the job from staying processing until the state expires
+            let failed: JobState<()> = JobState::Failed {
+                status: 500,
+                message: None,
+            };
+            let _ = store_job_state(&bucket, &key, &failed).await;
+        }
+    });
+    Ok(Job {
+        status_url: format!("/jobs/{}", job_id),
+        job_id,
+    })
+}
+
+pub async fn handle_get_job(req: Request, ctx: RouteContext<Context>) -> WorkerResult<Response> {
+    let tenant = match request_tenant(&req, &ctx.env).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e.to_response(),
+    };
+    match get_job(&ctx, &tenant).await {
+        Ok(state) => {
+            let mut headers = Headers::new();
+            headers.set("Content-Type", "application/json")?;
+            headers.set("Cache-Control", "no-store")?;
+            Ok(Response::from_bytes(state)?.with_headers(headers))
+        }
+        Err(e) => e.to_response(),
+    }
+}
+
+/// Stored state of the job, as JSON.
+async fn get_job(ctx: &RouteContext<Context>, tenant: &Tenant) -> ApiResult<Vec<u8>> {
+    let Some(job_id) = ctx.param("id").filter(|id| is_valid_upload_id(id)) else {
+        return Err(ApiError::new(400, "Invalid job ID"));
+    };
+    let Ok(bucket) = ctx.bucket("IMGS_BUCKET") else {
+        console_error!("failed to get bindings to the R2 bucket");
+        return Err(ApiError::no_msg(500));
+    };
+    bucket
+        .load(&job_key(tenant, job_id))
+        .await
+        .map_err(|e| {
+            console_error!("failed to load job state: {}", e);
+            ApiError::no_msg(500)
+        })?
+        .ok_or_else(|| ApiError::new(404, "Job not found"))
+}
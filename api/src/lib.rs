@@ -1,125 +1,1318 @@
+use std::io::Cursor;
+
 use futures::future;
-use image::{DynamicImage, GenericImageView, ImageError, ImageFormat};
-use serde::Serialize;
+use image::{DynamicImage, ImageError, ImageFormat};
+use serde::{Deserialize, Serialize};
 use worker::{
-    console_error, console_log, event, send::SendWrapper, Bucket, Context, Cors, Env, FormEntry,
-    HttpMetadata, Request, Response, Result as WorkerResult, RouteContext, Router,
+    console_error, console_log, event,
+    kv::KvStore,
+    send::SendWrapper,
+    wasm_bindgen::{JsCast, JsValue},
+    Bucket, Context, Env, File, FormEntry, MessageBatch, MessageExt, Method, Request, RequestInit,
+    Response, Result as WorkerResult, RouteContext, Router, ScheduleContext, ScheduledEvent,
+};
+
+use upix_lib::{
+    access_log::{log_access, now_ms, AccessRecord},
+    analytics::{write_data_point, UploadMetrics, UPLOAD_ANALYTICS_BINDING},
+    animation::{
+        count_frames, decode_animation, frames_allow_scale, keeps_animation, Animation, MAX_FRAMES,
+        MAX_TOTAL_PIXELS,
+    },
+    avatar::{prepare_avatar_source, render_avatar, AVATAR_SIZES},
+    blob::{BlobError, BlobStore},
+    canonical::canonicalize,
+    color_key::ColorKey,
+    config::Config,
+    content_hash::HashAlgorithm,
+    cors::AllowedOrigins,
+    data_url::DataUrl,
+    delta::apply_delta,
+    dimensions::Dimensions,
+    dynamic::DynamicHints,
+    encode_png,
+    error_code::{status_message, ErrorCode},
+    image_format_from_mime_type, is_valid_hash,
+    manifest::{store_manifest, Manifest, ManifestEntry},
+    namespace::{find_namespace, ColdStorage, Limits, Namespace, COLD_BUCKET_BINDING},
+    panic::{enter_request, request_id, set_panic_hook},
+    pipeline::{
+        self, accepts_upload_result, check_dropped_frames, check_requested_scales, encode_scaled,
+        parse_scales, store_variants, validate_dimensions, validate_img, variant_scales,
+        StoreError, UploadResult, UploadedImage, VariantStores, UPLOAD_RESULT_MEDIA_TYPE,
+    },
+    quality::advise,
+    quantize::{parse_max_colors, quantize},
+    route_guard::{allowed_methods, guard_request},
+    schema::KeySchema,
+    stats::{counter_key, send_counter_batch, CounterBatch, COUNTER_BINDING},
+    tags::{normalize_tags, split_tags},
+    tenant::{resolve_tenant, Tenant},
+    upload_index::{load_index_entry, put_index_entry, upload_index, IndexEntry},
+    upload_token::UploadToken,
+    variant::Variant,
+    variant_queue::{split_deferred_scales, QueueMessage, VariantJob},
+    warning::{color_warning, scale_warnings, Warning},
+    ApiError, ApiResult,
 };
 
-use upix_lib::{encode_image, sha256_hex, upscale_image, ApiError, ApiResult};
+mod accessibility;
+mod admin;
+mod annotations;
+mod api_key;
+mod circuit;
+mod cleanup;
+mod db;
+mod derive;
+mod direct_upload;
+mod export;
+mod images;
+mod jobs;
+mod packs;
+mod quality;
+mod rate_limit;
+mod remote;
+mod report;
+mod routes;
+mod stats;
+mod tilemap;
+mod upload_lock;
+mod upload_token;
+mod uploads;
+mod variant_queue;
+mod webhook;
+
+use api_key::authorize_request;
+use circuit::{guard_bucket, report_bucket_failure};
+use export::load_png_image;
+use jobs::{start_job, Job};
+use rate_limit::limit_upload_rate;
+use remote::fetch_remote_image;
+use routes::ROUTES;
+use upload_lock::lock_upload;
+use upload_token::{authorize_upload, redeem_upload_token, UploadAuth};
+use uploads::{record_upload, UploadClient, UploadOrigin, UploadRecord};
+use variant_queue::{enqueue_variants, variant_queue};
+use webhook::notify_upload;
 
 #[event(fetch)]
-async fn fetch(req: Request, env: Env, _ctx: Context) -> WorkerResult<Response> {
-    console_error_panic_hook::set_once();
+async fn fetch(req: Request, env: Env, ctx: Context) -> WorkerResult<Response> {
+    set_panic_hook();
+
+    if let Err(e) = Config::from_env(&env) {
+        return ApiError::from(e).to_response();
+    }
+
+    enter_request(&request_id(&req));
+    let started_at = now_ms();
+    let (method, path) = (req.method(), req.path());
+    // the router takes the context, so access logs are flushed by another handle to it
+    let log_ctx = context_handle(&ctx);
+    let log_env = env.clone();
+    // CORS is applied here to all the responses, including errors of the guard
+    let origin = req.headers().get("Origin")?;
+    let allowed_origins = request_allowed_origins(&req, &env).await;
+    let res = if method == Method::Options {
+        handle_preflight(&allowed_origins, origin.as_deref(), &path)
+    } else {
+        let guarded = match guard_request(ROUTES, &req) {
+            Ok(()) => authorize_request(ROUTES, &req, &env).await,
+            Err(e) => Err(e),
+        };
+        match guarded {
+            Ok(()) => route(req, env, ctx).await,
+            Err(e) => e.to_response(),
+        }
+    };
+    let res = res.and_then(|r| allowed_origins.apply(r, origin.as_deref()));
+    if let Ok(resp) = &res {
+        let record = AccessRecord::of_response(method, path, started_at, resp);
+        log_access(&log_env, &log_ctx, "api", record);
+    }
+    res
+}
+
+/// Clean up inconsistent variant sets, on the cron trigger.
+#[event(scheduled)]
+async fn scheduled(_: ScheduledEvent, env: Env, _: ScheduleContext) {
+    if let Err(e) = cleanup::clean_up_bucket(&env).await {
+        console_error!("cleanup failed: {:?}", e);
+    }
+}
 
-    let router = Router::new();
-    router
+/// Store variants deferred from uploads, consuming the variant queue. Jobs failing for good are
+/// dead-lettered (see variant_queue.rs).
+#[event(queue)]
+async fn queue(batch: MessageBatch<QueueMessage>, env: Env, _: Context) -> WorkerResult<()> {
+    let mut succeeded = Vec::new();
+    for message in batch.messages()? {
+        let res = match message.body() {
+            QueueMessage::Variants(job) => match variant_queue::store_job_variants(&env, job).await
+            {
+                Ok(()) => Ok(()),
+                Err(e) => {
+                    let error = e.to_string();
+                    variant_queue::report_job_failure(&env, job, e).await;
+                    Err(error)
+                }
+            },
+            // errors are logged by the deletion
+            QueueMessage::Delete(job) => admin::delete_queued_image(&env, job).await.map_err(|e| {
+                let message = e.message().unwrap_or_else(|| status_message(e.status()));
+                format!("{} {}", e.status(), message)
+            }),
+        };
+        match res {
+            Ok(()) => {
+                succeeded.push(message.id());
+                message.ack();
+            }
+            Err(error) => {
+                let dead =
+                    variant_queue::record_job_failure(&env, &message.id(), message.body(), &error)
+                        .await;
+                if dead {
+                    message.ack();
+                } else {
+                    message.retry();
+                }
+            }
+        }
+    }
+    variant_queue::clear_job_failures(&env, &succeeded).await;
+    Ok(())
+}
+
+/// Route the request, which has been checked against `ROUTES`.
+async fn route(req: Request, env: Env, ctx: Context) -> WorkerResult<Response> {
+    // `HEAD` is allowed wherever `GET` is, and answered by the handler of `GET`, whose body the
+    // runtime drops
+    let req = match req.method() {
+        Method::Head => {
+            let mut init = RequestInit::new();
+            init.with_method(Method::Get)
+                .with_headers(req.headers().clone());
+            Request::new_with_init(req.url()?.as_str(), &init)?
+        }
+        _ => req,
+    };
+    // handlers get the context of the request to run work after responding
+    Router::with_data(ctx)
         .get("/", handle_get)
+        .get_async("/images", images::handle_get_images)
         .post_async("/", handle_post_image)
+        .post_async("/:namespace", handle_post_image)
+        .post_async("/images/uploads", direct_upload::handle_post_upload_url)
+        .post_async("/images/commit", direct_upload::handle_post_commit)
+        .post_async("/upload-tokens", upload_token::handle_post_upload_token)
+        .get_async("/jobs/:id", jobs::handle_get_job)
+        .get_async("/images/trending", stats::handle_get_trending)
+        .get_async("/images/:hash", images::handle_get_image)
+        .delete_async("/images/:hash", images::handle_delete_image)
+        .get_async("/images/:hash/stats", stats::handle_get_image_stats)
+        .post_async("/images/:hash/report", report::handle_post_report)
+        .post_async("/images/:hash/derive", derive::handle_post_derive)
+        .get_async("/images/:hash/derivatives", derive::handle_get_derivatives)
+        .get_async("/images/:hash/quality", quality::handle_get_quality)
+        .get_async(
+            "/images/:hash/accessibility",
+            accessibility::handle_get_accessibility,
+        )
+        .get_async(
+            "/images/:hash/annotations",
+            annotations::handle_get_annotations,
+        )
+        .put_async(
+            "/images/:hash/annotations",
+            annotations::handle_put_annotations,
+        )
+        .get_async("/images/:hash/emoji.png", export::handle_get_emoji)
+        .get_async(
+            "/images/:hash/engine/:engine",
+            export::handle_get_engine_descriptor,
+        )
+        .post_async("/tilemap", tilemap::handle_post_tilemap)
+        .post_async("/packs", packs::handle_post_pack)
+        .get_async("/packs/:id/resolve", packs::handle_get_resolved_pack)
+        .get_async("/admin/reports", report::handle_get_reports)
+        .get_async("/admin/uploads/:hash", admin::handle_get_upload_origins)
+        .get("/admin/metrics", admin::handle_get_metrics)
+        .get_async("/admin/report/forecast", admin::handle_get_forecast)
+        .post_async("/admin/cache-epoch", admin::handle_post_cache_epoch)
+        .post_async("/admin/purge/:hash", admin::handle_post_purge)
+        .post_async("/admin/images/delete", admin::handle_post_bulk_delete)
+        .get_async("/admin/jobs", admin::handle_get_jobs)
+        .post_async("/admin/jobs/:id/retry", admin::handle_post_job_retry)
         .run(req, env)
         .await
 }
 
-fn handle_get(_req: Request, _ctx: RouteContext<()>) -> WorkerResult<Response> {
-    Response::ok("upix API")
+/// Origins allowed to access the api by the request: the tenant's ones, or the `ALLOWED_ORIGINS`
+/// var for the default tenant.
+async fn request_allowed_origins(req: &Request, env: &Env) -> AllowedOrigins {
+    match request_tenant(req, env).await {
+        Ok(tenant) if !tenant.id.is_empty() => tenant.allowed_origins(),
+        // handlers fail to resolve the tenant likewise, and respond with 500
+        _ => Config::from_env(env)
+            .map(|c| c.allowed_origins.clone())
+            .unwrap_or_default(),
+    }
 }
 
-// fn get_images(_req: Request, ctx: RouteContext<()>) -> Result<Response> {
-//     let bucket = ctx.bucket("IMGS_BUCKET")?;
-//     let images = bucket.list().limit(100).execute().await?.objects();
-//     console_log!("{}", images.len());
-//     if images.is_empty() {
-//         return Response::ok("no images found");
-//     }
+fn handle_preflight(
+    allowed_origins: &AllowedOrigins,
+    origin: Option<&str>,
+    path: &str,
+) -> WorkerResult<Response> {
+    let methods = allowed_methods(ROUTES, path);
+    if methods.is_empty() {
+        return ApiError::no_msg(404).to_response();
+    }
+    let cors = allowed_origins.preflight_cors(origin, methods);
+    Response::empty()?.with_status(204).with_cors(&cors)
+}
+
+/// Another handle to the context of the request, for work outliving borrows of the router's.
+fn context_handle(ctx: &Context) -> Context {
+    let js_ctx: &JsValue = ctx.as_ref().as_ref();
+    Context::new(js_ctx.clone().unchecked_into())
+}
 
-//     let images = images.iter().map(|img| img.key()).collect::<Vec<_>>();
-//     Response::from_json(&images)
-// }
+fn handle_get(_req: Request, _ctx: RouteContext<Context>) -> WorkerResult<Response> {
+    Response::ok("upix API")
+}
+
+/// Resolve the tenant by the host of the request.
+async fn request_tenant(req: &Request, env: &Env) -> ApiResult<Tenant> {
+    let host = req
+        .url()
+        .map_err(|_| ApiError::no_msg(500))?
+        .host_str()
+        .map(|h| h.to_string());
+    resolve_tenant(env, host.as_deref()).await.map_err(|e| {
+        console_error!("failed to resolve tenant: {:?}", e);
+        ApiError::no_msg(500)
+    })
+}
 
-async fn handle_post_image(req: Request, ctx: RouteContext<()>) -> WorkerResult<Response> {
-    let res = post_image(req, ctx).await;
+async fn handle_post_image(req: Request, ctx: RouteContext<Context>) -> WorkerResult<Response> {
+    let tenant = match request_tenant(&req, &ctx.env).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e.to_response(),
+    };
+    let full = accepts_upload_result(req.headers().get("Accept").ok().flatten().as_deref());
+    let res = post_image(req, ctx, &tenant).await;
     match res {
-        Ok(images) => Response::from_json(&images),
+        Ok(PostImageResponse::Done(result)) => upload_result_response(&result, full),
+        Ok(PostImageResponse::Accepted(job)) => job.to_response(),
+        Ok(PostImageResponse::Batch(files)) => Response::from_json(&BatchResult { files }),
         Err(e) => e.to_response(),
     }
-    .and_then(|r| r.with_cors(&Cors::default().with_origins(["*"])))
 }
 
-async fn post_image(mut req: Request, ctx: RouteContext<()>) -> ApiResult<Vec<UploadedImage>> {
+/// Respond with the result of the upload as an object if the client accepts it, or the array of
+/// the stored images otherwise (see `upix_lib::pipeline::accepts_upload_result`).
+pub(crate) fn upload_result_response(result: &UploadResult, full: bool) -> WorkerResult<Response> {
+    if !full {
+        return Response::from_json(&result.images);
+    }
+    let mut resp = Response::from_json(result)?;
+    resp.headers_mut()
+        .set("Content-Type", UPLOAD_RESULT_MEDIA_TYPE)?;
+    Ok(resp)
+}
+
+enum PostImageResponse {
+    /// The image has been processed and stored.
+    Done(UploadResult),
+    /// The image is large, and is processed after responding by the job.
+    Accepted(Job),
+    /// Files of the batch upload have been processed, each of which may have failed.
+    Batch(Vec<BatchFileResult>),
+}
+
+/// Max number of files in a batch upload. A batch counts as a single upload against the rate
+/// limit, so this also bounds the work of a counted upload.
+const MAX_BATCH_FILES: usize = 10;
+
+#[derive(Debug, Serialize)]
+struct BatchResult {
+    /// in the order of the files in the form data
+    files: Vec<BatchFileResult>,
+}
+
+#[derive(Debug, Serialize)]
+struct BatchFileResult {
+    /// name of the file in the form data
+    name: String,
+    /// status the upload would have had if the file was uploaded alone
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<UploadResult>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl BatchFileResult {
+    fn new(name: String, res: ApiResult<UploadResult>) -> Self {
+        match res {
+            Ok(result) => BatchFileResult {
+                name,
+                status: 200,
+                result: Some(result),
+                error: None,
+            },
+            Err(e) => BatchFileResult {
+                name,
+                status: e.status(),
+                result: None,
+                error: e.message().map(|m| m.to_string()),
+            },
+        }
+    }
+}
+
+/// Image data read from the request, or why it was rejected.
+type ImageData = ApiResult<(Vec<u8>, ImageFormat)>;
+
+async fn post_image(
+    mut req: Request,
+    ctx: RouteContext<Context>,
+    tenant: &Tenant,
+) -> ApiResult<PostImageResponse> {
+    let auth = authorize_upload(&req, &ctx).await?;
+    limit_upload_rate(&req, &ctx).await?;
+    guard_bucket(&ctx).await?;
+
+    let Some(namespace) = find_namespace(&ctx.env, ctx.param("namespace").map(|n| n.as_str()))
+    else {
+        return Err(ApiError::new(404, "Unknown namespace").with_code(ErrorCode::UnknownNamespace));
+    };
+    let mut limits = namespace.limits.clone();
+    if let UploadAuth::Token(token) = &auth {
+        // tokens minted by a tenant are good only for its own domains
+        if token.tenant != tenant.id {
+            return Err(ApiError::new(
+                403,
+                "Upload token is not valid for this tenant",
+            ));
+        }
+        if token.namespace != namespace.name {
+            return Err(ApiError::new(
+                403,
+                "Upload token is not valid for this namespace",
+            ));
+        }
+        limits.max_data_len = limits.max_data_len.min(token.max_size);
+        limits.max_async_data_len = limits.max_async_data_len.min(token.max_size);
+    }
+    let limits = &limits;
+
     let Ok(bucket) = ctx.bucket("IMGS_BUCKET") else {
         console_error!("failed to get bindings to the R2 bucket");
         return Err(ApiError::no_msg(500));
     };
     let bucket = SendWrapper::new(bucket);
 
-    let (img_data, img_fmt) = get_image_data_from_request(&mut req).await?;
-    let img = image::load_from_memory_with_format(&img_data, img_fmt).map_err(|e| match e {
-        ImageError::Decoding(_) => ApiError::new(400, "Failed to decode image"),
-        e => {
-            console_error!("failed to load image: {:?}", e);
-            ApiError::no_msg(500)
+    let mode = UploadMode::from_request(&req)?;
+    let quantize_colors = quantize_param(&req)?;
+    let color_key = color_key_param(&req)?;
+    if color_key.is_some() && matches!(mode, UploadMode::Delta { .. }) {
+        return Err(ApiError::new(400, "'key' can't be used in the delta mode"));
+    }
+    // the `scales` and `tags` fields are sent in the form data (or the JSON body), or as query
+    // params with raw image data
+    let url = req.url().map_err(|_| ApiError::no_msg(500))?;
+    let query_param = |name| {
+        url.query_pairs()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.into_owned())
+    };
+    let mut scales = query_param("scales");
+    let mut tags = query_param("tags").map(|t| split_tags(&t));
+    let mut batch = None;
+    let source = match &mode {
+        UploadMode::Delta { base } => {
+            let base_name = format!("{}.png", base);
+            let img = get_delta_frame_from_request(
+                &mut req, &bucket, tenant, &namespace, &base_name, limits,
+            )
+            .await?;
+            Some(UploadSource::Frame(img))
         }
-    })?;
-    validate_img_dimension(&img)?;
+        _ => {
+            let (mut files, fields) = get_image_data_from_request(&mut req, limits).await?;
+            scales = fields.scales.or(scales);
+            tags = fields.tags.or(tags);
+            if files.len() == 1 {
+                let (img_data, img_fmt) = files.remove(0).1?;
+                Some(UploadSource::Data(img_data, img_fmt))
+            } else {
+                batch = Some(files);
+                None
+            }
+        }
+    };
+    let scales = match scales {
+        Some(_) if matches!(mode, UploadMode::Avatar { .. }) => {
+            return Err(ApiError::new(
+                400,
+                "'scales' can't be used in the avatar mode",
+            ));
+        }
+        Some(s) => Some(parse_scales(&s, limits)?),
+        None => None,
+    };
+    let tags =
+        normalize_tags(tags.unwrap_or_default()).map_err(|e| ApiError::new(400, e.to_string()))?;
+    if batch.is_some() && matches!(auth, UploadAuth::Token(_)) {
+        return Err(ApiError::new(
+            403,
+            "Upload tokens can't be used for batch uploads",
+        ));
+    }
+    let origin = UploadOrigin {
+        tenant: tenant.id.clone(),
+        namespace: namespace.name.clone(),
+        key_prefix: KeySchema::CURRENT.key_prefix(tenant, &namespace),
+        uploader: Some(auth.uploader()),
+        client: UploadClient::of_request(&req, &ctx.env),
+    };
+    let token = match auth {
+        UploadAuth::Token(token) => Some(token),
+        UploadAuth::ApiKey(_) => None,
+    };
+    let new_task = |source| UploadTask {
+        mode: mode.clone(),
+        quantize_colors,
+        color_key,
+        scales: scales.clone(),
+        tags: tags.clone(),
+        source,
+        derivable: namespace.name.is_empty(),
+        origin: origin.clone(),
+        token: token.clone(),
+        public_base_url: tenant.public_base_url(&ctx.env),
+        limits: limits.clone(),
+        bucket: bucket.clone(),
+        cold_bucket: cold_bucket(&ctx.env),
+        env: ctx.env.clone(),
+        ctx: context_handle(&ctx.data),
+    };
+
+    let Some(source) = source else {
+        // files of a batch are processed concurrently, and failures of some don't fail the others
+        let tasks = batch.unwrap_or_default().into_iter().map(|(name, data)| {
+            let task =
+                data.map(|(img_data, img_fmt)| new_task(UploadSource::Data(img_data, img_fmt)));
+            async move {
+                let res = match task {
+                    Ok(task) => task.run().await,
+                    Err(e) => Err(e),
+                };
+                BatchFileResult::new(name, res)
+            }
+        });
+        return Ok(PostImageResponse::Batch(future::join_all(tasks).await));
+    };
+    let is_large =
+        matches!(&source, UploadSource::Data(data, _) if data.len() > limits.max_data_len);
+    let task = new_task(source);
+
+    if is_large {
+        let job = start_job(&ctx, tenant, task.run()).await?;
+        return Ok(PostImageResponse::Accepted(job));
+    }
+    task.run().await.map(PostImageResponse::Done)
+}
+
+/// Source of an upload.
+enum UploadSource {
+    /// Uploaded data in the format.
+    Data(Vec<u8>, ImageFormat),
+    /// Frame reconstructed from a delta patch.
+    Frame(DynamicImage),
+}
+
+/// Processing of an upload after the request is read, which may run after responding.
+struct UploadTask {
+    mode: UploadMode,
+    quantize_colors: Option<usize>,
+    /// color made transparent in uploaded data
+    color_key: Option<ColorKey>,
+    /// requested scales of variants, instead of the default ladder
+    scales: Option<Vec<u32>>,
+    tags: Vec<String>,
+    source: UploadSource,
+    /// whether images can be derived from the upload, which is the case in the root namespace
+    derivable: bool,
+    origin: UploadOrigin,
+    /// upload token to redeem once the upload is validated, so that rejected uploads don't use
+    /// it up
+    token: Option<UploadToken>,
+    /// base URL of images in the result, if configured
+    public_base_url: Option<String>,
+    limits: Limits,
+    bucket: SendWrapper<Bucket>,
+    cold_bucket: Option<SendWrapper<Bucket>>,
+    env: Env,
+    /// context of the request, which the task may outlive as an upload job
+    ctx: Context,
+}
+
+impl UploadTask {
+    async fn run(self) -> ApiResult<UploadResult> {
+        // concurrent uploads of the same data are processed one at a time, so that later ones are
+        // deduplicated against the first one instead of storing the same images again
+        let (data_hash, lease) = match &self.source {
+            UploadSource::Data(img_data, _) if self.is_deduplicable() => {
+                let hash = hash_algorithm(&self.env).hash_hex(img_data);
+                let lease = lock_upload(&self.env, &self.origin.key_prefix, &hash).await;
+                (Some(hash), lease)
+            }
+            _ => (None, None),
+        };
+        let public_base_url = self.public_base_url.clone();
+        let namespace = self.origin.namespace.clone();
+        let res = self.process(data_hash).await;
+        if let Some(lease) = lease {
+            lease.release().await;
+        }
+        match public_base_url {
+            Some(base_url) => res.map(|r| r.with_public_urls(&base_url, &namespace)),
+            None => res,
+        }
+    }
+
+    /// Whether repeated uploads of the same data result in the same images.
+    fn is_deduplicable(&self) -> bool {
+        matches!(self.mode, UploadMode::Default)
+            && self.quantize_colors.is_none()
+            && self.color_key.is_none()
+    }
+
+    /// Process the upload, with the hash of the uploaded data if already computed.
+    async fn process(self, data_hash: Option<String>) -> ApiResult<UploadResult> {
+        let deduplicable = self.is_deduplicable();
+        let UploadTask {
+            mode,
+            quantize_colors,
+            color_key,
+            scales,
+            tags,
+            source,
+            derivable,
+            origin,
+            token,
+            public_base_url: _,
+            limits,
+            bucket,
+            cold_bucket,
+            env,
+            ctx,
+        } = self;
+        let limits = &limits;
+        let started_at = now_ms();
+        let hash_algorithm = hash_algorithm(&env);
+
+        let mut warnings = Vec::new();
+        let mut advice = Vec::new();
+        let mut anim = None;
+        let mut format = None;
+        let (img, hash) = match source {
+            UploadSource::Frame(img) => {
+                // there are no original data for delta frames, so they are identified by the
+                // reconstructed image
+                let hash = png_hash(&img, hash_algorithm)?;
+                (img, hash)
+            }
+            UploadSource::Data(img_data, img_fmt) => {
+                let hash = data_hash.unwrap_or_else(|| hash_algorithm.hash_hex(&img_data));
+                // skip processing repeated uploads of the same data
+                if deduplicable {
+                    let index = upload_index(&env);
+                    let mut uploaded = None;
+                    // invalid data are left to the usual path to be rejected
+                    if let Some(dims) = decode_dimensions(&img_data, img_fmt) {
+                        let stored_scales = stored_scales(limits, dims, scales.as_deref());
+                        // data stored before switching hash algorithms are found by former hashes
+                        for hash in hash_algorithm.lookup_hashes(&img_data) {
+                            let buckets = VariantStores {
+                                store: &*bucket,
+                                cold_store: cold_bucket.as_deref(),
+                            };
+                            let found = find_uploaded_variants(
+                                buckets,
+                                index.as_ref(),
+                                &origin.key_prefix,
+                                &hash,
+                                dims,
+                                &stored_scales,
+                                limits,
+                            )
+                            .await;
+                            if let Some(images) = found {
+                                uploaded = Some((hash, images, dims));
+                                break;
+                            }
+                        }
+                    }
+                    if let Some((hash, images, dims)) = uploaded {
+                        if let Some(token) = &token {
+                            redeem_upload_token(&env, token).await?;
+                        }
+                        console_log!("deduplicated upload (hash: {})", &hash);
+                        // GIFs and APNGs are stored as animations with the default mode
+                        let frames = match keeps_animation(img_fmt) {
+                            true => count_frames(&img_data, img_fmt).unwrap_or(1),
+                            false => 1,
+                        };
+                        let record = UploadRecord {
+                            hash: &hash,
+                            format: Some(img_fmt),
+                            width: dims.width,
+                            height: dims.height,
+                            animated: frames > 1,
+                            images: &images,
+                            tags: &tags,
+                        };
+                        record_upload(&env, &origin, &record, true).await;
+                        count_upload(&env, &origin, &hash).await;
+                        let metrics = UploadMetrics {
+                            tenant: &origin.tenant,
+                            format: Some(img_fmt),
+                            pixels: dims.pixels(),
+                            processing_ms: now_ms().saturating_sub(started_at),
+                            deduplicated: true,
+                        };
+                        write_data_point(&env, UPLOAD_ANALYTICS_BINDING, &metrics.data_point());
+                        return Ok(UploadResult {
+                            images,
+                            warnings: Vec::new(),
+                            advice: Vec::new(),
+                            deduplicated: true,
+                            dynamic: DynamicHints::new(limits, dims, frames, derivable),
+                            deferred: Vec::new(),
+                        });
+                    }
+                }
+                let dims = validate_data_dimensions(&img_data, img_fmt, limits)?;
+                // animations are kept as such only with the default mode, and first frames are
+                // used otherwise
+                if matches!(mode, UploadMode::Default) {
+                    anim = decode_uploaded_animation(&img_data, img_fmt, dims)?;
+                }
+                let mut img = match &anim {
+                    Some(anim) => anim.first_frame(),
+                    None => decode_image(&img_data, img_fmt)?,
+                };
+                let mut hash = hash;
+                if let Some(key) = color_key {
+                    let keyed = match anim.take() {
+                        Some(a) => {
+                            let (a, keyed) = a.key_out(key);
+                            img = a.first_frame();
+                            anim = Some(a);
+                            keyed
+                        }
+                        None => {
+                            let (keyed_img, keyed) = key.apply_rgba(&img.to_rgba8());
+                            img = DynamicImage::ImageRgba8(keyed_img);
+                            keyed
+                        }
+                    };
+                    // keyed images differ from the original data, so they are identified by the
+                    // data along with the key
+                    hash = hash_algorithm.hash_hex(format!("{}:key={}", hash, key).as_bytes());
+                    warnings.push(Warning::color_keyed(key, keyed));
+                }
+                // GIFs and APNGs decoded above have a single frame unless kept as animations
+                let kept_as_animation =
+                    matches!(mode, UploadMode::Default) && keeps_animation(img_fmt);
+                if !kept_as_animation {
+                    warnings.extend(check_frames(&img_data, img_fmt, &env)?);
+                }
+                // advised on the source, before quantizing
+                advice = advise(&img.to_rgba8(), img_fmt);
+                format = Some(img_fmt);
+                (img, hash)
+            }
+        };
+        let (img, hash) = match quantize_colors {
+            Some(_) if anim.is_some() => {
+                return Err(ApiError::new(400, "Animations can't be quantized"));
+            }
+            Some(n) => {
+                // quantized images differ from the original data, so they are identified by
+                // themselves
+                let img = DynamicImage::ImageRgba8(quantize(&img.to_rgba8(), n));
+                let hash = png_hash(&img, hash_algorithm)?;
+                warnings.push(Warning::quantized(n));
+                (img, hash)
+            }
+            None => (img, hash),
+        };
+        let (img, hash) = match mode {
+            UploadMode::Avatar { crop } => {
+                let (w, h) = (img.width(), img.height());
+                let img = prepare_avatar_source(img, crop)
+                    .map_err(|e| ApiError::new(400, e.to_string()))?;
+                if (img.width(), img.height()) == (w, h) {
+                    (img, hash)
+                } else {
+                    // cropped images differ from the original data, so they are identified by
+                    // themselves
+                    warnings.push(Warning::cropped(w, h));
+                    let hash = png_hash(&img, hash_algorithm)?;
+                    (img, hash)
+                }
+            }
+            _ => (img, hash),
+        };
+        validate_img(&img, limits)?;
+        let long_side = Dimensions::of(&img).long_side();
+        if let Some(scales) = &scales {
+            check_requested_scales(limits, long_side, scales)?;
+        }
+        warnings.extend(color_warning(&img.to_rgba8()));
+        if !matches!(mode, UploadMode::Avatar { .. }) {
+            warnings.extend(scale_warnings(
+                limits,
+                anim.as_ref(),
+                long_side,
+                scales.as_deref(),
+            ));
+        }
+        if let Some(token) = &token {
+            redeem_upload_token(&env, token).await?;
+        }
+
+        let (img, anim) = canonicalize_upload(&env, img, anim);
+        let dims = Dimensions::of(&img);
+        let animated = anim.is_some();
+        let dynamic = DynamicHints::new(
+            limits,
+            dims,
+            anim.as_ref().map_or(1, |a| a.frames.len()),
+            derivable,
+        );
+        let uploader = ImageUploader {
+            img,
+            anim,
+            hash: hash.clone(),
+            key_prefix: origin.key_prefix.clone(),
+            limits: limits.clone(),
+            scales,
+            dest_fmt: ImageFormat::Png,
+            dest_bucket: bucket,
+            cold_bucket,
+            defer_variants: variant_queue(&env).is_some(),
+        };
+        let uploaded = match mode {
+            UploadMode::Default | UploadMode::Delta { .. } => uploader.upload_all().await,
+            UploadMode::Avatar { .. } => uploader.upload_avatars().await.map(|u| (u, Vec::new())),
+        };
+        let (uploaded, deferred) = match uploaded {
+            Ok(uploaded) => uploaded,
+            Err(e) => {
+                if e.is_storage_failure() {
+                    report_bucket_failure(&env).await;
+                }
+                return Err(e.into());
+            }
+        };
+
+        let record = UploadRecord {
+            hash: &hash,
+            format,
+            width: dims.width,
+            height: dims.height,
+            animated,
+            images: &uploaded,
+            tags: &tags,
+        };
+        record_upload(&env, &origin, &record, false).await;
+        index_upload(&env, &origin.key_prefix, &hash, &uploaded, limits).await;
+        // enqueued once recorded, so that the record is there to be updated
+        if !deferred.is_empty() {
+            let job = VariantJob {
+                tenant: origin.tenant.clone(),
+                namespace: origin.namespace.clone(),
+                key_prefix: origin.key_prefix.clone(),
+                hash: hash.clone(),
+                stored: uploaded.clone(),
+                scales: deferred.clone(),
+            };
+            enqueue_variants(&env, &ctx, job).await;
+        }
+        notify_upload(&ctx, &env, &origin, &record);
+        // failing to count uploads shouldn't fail the upload itself
+        count_upload(&env, &origin, &hash).await;
+        let metrics = UploadMetrics {
+            tenant: &origin.tenant,
+            format,
+            pixels: dims.pixels(),
+            processing_ms: now_ms().saturating_sub(started_at),
+            deduplicated: false,
+        };
+        write_data_point(&env, UPLOAD_ANALYTICS_BINDING, &metrics.data_point());
+
+        Ok(UploadResult {
+            images: uploaded,
+            warnings,
+            advice,
+            deduplicated: false,
+            dynamic,
+            deferred,
+        })
+    }
+}
+
+/// Dimensions of the image data, decoding only its header.
+fn decode_dimensions(img_data: &[u8], img_fmt: ImageFormat) -> Option<Dimensions> {
+    image::io::Reader::with_format(Cursor::new(img_data), img_fmt)
+        .into_dimensions()
+        .ok()
+        .map(Dimensions::from)
+}
+
+/// Scales of variants an upload of the image stores (at the requested scales, if any).
+fn stored_scales(limits: &Limits, dims: Dimensions, scales: Option<&[u32]>) -> Vec<u32> {
+    scales
+        .map_or_else(
+            || limits.pregenerated_scales(dims.long_side()),
+            <[u32]>::to_vec,
+        )
+        .into_iter()
+        .filter(|&scale| limits.cold_storage_of(scale) != Some(ColdStorage::OnDemand))
+        .collect()
+}
+
+/// Images stored for the image with the hash and the dimensions, if the same data has been
+/// uploaded before and all the variants at the scales are there. The index of uploads is looked
+/// up first (and checked by the original in the bucket), and images found only in the bucket are
+/// indexed.
+///
+/// Only the current key layout is looked at: data stored in older ones are just stored again.
+async fn find_uploaded_variants(
+    buckets: VariantStores<'_, Bucket>,
+    index: Option<&KvStore>,
+    key_prefix: &str,
+    hash: &str,
+    dims: Dimensions,
+    scales: &[u32],
+    limits: &Limits,
+) -> Option<Vec<UploadedImage>> {
+    let indexed = match index {
+        Some(kv) => load_index_entry(kv, key_prefix, hash)
+            .await
+            .unwrap_or_else(|e| {
+                console_error!("failed to look up the upload index: {:?}", e);
+                None
+            }),
+        None => None,
+    };
+    let uploaded_image = |scale: u32, size: u64| {
+        let variant = match scale {
+            1 => Variant::Original,
+            s => Variant::Upscaled(s),
+        };
+        let scaled = dims.saturating_scale(scale);
+        UploadedImage {
+            name: variant.file_name(hash),
+            scale: Some(scale),
+            width: scaled.width,
+            height: scaled.height,
+            size,
+            url: None,
+        }
+    };
+    // animations may lack some of the scales, and they are just processed again. Entries may
+    // outlive deleted images for a while, as deletions from KV take time to be seen everywhere,
+    // so hits are trusted only while the original is still in the bucket.
+    if let Some(entry) = indexed {
+        let original = format!("{}{}", key_prefix, Variant::Original.file_name(hash));
+        match buckets.store.head(original).await {
+            Ok(Some(_)) => {
+                return scales
+                    .iter()
+                    .map(|&scale| Some(uploaded_image(scale, entry.scaled(scale)?.size)))
+                    .collect();
+            }
+            Ok(None) => console_log!("indexed upload is gone from the bucket (hash: {})", hash),
+            Err(e) => console_error!("failed to look up the original in the bucket: {:?}", e),
+        }
+    }
+
+    let stored = list_stored_variants(buckets.store, key_prefix, hash).await?;
+    let cold_stored = match buckets.cold_store {
+        Some(cold_bucket) if scales.iter().any(|&s| limits.cold_storage_of(s).is_some()) => {
+            list_stored_variants(cold_bucket, key_prefix, hash).await?
+        }
+        _ => Vec::new(),
+    };
+    let images: Vec<_> = scales
+        .iter()
+        .map(|&scale| {
+            let variant = match scale {
+                1 => Variant::Original,
+                s => Variant::Upscaled(s),
+            };
+            let stored = match limits.cold_storage_of(scale) {
+                Some(_) if buckets.cold_store.is_some() => &cold_stored,
+                _ => &stored,
+            };
+            let &(_, size) = stored.iter().find(|(v, _)| *v == variant)?;
+            Some(uploaded_image(scale, u64::from(size)))
+        })
+        .collect::<Option<_>>()?;
+    if let Some(kv) = index {
+        let entry = IndexEntry::of_upload(&images, limits, buckets.cold_store.is_some());
+        if let Err(e) = put_index_entry(kv, key_prefix, hash, &entry).await {
+            console_error!("failed to index upload (hash: {}): {:?}", hash, e);
+        }
+    }
+    Some(images)
+}
+
+/// Variants of the image with the hash stored in the bucket, with their sizes.
+async fn list_stored_variants(
+    bucket: &Bucket,
+    key_prefix: &str,
+    hash: &str,
+) -> Option<Vec<(Variant, u32)>> {
+    let prefix = format!("{}{}", key_prefix, hash);
+    let objects = match bucket.list().prefix(&prefix).execute().await {
+        Ok(objects) => objects.objects(),
+        Err(e) => {
+            console_error!("failed to list objects in the bucket: {:?}", e);
+            return None;
+        }
+    };
+    let stored = objects
+        .iter()
+        .filter_map(|obj| {
+            let variant = Variant::parse(hash, obj.key().strip_prefix(key_prefix)?)?;
+            Some((variant, obj.size()))
+        })
+        .collect();
+    Some(stored)
+}
+
+/// Put the images stored for an upload with the limits into the index of uploads. Failing to
+/// index uploads shouldn't fail the upload itself, so errors are only logged.
+pub(crate) async fn index_upload(
+    env: &Env,
+    key_prefix: &str,
+    hash: &str,
+    images: &[UploadedImage],
+    limits: &Limits,
+) {
+    let Some(kv) = upload_index(env) else {
+        return;
+    };
+    let entry = IndexEntry::of_upload(images, limits, cold_bucket(env).is_some());
+    if let Err(e) = put_index_entry(&kv, key_prefix, hash, &entry).await {
+        console_error!("failed to index upload (hash: {}): {:?}", hash, e);
+    }
+}
+
+/// Bucket of variants in cold storage, if bound. They are kept with the original otherwise.
+pub(crate) fn cold_bucket(env: &Env) -> Option<SendWrapper<Bucket>> {
+    env.bucket(COLD_BUCKET_BINDING).ok().map(SendWrapper::new)
+}
+
+#[derive(Clone)]
+enum UploadMode {
+    /// Upload the image and its upscaled variants.
+    Default,
+    /// Upload the (square) image and avatar images in fixed sizes.
+    /// If `crop` is set, non-square images are center-cropped instead of rejected.
+    Avatar { crop: bool },
+    /// Upload a frame sent as a delta patch (see `upix_lib::delta`) against the `base` image,
+    /// and its upscaled variants.
+    Delta { base: String },
+}
+
+impl UploadMode {
+    fn from_request(req: &Request) -> ApiResult<Self> {
+        let Ok(url) = req.url() else {
+            return Err(ApiError::no_msg(500));
+        };
+        let mut mode = None;
+        let mut crop = false;
+        let mut base = None;
+        for (k, v) in url.query_pairs() {
+            match k.as_ref() {
+                "mode" => mode = Some(v.into_owned()),
+                "base" => base = Some(v.into_owned()),
+                "crop" if v == "center" => crop = true,
+                "crop" => return Err(ApiError::new(400, "'crop' must be 'center'")),
+                _ => {}
+            }
+        }
+        match mode.as_deref() {
+            None | Some("default") => Ok(UploadMode::Default),
+            Some("avatar") => Ok(UploadMode::Avatar { crop }),
+            Some("delta") => match base {
+                Some(base) if is_valid_hash(&base) => Ok(UploadMode::Delta { base }),
+                _ => Err(ApiError::new(
+                    400,
+                    "'base' must be a hash of an uploaded image",
+                )),
+            },
+            Some(m) => Err(ApiError::new(400, format!("Unknown upload mode: {}", m))),
+        }
+    }
+}
+
+/// Parse `max_colors` (or `quantize`) query param: number of colors to quantize the uploaded
+/// image to.
+fn quantize_param(req: &Request) -> ApiResult<Option<usize>> {
+    let Ok(url) = req.url() else {
+        return Err(ApiError::no_msg(500));
+    };
+    let Some((k, v)) = url
+        .query_pairs()
+        .find(|(k, _)| k == "max_colors" || k == "quantize")
+    else {
+        return Ok(None);
+    };
+    parse_max_colors(&v, &k)
+        .map(Some)
+        .map_err(|e| ApiError::new(400, e))
+}
+
+/// Parse `key` query param: color made transparent in the uploaded image (see
+/// `upix_lib::color_key`).
+fn color_key_param(req: &Request) -> ApiResult<Option<ColorKey>> {
+    let Ok(url) = req.url() else {
+        return Err(ApiError::no_msg(500));
+    };
+    let Some((_, v)) = url.query_pairs().find(|(k, _)| k == "key") else {
+        return Ok(None);
+    };
+    v.parse().map(Some).map_err(|e| ApiError::new(400, e))
+}
+
+/// Algorithm of hashes of new uploads (see `upix_lib::content_hash`).
+pub(crate) fn hash_algorithm(env: &Env) -> HashAlgorithm {
+    Config::from_env(env)
+        .map(|c| c.hash_algorithm)
+        .unwrap_or_default()
+}
+
+/// Canonical forms of the uploaded image and its animation, as stored (see lib/src/canonical.rs).
+fn canonicalize_upload(
+    env: &Env,
+    img: DynamicImage,
+    anim: Option<Animation>,
+) -> (DynamicImage, Option<Animation>) {
+    let keep_comments = Config::from_env(env).is_ok_and(|c| c.keep_comments);
+    canonicalize(img, anim, keep_comments)
+}
+
+/// Hash of the image encoded as PNG, for images without original data.
+fn png_hash(img: &DynamicImage, algorithm: HashAlgorithm) -> ApiResult<String> {
+    pipeline::png_hash(img, algorithm).map_err(|e| {
+        console_error!("failed to encode image: {:?}", e);
+        ApiError::no_msg(500)
+    })
+}
 
-    let uploader = ImageUploader {
-        img,
-        hash: sha256_hex(&img_data),
-        dest_fmt: ImageFormat::Png,
-        dest_bucket: bucket,
+/// Count the upload of the image in the namespace of the tenant it was uploaded to.
+async fn count_upload(env: &Env, origin: &UploadOrigin, hash: &str) {
+    let Ok(ns) = env.durable_object(COUNTER_BINDING) else {
+        console_error!("failed to get bindings to the counter");
+        return;
+    };
+    let batch = CounterBatch {
+        uploads: [(counter_key(&origin.tenant, &origin.namespace, hash), 1)].into(),
+        ..CounterBatch::default()
     };
-    let upload_res = uploader.upload_all().await;
-    upload_res.map_err(|_| ApiError::no_msg(500))
+    if let Err(e) = send_counter_batch(&ns, &batch).await {
+        console_error!("failed to count upload: {:?}", e);
+    }
 }
 
-const MAX_DATA_LEN: usize = 512 * 1024;
+/// Fields of uploads besides the image data, given along with it.
+#[derive(Debug, Default)]
+struct UploadFields {
+    scales: Option<String>,
+    tags: Option<Vec<String>>,
+}
 
-async fn get_image_data_from_request(req: &mut Request) -> ApiResult<(Vec<u8>, ImageFormat)> {
+/// Read the image data (or files of a batch upload, by name), and the fields sent along with it.
+async fn get_image_data_from_request(
+    req: &mut Request,
+    limits: &Limits,
+) -> ApiResult<(Vec<(String, ImageData)>, UploadFields)> {
     let Ok(Some(content_type)) = req.headers().get("Content-Type") else {
         return Err(ApiError::new(400, "Missing Content-Type header"));
     };
 
     if content_type.starts_with("multipart/form-data") {
-        get_image_data_from_form_data(req).await
+        get_image_data_from_form_data(req, limits).await
+    } else if content_type.starts_with("application/json") {
+        let Ok(JsonUpload { url, data, tags }) = req.json().await else {
+            return Err(ApiError::new(400, "Invalid upload request"));
+        };
+        let img_data = match (url, data) {
+            (Some(url), None) => fetch_remote_image(&url, limits).await?,
+            (None, Some(data)) => decode_data_url(&data, limits)?,
+            _ => {
+                return Err(ApiError::new(
+                    400,
+                    "Upload request must have either 'url' or 'data'",
+                ))
+            }
+        };
+        let fields = UploadFields { scales: None, tags };
+        Ok((vec![(String::new(), Ok(img_data))], fields))
     } else {
-        get_image_data_from_req_body(req, &content_type).await
+        let img_data = get_image_data_from_req_body(req, &content_type, limits).await?;
+        Ok((vec![(String::new(), Ok(img_data))], UploadFields::default()))
     }
 }
 
+/// Reconstruct a frame from the delta patch in the request body and the base image.
+async fn get_delta_frame_from_request(
+    req: &mut Request,
+    bucket: &Bucket,
+    tenant: &Tenant,
+    namespace: &Namespace,
+    base_name: &str,
+    limits: &Limits,
+) -> ApiResult<DynamicImage> {
+    let Ok(patch) = req.bytes().await else {
+        console_error!("could not read request body from the request");
+        return Err(ApiError::no_msg(500));
+    };
+    if patch.len() > limits.max_data_len {
+        return Err(ApiError::new(413, "Too large delta patch"));
+    }
+
+    let base = load_png_image(bucket, tenant, namespace, base_name)
+        .await
+        .map_err(|e| {
+            if e.status() == 404 {
+                ApiError::new(404, "Base image not found")
+            } else {
+                e
+            }
+        })?;
+    let frame =
+        apply_delta(&base.to_rgba8(), &patch).map_err(|e| ApiError::new(400, e.to_string()))?;
+    Ok(DynamicImage::ImageRgba8(frame))
+}
+
+/// JSON body of uploads, which gives the image by either of the fields.
+#[derive(Debug, Deserialize)]
+struct JsonUpload {
+    /// HTTPS URL to fetch the image from
+    #[serde(default)]
+    url: Option<String>,
+    /// data URL of the image, like `data:image/png;base64,...`
+    #[serde(default)]
+    data: Option<String>,
+    #[serde(default)]
+    tags: Option<Vec<String>>,
+}
+
+fn decode_data_url(data: &str, limits: &Limits) -> ImageData {
+    let Some(data_url) = DataUrl::parse(data) else {
+        return Err(ApiError::new(400, "'data' must be a base64 data URL"));
+    };
+    let img_fmt = validate_img_format(data_url.mime, limits)?;
+    let max_len = limits.max_accepted_data_len();
+    // the bound is up to 2 bytes larger than the data, for the padding
+    if data_url.max_decoded_len() > max_len + 2 {
+        return Err(ApiError::new(413, "Too large image data"));
+    }
+    let Some(img_data) = data_url.decode() else {
+        return Err(ApiError::new(400, "'data' is not valid base64"));
+    };
+    if img_data.len() > max_len {
+        return Err(ApiError::new(413, "Too large image data"));
+    }
+    Ok((img_data, img_fmt))
+}
+
 async fn get_image_data_from_req_body(
     req: &mut Request,
     ctype: &str,
+    limits: &Limits,
 ) -> ApiResult<(Vec<u8>, ImageFormat)> {
-    let img_fmt = validate_img_format(ctype)?;
+    let img_fmt = validate_img_format(ctype, limits)?;
 
     let Ok(img_data) = req.bytes().await else {
         console_error!("could not read request body from the request");
         return Err(ApiError::no_msg(500));
     };
-    if img_data.len() > MAX_DATA_LEN {
+    if img_data.len() > limits.max_accepted_data_len() {
         return Err(ApiError::new(413, "Too large image data"));
     }
     Ok((img_data, img_fmt))
 }
 
-async fn get_image_data_from_form_data(req: &mut Request) -> ApiResult<(Vec<u8>, ImageFormat)> {
+/// Read the `file` fields of the form data. Repeated fields make a batch upload, whose files are
+/// processed synchronously, so they are limited to `max_data_len` each.
+async fn get_image_data_from_form_data(
+    req: &mut Request,
+    limits: &Limits,
+) -> ApiResult<(Vec<(String, ImageData)>, UploadFields)> {
     let Ok(form_data) = req.form_data().await else {
         console_error!("could not read form data from the request");
         return Err(ApiError::no_msg(500));
     };
 
-    let Some(file_entry) = form_data.get("file") else {
+    let entries = form_data.get_all("file").unwrap_or_default();
+    if entries.is_empty() {
         return Err(ApiError::new(400, "Missing 'file' field in form data"));
+    }
+    if entries.len() > MAX_BATCH_FILES {
+        return Err(ApiError::new(
+            400,
+            format!("Too many files in a batch upload (> {})", MAX_BATCH_FILES),
+        ));
+    }
+    let max_len = match entries.len() {
+        1 => limits.max_accepted_data_len(),
+        _ => limits.max_data_len,
     };
-    let FormEntry::File(file) = file_entry else {
-        return Err(ApiError::new(400, "'file' field is not a file"));
+    let mut files = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let FormEntry::File(file) = entry else {
+            files.push((
+                String::new(),
+                Err(ApiError::new(400, "'file' field is not a file")),
+            ));
+            continue;
+        };
+        files.push((file.name(), read_form_file(&file, max_len, limits).await));
+    }
+
+    let text_field = |name| match form_data.get(name) {
+        Some(FormEntry::Field(s)) => Ok(Some(s)),
+        Some(FormEntry::File(_)) => Err(ApiError::new(
+            400,
+            format!("'{}' field is not a text", name),
+        )),
+        None => Ok(None),
+    };
+    let fields = UploadFields {
+        scales: text_field("scales")?,
+        tags: text_field("tags")?.map(|t| split_tags(&t)),
     };
+    Ok((files, fields))
+}
 
-    if file.size() > MAX_DATA_LEN {
+async fn read_form_file(file: &File, max_len: usize, limits: &Limits) -> ImageData {
+    if file.size() > max_len {
         return Err(ApiError::new(413, "Too large image data"));
     }
 
-    let img_fmt = validate_img_format(&file.type_())?;
+    let img_fmt = validate_img_format(&file.type_(), limits)?;
     let Ok(img_data) = file.bytes().await else {
         console_error!("could not read file data from the form data");
         return Err(ApiError::no_msg(500));
@@ -127,124 +1320,279 @@ async fn get_image_data_from_form_data(req: &mut Request) -> ApiResult<(Vec<u8>,
     Ok((img_data, img_fmt))
 }
 
-fn validate_img_format(content_type: &str) -> ApiResult<ImageFormat> {
-    if !content_type.starts_with("image/") {
-        return Err(ApiError::new(400, "Content-Type is not for an image"));
-    }
-    let Some(img_fmt) = ImageFormat::from_mime_type(content_type) else {
-        return Err(ApiError::new(400, "Content-Type is not for an image"));
+/// Check the dimensions of the image data in its header against the limits, before decoding any
+/// frame of it, so that small data of huge images can't exhaust the memory.
+fn validate_data_dimensions(
+    img_data: &[u8],
+    img_fmt: ImageFormat,
+    limits: &Limits,
+) -> ApiResult<Dimensions> {
+    let Some(dims) = decode_dimensions(img_data, img_fmt) else {
+        return Err(ApiError::new(400, "Failed to decode image").with_code(ErrorCode::DecodeFailed));
     };
-
-    match img_fmt {
-        ImageFormat::Png | ImageFormat::WebP | ImageFormat::Bmp | ImageFormat::Gif => Ok(img_fmt),
-        _ => Err(ApiError::new(
-            400,
-            format!("Unsupported image format: {}", img_fmt.extensions_str()[0]),
-        )),
-    }
+    validate_dimensions(dims, limits)?;
+    Ok(dims)
 }
 
-const MAX_PIXELS: u32 = 65536;
-const MAX_LONG_SIDE_LEN: u32 = 1024;
-const MAX_ASPECT_RATIO: f64 = 16.0;
-
-fn validate_img_dimension(img: &DynamicImage) -> ApiResult<()> {
-    let (w, h) = img.dimensions();
-    if w * h > MAX_PIXELS {
+/// Decode all frames of the image data of the dimensions (checked by
+/// [`validate_data_dimensions`]) if it is an animated GIF or APNG.
+///
+/// Frames are decoded at once only after the number of frames (counted one frame at a time) is
+/// checked against the limits, so that small data of long animations can't exhaust the memory.
+fn decode_uploaded_animation(
+    img_data: &[u8],
+    img_fmt: ImageFormat,
+    dims: Dimensions,
+) -> ApiResult<Option<Animation>> {
+    if !keeps_animation(img_fmt) {
+        return Ok(None);
+    }
+    let frames = count_frames(img_data, img_fmt).map_err(|e| match e {
+        ImageError::Decoding(_) => {
+            ApiError::new(400, "Failed to decode image").with_code(ErrorCode::DecodeFailed)
+        }
+        e => {
+            console_error!("failed to count frames: {:?}", e);
+            ApiError::no_msg(500)
+        }
+    })?;
+    if frames <= 1 {
+        return Ok(None);
+    }
+    if frames > MAX_FRAMES {
         return Err(ApiError::new(
             400,
-            format!("Image has too many pixels ({} > {})", w * h, MAX_PIXELS),
-        ));
+            format!("Animation has too many frames (> {})", MAX_FRAMES),
+        )
+        .with_code(ErrorCode::TooManyFrames));
     }
-
-    let (long, short) = if w > h { (w, h) } else { (h, w) };
-    if long > MAX_LONG_SIDE_LEN {
+    if !frames_allow_scale(dims, frames, 1) {
         return Err(ApiError::new(
             400,
             format!(
-                "Long side of image is too long ({} > {})",
-                long, MAX_LONG_SIDE_LEN
+                "Animation has too many pixels in all frames (> {})",
+                MAX_TOTAL_PIXELS
             ),
-        ));
+        )
+        .with_code(ErrorCode::TooManyPixels));
+    }
+    decode_animation(img_data, img_fmt).map_err(|e| match e {
+        ImageError::Decoding(_) => {
+            ApiError::new(400, "Failed to decode image").with_code(ErrorCode::DecodeFailed)
+        }
+        e => {
+            console_error!("failed to load animation: {:?}", e);
+            ApiError::no_msg(500)
+        }
+    })
+}
+
+/// Check the image of which only the first frame is kept against the multi-frame policy.
+fn check_frames(img_data: &[u8], img_fmt: ImageFormat, env: &Env) -> ApiResult<Option<Warning>> {
+    let frames = count_frames(img_data, img_fmt).map_err(|e| match e {
+        ImageError::Decoding(_) => {
+            ApiError::new(400, "Failed to decode image").with_code(ErrorCode::DecodeFailed)
+        }
+        e => {
+            console_error!("failed to count frames: {:?}", e);
+            ApiError::no_msg(500)
+        }
+    })?;
+    let policy = Config::from_env(env)
+        .map(|c| c.multi_frame_policy)
+        .unwrap_or_default();
+    check_dropped_frames(policy, frames)
+}
+
+fn decode_image(img_data: &[u8], img_fmt: ImageFormat) -> ApiResult<DynamicImage> {
+    image::load_from_memory_with_format(img_data, img_fmt).map_err(|e| match e {
+        ImageError::Decoding(_) => {
+            ApiError::new(400, "Failed to decode image").with_code(ErrorCode::DecodeFailed)
+        }
+        e => {
+            console_error!("failed to load image: {:?}", e);
+            ApiError::no_msg(500)
+        }
+    })
+}
+
+fn validate_img_format(content_type: &str, limits: &Limits) -> ApiResult<ImageFormat> {
+    if !content_type.starts_with("image/") {
+        return Err(ApiError::new(400, "Content-Type is not for an image")
+            .with_code(ErrorCode::UnsupportedFormat));
     }
-    if f64::from(long) / f64::from(short) > MAX_ASPECT_RATIO {
+    let Some(img_fmt) = image_format_from_mime_type(content_type) else {
+        return Err(ApiError::new(400, "Content-Type is not for an image")
+            .with_code(ErrorCode::UnsupportedFormat));
+    };
+
+    if !limits.allows_format(img_fmt) {
         return Err(ApiError::new(
             400,
-            format!(
-                "Aspect retio of image is out of range ({} : {} > {} : 1)",
-                long, short, MAX_ASPECT_RATIO
-            ),
-        ));
+            format!("Unsupported image format: {}", img_fmt.extensions_str()[0]),
+        )
+        .with_code(ErrorCode::UnsupportedFormat));
     }
-    Ok(())
+    Ok(img_fmt)
+}
+
+/// Failures of uploads of images to the bucket, with the stage and the object they failed at.
+#[derive(Debug, thiserror::Error)]
+pub enum UploadError {
+    #[error("failed to encode {key}: {source}")]
+    Encode { key: String, source: ImageError },
+    #[error("failed to store {key}: {source}")]
+    Store { key: String, source: BlobError },
+    #[error("failed to store variants of {hash}: {source}")]
+    Variants { hash: String, source: StoreError },
+    #[error("failed to store manifest of {hash}: {source}")]
+    Manifest { hash: String, source: BlobError },
 }
 
-/// Uploads an image to a bucket. Returns the file name (stem + extension for the image format) of the uploaded image if succeeded.
+impl UploadError {
+    /// Whether the upload failed at the bucket, rather than at processing the image.
+    pub fn is_storage_failure(&self) -> bool {
+        match self {
+            UploadError::Encode { .. } => false,
+            UploadError::Store { .. } | UploadError::Manifest { .. } => true,
+            UploadError::Variants { source, .. } => matches!(source, StoreError::Blob(_)),
+        }
+    }
+}
+
+impl From<UploadError> for ApiError {
+    fn from(e: UploadError) -> Self {
+        console_error!("upload failed: {}", e);
+        if e.is_storage_failure() {
+            ApiError::new(500, "Failed to store image").with_code(ErrorCode::StorageFailed)
+        } else {
+            ApiError::new(500, "Failed to encode image").with_code(ErrorCode::EncodeFailed)
+        }
+    }
+}
+
+/// Uploads an image to a bucket under the `key_prefix`. Returns the file name (stem + extension for the image format) of the uploaded image if succeeded.
 #[worker::send]
 async fn upload_image_to_bucket(
+    key_prefix: &str,
     stem: &str,
     data: Vec<u8>,
     img_fmt: ImageFormat,
     bucket: SendWrapper<Bucket>,
-) -> Result<String, ()> {
+) -> Result<String, UploadError> {
     console_log!("uploading image... (stem: {})", stem);
 
-    let key = format!("{}.{}", stem, img_fmt.extensions_str()[0]);
-    let meta = HttpMetadata {
-        content_type: Some(img_fmt.to_mime_type().to_string()),
-        ..HttpMetadata::default()
-    };
-
-    let put_res = bucket.put(&key, data).http_metadata(meta).execute().await;
-    match put_res {
-        Ok(_) => Ok(key),
-        Err(e) => {
-            console_error!("failed to upload image to the bucket: {:?}", e);
-            Err(())
-        }
-    }
+    let name = format!("{}.{}", stem, img_fmt.extensions_str()[0]);
+    let key = format!("{}{}", key_prefix, name);
+    bucket
+        .store(&key, data, img_fmt.to_mime_type())
+        .await
+        .map_err(|source| UploadError::Store { key, source })?;
+    Ok(name)
 }
 
 struct ImageUploader {
+    /// the image, or the first frame if it is animated
     img: DynamicImage,
+    /// stored as APNG if set
+    anim: Option<Animation>,
     hash: String,
+    /// prefix of keys of uploaded images (namespace of the tenant)
+    key_prefix: String,
+    limits: Limits,
+    /// requested scales of variants, instead of the default ladder
+    scales: Option<Vec<u32>>,
     dest_fmt: ImageFormat,
     dest_bucket: SendWrapper<Bucket>,
-}
-
-#[derive(Debug, Serialize)]
-struct UploadedImage {
-    name: String,
-    scale: u32,
-    width: u32,
-    height: u32,
+    /// bucket of variants in cold storage, which are stored in `dest_bucket` if unset
+    cold_bucket: Option<SendWrapper<Bucket>>,
+    /// whether upscaled variants are left to the variant queue (see `variant_queue`)
+    defer_variants: bool,
 }
 
 impl ImageUploader {
-    async fn upload_all(&self) -> Result<Vec<UploadedImage>, ()> {
-        let (w, h) = self.img.dimensions();
-        let long = u32::max(w, h);
+    /// Upload the image and its upscaled variants, and return them along with the scales of the
+    /// variants deferred to the variant queue.
+    async fn upload_all(&self) -> Result<(Vec<UploadedImage>, Vec<u32>), UploadError> {
+        let scales: Vec<_> = variant_scales(
+            &self.limits,
+            &self.img,
+            self.anim.as_ref(),
+            self.scales.as_deref(),
+        )
+        .into_iter()
+        .filter(|&s| self.limits.cold_storage_of(s) != Some(ColdStorage::OnDemand))
+        .collect();
+        let (scales, deferred) = match self.defer_variants {
+            true => split_deferred_scales(&scales).unwrap_or((scales, Vec::new())),
+            false => (scales, Vec::new()),
+        };
+        let stores = VariantStores {
+            store: &*self.dest_bucket,
+            cold_store: self.cold_bucket.as_deref(),
+        };
+        let uploaded = store_variants(
+            stores,
+            &self.key_prefix,
+            &self.hash,
+            &self.img,
+            self.anim.as_ref(),
+            &self.limits,
+            Some(&scales),
+        )
+        .await
+        .map_err(|source| UploadError::Variants {
+            hash: self.hash.clone(),
+            source,
+        })?;
+        console_log!("uploaded {} images (hash: {})", uploaded.len(), &self.hash);
+        Ok((uploaded, deferred))
+    }
 
-        let tasks = [1, 2, 4, 8, 16]
+    async fn upload_avatars(&self) -> Result<Vec<UploadedImage>, UploadError> {
+        let tasks = std::iter::once(Box::pin(self.upload_original_image()) as future::BoxFuture<_>)
+            .chain(
+                AVATAR_SIZES
+                    .into_iter()
+                    .map(|size| Box::pin(self.upload_avatar_image(size)) as future::BoxFuture<_>),
+            );
+        let (uploaded, objects) = future::join_all(tasks)
+            .await
             .into_iter()
-            .take_while(|&x| long * x <= 1024)
-            .map(|scale| {
-                if scale == 1 {
-                    Box::pin(self.upload_original_image()) as future::BoxFuture<_>
-                } else {
-                    Box::pin(self.upload_upscaled_image(scale)) as future::BoxFuture<_>
-                }
-            });
-        future::join_all(tasks).await.into_iter().collect()
+            .collect::<Result<(Vec<_>, Vec<_>), _>>()?;
+
+        let manifest = Manifest {
+            hash: self.hash.clone(),
+            objects,
+        };
+        store_manifest(&*self.dest_bucket, &self.key_prefix, &manifest)
+            .await
+            .map_err(|source| UploadError::Manifest {
+                hash: self.hash.clone(),
+                source,
+            })?;
+        Ok(uploaded)
     }
 
-    async fn upload_original_image(&self) -> Result<UploadedImage, ()> {
-        let mut img_data = Vec::new();
-        encode_image(&self.img, self.dest_fmt, &mut img_data).map_err(|e| {
-            console_error!("failed to encode image: {:?}", e);
-        })?;
+    /// Encode the image (or animation) upscaled by the scale factor.
+    fn encode_scaled(&self, scale: u32) -> Result<(Vec<u8>, Dimensions), UploadError> {
+        let variant = match scale {
+            1 => Variant::Original,
+            s => Variant::Upscaled(s),
+        };
+        encode_scaled(&self.img, self.anim.as_ref(), scale).map_err(|source| UploadError::Encode {
+            key: format!("{}{}", self.key_prefix, variant.file_name(&self.hash)),
+            source,
+        })
+    }
+
+    async fn upload_original_image(&self) -> Result<(UploadedImage, ManifestEntry), UploadError> {
+        let (img_data, dims) = self.encode_scaled(1)?;
+        let entry = ManifestEntry::new(Variant::Original.file_name(&self.hash), &img_data);
+        let size = entry.size;
 
         let name = upload_image_to_bucket(
+            &self.key_prefix,
             &self.hash,
             img_data,
             self.dest_fmt,
@@ -253,34 +1601,77 @@ impl ImageUploader {
         .await?;
         console_log!("uploaded original image (name: {})", &name);
 
-        Ok(UploadedImage {
+        let uploaded = UploadedImage {
             name,
-            scale: 1,
-            width: self.img.width(),
-            height: self.img.height(),
-        })
+            scale: Some(1),
+            width: dims.width,
+            height: dims.height,
+            size,
+            url: None,
+        };
+        Ok((uploaded, entry))
     }
 
-    async fn upload_upscaled_image(&self, scale: u32) -> Result<UploadedImage, ()> {
-        let scaled = upscale_image(&self.img, scale);
+    async fn upload_avatar_image(
+        &self,
+        size: u32,
+    ) -> Result<(UploadedImage, ManifestEntry), UploadError> {
+        let avatar = render_avatar(&self.img, size);
+        let file_name = Variant::Avatar(size).file_name(&self.hash);
 
         let mut img_data = Vec::new();
-        encode_image(&scaled, self.dest_fmt, &mut img_data).map_err(|e| {
-            console_error!("failed to encode image: {:?}", e);
+        encode_png(&avatar, &mut img_data, true).map_err(|source| UploadError::Encode {
+            key: format!("{}{}", self.key_prefix, file_name),
+            source,
         })?;
+        let entry = ManifestEntry::new(file_name, &img_data);
+        let data_size = entry.size;
 
-        // stem (file name without extension) is the hash followed by the scale
-        let stem = format!("{}_{}x", self.hash, scale);
-
-        let name = upload_image_to_bucket(&stem, img_data, self.dest_fmt, self.dest_bucket.clone())
-            .await?;
-        console_log!("uploaded {}x upscaled image (name: {})", scale, &name);
+        let stem = format!("{}_avatar_{}", self.hash, size);
+        let name = upload_image_to_bucket(
+            &self.key_prefix,
+            &stem,
+            img_data,
+            self.dest_fmt,
+            self.dest_bucket.clone(),
+        )
+        .await?;
+        console_log!("uploaded {}px avatar image (name: {})", size, &name);
 
-        Ok(UploadedImage {
+        let uploaded = UploadedImage {
             name,
-            scale,
-            width: scaled.width(),
-            height: scaled.height(),
-        })
+            scale: None,
+            width: size,
+            height: size,
+            size: data_size,
+            url: None,
+        };
+        Ok((uploaded, entry))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_upload_error() {
+        let e = UploadError::Variants {
+            hash: "abc".to_string(),
+            source: StoreError::Blob(BlobError("failed to put abc_2x.png".to_string())),
+        };
+        assert!(e.is_storage_failure());
+        assert_eq!(
+            e.to_string(),
+            "failed to store variants of abc: failed to put abc_2x.png"
+        );
+
+        let e = UploadError::Encode {
+            key: "abc.png".to_string(),
+            source: ImageError::Limits(image::error::LimitError::from_kind(
+                image::error::LimitErrorKind::InsufficientMemory,
+            )),
+        };
+        assert!(!e.is_storage_failure());
     }
 }
@@ -0,0 +1,134 @@
+//! Asset packs (see lib/src/pack.rs) in the `packs` table of the D1 database.
+
+use serde::{Deserialize, Serialize};
+use worker::{
+    console_error, wasm_bindgen::JsValue, Context, D1Database, Date, Request, Response,
+    Result as WorkerResult, RouteContext,
+};
+
+use upix_lib::{
+    namespace::find_namespace,
+    pack::{is_valid_pack_id, AssetPack, ResolvedPack},
+    tenant::Tenant,
+    ApiError, ApiResult,
+};
+
+use crate::{
+    db::{db_error, get_db},
+    request_tenant,
+};
+
+#[derive(Debug, Serialize)]
+struct StoredPack {
+    id: String,
+    /// get this for URLs of the entries
+    resolve_url: String,
+}
+
+/// Store the pack, and respond with its ID. Storing the same pack again responds with the same ID.
+pub async fn handle_post_pack(
+    mut req: Request,
+    ctx: RouteContext<Context>,
+) -> WorkerResult<Response> {
+    let tenant = match request_tenant(&req, &ctx.env).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e.to_response(),
+    };
+    match post_pack(&mut req, &ctx, &tenant).await {
+        Ok(pack) => Response::from_json(&pack).map(|r| r.with_status(201)),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn post_pack(
+    req: &mut Request,
+    ctx: &RouteContext<Context>,
+    tenant: &Tenant,
+) -> ApiResult<StoredPack> {
+    let Ok(pack) = req.json::<AssetPack>().await else {
+        return Err(ApiError::new(400, "Invalid pack"));
+    };
+    pack.validate(|name| find_namespace(&ctx.env, name).map(|ns| ns.limits.max_scale))
+        .map_err(|e| ApiError::new(400, e.to_string()))?;
+    let pack = pack.canonical();
+    let id = pack.id();
+
+    let db = get_db(ctx)?;
+    store_pack(&db, &tenant.id, &id, &pack).await?;
+    Ok(StoredPack {
+        resolve_url: format!("/packs/{}/resolve", id),
+        id,
+    })
+}
+
+/// URLs of the entries of the pack, served by the dyn worker.
+pub async fn handle_get_resolved_pack(
+    req: Request,
+    ctx: RouteContext<Context>,
+) -> WorkerResult<Response> {
+    let tenant = match request_tenant(&req, &ctx.env).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e.to_response(),
+    };
+    match get_resolved_pack(&ctx, &tenant).await {
+        Ok(resolved) => Response::from_json(&resolved),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn get_resolved_pack(
+    ctx: &RouteContext<Context>,
+    tenant: &Tenant,
+) -> ApiResult<ResolvedPack> {
+    let Some(id) = ctx.param("id").filter(|id| is_valid_pack_id(id)) else {
+        return Err(ApiError::new(400, "Invalid pack ID"));
+    };
+    let Some(base_url) = tenant.public_base_url(&ctx.env) else {
+        return Err(ApiError::new(503, "PUBLIC_BASE_URL is not configured"));
+    };
+    let db = get_db(ctx)?;
+    let pack = find_pack(&db, &tenant.id, id)
+        .await?
+        .ok_or_else(|| ApiError::new(404, "Pack not found"))?;
+    Ok(pack.resolve(&base_url))
+}
+
+/// Store the pack of the tenant, unless it is already stored.
+async fn store_pack(db: &D1Database, tenant: &str, id: &str, pack: &AssetPack) -> ApiResult<()> {
+    db.prepare(
+        "INSERT INTO packs (tenant, id, pack, created_at) VALUES (?1, ?2, ?3, ?4) \
+         ON CONFLICT (tenant, id) DO NOTHING",
+    )
+    .bind(&[
+        JsValue::from(tenant),
+        JsValue::from(id),
+        JsValue::from(serde_json::to_string(pack).unwrap()),
+        JsValue::from(Date::now().as_millis() as f64),
+    ])
+    .map_err(db_error)?
+    .run()
+    .await
+    .map_err(db_error)?;
+    Ok(())
+}
+
+/// The pack of the tenant with the ID.
+async fn find_pack(db: &D1Database, tenant: &str, id: &str) -> ApiResult<Option<AssetPack>> {
+    #[derive(Deserialize)]
+    struct PackRow {
+        pack: String,
+    }
+    let row = db
+        .prepare("SELECT pack FROM packs WHERE tenant = ?1 AND id = ?2")
+        .bind(&[JsValue::from(tenant), JsValue::from(id)])
+        .map_err(db_error)?
+        .first::<PackRow>(None)
+        .await
+        .map_err(db_error)?;
+    row.map(|r| serde_json::from_str(&r.pack))
+        .transpose()
+        .map_err(|e| {
+            console_error!("failed to parse stored pack {}: {:?}", id, e);
+            ApiError::no_msg(500)
+        })
+}
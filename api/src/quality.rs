@@ -0,0 +1,40 @@
+use worker::{Context, Headers, Request, Response, Result as WorkerResult, RouteContext};
+
+use upix_lib::{
+    cache_policy::CacheRoute,
+    extract::{path_param, Hash},
+    quality::QualityScore,
+    tenant::Tenant,
+    ApiResult,
+};
+
+use crate::{export::load_original_image, request_tenant};
+
+/// Heuristic scores of how much the image looks like pixel art. The scores are of the original
+/// image and thus immutable, so they are cached like variants.
+pub async fn handle_get_quality(
+    req: Request,
+    ctx: RouteContext<Context>,
+) -> WorkerResult<Response> {
+    let tenant = match request_tenant(&req, &ctx.env).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e.to_response(),
+    };
+    let resp = match get_quality(&ctx, &tenant).await {
+        Ok(score) => {
+            let mut headers = Headers::new();
+            tenant
+                .cache_policy(CacheRoute::Variant, &ctx.env)
+                .apply(&mut headers)?;
+            Response::from_json(&score).map(|r| r.with_headers(headers))
+        }
+        Err(e) => e.to_response(),
+    };
+    resp
+}
+
+async fn get_quality(ctx: &RouteContext<Context>, tenant: &Tenant) -> ApiResult<QualityScore> {
+    let Hash(hash) = path_param(ctx, "hash")?;
+    let img = load_original_image(ctx, tenant, &hash).await?;
+    Ok(QualityScore::of(&img.to_rgba8()))
+}
@@ -0,0 +1,55 @@
+use worker::{console_error, Context, Request, RouteContext};
+
+use upix_lib::{
+    config::Config,
+    notify::{notify_admins, AdminEvent},
+    rate_limit::{check_rate_limit, client_key, RATE_LIMITER_BINDING},
+    ApiError, ApiResult,
+};
+
+/// Count the upload against the rate limit of the client, and reject it with 429 if the client
+/// has uploaded too much. Operators are notified when a client uses up its hourly quota.
+///
+/// Uploads are let through if the limiter is not bound or fails, so that it never takes uploads
+/// down by itself.
+pub async fn limit_upload_rate(req: &Request, ctx: &RouteContext<Context>) -> ApiResult<()> {
+    let Ok(ns) = ctx.durable_object(RATE_LIMITER_BINDING) else {
+        return Ok(());
+    };
+    let Ok(config) = Config::from_env(&ctx.env) else {
+        return Err(ApiError::no_msg(500));
+    };
+    let ip = req
+        .headers()
+        .get("CF-Connecting-IP")
+        .ok()
+        .flatten()
+        .unwrap_or_default();
+
+    let limit = config.upload_rate_limit;
+    match check_rate_limit(&ns, &client_key(&ip), limit).await {
+        Ok(decision) if decision.allowed => {
+            if decision.exhausted {
+                let env = ctx.env.clone();
+                let event = AdminEvent::QuotaExhausted {
+                    per_hour: limit.per_hour,
+                };
+                ctx.data
+                    .wait_until(async move { notify_admins(&env, event).await });
+            }
+            Ok(())
+        }
+        Ok(decision) => {
+            let retry_after = decision.retry_after.unwrap_or(60);
+            Err(ApiError::new(
+                429,
+                format!("Too many uploads. Retry after {} seconds", retry_after),
+            )
+            .with_header("Retry-After", retry_after.to_string()))
+        }
+        Err(e) => {
+            console_error!("failed to check rate limit: {:?}", e);
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,84 @@
+//! Uploads of images fetched from remote URLs.
+//!
+//! `POST /` with `application/json` like `{ "url": "https://..." }` fetches the image, and then
+//! processes it in the same way as uploaded data. The size and the format are checked against the
+//! limits of the namespace before the body is read, and the size again while it is streamed, so
+//! that oversized responses are never buffered as a whole.
+
+use futures::StreamExt;
+use image::ImageFormat;
+use worker::{console_error, console_log, Fetch, Headers, Method, Request, RequestInit, Url};
+
+use upix_lib::{namespace::Limits, ApiError, ApiResult};
+
+use crate::validate_img_format;
+
+/// Fetch the image at the URL, within the limits.
+pub(crate) async fn fetch_remote_image(
+    url: &str,
+    limits: &Limits,
+) -> ApiResult<(Vec<u8>, ImageFormat)> {
+    let url = Url::parse(url)
+        .ok()
+        .filter(|u| u.scheme() == "https" && u.host_str().is_some())
+        .ok_or_else(|| ApiError::new(400, "'url' must be an HTTPS URL"))?;
+
+    let mut headers = Headers::new();
+    headers
+        .set("Accept", "image/*")
+        .map_err(|_| ApiError::no_msg(500))?;
+    let mut init = RequestInit::new();
+    init.with_method(Method::Get).with_headers(headers);
+    let req = Request::new_with_init(url.as_str(), &init).map_err(|e| {
+        console_error!("failed to build request to the remote image: {:?}", e);
+        ApiError::no_msg(500)
+    })?;
+    let mut resp = Fetch::Request(req).send().await.map_err(|e| {
+        console_log!("failed to fetch remote image ({}): {:?}", url, e);
+        ApiError::new(400, "Failed to fetch the image")
+    })?;
+    if !(200..300).contains(&resp.status_code()) {
+        return Err(ApiError::new(
+            400,
+            format!("Failed to fetch the image (status {})", resp.status_code()),
+        ));
+    }
+
+    let content_type = resp
+        .headers()
+        .get("Content-Type")
+        .ok()
+        .flatten()
+        .unwrap_or_default();
+    let mime = content_type.split(';').next().unwrap_or_default().trim();
+    let img_fmt = validate_img_format(mime, limits)?;
+
+    let max_len = limits.max_accepted_data_len();
+    let content_length = resp
+        .headers()
+        .get("Content-Length")
+        .ok()
+        .flatten()
+        .and_then(|l| l.parse::<usize>().ok());
+    if content_length.is_some_and(|l| l > max_len) {
+        return Err(ApiError::new(413, "Too large image data"));
+    }
+
+    // Content-Length may be absent or wrong, so count bytes as they arrive
+    let mut stream = resp.stream().map_err(|e| {
+        console_error!("failed to read remote image: {:?}", e);
+        ApiError::no_msg(500)
+    })?;
+    let mut img_data = Vec::with_capacity(content_length.unwrap_or_default());
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| {
+            console_log!("failed to read remote image ({}): {:?}", url, e);
+            ApiError::new(400, "Failed to fetch the image")
+        })?;
+        if img_data.len() + chunk.len() > max_len {
+            return Err(ApiError::new(413, "Too large image data"));
+        }
+        img_data.extend_from_slice(&chunk);
+    }
+    Ok((img_data, img_fmt))
+}
@@ -0,0 +1,291 @@
+use serde::{Deserialize, Serialize};
+use worker::{
+    console_error, console_log, wasm_bindgen::JsValue, Context, Date, Request, Response,
+    Result as WorkerResult, RouteContext,
+};
+
+use upix_lib::{
+    extract::{path_param, Hash},
+    hmac_sha256_hex,
+    namespace::Namespace,
+    notify::{notify_admins, AdminEvent},
+    schema::KeySchema,
+    tenant::Tenant,
+    upload_index::{load_index_entry, upload_index},
+    ApiError, ApiResult,
+};
+
+use crate::{
+    db::{db_error, get_db},
+    direct_upload::query_namespace,
+    request_tenant,
+    uploads::IP_HASH_SECRET,
+};
+
+const MAX_REASON_LEN: usize = 1000;
+const MAX_CONTACT_LEN: usize = 200;
+
+/// Each client can file at most this many reports per hour.
+const MAX_REPORTS_PER_HOUR: u32 = 5;
+const HOUR_MS: u64 = 60 * 60 * 1000;
+
+#[derive(Debug, Deserialize)]
+struct ReportRequest {
+    reason: String,
+    contact: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Report {
+    id: u64,
+    /// ID of the tenant the image was reported in, empty for the default tenant
+    tenant: String,
+    /// namespace the image was reported in, empty for the root namespace
+    namespace: String,
+    hash: String,
+    reason: String,
+    contact: Option<String>,
+    created_at: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct RecentReports {
+    n: u32,
+    /// when the oldest of the reports was filed, if any
+    oldest: Option<u64>,
+}
+
+/// Report the image in the root namespace of the tenant, or in the one given by the `namespace`
+/// query param.
+pub async fn handle_post_report(
+    req: Request,
+    ctx: RouteContext<Context>,
+) -> WorkerResult<Response> {
+    let tenant = match request_tenant(&req, &ctx.env).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e.to_response(),
+    };
+    let res = post_report(req, ctx, &tenant).await;
+    match res {
+        Ok(()) => Ok(Response::empty()?.with_status(202)),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn post_report(
+    mut req: Request,
+    ctx: RouteContext<Context>,
+    tenant: &Tenant,
+) -> ApiResult<()> {
+    let Hash(hash) = path_param(&ctx, "hash")?;
+    let namespace = query_namespace(&req, &ctx)?;
+    let Ok(report) = req.json::<ReportRequest>().await else {
+        return Err(ApiError::new(400, "Invalid report"));
+    };
+    let reason = report.reason.trim();
+    if reason.is_empty() || reason.chars().count() > MAX_REASON_LEN {
+        return Err(ApiError::new(
+            400,
+            format!("'reason' must be 1 to {} characters", MAX_REASON_LEN),
+        ));
+    }
+    let contact = report
+        .contact
+        .map(|c| c.trim().to_string())
+        .filter(|c| !c.is_empty());
+    if contact
+        .as_ref()
+        .is_some_and(|c| c.chars().count() > MAX_CONTACT_LEN)
+    {
+        return Err(ApiError::new(
+            400,
+            format!("'contact' must be up to {} characters", MAX_CONTACT_LEN),
+        ));
+    }
+
+    if !image_exists(&ctx, tenant, &namespace, &hash).await? {
+        return Err(ApiError::new(404, "Image not found"));
+    }
+
+    // reporters are identified by IP addresses hashed with a secret, so that raw addresses are
+    // never stored, nor recovered by hashing every address
+    let Ok(secret) = ctx.secret(IP_HASH_SECRET) else {
+        console_error!("{} is not configured", IP_HASH_SECRET);
+        return Err(ApiError::no_msg(500));
+    };
+    let ip = req
+        .headers()
+        .get("CF-Connecting-IP")
+        .ok()
+        .flatten()
+        .unwrap_or_default();
+    let reporter = hmac_sha256_hex(secret.to_string().as_bytes(), &ip);
+    let now = Date::now().as_millis();
+
+    let db = get_db(&ctx)?;
+    let recent = db
+        .prepare(
+            "SELECT COUNT(*) AS n, MIN(created_at) AS oldest FROM reports \
+             WHERE reporter = ?1 AND created_at > ?2",
+        )
+        .bind(&[
+            JsValue::from(reporter.as_str()),
+            JsValue::from((now - HOUR_MS) as f64),
+        ])
+        .map_err(db_error)?
+        .first::<RecentReports>(None)
+        .await
+        .map_err(db_error)?;
+    if let Some(RecentReports {
+        n,
+        oldest: Some(oldest),
+    }) = recent
+    {
+        if n >= MAX_REPORTS_PER_HOUR {
+            // another report can be filed once the oldest one is out of the window
+            let retry_after = (oldest + HOUR_MS).saturating_sub(now).div_ceil(1000).max(1);
+            return Err(ApiError::new(
+                429,
+                format!("Too many reports. Retry after {} seconds", retry_after),
+            )
+            .with_header("Retry-After", retry_after.to_string()));
+        }
+    }
+
+    db.prepare(
+        "INSERT INTO reports (tenant, namespace, hash, reason, contact, reporter, created_at) \
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+    )
+    .bind(&[
+        JsValue::from(tenant.id.as_str()),
+        JsValue::from(namespace.name.as_str()),
+        JsValue::from(hash.as_str()),
+        JsValue::from(reason),
+        contact.as_deref().map_or(JsValue::NULL, JsValue::from),
+        JsValue::from(reporter.as_str()),
+        JsValue::from(now as f64),
+    ])
+    .map_err(db_error)?
+    .run()
+    .await
+    .map_err(db_error)?;
+    console_log!("recorded abuse report (hash: {})", hash);
+
+    let event = AdminEvent::AbuseReport {
+        tenant: tenant.id.clone(),
+        namespace: namespace.name,
+        hash,
+        reason: reason.to_string(),
+        contact,
+    };
+    let env = ctx.env.clone();
+    ctx.data
+        .wait_until(async move { notify_admins(&env, event).await });
+    Ok(())
+}
+
+const DEFAULT_REPORTS_LIMIT: u32 = 50;
+const MAX_REPORTS_LIMIT: u32 = 500;
+
+#[derive(Debug, Serialize)]
+struct ReportList {
+    reports: Vec<Report>,
+    /// Pass this as `cursor` to get the next page. Absent if there are no more reports.
+    cursor: Option<u64>,
+}
+
+/// Whether the image with the hash exists in the namespace of the tenant: by the index of uploads
+/// if it has the image, and by the bucket otherwise.
+async fn image_exists(
+    ctx: &RouteContext<Context>,
+    tenant: &Tenant,
+    namespace: &Namespace,
+    hash: &str,
+) -> ApiResult<bool> {
+    if let Some(kv) = upload_index(&ctx.env) {
+        let key_prefix = KeySchema::CURRENT.key_prefix(tenant, namespace);
+        match load_index_entry(&kv, &key_prefix, hash).await {
+            Ok(Some(_)) => return Ok(true),
+            Ok(None) => {}
+            Err(e) => console_error!("failed to look up the upload index: {:?}", e),
+        }
+    }
+
+    let Ok(bucket) = ctx.bucket("IMGS_BUCKET") else {
+        console_error!("failed to get bindings to the R2 bucket");
+        return Err(ApiError::no_msg(500));
+    };
+    for schema in KeySchema::READABLE {
+        let key = format!("{}{}.png", schema.key_prefix(tenant, namespace), hash);
+        match bucket.head(key).await {
+            Ok(Some(_)) => return Ok(true),
+            Ok(None) => {}
+            Err(e) => {
+                console_error!("failed to check existence of the image: {:?}", e);
+                return Err(ApiError::no_msg(500));
+            }
+        }
+    }
+    Ok(false)
+}
+
+pub async fn handle_get_reports(
+    req: Request,
+    ctx: RouteContext<Context>,
+) -> WorkerResult<Response> {
+    let res = get_reports(req, ctx).await;
+    match res {
+        Ok(reports) => Response::from_json(&reports),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn get_reports(req: Request, ctx: RouteContext<Context>) -> ApiResult<ReportList> {
+    let Ok(url) = req.url() else {
+        return Err(ApiError::no_msg(500));
+    };
+    let mut limit = DEFAULT_REPORTS_LIMIT;
+    let mut cursor = None;
+    for (k, v) in url.query_pairs() {
+        match k.as_ref() {
+            "limit" => {
+                limit = v
+                    .parse()
+                    .ok()
+                    .filter(|l| (1..=MAX_REPORTS_LIMIT).contains(l))
+                    .ok_or_else(|| {
+                        ApiError::new(400, format!("'limit' must be in 1..={}", MAX_REPORTS_LIMIT))
+                    })?
+            }
+            "cursor" => {
+                cursor = Some(
+                    v.parse::<u64>()
+                        .map_err(|_| ApiError::new(400, "Invalid cursor"))?,
+                )
+            }
+            _ => {}
+        }
+    }
+
+    let db = get_db(&ctx)?;
+    let reports = db
+        .prepare(
+            "SELECT id, tenant, namespace, hash, reason, contact, created_at FROM reports \
+             WHERE ?1 IS NULL OR id < ?1 ORDER BY id DESC LIMIT ?2",
+        )
+        .bind(&[
+            cursor.map_or(JsValue::NULL, |c| JsValue::from(c as f64)),
+            JsValue::from(limit),
+        ])
+        .map_err(db_error)?
+        .all()
+        .await
+        .map_err(db_error)?
+        .results::<Report>()
+        .map_err(db_error)?;
+
+    let cursor = (reports.len() == limit as usize)
+        .then(|| reports.last().map(|r| r.id))
+        .flatten();
+    Ok(ReportList { reports, cursor })
+}
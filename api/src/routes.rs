@@ -0,0 +1,92 @@
+//! Methods, body sizes and scopes of API keys (see api_key.rs) allowed per route (see
+//! lib/src/route_guard.rs). Routes added to the router must be added here too, or they are
+//! rejected with 404.
+
+use worker::Method::{Delete, Get, Post, Put};
+
+use upix_lib::{
+    auth::Scope::{Admin, List, Write},
+    route_guard::{
+        BodyLimit::{self, Empty, Handler, Max},
+        RouteRule,
+    },
+};
+
+/// Bodies of JSON requests.
+const JSON_BODY: BodyLimit = Max(16 * 1024);
+
+pub(crate) const ROUTES: &[RouteRule] = &[
+    RouteRule::new(Get, "/", Empty),
+    RouteRule::new(Get, "/images", Empty).scoped(List),
+    // uploads are limited per namespace
+    RouteRule::new(Post, "/", Handler)
+        .scoped(Write)
+        .with_upload_tokens(),
+    RouteRule::new(Post, "/:namespace", Handler)
+        .scoped(Write)
+        .with_upload_tokens(),
+    RouteRule::new(Post, "/images/uploads", JSON_BODY).scoped(Write),
+    RouteRule::new(Post, "/images/commit", JSON_BODY).scoped(Write),
+    RouteRule::new(Post, "/upload-tokens", JSON_BODY).scoped(Write),
+    RouteRule::new(Get, "/jobs/:id", Empty),
+    RouteRule::new(Get, "/images/trending", Empty),
+    RouteRule::new(Get, "/images/:hash", Empty),
+    RouteRule::new(Delete, "/images/:hash", Empty).scoped(Admin),
+    RouteRule::new(Get, "/images/:hash/stats", Empty),
+    RouteRule::new(Post, "/images/:hash/report", JSON_BODY),
+    RouteRule::new(Post, "/images/:hash/derive", JSON_BODY).scoped(Write),
+    RouteRule::new(Get, "/images/:hash/derivatives", Empty),
+    RouteRule::new(Get, "/images/:hash/quality", Empty),
+    RouteRule::new(Get, "/images/:hash/accessibility", Empty),
+    RouteRule::new(Get, "/images/:hash/annotations", Empty),
+    RouteRule::new(Put, "/images/:hash/annotations", JSON_BODY).scoped(Write),
+    RouteRule::new(Get, "/images/:hash/emoji.png", Empty),
+    RouteRule::new(Get, "/images/:hash/engine/:engine", Empty),
+    // grids of up to 64 x 64 hashes
+    RouteRule::new(Post, "/tilemap", Max(512 * 1024)),
+    // packs of up to 256 entries
+    RouteRule::new(Post, "/packs", Max(64 * 1024)).scoped(Write),
+    RouteRule::new(Get, "/packs/:id/resolve", Empty),
+    RouteRule::new(Get, "/admin/reports", Empty).scoped(Admin),
+    RouteRule::new(Get, "/admin/uploads/:hash", Empty).scoped(Admin),
+    RouteRule::new(Get, "/admin/metrics", Empty).scoped(Admin),
+    RouteRule::new(Get, "/admin/report/forecast", Empty).scoped(Admin),
+    RouteRule::new(Post, "/admin/cache-epoch", Empty).scoped(Admin),
+    RouteRule::new(Post, "/admin/purge/:hash", Empty).scoped(Admin),
+    RouteRule::new(Post, "/admin/images/delete", JSON_BODY).scoped(Admin),
+    RouteRule::new(Get, "/admin/jobs", Empty).scoped(Admin),
+    RouteRule::new(Post, "/admin/jobs/:id/retry", Empty).scoped(Admin),
+];
+
+#[cfg(test)]
+mod test {
+    use upix_lib::route_guard::{check_route, required_scope};
+    use worker::Method;
+
+    use super::*;
+
+    #[test]
+    fn test_routes() {
+        let status = |method: Method, path: &str, len: Option<usize>| match check_route(
+            ROUTES, &method, path, len,
+        ) {
+            Ok(()) => 200,
+            Err(e) => e.status(),
+        };
+        // public, and not taken for uploads into a namespace
+        assert_eq!(required_scope(ROUTES, &Post, "/tilemap"), None);
+        assert_eq!(status(Post, "/tilemap", Some(512 * 1024)), 200);
+        assert_eq!(status(Post, "/tilemap", Some(512 * 1024 + 1)), 413);
+        assert_eq!(required_scope(ROUTES, &Post, "/avatars"), Some(Write));
+        assert_eq!(status(Post, "/avatars", Some(1 << 20)), 200);
+
+        assert_eq!(required_scope(ROUTES, &Post, "/packs"), Some(Write));
+        assert_eq!(status(Post, "/packs", Some(64 * 1024 + 1)), 413);
+
+        assert_eq!(
+            required_scope(ROUTES, &Post, "/images/abc/derive"),
+            Some(Write)
+        );
+        assert_eq!(status(Method::Head, "/images/abc", None), 200);
+    }
+}
@@ -0,0 +1,149 @@
+use serde::Serialize;
+use worker::{
+    console_error, console_log, Cache, Context, Request, Response, Result as WorkerResult,
+    RouteContext,
+};
+
+use upix_lib::{
+    cache_policy::CacheRoute,
+    extract::{path_param, Hash},
+    stats::{
+        counter_key, fetch_image_stats, fetch_trending, parse_trending_window, ImageStats,
+        TrendingEntry, COUNTER_BINDING,
+    },
+    tenant::Tenant,
+    ApiError, ApiResult,
+};
+
+use crate::{direct_upload::query_namespace, request_tenant};
+
+/// Statistics of the image in the root namespace of the tenant, or in the one given by the
+/// `namespace` query param.
+pub async fn handle_get_image_stats(
+    req: Request,
+    ctx: RouteContext<Context>,
+) -> WorkerResult<Response> {
+    let tenant = match request_tenant(&req, &ctx.env).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e.to_response(),
+    };
+    let res = get_image_stats(&req, ctx, &tenant).await;
+    match res {
+        Ok(stats) => Response::from_json(&stats),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn get_image_stats(
+    req: &Request,
+    ctx: RouteContext<Context>,
+    tenant: &Tenant,
+) -> ApiResult<ImageStats> {
+    let Hash(hash) = path_param(&ctx, "hash")?;
+    let namespace = query_namespace(req, &ctx)?;
+    let Ok(ns) = ctx.durable_object(COUNTER_BINDING) else {
+        console_error!("failed to get bindings to the counter");
+        return Err(ApiError::no_msg(500));
+    };
+
+    let key = counter_key(&tenant.id, &namespace.name, &hash);
+    let mut stats = fetch_image_stats(&ns, &key).await.map_err(|e| {
+        console_error!("failed to fetch image stats: {:?}", e);
+        ApiError::no_msg(500)
+    })?;
+    stats.hash = hash;
+    Ok(stats)
+}
+
+const DEFAULT_TRENDING_WINDOW: &str = "24h";
+const DEFAULT_TRENDING_LIMIT: usize = 20;
+const MAX_TRENDING_LIMIT: usize = 100;
+
+#[derive(Debug, Serialize)]
+struct Trending {
+    window: String,
+    images: Vec<TrendingEntry>,
+}
+
+/// Most viewed images of the tenant. Responses are cached by URL, which tells tenants apart.
+pub async fn handle_get_trending(
+    req: Request,
+    ctx: RouteContext<Context>,
+) -> WorkerResult<Response> {
+    let tenant = match request_tenant(&req, &ctx.env).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e.to_response(),
+    };
+    let cache = Cache::default();
+    match cache.get(&req, false).await {
+        Ok(Some(resp)) => {
+            console_log!("cache hit: {}", req.path());
+            return Ok(resp);
+        }
+        Ok(None) => {}
+        Err(e) => console_error!("failed to match request against cache: {:?}", e),
+    }
+
+    let cache_policy = tenant.cache_policy(CacheRoute::Trending, &ctx.env);
+    let res = get_trending(&req, ctx, &tenant).await;
+    let resp = match res {
+        Ok(trending) => {
+            let mut resp = Response::from_json(&trending)?;
+            cache_policy.apply(resp.headers_mut())?;
+            if let Err(e) = cache.put(&req, resp.cloned()?).await {
+                console_error!("failed to cache response: {:?}", e);
+            }
+            Ok(resp)
+        }
+        Err(e) => e.to_response(),
+    };
+    resp
+}
+
+async fn get_trending(
+    req: &Request,
+    ctx: RouteContext<Context>,
+    tenant: &Tenant,
+) -> ApiResult<Trending> {
+    let Ok(url) = req.url() else {
+        return Err(ApiError::no_msg(500));
+    };
+    let mut window = DEFAULT_TRENDING_WINDOW.to_string();
+    let mut limit = DEFAULT_TRENDING_LIMIT;
+    for (k, v) in url.query_pairs() {
+        match k.as_ref() {
+            "window" => window = v.into_owned(),
+            "limit" => {
+                limit = v
+                    .parse()
+                    .ok()
+                    .filter(|l| (1..=MAX_TRENDING_LIMIT).contains(l))
+                    .ok_or_else(|| {
+                        ApiError::new(
+                            400,
+                            format!("'limit' must be in 1..={}", MAX_TRENDING_LIMIT),
+                        )
+                    })?
+            }
+            _ => {}
+        }
+    }
+    let Some(window_hours) = parse_trending_window(&window) else {
+        return Err(ApiError::new(
+            400,
+            "'window' must be like '24h' or '7d' (up to 7 days)",
+        ));
+    };
+
+    let Ok(ns) = ctx.durable_object(COUNTER_BINDING) else {
+        console_error!("failed to get bindings to the counter");
+        return Err(ApiError::no_msg(500));
+    };
+    let images = fetch_trending(&ns, &tenant.id, window_hours, limit)
+        .await
+        .map_err(|e| {
+            console_error!("failed to fetch trending images: {:?}", e);
+            ApiError::no_msg(500)
+        })?;
+    Ok(Trending { window, images })
+}
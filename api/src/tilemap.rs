@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+
+use futures::future;
+use image::{DynamicImage, ImageFormat};
+use worker::{
+    console_error, Context, Headers, Request, Response, Result as WorkerResult, RouteContext,
+};
+
+use upix_lib::{
+    encode_image, is_valid_hash,
+    tenant::Tenant,
+    tilemap::{assemble_tilemap, TilemapManifest},
+    ApiError, ApiResult,
+};
+
+use crate::{export::load_original_image, request_tenant};
+
+pub async fn handle_post_tilemap(
+    mut req: Request,
+    ctx: RouteContext<Context>,
+) -> WorkerResult<Response> {
+    let tenant = match request_tenant(&req, &ctx.env).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e.to_response(),
+    };
+    let resp = match post_tilemap(&mut req, &ctx, &tenant).await {
+        Ok(img_data) => {
+            let headers: Headers = [("Content-Type", "image/png")].iter().collect();
+            Ok(Response::from_bytes(img_data)?.with_headers(headers))
+        }
+        Err(e) => e.to_response(),
+    };
+    resp
+}
+
+async fn post_tilemap(
+    req: &mut Request,
+    ctx: &RouteContext<Context>,
+    tenant: &Tenant,
+) -> ApiResult<Vec<u8>> {
+    let manifest: TilemapManifest = req
+        .json()
+        .await
+        .map_err(|_| ApiError::new(400, "Invalid tilemap manifest"))?;
+    manifest
+        .validate()
+        .map_err(|e| ApiError::new(400, e.to_string()))?;
+
+    let hashes = manifest.distinct_hashes();
+    if let Some(h) = hashes.iter().find(|h| !is_valid_hash(h)) {
+        return Err(ApiError::new(400, format!("Invalid image hash: {}", h)));
+    }
+    let tiles = future::try_join_all(hashes.into_iter().map(|hash| async move {
+        load_original_image(ctx, tenant, hash)
+            .await
+            .map_err(|e| {
+                if e.status() == 404 {
+                    ApiError::new(404, format!("Tile not found: {}", hash))
+                } else {
+                    e
+                }
+            })
+            .map(|img| (hash, img))
+    }))
+    .await?;
+    let tiles: HashMap<&str, DynamicImage> = tiles.into_iter().collect();
+
+    let grid: Vec<Vec<_>> = manifest
+        .tiles
+        .iter()
+        .map(|row| {
+            row.iter()
+                .map(|cell| cell.as_deref().and_then(|h| tiles.get(h)))
+                .collect()
+        })
+        .collect();
+    let tilemap = assemble_tilemap(&grid).map_err(|e| ApiError::new(400, e.to_string()))?;
+
+    let mut img_data = Vec::new();
+    encode_image(
+        &DynamicImage::ImageRgba8(tilemap),
+        ImageFormat::Png,
+        &mut img_data,
+    )
+    .map_err(|e| {
+        console_error!("failed to encode image: {:?}", e);
+        ApiError::no_msg(500)
+    })?;
+    Ok(img_data)
+}
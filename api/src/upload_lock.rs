@@ -0,0 +1,58 @@
+//! Serializing concurrent uploads of the same data (see lib/src/upload_lock.rs).
+//!
+//! Like the circuit breaker, locks are skipped if they are not bound or fail, so that they never
+//! take uploads down by themselves.
+
+use std::time::Duration;
+
+use worker::{console_error, console_log, Date, Delay, Env, ObjectNamespace};
+
+use upix_lib::upload_lock::{poll_delay_ms, try_lock, unlock, MAX_WAIT_MS, UPLOAD_LOCK_BINDING};
+
+use crate::direct_upload::new_upload_id;
+
+/// Lease of processing the data, released once the upload is done.
+pub(crate) struct UploadLease {
+    ns: ObjectNamespace,
+    name: String,
+    owner: String,
+}
+
+/// Wait until no other upload is processing the same data under the prefix, and take the lease.
+/// `None` if the lock is not available, or others have held it for too long.
+pub(crate) async fn lock_upload(env: &Env, key_prefix: &str, hash: &str) -> Option<UploadLease> {
+    let ns = env.durable_object(UPLOAD_LOCK_BINDING).ok()?;
+    let name = format!("{}{}", key_prefix, hash);
+    let owner = new_upload_id().ok()?;
+    let started_at = Date::now().as_millis();
+    let mut attempts = 0;
+    loop {
+        match try_lock(&ns, &name, &owner).await {
+            Ok(true) => break,
+            Ok(false) => {}
+            Err(e) => {
+                console_error!("failed to lock upload of {}: {:?}", hash, e);
+                return None;
+            }
+        }
+        if Date::now().as_millis().saturating_sub(started_at) >= MAX_WAIT_MS {
+            console_error!("gave up waiting for another upload of {}", hash);
+            return None;
+        }
+        Delay::from(Duration::from_millis(poll_delay_ms(attempts))).await;
+        attempts += 1;
+    }
+    if attempts > 0 {
+        console_log!("waited for another upload of {}", hash);
+    }
+    Some(UploadLease { ns, name, owner })
+}
+
+impl UploadLease {
+    /// Let the next upload of the same data proceed.
+    pub(crate) async fn release(self) {
+        if let Err(e) = unlock(&self.ns, &self.name, &self.owner).await {
+            console_error!("failed to unlock upload of {}: {:?}", self.name, e);
+        }
+    }
+}
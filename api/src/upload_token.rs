@@ -0,0 +1,187 @@
+//! One-time upload tokens, which let end users upload without the API key (see
+//! lib/src/upload_token.rs).
+//!
+//! Nonces of redeemed tokens are recorded in the `used_upload_tokens` table of the D1 database
+//! until the tokens expire.
+
+use serde::{Deserialize, Serialize};
+use worker::{
+    console_error, console_log, wasm_bindgen::JsValue, Context, Date, Env, Request, Response,
+    Result as WorkerResult, RouteContext,
+};
+
+use upix_lib::{
+    auth::{bearer_token, Scope},
+    namespace::find_namespace,
+    upload_token::{is_upload_token, UploadToken, UPLOAD_TOKEN_SECRET},
+    ApiError, ApiResult,
+};
+
+use crate::{
+    api_key::require_scope, db::db_error, direct_upload::new_upload_id, request_tenant,
+    uploads::Uploader,
+};
+
+/// Tokens are valid for this many seconds, unless requested otherwise.
+const DEFAULT_TOKEN_TTL_SECS: u64 = 10 * 60;
+const MAX_TOKEN_TTL_SECS: u64 = 60 * 60;
+
+#[derive(Debug, Deserialize)]
+struct UploadTokenRequest {
+    #[serde(default)]
+    namespace: Option<String>,
+    /// max size of the image data in bytes. defaults to the max of the namespace
+    #[serde(default)]
+    max_size: Option<usize>,
+    #[serde(default)]
+    ttl_secs: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct IssuedUploadToken {
+    /// send as the bearer token of `POST /` (or `POST /{namespace}`)
+    token: String,
+    /// unix time in milliseconds
+    expires_at: u64,
+    max_size: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    namespace: Option<String>,
+}
+
+/// How an upload is authorized.
+pub(crate) enum UploadAuth {
+    ApiKey(Uploader),
+    Token(UploadToken),
+}
+
+impl UploadAuth {
+    pub(crate) fn uploader(&self) -> Uploader {
+        match self {
+            UploadAuth::ApiKey(uploader) => uploader.clone(),
+            UploadAuth::Token(token) => Uploader::Token(token.nonce.clone()),
+        }
+    }
+}
+
+fn token_secret(ctx: &RouteContext<Context>) -> ApiResult<String> {
+    ctx.secret(UPLOAD_TOKEN_SECRET)
+        .map(|s| s.to_string())
+        .map_err(|_| {
+            console_error!("{} is not configured", UPLOAD_TOKEN_SECRET);
+            ApiError::no_msg(500)
+        })
+}
+
+pub async fn handle_post_upload_token(
+    mut req: Request,
+    ctx: RouteContext<Context>,
+) -> WorkerResult<Response> {
+    match post_upload_token(&mut req, &ctx).await {
+        Ok(token) => Response::from_json(&token),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn post_upload_token(
+    req: &mut Request,
+    ctx: &RouteContext<Context>,
+) -> ApiResult<IssuedUploadToken> {
+    require_scope(req, &ctx.env, Scope::Write).await?;
+    let secret = token_secret(ctx)?;
+    let tenant = request_tenant(req, &ctx.env).await?;
+
+    let Ok(UploadTokenRequest {
+        namespace,
+        max_size,
+        ttl_secs,
+    }) = req.json().await
+    else {
+        return Err(ApiError::new(400, "Invalid upload token request"));
+    };
+    let Some(namespace) = find_namespace(&ctx.env, namespace.as_deref()) else {
+        return Err(ApiError::new(404, "Unknown namespace"));
+    };
+    let max_accepted = namespace.limits.max_accepted_data_len();
+    let max_size = max_size.unwrap_or(max_accepted);
+    if max_size == 0 || max_size > max_accepted {
+        return Err(ApiError::new(
+            400,
+            format!("max_size must be in 1..={}", max_accepted),
+        ));
+    }
+    let ttl_secs = ttl_secs.unwrap_or(DEFAULT_TOKEN_TTL_SECS);
+    if !(1..=MAX_TOKEN_TTL_SECS).contains(&ttl_secs) {
+        return Err(ApiError::new(
+            400,
+            format!("ttl_secs must be in 1..={}", MAX_TOKEN_TTL_SECS),
+        ));
+    }
+
+    let expires_at = Date::now().as_millis() / 1000 + ttl_secs;
+    let token = UploadToken {
+        nonce: new_upload_id()?,
+        expires_at,
+        max_size,
+        tenant: tenant.id,
+        namespace: namespace.name.clone(),
+    };
+    Ok(IssuedUploadToken {
+        token: token.sign(secret.as_bytes()),
+        expires_at: expires_at * 1000,
+        max_size,
+        namespace: (!namespace.name.is_empty()).then_some(namespace.name),
+    })
+}
+
+/// Check that the request carries a valid API key or upload token. Tokens are only checked here,
+/// and have to be redeemed with [`redeem_upload_token`] once the upload is validated.
+pub(crate) async fn authorize_upload(
+    req: &Request,
+    ctx: &RouteContext<Context>,
+) -> ApiResult<UploadAuth> {
+    let Some(token) = bearer_token(req).filter(|t| is_upload_token(t)) else {
+        return require_scope(req, &ctx.env, Scope::Write)
+            .await
+            .map(UploadAuth::ApiKey);
+    };
+    let secret = token_secret(ctx)?;
+    let now = Date::now().as_millis() / 1000;
+    UploadToken::verify(&token, secret.as_bytes(), now)
+        .map(UploadAuth::Token)
+        .map_err(|e| ApiError::new(403, e.to_string()))
+}
+
+/// Mark the token used, failing if it has been used before.
+pub(crate) async fn redeem_upload_token(env: &Env, token: &UploadToken) -> ApiResult<()> {
+    let Ok(db) = env.d1("DB") else {
+        console_error!("failed to get bindings to the D1 database");
+        return Err(ApiError::no_msg(500));
+    };
+    let now = Date::now().as_millis() / 1000;
+
+    // nonces of expired tokens are no longer needed, as the tokens are rejected anyway
+    db.prepare("DELETE FROM used_upload_tokens WHERE expires_at <= ?1")
+        .bind(&[JsValue::from(now as f64)])
+        .map_err(db_error)?
+        .run()
+        .await
+        .map_err(db_error)?;
+
+    let inserted = db
+        .prepare(
+            "INSERT INTO used_upload_tokens (nonce, expires_at) VALUES (?1, ?2) ON CONFLICT DO NOTHING RETURNING nonce",
+        )
+        .bind(&[
+            JsValue::from(token.nonce.as_str()),
+            JsValue::from(token.expires_at as f64),
+        ])
+        .map_err(db_error)?
+        .first::<String>(Some("nonce"))
+        .await
+        .map_err(db_error)?;
+    if inserted.is_none() {
+        return Err(ApiError::new(403, "Upload token has already been used"));
+    }
+    console_log!("redeemed upload token (nonce: {})", token.nonce);
+    Ok(())
+}
@@ -0,0 +1,439 @@
+//! Records of uploads in the `uploads` table of the D1 database.
+//!
+//! Each upload is recorded with what was uploaded and what was stored for it, so that metadata of
+//! images and listings by tags (`GET /images?source=db`) are answered by queries instead of
+//! listing the bucket. Images uploaded before the table existed have no records: the metadata
+//! endpoint falls back to the bucket for them, and `GET /images` lists the bucket by default until
+//! records are backfilled.
+//!
+//! Who uploaded an image, and from where, is also recorded for abuse investigations. It is only
+//! exposed to operators by `GET /admin/uploads/{hash}`, and never in public metadata.
+
+use std::fmt;
+
+use image::ImageFormat;
+use serde::{Deserialize, Serialize};
+use worker::{console_error, wasm_bindgen::JsValue, D1Database, Date, Env, Request};
+
+use upix_lib::{
+    bulk_delete::DeleteFilter, hmac_sha256_hex, pipeline::UploadedImage, sha256_hex, ApiResult,
+};
+
+use crate::db::db_error;
+
+/// Who uploaded an image.
+#[derive(Debug, Clone)]
+pub(crate) enum Uploader {
+    /// Identified by the SHA-256 of the key, as API keys are in KV.
+    ApiKey(String),
+    /// Identified by the nonce of the token.
+    Token(String),
+}
+
+impl Uploader {
+    pub(crate) fn api_key(key: &str) -> Self {
+        Uploader::ApiKey(sha256_hex(key.as_bytes()))
+    }
+}
+
+impl fmt::Display for Uploader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Uploader::ApiKey(hash) => write!(f, "key:{}", hash),
+            Uploader::Token(nonce) => write!(f, "token:{}", nonce),
+        }
+    }
+}
+
+/// Max length of user agents recorded, in bytes. Longer ones are truncated.
+const MAX_USER_AGENT_LEN: usize = 256;
+
+/// Name of the secret which IP addresses of clients are hashed with. Addresses are not recorded
+/// without it.
+pub(crate) const IP_HASH_SECRET: &str = "IP_HASH_SECRET";
+
+/// Client an upload was sent from.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct UploadClient {
+    /// HMAC-SHA256 of the IP address keyed with `IP_HASH_SECRET`, so that raw addresses are never
+    /// stored, nor recovered by hashing every address
+    pub ip_hash: Option<String>,
+    pub country: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl UploadClient {
+    pub(crate) fn of_request(req: &Request, env: &Env) -> Self {
+        let header = |name| req.headers().get(name).ok().flatten();
+        let user_agent = header("User-Agent").map(|mut ua| {
+            if ua.len() > MAX_USER_AGENT_LEN {
+                let mut end = MAX_USER_AGENT_LEN;
+                while !ua.is_char_boundary(end) {
+                    end -= 1;
+                }
+                ua.truncate(end);
+            }
+            ua
+        });
+        let secret = env.secret(IP_HASH_SECRET).ok().map(|s| s.to_string());
+        let ip_hash = header("CF-Connecting-IP")
+            .zip(secret)
+            .map(|(ip, secret)| hmac_sha256_hex(secret.as_bytes(), &ip));
+        UploadClient {
+            ip_hash,
+            country: req.cf().and_then(|cf| cf.country()),
+            user_agent,
+        }
+    }
+}
+
+/// Where an upload is stored, and by whom.
+#[derive(Debug, Clone)]
+pub(crate) struct UploadOrigin {
+    pub tenant: String,
+    pub namespace: String,
+    pub key_prefix: String,
+    pub uploader: Option<Uploader>,
+    pub client: UploadClient,
+}
+
+/// What was stored for an upload.
+pub(crate) struct UploadRecord<'a> {
+    pub hash: &'a str,
+    /// format of the uploaded data, absent for frames reconstructed from delta patches
+    pub format: Option<ImageFormat>,
+    pub width: u32,
+    pub height: u32,
+    pub animated: bool,
+    pub images: &'a [UploadedImage],
+    /// added to the tags of the image, if it has been uploaded before
+    pub tags: &'a [String],
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct UploadRow {
+    pub hash: String,
+    pub key_prefix: String,
+    pub width: u32,
+    pub height: u32,
+    pub animated: u32,
+    /// JSON array of `UploadedImage`
+    pub variants: String,
+    pub uploaded_at: u64,
+}
+
+impl UploadRow {
+    pub(crate) fn variants(&self) -> Vec<UploadedImage> {
+        serde_json::from_str(&self.variants).unwrap_or_else(|e| {
+            console_error!("invalid variants of upload {}: {}", self.hash, e);
+            Vec::new()
+        })
+    }
+}
+
+/// Record the upload along with its tags. Repeated uploads of the same image update what is
+/// stored, unless `deduplicated`, and keep the time, the uploader and the client of the first
+/// upload.
+///
+/// Failing to record an upload doesn't fail the upload itself, so errors are only logged.
+pub(crate) async fn record_upload(
+    env: &Env,
+    origin: &UploadOrigin,
+    record: &UploadRecord<'_>,
+    deduplicated: bool,
+) {
+    let Ok(db) = env.d1("DB") else {
+        console_error!("failed to get bindings to the D1 database");
+        return;
+    };
+    let on_conflict = match deduplicated {
+        true => "DO NOTHING",
+        false => {
+            "DO UPDATE SET key_prefix = excluded.key_prefix, format = excluded.format, \
+             width = excluded.width, height = excluded.height, animated = excluded.animated, \
+             variants = excluded.variants"
+        }
+    };
+    let query = format!(
+        "INSERT INTO uploads (tenant, namespace, hash, key_prefix, format, width, height, \
+         animated, uploader, variants, uploaded_at, client_ip_hash, client_country, \
+         client_user_agent) \
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14) \
+         ON CONFLICT (tenant, namespace, hash) {}",
+        on_conflict
+    );
+    let upload = db.prepare(query).bind(&[
+        JsValue::from(origin.tenant.as_str()),
+        JsValue::from(origin.namespace.as_str()),
+        JsValue::from(record.hash),
+        JsValue::from(origin.key_prefix.as_str()),
+        record
+            .format
+            .map_or(JsValue::NULL, |f| JsValue::from(f.extensions_str()[0])),
+        JsValue::from(record.width),
+        JsValue::from(record.height),
+        JsValue::from(u32::from(record.animated)),
+        origin
+            .uploader
+            .as_ref()
+            .map_or(JsValue::NULL, |u| JsValue::from(u.to_string())),
+        JsValue::from(serde_json::to_string(record.images).unwrap()),
+        JsValue::from(Date::now().as_millis() as f64),
+        optional(&origin.client.ip_hash),
+        optional(&origin.client.country),
+        optional(&origin.client.user_agent),
+    ]);
+    // `WHERE true` tells SQLite that `ON CONFLICT` is not a part of the join
+    let tags = db
+        .prepare(
+            "INSERT INTO upload_tags (tenant, namespace, hash, tag) \
+             SELECT ?1, ?2, ?3, value FROM json_each(?4) WHERE true ON CONFLICT DO NOTHING",
+        )
+        .bind(&[
+            JsValue::from(origin.tenant.as_str()),
+            JsValue::from(origin.namespace.as_str()),
+            JsValue::from(record.hash),
+            JsValue::from(serde_json::to_string(record.tags).unwrap()),
+        ]);
+    let res = match (upload, tags) {
+        (Ok(upload), Ok(tags)) => db.batch(vec![upload, tags]).await.map(|_| ()),
+        (Err(e), _) | (_, Err(e)) => Err(e),
+    };
+    if let Err(e) = res {
+        console_error!("failed to record upload (hash: {}): {:?}", record.hash, e);
+    }
+}
+
+fn optional(value: &Option<String>) -> JsValue {
+    value.as_deref().map_or(JsValue::NULL, JsValue::from)
+}
+
+/// Where the image was uploaded, by whom and from where, as told to operators.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct UploadOriginRow {
+    pub tenant: String,
+    pub namespace: String,
+    pub uploader: Option<String>,
+    pub client_ip_hash: Option<String>,
+    pub client_country: Option<String>,
+    pub client_user_agent: Option<String>,
+    pub uploaded_at: u64,
+}
+
+/// Origins of the image in all the namespaces of all the tenants it has been uploaded to, oldest
+/// first.
+pub(crate) async fn find_upload_origins(
+    db: &D1Database,
+    hash: &str,
+) -> ApiResult<Vec<UploadOriginRow>> {
+    db.prepare(
+        "SELECT tenant, namespace, uploader, client_ip_hash, client_country, client_user_agent, \
+         uploaded_at FROM uploads WHERE hash = ?1 ORDER BY uploaded_at",
+    )
+    .bind(&[JsValue::from(hash)])
+    .map_err(db_error)?
+    .all()
+    .await
+    .map_err(db_error)?
+    .results::<UploadOriginRow>()
+    .map_err(db_error)
+}
+
+/// Tags of the image in the namespace of the tenant, in the alphabetical order.
+pub(crate) async fn find_upload_tags(
+    db: &D1Database,
+    tenant: &str,
+    namespace: &str,
+    hash: &str,
+) -> ApiResult<Vec<String>> {
+    #[derive(Deserialize)]
+    struct TagRow {
+        tag: String,
+    }
+    let rows = db
+        .prepare(
+            "SELECT tag FROM upload_tags WHERE tenant = ?1 AND namespace = ?2 AND hash = ?3 \
+             ORDER BY tag",
+        )
+        .bind(&[
+            JsValue::from(tenant),
+            JsValue::from(namespace),
+            JsValue::from(hash),
+        ])
+        .map_err(db_error)?
+        .all()
+        .await
+        .map_err(db_error)?
+        .results::<TagRow>()
+        .map_err(db_error)?;
+    Ok(rows.into_iter().map(|r| r.tag).collect())
+}
+
+const UPLOAD_COLUMNS: &str = "hash, key_prefix, width, height, animated, variants, uploaded_at";
+
+/// The record of the image in the namespace of the tenant.
+pub(crate) async fn find_upload(
+    db: &D1Database,
+    tenant: &str,
+    namespace: &str,
+    hash: &str,
+) -> ApiResult<Option<UploadRow>> {
+    db.prepare(format!(
+        "SELECT {} FROM uploads WHERE tenant = ?1 AND namespace = ?2 AND hash = ?3",
+        UPLOAD_COLUMNS
+    ))
+    .bind(&[
+        JsValue::from(tenant),
+        JsValue::from(namespace),
+        JsValue::from(hash),
+    ])
+    .map_err(db_error)?
+    .first::<UploadRow>(None)
+    .await
+    .map_err(db_error)
+}
+
+/// Records of images in the namespace of the tenant, newest first, uploaded before the cursor
+/// (upload time and hash of the last record of the previous page) if any, and tagged with the tag
+/// if any.
+pub(crate) async fn list_uploads(
+    db: &D1Database,
+    tenant: &str,
+    namespace: &str,
+    tag: Option<&str>,
+    cursor: Option<&(u64, String)>,
+    limit: u32,
+) -> ApiResult<Vec<UploadRow>> {
+    let (before, before_hash) = match cursor {
+        Some((t, h)) => (JsValue::from(*t as f64), JsValue::from(h.as_str())),
+        None => (JsValue::NULL, JsValue::NULL),
+    };
+    db.prepare(format!(
+        "SELECT {} FROM uploads WHERE tenant = ?1 AND namespace = ?2 \
+         AND (?3 IS NULL OR uploaded_at < ?3 OR (uploaded_at = ?3 AND hash < ?4)) \
+         AND (?6 IS NULL OR hash IN (SELECT hash FROM upload_tags \
+             WHERE tenant = ?1 AND namespace = ?2 AND tag = ?6)) \
+         ORDER BY uploaded_at DESC, hash DESC LIMIT ?5",
+        UPLOAD_COLUMNS
+    ))
+    .bind(&[
+        JsValue::from(tenant),
+        JsValue::from(namespace),
+        before,
+        before_hash,
+        JsValue::from(limit),
+        tag.map_or(JsValue::NULL, JsValue::from),
+    ])
+    .map_err(db_error)?
+    .all()
+    .await
+    .map_err(db_error)?
+    .results::<UploadRow>()
+    .map_err(db_error)
+}
+
+/// Hashes of images in the namespace of the tenant matching the filter of a bulk deletion, oldest
+/// first, up to the limit.
+pub(crate) async fn find_upload_hashes(
+    db: &D1Database,
+    tenant: &str,
+    namespace: &str,
+    filter: &DeleteFilter,
+    limit: u32,
+) -> ApiResult<Vec<String>> {
+    #[derive(Deserialize)]
+    struct HashRow {
+        hash: String,
+    }
+    let rows = db
+        .prepare(
+            "SELECT hash FROM uploads WHERE tenant = ?1 AND namespace = ?2 \
+             AND (?3 IS NULL OR uploaded_at < ?3) AND (?4 IS NULL OR uploader = ?4) \
+             AND (?5 IS NULL OR hash IN (SELECT hash FROM upload_tags \
+                 WHERE tenant = ?1 AND namespace = ?2 AND tag = ?5)) \
+             ORDER BY uploaded_at, hash LIMIT ?6",
+        )
+        .bind(&[
+            JsValue::from(tenant),
+            JsValue::from(namespace),
+            filter
+                .older_than
+                .map_or(JsValue::NULL, |t| JsValue::from(t as f64)),
+            optional(&filter.uploader),
+            optional(&filter.tag),
+            JsValue::from(limit),
+        ])
+        .map_err(db_error)?
+        .all()
+        .await
+        .map_err(db_error)?
+        .results::<HashRow>()
+        .map_err(db_error)?;
+    Ok(rows.into_iter().map(|r| r.hash).collect())
+}
+
+/// Replace the stored images of the record, after variants deferred from the upload are stored.
+/// Errors are only logged, as the images are stored anyway.
+pub(crate) async fn update_upload_variants(
+    env: &Env,
+    tenant: &str,
+    namespace: &str,
+    hash: &str,
+    images: &[UploadedImage],
+) {
+    let Ok(db) = env.d1("DB") else {
+        console_error!("failed to get bindings to the D1 database");
+        return;
+    };
+    let res = db
+        .prepare(
+            "UPDATE uploads SET variants = ?4 WHERE tenant = ?1 AND namespace = ?2 AND hash = ?3",
+        )
+        .bind(&[
+            JsValue::from(tenant),
+            JsValue::from(namespace),
+            JsValue::from(hash),
+            JsValue::from(serde_json::to_string(images).unwrap()),
+        ]);
+    let res = match res {
+        Ok(stmt) => stmt.run().await.map(|_| ()),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = res {
+        console_error!("failed to update the record of {}: {:?}", hash, e);
+    }
+}
+
+/// Delete the record, the tags and the annotations of the image in the namespace of the tenant,
+/// after the image itself is deleted. Errors are only logged, as the image is gone anyway.
+pub(crate) async fn delete_upload(env: &Env, tenant: &str, namespace: &str, hash: &str) {
+    let Ok(db) = env.d1("DB") else {
+        console_error!("failed to get bindings to the D1 database");
+        return;
+    };
+    let stmts = ["uploads", "upload_tags", "annotations"]
+        .into_iter()
+        .map(|table| {
+            db.prepare(format!(
+                "DELETE FROM {} WHERE tenant = ?1 AND namespace = ?2 AND hash = ?3",
+                table
+            ))
+            .bind(&[
+                JsValue::from(tenant),
+                JsValue::from(namespace),
+                JsValue::from(hash),
+            ])
+        })
+        .collect::<Result<Vec<_>, _>>();
+    let res = match stmts {
+        Ok(stmts) => db.batch(stmts).await.map(|_| ()),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = res {
+        console_error!(
+            "failed to delete record of upload (hash: {}): {:?}",
+            hash,
+            e
+        );
+    }
+}
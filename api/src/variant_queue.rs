@@ -0,0 +1,346 @@
+//! Producer and consumer of the queue of deferred variants and bulk deletions (see
+//! lib/src/variant_queue.rs).
+//!
+//! Failed messages are retried by the queue, and each failure is recorded in the `failed_jobs`
+//! table of D1. Jobs failing `MAX_JOB_ATTEMPTS` times are dead-lettered there rather than retried
+//! further, and sent again by `POST /admin/jobs/{id}/retry`. Jobs of variants which couldn't be
+//! sent are processed after responding instead, without retries, while bulk deletions fail.
+
+use serde::{Deserialize, Serialize};
+use worker::{
+    console_error, console_log, wasm_bindgen::JsValue, Context, D1Database, Date, Env, Queue,
+};
+
+use upix_lib::{
+    bulk_delete::DeleteJob,
+    job_summary::JobStatus,
+    namespace::find_namespace,
+    notify::{notify_admins, AdminEvent},
+    pipeline::{StoreError, UploadedImage, VariantStores},
+    variant_queue::{
+        merge_uploaded, store_deferred_variants, QueueMessage, VariantJob, MAX_JOB_ATTEMPTS,
+        VARIANT_QUEUE_BINDING,
+    },
+    ApiError, ApiResult,
+};
+
+use crate::{cold_bucket, db::db_error, index_upload, uploads::update_upload_variants};
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum VariantQueueError {
+    #[error(transparent)]
+    Store(#[from] StoreError),
+    #[error(transparent)]
+    Worker(#[from] worker::Error),
+}
+
+/// The queue, if bound. Uploads store all their variants in the request otherwise.
+pub(crate) fn variant_queue(env: &Env) -> Option<Queue> {
+    env.queue(VARIANT_QUEUE_BINDING).ok()
+}
+
+/// Send the job to the queue, or process it after responding if the queue fails.
+pub(crate) async fn enqueue_variants(env: &Env, ctx: &Context, job: VariantJob) {
+    let sent = match variant_queue(env) {
+        Some(queue) => queue
+            .send(&QueueMessage::Variants(job.clone()))
+            .await
+            .map_err(|e| format!("{:?}", e)),
+        None => Err("the queue is not bound".to_string()),
+    };
+    if let Err(e) = sent {
+        console_error!("failed to enqueue variants of {}: {}", job.hash, e);
+        let env = env.clone();
+        ctx.wait_until(async move { process_job(&env, &job).await });
+    }
+}
+
+/// Send deletions of the images of the tenant to the queue.
+pub(crate) async fn enqueue_deletes(env: &Env, tenant: &str, hashes: &[String]) -> ApiResult<()> {
+    let Some(queue) = variant_queue(env) else {
+        console_error!("bulk deletions need the queue ({})", VARIANT_QUEUE_BINDING);
+        return Err(ApiError::new(503, "Bulk deletions are not configured"));
+    };
+    if hashes.is_empty() {
+        return Ok(());
+    }
+    let messages: Vec<_> = hashes
+        .iter()
+        .map(|hash| {
+            QueueMessage::Delete(DeleteJob {
+                tenant: tenant.to_string(),
+                hash: hash.clone(),
+            })
+        })
+        .collect();
+    queue.send_batch(messages).await.map_err(|e| {
+        console_error!("failed to enqueue deletions: {:?}", e);
+        ApiError::no_msg(500)
+    })
+}
+
+async fn process_job(env: &Env, job: &VariantJob) {
+    if let Err(e) = store_job_variants(env, job).await {
+        report_job_failure(env, job, e).await;
+    }
+}
+
+/// Log the failure of the job, and notify operators of it.
+pub(crate) async fn report_job_failure(env: &Env, job: &VariantJob, e: VariantQueueError) {
+    console_error!("failed to store variants of {}: {}", job.hash, e);
+    let event = AdminEvent::ReplicationFailed {
+        hash: job.hash.clone(),
+        error: e.to_string(),
+    };
+    notify_admins(env, event).await;
+}
+
+/// Store the variants of the job, and update the record and the index entry of the upload.
+pub(crate) async fn store_job_variants(
+    env: &Env,
+    job: &VariantJob,
+) -> Result<(), VariantQueueError> {
+    let bucket = env.bucket("IMGS_BUCKET")?;
+    let cold_bucket = cold_bucket(env);
+    let namespace = (!job.namespace.is_empty()).then_some(job.namespace.as_str());
+    let Some(namespace) = find_namespace(env, namespace) else {
+        console_error!("namespace of {} is no longer configured", job.hash);
+        return Ok(());
+    };
+    let stores = VariantStores {
+        store: &bucket,
+        cold_store: cold_bucket.as_deref(),
+    };
+    let limits = &namespace.limits;
+    let stored =
+        store_deferred_variants(stores, &job.key_prefix, &job.hash, &job.scales, limits).await?;
+    let Some(stored) = stored else {
+        console_log!("{} has been deleted since uploaded", job.hash);
+        return Ok(());
+    };
+
+    let mut images: Vec<UploadedImage> = job.stored.clone();
+    merge_uploaded(&mut images, stored);
+    update_upload_variants(env, &job.tenant, &job.namespace, &job.hash, &images).await;
+    index_upload(env, &job.key_prefix, &job.hash, &images, limits).await;
+    console_log!(
+        "stored deferred variants of {} at scales {:?}",
+        job.hash,
+        job.scales
+    );
+    Ok(())
+}
+
+// states of jobs in the `failed_jobs` table (see migrations/0010_create_failed_jobs.sql)
+const RETRYING: &str = "retrying";
+const DEAD: &str = "dead";
+const RETRIED: &str = "retried";
+
+fn failed_jobs_db(env: &Env) -> Option<D1Database> {
+    env.d1("DB")
+        .map_err(|_| console_error!("failed to get bindings to the D1 database"))
+        .ok()
+}
+
+/// Record the failure of the job of the message with the error, and tell whether the job is
+/// dead-lettered, so that the message is not retried any more. Failures which can't be recorded
+/// are left to the queue to retry.
+pub(crate) async fn record_job_failure(
+    env: &Env,
+    message_id: &str,
+    message: &QueueMessage,
+    error: &str,
+) -> bool {
+    let Some(db) = failed_jobs_db(env) else {
+        return false;
+    };
+    let Ok(body) = serde_json::to_string(message) else {
+        return false;
+    };
+    let (tenant, hash) = message.image();
+    let status = db
+        .prepare(
+            "INSERT INTO failed_jobs \
+             (id, kind, tenant, hash, message, error, attempts, status, failed_at) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, 1, ?7, ?8) \
+             ON CONFLICT (id) DO UPDATE SET error = excluded.error, attempts = attempts + 1, \
+             status = CASE WHEN attempts + 1 >= ?9 THEN ?10 ELSE ?7 END, \
+             failed_at = excluded.failed_at \
+             RETURNING status",
+        )
+        .bind(&[
+            JsValue::from(message_id),
+            JsValue::from(message.kind()),
+            JsValue::from(tenant),
+            JsValue::from(hash),
+            JsValue::from(body),
+            JsValue::from(error),
+            JsValue::from(RETRYING),
+            JsValue::from(Date::now().as_millis() as f64),
+            JsValue::from(MAX_JOB_ATTEMPTS),
+            JsValue::from(DEAD),
+        ]);
+    let status = match status {
+        Ok(stmt) => stmt.first::<String>(Some("status")).await,
+        Err(e) => Err(e),
+    };
+    match status {
+        Ok(status) => {
+            let dead = status.as_deref() == Some(DEAD);
+            if dead {
+                console_error!(
+                    "dead-lettered job {} ({} of {})",
+                    message_id,
+                    message.kind(),
+                    hash
+                );
+            }
+            dead
+        }
+        Err(e) => {
+            console_error!("failed to record failure of job {}: {:?}", message_id, e);
+            false
+        }
+    }
+}
+
+/// Forget failures of the jobs of the messages, which have succeeded on retries.
+pub(crate) async fn clear_job_failures(env: &Env, message_ids: &[String]) {
+    if message_ids.is_empty() {
+        return;
+    }
+    let Some(db) = failed_jobs_db(env) else {
+        return;
+    };
+    let placeholders: Vec<_> = (2..message_ids.len() + 2)
+        .map(|i| format!("?{}", i))
+        .collect();
+    let query = format!(
+        "DELETE FROM failed_jobs WHERE status = ?1 AND id IN ({})",
+        placeholders.join(", ")
+    );
+    let mut params = vec![JsValue::from(RETRYING)];
+    params.extend(message_ids.iter().map(|id| JsValue::from(id.as_str())));
+    let res = match db.prepare(query).bind(&params) {
+        Ok(stmt) => stmt.run().await.map(|_| ()),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = res {
+        console_error!("failed to clear failures of jobs: {:?}", e);
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct FailedJobMessage {
+    message: String,
+    status: String,
+}
+
+/// Send the dead-lettered job again, as a new message with its attempts starting over.
+pub(crate) async fn retry_dead_job(env: &Env, db: &D1Database, id: &str) -> ApiResult<()> {
+    let job = db
+        .prepare("SELECT message, status FROM failed_jobs WHERE id = ?1")
+        .bind(&[JsValue::from(id)])
+        .map_err(db_error)?
+        .first::<FailedJobMessage>(None)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| ApiError::new(404, "Job not found"))?;
+    if job.status != DEAD {
+        return Err(ApiError::new(
+            409,
+            format!(
+                "Only dead-lettered jobs can be retried, but it is {}",
+                job.status
+            ),
+        ));
+    }
+    let message: QueueMessage = serde_json::from_str(&job.message).map_err(|e| {
+        console_error!("failed to decode the message of job {}: {:?}", id, e);
+        ApiError::no_msg(500)
+    })?;
+    let Some(queue) = variant_queue(env) else {
+        console_error!("retries of jobs need the queue ({})", VARIANT_QUEUE_BINDING);
+        return Err(ApiError::new(503, "The queue is not configured"));
+    };
+    queue.send(&message).await.map_err(|e| {
+        console_error!("failed to send job {} again: {:?}", id, e);
+        ApiError::no_msg(500)
+    })?;
+
+    db.prepare("UPDATE failed_jobs SET status = ?1, retried_at = ?2 WHERE id = ?3")
+        .bind(&[
+            JsValue::from(RETRIED),
+            JsValue::from(Date::now().as_millis() as f64),
+            JsValue::from(id),
+        ])
+        .map_err(db_error)?
+        .run()
+        .await
+        .map_err(db_error)?;
+    console_log!(
+        "sent job {} again ({} of {})",
+        id,
+        message.kind(),
+        message.image().1
+    );
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct FailedJobChange {
+    status: String,
+    changed_at: u64,
+}
+
+/// Statuses of failed jobs which changed since the time, with when they did.
+pub(crate) async fn list_failed_job_statuses(
+    db: &D1Database,
+    since_ms: u64,
+) -> ApiResult<Vec<(JobStatus, u64)>> {
+    let changes = db
+        .prepare(
+            "SELECT status, COALESCE(retried_at, failed_at) AS changed_at FROM failed_jobs \
+             WHERE COALESCE(retried_at, failed_at) > ?1",
+        )
+        .bind(&[JsValue::from(since_ms as f64)])
+        .map_err(db_error)?
+        .all()
+        .await
+        .map_err(db_error)?
+        .results::<FailedJobChange>()
+        .map_err(db_error)?;
+    Ok(changes
+        .into_iter()
+        .filter_map(|c| Some((JobStatus::of_failed_job(&c.status)?, c.changed_at)))
+        .collect())
+}
+
+/// A dead-lettered job, with the context of its failure.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct DeadJob {
+    /// pass this to `POST /admin/jobs/{id}/retry`
+    id: String,
+    kind: String,
+    tenant: String,
+    hash: String,
+    /// error of the last attempt
+    error: String,
+    attempts: u32,
+    failed_at: u64,
+}
+
+/// Most recently dead-lettered jobs, newest first.
+pub(crate) async fn list_dead_jobs(db: &D1Database, limit: u32) -> ApiResult<Vec<DeadJob>> {
+    db.prepare(
+        "SELECT id, kind, tenant, hash, error, attempts, failed_at FROM failed_jobs \
+         WHERE status = ?1 ORDER BY failed_at DESC LIMIT ?2",
+    )
+    .bind(&[JsValue::from(DEAD), JsValue::from(limit)])
+    .map_err(db_error)?
+    .all()
+    .await
+    .map_err(db_error)?
+    .results::<DeadJob>()
+    .map_err(db_error)
+}
@@ -0,0 +1,88 @@
+//! Webhook notified of new uploads, so that downstream services (indexing etc.) don't have to poll.
+//!
+//! Enabled by setting the `UPLOAD_WEBHOOK_URL` var. Once the image and its variants are stored,
+//! a JSON payload describing them is POSTed to the URL after responding. Deduplicated uploads
+//! store nothing new, and aren't notified. Deliveries are not retried, so failures are only logged.
+
+use serde::Serialize;
+use worker::{
+    console_error, console_log, wasm_bindgen::JsValue, Context, Env, Fetch, Headers, Method,
+    Request, RequestInit, Result as WorkerResult,
+};
+
+use upix_lib::pipeline::UploadedImage;
+
+use crate::uploads::{UploadOrigin, UploadRecord};
+
+const UPLOAD_WEBHOOK_URL: &str = "UPLOAD_WEBHOOK_URL";
+
+#[derive(Debug, Serialize)]
+struct UploadPayload<'a> {
+    event: &'static str,
+    hash: &'a str,
+    tenant: &'a str,
+    namespace: &'a str,
+    /// extension of the format of the uploaded data, absent for frames of delta patches
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<&'static str>,
+    width: u32,
+    height: u32,
+    animated: bool,
+    variants: &'a [UploadedImage],
+    tags: &'a [String],
+}
+
+fn webhook_url(env: &Env) -> Option<String> {
+    env.var(UPLOAD_WEBHOOK_URL)
+        .ok()
+        .map(|v| v.to_string())
+        .filter(|u| !u.is_empty())
+}
+
+/// Notify the webhook of the stored upload after responding, if it is configured.
+pub(crate) fn notify_upload(
+    ctx: &Context,
+    env: &Env,
+    origin: &UploadOrigin,
+    record: &UploadRecord<'_>,
+) {
+    let Some(url) = webhook_url(env) else {
+        return;
+    };
+    let payload = UploadPayload {
+        event: "upload",
+        hash: record.hash,
+        tenant: &origin.tenant,
+        namespace: &origin.namespace,
+        format: record.format.map(|f| f.extensions_str()[0]),
+        width: record.width,
+        height: record.height,
+        animated: record.animated,
+        variants: record.images,
+        tags: record.tags,
+    };
+    let body = serde_json::to_string(&payload).unwrap();
+    let hash = record.hash.to_string();
+    ctx.wait_until(async move {
+        match post_payload(&url, body).await {
+            Ok(()) => console_log!("notified webhook of upload (hash: {})", hash),
+            Err(e) => console_error!("failed to notify webhook of upload {}: {:?}", hash, e),
+        }
+    });
+}
+
+async fn post_payload(url: &str, body: String) -> WorkerResult<()> {
+    let mut headers = Headers::new();
+    headers.set("Content-Type", "application/json")?;
+    let mut init = RequestInit::new();
+    init.with_method(Method::Post)
+        .with_headers(headers)
+        .with_body(Some(JsValue::from_str(&body)));
+
+    let req = Request::new_with_init(url, &init)?;
+    let resp = Fetch::Request(req).send().await?;
+    if !(200..300).contains(&resp.status_code()) {
+        return Err(format!("webhook responded with {}", resp.status_code()).into());
+    }
+    Ok(())
+}
@@ -0,0 +1,282 @@
+//! Dry-run of the upload pipeline on local files.
+//!
+//! Runs the same steps as `POST /` of the api worker: validates the image against the limits,
+//! computes the hash, and stores the variants into a directory instead of the bucket. The
+//! manifest is printed as JSON to stdout, in the shape of the upload response plus the hash.
+//!
+//! ```text
+//! upix <FILE> [--out <DIR>] [--limits <JSON>] [--quantize <N>] [--multi-frame <POLICY>]
+//!      [--scales <LIST>] [--hash <ALGORITHM>]
+//! ```
+//!
+//! `--limits` takes (partial) limits as in the `NAMESPACES` var, like `{ "max_scale": 4 }`.
+//! `--multi-frame` takes the policy as in the `MULTI_FRAME_POLICY` var. `--scales` takes scale
+//! factors to generate as in the `scales` field of uploads, like `2,4`. `--hash` takes the
+//! algorithm of hashes as in the `HASH_ALGORITHM` var.
+
+use std::{fs, path::PathBuf, process::ExitCode};
+
+use futures::executor::block_on;
+use image::DynamicImage;
+use serde::Serialize;
+
+use upix_lib::{
+    animation::{count_frames, decode_animation, keeps_animation, MAX_FRAMES},
+    blob::{BlobError, BlobStore, FsStore},
+    canonical::canonicalize,
+    content_hash::HashAlgorithm,
+    dimensions::Dimensions,
+    dynamic::DynamicHints,
+    namespace::Limits,
+    pipeline::{
+        check_dropped_frames, check_requested_scales, parse_scales, png_hash, store_variants,
+        validate_img, MultiFramePolicy, UploadResult, VariantStores,
+    },
+    quality::advise,
+    quantize::{quantize, QUANTIZE_COLORS_RANGE},
+    warning::{color_warning, scale_warnings, Warning},
+    ApiError,
+};
+
+const USAGE: &str = "usage: upix <FILE> [--out <DIR>] [--limits <JSON>] [--quantize <N>] \
+                     [--multi-frame <POLICY>] [--scales <LIST>] [--hash <ALGORITHM>] \
+                     [--keep-comments]";
+
+struct Args {
+    file: PathBuf,
+    out_dir: Option<PathBuf>,
+    limits: Limits,
+    quantize: Option<usize>,
+    multi_frame_policy: MultiFramePolicy,
+    /// parsed against the limits, which may come later
+    scales: Option<String>,
+    hash_algorithm: HashAlgorithm,
+    /// keep comments of animations, like the `KEEP_COMMENTS` var of the api worker
+    keep_comments: bool,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut file = None;
+    let mut out_dir = None;
+    let mut limits = Limits::default();
+    let mut quantize = None;
+    let mut multi_frame_policy = MultiFramePolicy::default();
+    let mut scales = None;
+    let mut hash_algorithm = HashAlgorithm::default();
+    let mut keep_comments = false;
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or_else(|| format!("{} needs a value", name));
+        match arg.as_str() {
+            "--out" => out_dir = Some(PathBuf::from(value("--out")?)),
+            "--limits" => {
+                limits = serde_json::from_str(&value("--limits")?)
+                    .map_err(|e| format!("invalid limits: {}", e))?;
+            }
+            "--quantize" => {
+                let n = value("--quantize")?
+                    .parse()
+                    .ok()
+                    .filter(|n| QUANTIZE_COLORS_RANGE.contains(n))
+                    .ok_or_else(|| {
+                        format!(
+                            "--quantize must be in {}..={}",
+                            QUANTIZE_COLORS_RANGE.start(),
+                            QUANTIZE_COLORS_RANGE.end()
+                        )
+                    })?;
+                quantize = Some(n);
+            }
+            "--multi-frame" => {
+                multi_frame_policy = value("--multi-frame")?
+                    .parse()
+                    .map_err(|e| format!("invalid --multi-frame: {}", e))?;
+            }
+            "--scales" => scales = Some(value("--scales")?),
+            "--hash" => {
+                hash_algorithm = value("--hash")?
+                    .parse()
+                    .map_err(|e| format!("invalid --hash: {}", e))?;
+            }
+            "--keep-comments" => keep_comments = true,
+            a if a.starts_with("--") => return Err(format!("unknown option: {}", a)),
+            _ if file.is_some() => return Err("only one file can be given".to_string()),
+            _ => file = Some(PathBuf::from(arg)),
+        }
+    }
+    Ok(Args {
+        file: file.ok_or("missing file")?,
+        out_dir,
+        limits,
+        quantize,
+        multi_frame_policy,
+        scales,
+        hash_algorithm,
+        keep_comments,
+    })
+}
+
+/// Output of the dry-run.
+#[derive(Debug, Serialize)]
+struct Manifest {
+    hash: String,
+    #[serde(flatten)]
+    result: UploadResult,
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    match run(&args) {
+        Ok(manifest) => {
+            println!("{}", serde_json::to_string_pretty(&manifest).unwrap());
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("rejected: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: &Args) -> Result<Manifest, String> {
+    let limits = &args.limits;
+    let scales = args
+        .scales
+        .as_deref()
+        .map(|s| parse_scales(s, limits))
+        .transpose()
+        .map_err(api_error)?;
+    let img_data = fs::read(&args.file).map_err(|e| format!("failed to read file: {}", e))?;
+    // the api worker processes data larger than `max_data_len` asynchronously, in the same way
+    if img_data.len() > limits.max_accepted_data_len() {
+        return Err(format!(
+            "[413] Too large image data ({} > {} bytes)",
+            img_data.len(),
+            limits.max_accepted_data_len()
+        ));
+    }
+    let img_fmt = image::guess_format(&img_data).map_err(|_| "[400] Not an image")?;
+    if !limits.allows_format(img_fmt) {
+        return Err(format!(
+            "[400] Unsupported image format: {}",
+            img_fmt.extensions_str()[0]
+        ));
+    }
+
+    let mut warnings = Vec::new();
+    let anim = match keeps_animation(img_fmt) {
+        true => decode_animation(&img_data, img_fmt).map_err(decode_error)?,
+        false => None,
+    };
+    if anim.as_ref().is_some_and(|a| a.frames.len() > MAX_FRAMES) {
+        return Err(format!(
+            "[400] Animation has too many frames (> {})",
+            MAX_FRAMES
+        ));
+    }
+    let img = match &anim {
+        Some(anim) => anim.first_frame(),
+        None => image::load_from_memory_with_format(&img_data, img_fmt).map_err(decode_error)?,
+    };
+    // GIFs and APNGs are kept as animations as with the default mode of the api worker
+    if !keeps_animation(img_fmt) {
+        let frames = count_frames(&img_data, img_fmt).map_err(decode_error)?;
+        warnings.extend(check_dropped_frames(args.multi_frame_policy, frames).map_err(api_error)?);
+    }
+    let advice = advise(&img.to_rgba8(), img_fmt);
+    let (img, hash) = match args.quantize {
+        Some(_) if anim.is_some() => return Err("[400] Animations can't be quantized".to_string()),
+        Some(n) => {
+            let img = DynamicImage::ImageRgba8(quantize(&img.to_rgba8(), n));
+            let hash = png_hash(&img, args.hash_algorithm).map_err(encode_error)?;
+            warnings.push(Warning::quantized(n));
+            (img, hash)
+        }
+        None => (img, args.hash_algorithm.hash_hex(&img_data)),
+    };
+
+    validate_img(&img, limits).map_err(api_error)?;
+    let long_side = img.width().max(img.height());
+    if let Some(scales) = &scales {
+        check_requested_scales(limits, long_side, scales).map_err(api_error)?;
+    }
+    warnings.extend(color_warning(&img.to_rgba8()));
+    warnings.extend(scale_warnings(
+        limits,
+        anim.as_ref(),
+        long_side,
+        scales.as_deref(),
+    ));
+
+    let (img, anim) = canonicalize(img, anim, args.keep_comments);
+    let images = match &args.out_dir {
+        Some(dir) => block_on(store_variants(
+            VariantStores::new(&FsStore::new(dir)),
+            "",
+            &hash,
+            &img,
+            anim.as_ref(),
+            limits,
+            scales.as_deref(),
+        )),
+        None => block_on(store_variants(
+            VariantStores::new(&DiscardStore),
+            "",
+            &hash,
+            &img,
+            anim.as_ref(),
+            limits,
+            scales.as_deref(),
+        )),
+    }
+    .map_err(|e| e.to_string())?;
+
+    // derived images are stored in the root namespace of the api worker, which the CLI stands in
+    let dynamic = DynamicHints::new(
+        limits,
+        Dimensions::of(&img),
+        anim.as_ref().map_or(1, |a| a.frames.len()),
+        true,
+    );
+    Ok(Manifest {
+        hash,
+        result: UploadResult {
+            images,
+            warnings,
+            advice,
+            deduplicated: false,
+            dynamic,
+            deferred: Vec::new(),
+        },
+    })
+}
+
+/// Store for dry-runs without the output directory, which only encodes variants.
+struct DiscardStore;
+
+impl BlobStore for DiscardStore {
+    async fn load(&self, _: &str) -> Result<Option<Vec<u8>>, BlobError> {
+        Ok(None)
+    }
+
+    async fn store(&self, _: &str, _: Vec<u8>, _: &str) -> Result<(), BlobError> {
+        Ok(())
+    }
+}
+
+fn api_error(e: ApiError) -> String {
+    format!("[{}] {}", e.status(), e.message().unwrap_or("(no message)"))
+}
+
+fn decode_error(e: image::ImageError) -> String {
+    format!("[400] Failed to decode image: {}", e)
+}
+
+fn encode_error(e: image::ImageError) -> String {
+    format!("failed to encode image: {}", e)
+}
@@ -0,0 +1,37 @@
+//! Annotations of images drawn over them by `?annotations=1` (see lib/src/annotation.rs), which
+//! are put by the api worker to the D1 database.
+
+use worker::{console_error, wasm_bindgen::JsValue, Env};
+
+use upix_lib::{annotation::Annotation, namespace::Namespace, tenant::Tenant, ApiError, ApiResult};
+
+/// Annotations of the image in the namespace of the tenant, in the order they were put.
+pub(crate) async fn load_annotations(
+    env: &Env,
+    tenant: &Tenant,
+    namespace: &Namespace,
+    hash: &str,
+) -> ApiResult<Vec<Annotation>> {
+    let Ok(db) = env.d1("DB") else {
+        console_error!("Failed to get bindings to the D1 database");
+        return Err(ApiError::no_msg(500));
+    };
+    let res = db
+        .prepare(
+            "SELECT name, x, y, width, height FROM annotations \
+             WHERE tenant = ?1 AND namespace = ?2 AND hash = ?3 ORDER BY position",
+        )
+        .bind(&[
+            JsValue::from(tenant.id.as_str()),
+            JsValue::from(namespace.name.as_str()),
+            JsValue::from(hash),
+        ]);
+    let res = match res {
+        Ok(stmt) => stmt.all().await.and_then(|r| r.results::<Annotation>()),
+        Err(e) => Err(e),
+    };
+    res.map_err(|e| {
+        console_error!("Failed to load annotations of {}: {:?}", hash, e);
+        ApiError::no_msg(500)
+    })
+}
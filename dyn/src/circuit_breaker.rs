@@ -0,0 +1,39 @@
+use upix_lib::circuit::{BreakerState, CircuitDecision};
+use worker::*;
+
+const STATE_KEY: &str = "state";
+
+/// Durable Object that counts failures of bucket operations, one object for the whole deployment
+/// (see lib/src/circuit.rs).
+///
+/// Storage layout:
+/// - `state`: failures in the current window and the end of the last cool-down
+#[durable_object]
+pub struct BucketCircuitBreaker {
+    state: State,
+}
+
+#[durable_object]
+impl DurableObject for BucketCircuitBreaker {
+    fn new(state: State, _env: Env) -> Self {
+        Self { state }
+    }
+
+    async fn fetch(&mut self, req: Request) -> Result<Response> {
+        let now = Date::now().as_millis();
+        let mut storage = self.state.storage();
+        let mut breaker = storage
+            .get::<BreakerState>(STATE_KEY)
+            .await
+            .unwrap_or_default();
+        match (req.method(), req.path().as_str()) {
+            (Method::Get, "/state") => {}
+            (Method::Post, "/failure") => {
+                breaker.record_failure(now);
+                storage.put(STATE_KEY, &breaker).await?;
+            }
+            _ => return Response::error("Not Found", 404),
+        }
+        Response::from_json(&CircuitDecision::of(&breaker, now))
+    }
+}
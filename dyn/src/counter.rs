@@ -0,0 +1,279 @@
+use std::{cell::RefCell, collections::HashMap};
+
+use upix_lib::stats::{
+    hour_index, rank_trending, send_counter_batch, views_within, CounterBatch, CounterBuffer,
+    HourlyViews, ImageStats, ScaleViews, ViewStats, COUNTER_BINDING, MAX_TRENDING_WINDOW_HOURS,
+};
+use worker::*;
+
+/// How long hourly view buckets are kept.
+const KEEP_HOURS: u64 = MAX_TRENDING_WINDOW_HOURS;
+
+fn hour_key(hour: u64) -> String {
+    // zero-padded so that keys are sorted in chronological order
+    format!("hour:{:010}", hour)
+}
+
+/// Key of views of the image within the hour. Views are kept per image rather than per hour, as
+/// values of the storage are limited in size.
+fn hour_views_key(hour: u64, hash: &str) -> String {
+    format!("{}:{}", hour_key(hour), hash)
+}
+
+/// Max number of keys of a single `get_multiple` or `delete_multiple` of the storage.
+const MAX_KEYS_PER_CALL: usize = 128;
+
+/// Key of all-time views of variants by scale.
+const SCALES_KEY: &str = "scales";
+
+fn total_key(hash: &str) -> String {
+    format!("total:{}", hash)
+}
+
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+struct Totals {
+    views: u64,
+    uploads: u64,
+}
+
+#[derive(serde::Deserialize)]
+struct TrendingQuery {
+    /// ID of the tenant whose images are ranked, empty for the default tenant
+    #[serde(default)]
+    tenant: String,
+    window_hours: u64,
+    limit: usize,
+}
+
+/// Durable Object that keeps view/upload counts of images.
+///
+/// Storage layout:
+/// Images are counted by their counter key (see `upix_lib::stats::counter_key`), `{key}` below.
+/// - `total:{key}`: all-time counts of the image
+/// - `hour:{hour index}:{key}`: views of the image within the hour, kept for `KEEP_HOURS`
+/// - `scales`: all-time views of variants by scale (JSON)
+#[durable_object]
+pub struct ImageCounter {
+    state: State,
+}
+
+#[durable_object]
+impl DurableObject for ImageCounter {
+    fn new(state: State, _env: Env) -> Self {
+        Self { state }
+    }
+
+    async fn fetch(&mut self, mut req: Request) -> Result<Response> {
+        let path = req.path();
+        match (req.method(), path.as_str()) {
+            (Method::Post, "/batch") => {
+                let batch: CounterBatch = req.json().await?;
+                self.apply_batch(batch).await?;
+                Ok(Response::empty()?.with_status(204))
+            }
+            (Method::Get, p) if p.starts_with("/stats/") => {
+                let key = &p["/stats/".len()..];
+                Response::from_json(&self.image_stats(key).await?)
+            }
+            (Method::Get, "/trending") => {
+                let query: TrendingQuery = req.query()?;
+                let (now_hour, buckets) = self.load_recent_hours(query.window_hours).await?;
+                let ranking = rank_trending(
+                    &buckets,
+                    &query.tenant,
+                    now_hour,
+                    query.window_hours,
+                    query.limit,
+                );
+                Response::from_json(&ranking)
+            }
+            (Method::Get, "/scales") => Response::from_json(&self.load_scale_views().await?),
+            _ => Response::error("Not Found", 404),
+        }
+    }
+}
+
+impl ImageCounter {
+    async fn apply_batch(&mut self, batch: CounterBatch) -> Result<()> {
+        let now_hour = hour_index(Date::now().as_millis());
+        let mut storage = self.state.storage();
+
+        // all-time counts
+        let hashes = batch.views.keys().chain(batch.uploads.keys());
+        for hash in hashes.collect::<std::collections::HashSet<_>>() {
+            let key = total_key(hash);
+            let mut totals = storage.get::<Totals>(&key).await.unwrap_or_default();
+            totals.views += batch.views.get(hash).copied().unwrap_or_default();
+            totals.uploads += batch.uploads.get(hash).copied().unwrap_or_default();
+            storage.put(&key, totals).await?;
+        }
+
+        // views in the current hour
+        let keys = batch
+            .views
+            .keys()
+            .map(|hash| hour_views_key(now_hour, hash))
+            .collect();
+        let hourly = self.load_counts(keys).await?;
+        for (hash, n) in batch.views {
+            let key = hour_views_key(now_hour, &hash);
+            let views = hourly.get(&key).copied().unwrap_or_default() + n;
+            storage.put(&key, views as f64).await?;
+        }
+
+        // views by scale
+        if !batch.scale_views.is_empty() {
+            let mut scale_views = self.load_scale_views().await?;
+            for (scale, n) in batch.scale_views {
+                *scale_views.entry(scale).or_default() += n;
+            }
+            storage
+                .put(SCALES_KEY, serde_json::to_string(&scale_views)?)
+                .await?;
+        }
+
+        // drop expired buckets
+        let end = hour_key(now_hour.saturating_sub(KEEP_HOURS));
+        let expired = storage
+            .list_with_options(ListOptions::new().prefix("hour:").end(&end))
+            .await?;
+        let expired_keys: Vec<String> = expired
+            .keys()
+            .into_iter()
+            .filter_map(|k| k.ok()?.as_string())
+            .collect();
+        for keys in expired_keys.chunks(MAX_KEYS_PER_CALL) {
+            storage.delete_multiple(keys.to_vec()).await?;
+        }
+        Ok(())
+    }
+
+    /// Counts stored under the keys, leaving out missing ones. Unlike `Storage::get`, which fails
+    /// for missing keys too, failures of the storage are returned as such.
+    async fn load_counts(&self, keys: Vec<String>) -> Result<HashMap<String, u64>> {
+        let storage = self.state.storage();
+        let mut counts = HashMap::new();
+        for keys in keys.chunks(MAX_KEYS_PER_CALL) {
+            let values = storage.get_multiple(keys.to_vec()).await?;
+            for entry in values.entries() {
+                let entry: js_sys::Array = entry?.into();
+                if let (Some(key), Some(n)) = (entry.get(0).as_string(), entry.get(1).as_f64()) {
+                    counts.insert(key, n as u64);
+                }
+            }
+        }
+        Ok(counts)
+    }
+
+    async fn load_scale_views(&self) -> Result<ScaleViews> {
+        match self.state.storage().get::<String>(SCALES_KEY).await {
+            Ok(json) => Ok(serde_json::from_str(&json)?),
+            Err(_) => Ok(ScaleViews::new()),
+        }
+    }
+
+    /// Load hourly buckets within `window_hours` until now.
+    async fn load_recent_hours(&self, window_hours: u64) -> Result<(u64, Vec<(u64, HourlyViews)>)> {
+        let now_hour = hour_index(Date::now().as_millis());
+        let start = hour_key((now_hour + 1).saturating_sub(window_hours));
+        let entries = self
+            .state
+            .storage()
+            .list_with_options(ListOptions::new().prefix("hour:").start(&start))
+            .await?;
+
+        let mut buckets = HashMap::<u64, HourlyViews>::new();
+        for entry in entries.entries() {
+            let entry: js_sys::Array = entry?.into();
+            let (Some(key), Some(n)) = (entry.get(0).as_string(), entry.get(1).as_f64()) else {
+                continue;
+            };
+            let Some((hour, hash)) = parse_hour_views_key(&key) else {
+                continue;
+            };
+            buckets
+                .entry(hour)
+                .or_default()
+                .insert(hash.to_string(), n as u64);
+        }
+        Ok((now_hour, buckets.into_iter().collect()))
+    }
+
+    async fn image_stats(&self, key: &str) -> Result<ImageStats> {
+        let totals = self
+            .state
+            .storage()
+            .get::<Totals>(&total_key(key))
+            .await
+            .unwrap_or_default();
+        // views of the image alone are looked up, rather than listing those of all images
+        let now_hour = hour_index(Date::now().as_millis());
+        let hours = (now_hour + 1).saturating_sub(KEEP_HOURS)..=now_hour;
+        let keys = hours.clone().map(|h| hour_views_key(h, key)).collect();
+        let counts = self.load_counts(keys).await?;
+        let buckets: Vec<_> = hours
+            .filter_map(|h| {
+                let n = counts.get(&hour_views_key(h, key))?;
+                Some((h, HourlyViews::from([(key.to_string(), *n)])))
+            })
+            .collect();
+
+        Ok(ImageStats {
+            hash: key.to_string(),
+            views: ViewStats {
+                total: totals.views,
+                last_24h: views_within(&buckets, key, now_hour, 24),
+                last_7d: views_within(&buckets, key, now_hour, KEEP_HOURS),
+            },
+            uploads: totals.uploads,
+        })
+    }
+}
+
+/// Hour index and counter key of a key of hourly views.
+fn parse_hour_views_key(key: &str) -> Option<(u64, &str)> {
+    let (hour, hash) = key.strip_prefix("hour:")?.split_once(':')?;
+    Some((hour.parse().ok()?, hash))
+}
+
+thread_local! {
+    static VIEW_BUFFER: RefCell<CounterBuffer> = RefCell::new(CounterBuffer::default());
+}
+
+/// Record a view of the image under the counter key, at the scale if it is a scaled variant. Views are buffered in the
+/// isolate and flushed to the counter in batches.
+pub fn record_view(key: &str, scale: Option<u32>, env: &Env, ctx: &Context) {
+    let now = Date::now().as_millis();
+    let batch = VIEW_BUFFER.with(|buf| {
+        let mut buf = buf.borrow_mut();
+        buf.record_view(key, scale);
+        buf.should_flush(now).then(|| buf.take_batch(now))
+    });
+    let Some(batch) = batch else {
+        return;
+    };
+
+    let Ok(ns) = env.durable_object(COUNTER_BINDING) else {
+        console_error!("Failed to get bindings to the counter");
+        return;
+    };
+    ctx.wait_until(async move {
+        if let Err(e) = send_counter_batch(&ns, &batch).await {
+            console_error!("Failed to send view counts: {:?}", e);
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_hour_views_key() {
+        let key = hour_views_key(12, "abc");
+        assert_eq!(key, "hour:0000000012:abc");
+        assert_eq!(parse_hour_views_key(&key), Some((12, "abc")));
+        // buckets of all images within the hour, stored before views were kept per image
+        assert_eq!(parse_hour_views_key(&hour_key(12)), None);
+    }
+}
@@ -1,25 +1,140 @@
+use std::{io::Cursor, str::FromStr};
+
+use image::{
+    codecs::{avif::AvifEncoder, png::PngDecoder},
+    DynamicImage,
+};
 use regex::Regex;
 use send::SendWrapper;
-use upix_lib::{encode_image, sha256_hex, upscale_image, ApiError, ApiResult};
-use worker::*;
+use serde::Serialize;
+use upix_lib::{
+    access_log::{log_access, now_ms, AccessRecord, CACHE_STATUS_HEADER},
+    analytics::{write_data_point, ServeMetrics, SERVE_ANALYTICS_BINDING},
+    animation::{
+        decode_animation, encode_animated_webp, encode_apng, encode_gif, Animation, FrameTiming,
+        SPEED_RANGE,
+    },
+    annotation::{annotations_digest, draw_annotations, Annotation},
+    auth::bearer_token,
+    cache_epoch::{
+        apply_epoch, apply_image_epochs, load_cache_epoch, load_image_epoch, strip_reserved_params,
+    },
+    cache_policy::{CachePolicy, CacheRoute},
+    color_vision::ColorVision,
+    compare::CompareMode,
+    config::Config,
+    content::{extract_palette, PaletteEntry},
+    crop::Crop,
+    debug::{check_debug, Trace, DEBUG_HEADER, DEBUG_SECRET},
+    dimensions::Dimensions,
+    downscale::{downscale_image, thumbnail_image, Downscale, THUMB_MAX_SIDE},
+    dynamic::{SERVED_FORMATS, STILL_ONLY_FORMATS},
+    encode_image, encode_png,
+    etag::{matches_if_none_match, variant_etag},
+    namespace::{find_namespace, Limits, Namespace, COLD_BUCKET_BINDING},
+    orientation::{Flip, Orientation, Rotation},
+    panic::{enter_request, request_id, set_panic_hook},
+    playback::PlaybackControl,
+    quantize::{parse_max_colors, quantize},
+    route_guard::{guard_request, BodyLimit, RouteRule},
+    schema::{find_versioned, KeySchema},
+    sha256_hex,
+    stats::counter_key,
+    tenant::{resolve_tenant, Tenant},
+    upload_index::{load_index_entry, upload_index},
+    upscale_image,
+    variant::Variant,
+    ApiError, ApiResult,
+};
+use worker::{kv::KvStore, *};
+
+mod annotations;
+mod circuit_breaker;
+mod counter;
+mod rate_limiter;
+mod upload_lock;
+
+pub use circuit_breaker::BucketCircuitBreaker;
+pub use counter::ImageCounter;
+pub use rate_limiter::UploadRateLimiter;
+pub use upload_lock::UploadLock;
 
 #[event(fetch)]
 async fn fetch(req: Request, env: Env, ctx: Context) -> Result<Response> {
-    console_error_panic_hook::set_once();
+    set_panic_hook();
+
+    if let Err(e) = Config::from_env(&env) {
+        return ApiError::from(e).to_response();
+    }
+
+    let started_at = now_ms();
+    let (method, path) = (req.method(), req.path());
+    let env2 = env.clone();
+    let res = serve(req, env, &ctx).await;
+    if let Ok(resp) = &res {
+        let record = AccessRecord::of_response(method, path, started_at, resp);
+        // only responses through the cache are of variants
+        if let (Some(cache), Some(parts)) = (&record.cache, match_req_path(&record.path)) {
+            let metrics = ServeMetrics {
+                hash: &parts.hash,
+                scale: parts.scale,
+                cache,
+                bytes: record.bytes.unwrap_or_default(),
+            };
+            write_data_point(&env2, SERVE_ANALYTICS_BINDING, &metrics.data_point());
+        }
+        log_access(&env2, &ctx, "dyn", record);
+    }
+    res
+}
+
+async fn serve(req: Request, env: Env, ctx: &Context) -> Result<Response> {
+    let host = req.url()?.host_str().map(|h| h.to_string());
+    let tenant = match resolve_tenant(&env, host.as_deref()).await {
+        Ok(tenant) => tenant,
+        Err(e) => {
+            console_error!("Failed to resolve tenant: {:?}", e);
+            return ApiError::no_msg(500).to_response();
+        }
+    };
+    let cors = tenant.cors(req.headers().get("Origin")?.as_deref());
 
-    match handle(req, env, ctx).await {
+    let cache_policy = tenant.cache_policy(CacheRoute::Variant, &env);
+    enter_request(&request_id(&req));
+    match handle(req, env, ctx, &tenant, &cache_policy).await {
         Ok(resp) => Ok(resp),
-        Err(e) => e.to_response(),
+        Err(e) => e.to_response().and_then(|mut resp| {
+            // let clients and the edge remember missing images for a while
+            if resp.status_code() == 404 {
+                cache_policy.apply_negative(resp.headers_mut())?;
+            }
+            Ok(resp)
+        }),
     }
+    .and_then(|r| r.with_cors(&cors))
 }
 
+/// Images are only read, without bodies.
+const ROUTES: &[RouteRule] = &[RouteRule::new(Method::Get, "/*", BodyLimit::Empty)];
+
 const MIN_PATH_LEN: usize = 66; // 64 (hash) + 1 (heading "/") + 1 (".")
 
-async fn handle(req: Request, env: Env, ctx: Context) -> ApiResult<Response> {
-    // deny methods other than GET
-    if req.method() != Method::Get {
-        console_log!("Unsupported method: {:?}", req.method());
-        return Err(ApiError::no_msg(405)); // 405 Method Not Allowed
+async fn handle(
+    req: Request,
+    env: Env,
+    ctx: &Context,
+    tenant: &Tenant,
+    cache_policy: &CachePolicy,
+) -> ApiResult<Response> {
+    if let Err(e) = guard_request(ROUTES, &req) {
+        console_log!("Request denied: {:?} {}", req.method(), req.path());
+        return Err(e);
+    }
+    if let Some(parts) = match_palette_path(&req.path()) {
+        return get_palette(&parts, &env, tenant, cache_policy).await;
+    }
+    if let Some(parts) = match_compare_path(&req.path()) {
+        return get_compare(&req, &parts, &env, ctx, tenant, cache_policy).await;
     }
     // rough path validation
     if req.path().len() < MIN_PATH_LEN {
@@ -27,145 +142,1255 @@ async fn handle(req: Request, env: Env, ctx: Context) -> ApiResult<Response> {
         return Err(ApiError::no_msg(404));
     }
 
+    let Some(mut parts) = match_req_path(&req.path()) else {
+        console_log!("Path doesn't match the pattern: {}", req.path());
+        return Err(ApiError::no_msg(404));
+    };
+    // `format` query param takes the place of the extension, for clients which can't change paths
+    let format = parse_format(&req)?;
+    if let Some(format) = &format {
+        parts.ext = format.clone();
+    }
+    // `thumb` query param takes the place of `_thumb` in the path likewise
+    if parse_thumb(&req)? {
+        if parts.scale != 1 || parts.downscale.is_some() || parts.fit.is_some() {
+            return Err(ApiError::new(400, "'thumb' can't be used with sizes"));
+        }
+        parts.thumb = true;
+    }
+
     // get bindings to the bucket
     let Ok(bucket) = env.bucket("IMGS_BUCKET") else {
         console_error!("Failed to get bindings to the R2 bucket");
         return Err(ApiError::no_msg(500));
     };
     let bucket = SendWrapper::new(bucket);
+    let cold_bucket = env.bucket(COLD_BUCKET_BINDING).ok().map(SendWrapper::new);
+    let index = upload_index(&env).map(SendWrapper::new);
+
+    let Some(namespace) = find_namespace(&env, parts.namespace.as_deref()) else {
+        console_log!("Unknown namespace: {:?}", parts.namespace);
+        return Err(ApiError::no_msg(404));
+    };
+    let limits = namespace.limits.clone();
+    // regions of annotations are in pixels of the original, so they can't be drawn over
+    // transformed or downscaled images
+    let annotations = match parse_annotations(&req)? {
+        true if parts.downscale.is_some() || parts.thumb => {
+            return Err(ApiError::new(
+                400,
+                "'annotations' can't be used with downscaling",
+            ));
+        }
+        true => Some(annotations::load_annotations(&env, tenant, &namespace, &parts.hash).await?),
+        false => None,
+    };
+    let view_key = counter_key(&tenant.id, &namespace.name, &parts.hash);
+    let src = SourceImage {
+        tenant: tenant.clone(),
+        namespace,
+        file_name: format!("{}.png", parts.hash),
+        annotations: annotations.clone().unwrap_or_default(),
+    };
+
+    // animations requested as `.png` are served as WebP to clients accepting it, so the cache
+    // has to tell those clients apart. Formats chosen by the query param are served as is.
+    let accepts_webp = parts.ext == "png"
+        && format.is_none()
+        && parts.frame.is_none()
+        && !parts.thumb
+        && req
+            .headers()
+            .get("Accept")
+            .ok()
+            .flatten()
+            .is_some_and(|a| a.contains("image/webp"));
+    let options = OutputOptions {
+        accepts_webp,
+        speed: parse_speed(&req)?,
+        crop: parse_crop(&req)?,
+        orientation: parse_orientation(&req)?,
+        max_colors: parse_max_colors_param(&req)?,
+        simulate: parse_simulate(&req)?,
+        playback: parse_playback(&req)?,
+        annotations: annotations.as_deref().map(annotations_digest),
+    };
+    if options.annotations.is_some()
+        && (options.crop.is_some() || !options.orientation.is_identity())
+    {
+        return Err(ApiError::new(
+            400,
+            "'annotations' can't be used with 'crop', 'rot' or 'flip'",
+        ));
+    }
+    if is_debug_request(&req, &env)? {
+        // the cache is neither read nor written, so that the response tells the current state
+        console_log!("Debugging: {}", req.path());
+        let img =
+            generate_upscaled_image(&parts, &src, &limits, options, bucket, cold_bucket, index)
+                .await?;
+        return make_debug_response(img, cache_policy).and_then(|r| with_cache_status(r, "bypass"));
+    }
+    let (epoch, image_epochs) = load_epochs(&env, &[&parts.hash]).await;
+    let image_epoch = image_epochs[0];
+    let cache_key = make_cache_key(&req, options, epoch, image_epoch)?;
+
+    // clients having the variant already are answered before it is looked up or loaded
+    let etag = variant_etag(
+        &parts.hash,
+        &describe_variant(&parts, options, epoch, image_epoch),
+    );
+    // `*` is matched once the image is known to exist. Revalidations are not counted as views.
+    let if_none_match = req.headers().get("If-None-Match").ok().flatten();
+    let not_modified = |exists: bool| {
+        if_none_match
+            .as_deref()
+            .is_some_and(|v| matches_if_none_match(v, &etag, exists))
+    };
+    if not_modified(false) {
+        console_log!("Not modified: {}", req.path());
+        let resp = make_not_modified_response(&etag, accepts_webp, cache_policy)?;
+        return with_cache_status(resp, "revalidated");
+    }
 
     // return cached response if available
     let cache = Cache::default();
-    let cached_resp = cache.get(&req, false).await.map_err(|e| {
+    let cached_resp = cache.get(&cache_key, false).await.map_err(|e| {
         console_error!("Failed to match request against cache: {:?}", e);
         ApiError::no_msg(500)
     })?;
     if let Some(resp) = cached_resp {
         console_log!("Cache hit: {}", req.path());
-        return Ok(resp);
+        if not_modified(true) {
+            let resp = make_not_modified_response(&etag, accepts_webp, cache_policy)?;
+            return with_cache_status(resp, "revalidated");
+        }
+        counter::record_view(&view_key, view_scale(&parts, &resp), &env, ctx);
+        if is_stale(&resp, cache_policy) {
+            // serve the stale response as is, and regenerate it in the background
+            console_log!("Revalidating stale cache entry: {}", req.path());
+            let cache_policy = cache_policy.clone();
+            let etag = etag.clone();
+            ctx.wait_until(async move {
+                let res = generate_upscaled_image(
+                    &parts,
+                    &src,
+                    &limits,
+                    options,
+                    bucket,
+                    cold_bucket,
+                    index,
+                )
+                .await;
+                match res {
+                    Ok(img) => {
+                        let resp = make_image_response(img, Some(&etag), &cache_policy);
+                        put_cache(&cache, &cache_key, resp).await;
+                    }
+                    Err(e) => console_error!("Failed to revalidate {}: {:?}", req.path(), e),
+                }
+            });
+        }
+        return with_cache_status(resp, "hit");
     }
 
     // generate a response with upscaled image
-    let img_data = generate_upscaled_image(&req.path(), bucket).await?;
-    let hash = sha256_hex(&img_data);
+    let img =
+        generate_upscaled_image(&parts, &src, &limits, options, bucket, cold_bucket, index).await?;
+    let mut resp = make_image_response(img, Some(&etag), cache_policy);
+
+    // cache the response
+    let resp2 = resp.cloned().unwrap();
+    ctx.wait_until(async move {
+        put_cache(&cache, &cache_key, resp2).await;
+    });
+
+    if not_modified(true) {
+        let resp = make_not_modified_response(&etag, accepts_webp, cache_policy)?;
+        return with_cache_status(resp, "revalidated");
+    }
+    counter::record_view(&view_key, view_scale(&parts, &resp), &env, ctx);
+    with_cache_status(resp, "miss")
+}
+
+/// Tell whether the response was served from the cache, for clients and access logs.
+fn with_cache_status(resp: Response, status: &str) -> ApiResult<Response> {
+    let mut headers = resp.headers().clone();
+    headers.set(CACHE_STATUS_HEADER, status).map_err(|e| {
+        console_error!("Failed to set cache status header: {:?}", e);
+        ApiError::no_msg(500)
+    })?;
+    Ok(resp.with_headers(headers))
+}
+
+/// Options of the output image, besides ones in the path.
+#[derive(Debug, Clone, Copy)]
+struct OutputOptions {
+    /// Whether the client accepts WebP in place of PNG.
+    accepts_webp: bool,
+    /// Speed factor of animations.
+    speed: Option<f64>,
+    /// Region of the source to crop before scaling.
+    crop: Option<Crop>,
+    /// Rotation and flip of the (cropped) source before scaling.
+    orientation: Orientation,
+    /// Number of colors to quantize the source to before scaling.
+    max_colors: Option<usize>,
+    /// Color vision deficiency to simulate on the (quantized) source before scaling.
+    simulate: Option<ColorVision>,
+    /// Overrides of the loop count and the comment of animations.
+    playback: PlaybackControl,
+    /// Digest of the annotations to outline over the output, if requested.
+    annotations: Option<u64>,
+}
+
+fn parse_speed(req: &Request) -> ApiResult<Option<f64>> {
+    let Ok(url) = req.url() else {
+        return Err(ApiError::no_msg(500));
+    };
+    let Some((_, v)) = url.query_pairs().find(|(k, _)| k == "speed") else {
+        return Ok(None);
+    };
+    match v.parse::<f64>() {
+        Ok(speed) if SPEED_RANGE.contains(&speed) => Ok(Some(speed)),
+        _ => Err(ApiError::new(
+            400,
+            format!(
+                "'speed' must be in {}..={}",
+                SPEED_RANGE.start(),
+                SPEED_RANGE.end()
+            ),
+        )),
+    }
+}
+
+fn parse_crop(req: &Request) -> ApiResult<Option<Crop>> {
+    let Ok(url) = req.url() else {
+        return Err(ApiError::no_msg(500));
+    };
+    let Some((_, v)) = url.query_pairs().find(|(k, _)| k == "crop") else {
+        return Ok(None);
+    };
+    v.parse().map(Some).map_err(|e| ApiError::new(400, e))
+}
+
+fn parse_orientation(req: &Request) -> ApiResult<Orientation> {
+    let Ok(url) = req.url() else {
+        return Err(ApiError::no_msg(500));
+    };
+    Ok(Orientation {
+        rotation: parse_query_param(&url, "rot")?,
+        flip: parse_query_param(&url, "flip")?,
+    })
+}
+
+fn parse_simulate(req: &Request) -> ApiResult<Option<ColorVision>> {
+    let Ok(url) = req.url() else {
+        return Err(ApiError::no_msg(500));
+    };
+    parse_query_param(&url, "simulate")
+}
+
+fn parse_playback(req: &Request) -> ApiResult<PlaybackControl> {
+    let Ok(url) = req.url() else {
+        return Err(ApiError::no_msg(500));
+    };
+    Ok(PlaybackControl {
+        loops: parse_query_param(&url, "loop")?.unwrap_or_default(),
+        comment: parse_query_param(&url, "comment")?.unwrap_or_default(),
+    })
+}
+
+fn parse_max_colors_param(req: &Request) -> ApiResult<Option<usize>> {
+    let Ok(url) = req.url() else {
+        return Err(ApiError::no_msg(500));
+    };
+    let Some((_, v)) = url.query_pairs().find(|(k, _)| k == "max_colors") else {
+        return Ok(None);
+    };
+    parse_max_colors(&v, "max_colors")
+        .map(Some)
+        .map_err(|e| ApiError::new(400, e))
+}
+
+fn parse_query_param<T: FromStr<Err = String>>(url: &Url, name: &str) -> ApiResult<Option<T>> {
+    url.query_pairs()
+        .find(|(k, _)| k == name)
+        .map(|(_, v)| v.parse().map_err(|e| ApiError::new(400, e)))
+        .transpose()
+}
+
+/// The region to crop the source to if any, checked to lie within the source.
+fn check_crop(crop: Option<Crop>, src: Dimensions) -> ApiResult<Option<Crop>> {
+    match crop {
+        Some(crop) if !crop.fits_in(src) => Err(ApiError::new(
+            400,
+            format!(
+                "Crop must lie within the image ({} x {})",
+                src.width, src.height
+            ),
+        )),
+        crop => Ok(crop),
+    }
+}
+
+fn parse_thumb(req: &Request) -> ApiResult<bool> {
+    let Ok(url) = req.url() else {
+        return Err(ApiError::no_msg(500));
+    };
+    Ok(url.query_pairs().any(|(k, v)| k == "thumb" && v == "1"))
+}
 
-    let resp_headers: Headers = [
-        ("Content-Type", "image/png"),
-        ("Cache-Control", "public, max-age=31536000"),
-        ("ETag", &hash),
+fn parse_annotations(req: &Request) -> ApiResult<bool> {
+    let Ok(url) = req.url() else {
+        return Err(ApiError::no_msg(500));
+    };
+    Ok(url
+        .query_pairs()
+        .any(|(k, v)| k == "annotations" && v == "1"))
+}
+
+/// Formats selectable by the `format` query param.
+const QUERY_FORMATS: [&str; 4] = ["webp", "gif", "bmp", "png"];
+
+fn parse_format(req: &Request) -> ApiResult<Option<String>> {
+    let Ok(url) = req.url() else {
+        return Err(ApiError::no_msg(500));
+    };
+    let Some((_, v)) = url.query_pairs().find(|(k, _)| k == "format") else {
+        return Ok(None);
+    };
+    if !QUERY_FORMATS.contains(&v.as_ref()) {
+        return Err(ApiError::new(
+            400,
+            format!("'format' must be one of {}", QUERY_FORMATS.join(", ")),
+        ));
+    }
+    Ok(Some(v.into_owned()))
+}
+
+/// Epochs of cached variants (see `upix_lib::cache_epoch`): the global one, and those of the
+/// images with the hashes. Serving from older epochs beats failing to serve.
+async fn load_epochs(env: &Env, hashes: &[&str]) -> (u64, Vec<u64>) {
+    let epoch = load_cache_epoch(env).await.unwrap_or_else(|e| {
+        console_error!("Failed to load cache epoch: {:?}", e);
+        0
+    });
+    let mut image_epochs = Vec::with_capacity(hashes.len());
+    for hash in hashes {
+        image_epochs.push(load_image_epoch(env, hash).await.unwrap_or_else(|e| {
+            console_error!("Failed to load cache epoch of {}: {:?}", hash, e);
+            0
+        }));
+    }
+    (epoch, image_epochs)
+}
+
+/// Key of the cache entry for the request. The query params are kept, so that entries of
+/// different `format`s and `speed`s are apart, and so are entries of different cache epochs.
+/// Entries with annotations drawn are keyed by their digest, so that they are never served after
+/// the annotations are replaced.
+fn make_cache_key(
+    req: &Request,
+    options: OutputOptions,
+    epoch: u64,
+    image_epoch: u64,
+) -> ApiResult<String> {
+    let mut url = request_cache_url(req)?;
+    if options.accepts_webp {
+        url.query_pairs_mut().append_pair("_accept", "webp");
+    }
+    if let Some(digest) = options.annotations {
+        url.query_pairs_mut()
+            .append_pair("_annotations", &format!("{:016x}", digest));
+    }
+    apply_epoch(&mut url, epoch);
+    apply_image_epochs(&mut url, &[image_epoch]);
+    Ok(url.to_string())
+}
+
+/// URL of the request as the base of its cache key, without reserved params.
+fn request_cache_url(req: &Request) -> ApiResult<Url> {
+    let Ok(mut url) = req.url() else {
+        return Err(ApiError::no_msg(500));
+    };
+    strip_reserved_params(&mut url);
+    Ok(url)
+}
+
+fn is_debug_request(req: &Request, env: &Env) -> ApiResult<bool> {
+    let Ok(url) = req.url() else {
+        return Err(ApiError::no_msg(500));
+    };
+    let nocache = url.query_pairs().any(|(k, v)| k == "nocache" && v == "1");
+    let header = req.headers().get(DEBUG_HEADER).ok().flatten();
+    let secret = env.secret(DEBUG_SECRET).ok().map(|s| s.to_string());
+    check_debug(
+        header.as_deref(),
+        bearer_token(req).as_deref(),
+        nocache,
+        secret.as_deref(),
+    )
+}
+
+/// Response of the image with the debug headers, which is stored by nobody.
+fn make_debug_response(img: GeneratedImage, cache_policy: &CachePolicy) -> ApiResult<Response> {
+    let trace = img.trace.clone();
+    let mut resp = make_image_response(img, None, cache_policy);
+    let headers = resp.headers_mut();
+    let res = headers.set("Cache-Control", "no-store").and_then(|_| {
+        trace
+            .headers()
+            .into_iter()
+            .try_for_each(|(name, value)| headers.set(name, &value))
+    });
+    res.map_err(|e| {
+        console_error!("Failed to set debug headers: {:?}", e);
+        ApiError::no_msg(500)
+    })?;
+    Ok(resp)
+}
+
+/// Header recording when the response was generated (unix time in milliseconds).
+const GENERATED_AT_HEADER: &str = "X-Upix-Generated-At";
+/// Header recording the scale resolved for `fit`, so that views of cached responses are counted
+/// by it.
+const FIT_SCALE_HEADER: &str = "X-Upix-Fit-Scale";
+
+struct GeneratedImage {
+    data: Vec<u8>,
+    content_type: &'static str,
+    /// Whether the format was negotiated by the `Accept` header.
+    negotiated: bool,
+    /// Scale resolved for `fit`, which views are counted by.
+    fit_scale: Option<u32>,
+    trace: Trace,
+}
+
+/// Response of the image, with the entity tag of the variant if any (or of the data otherwise).
+fn make_image_response(
+    img: GeneratedImage,
+    etag: Option<&str>,
+    cache_policy: &CachePolicy,
+) -> Response {
+    let etag = match etag {
+        Some(etag) => etag.to_string(),
+        None => format!("\"{}\"", sha256_hex(&img.data)),
+    };
+    let generated_at = Date::now().as_millis().to_string();
+
+    let mut resp_headers: Headers = [
+        ("Content-Type", img.content_type),
+        ("ETag", &etag),
+        (GENERATED_AT_HEADER, &generated_at),
     ]
     .iter()
     .collect();
-    let mut resp = Response::from_bytes(img_data)
+    cache_policy.apply(&mut resp_headers).unwrap();
+    if img.negotiated {
+        resp_headers.append("Vary", "Accept").unwrap();
+    }
+    if let Some(scale) = img.fit_scale {
+        resp_headers
+            .set(FIT_SCALE_HEADER, &scale.to_string())
+            .unwrap();
+    }
+    Response::from_bytes(img.data)
         .map(|r| r.with_headers(resp_headers))
-        .unwrap();
+        .unwrap()
+}
 
-    // cache the response
-    let resp2 = resp.cloned().unwrap();
-    ctx.wait_until(async move {
-        match cache.put(&req, resp2).await {
-            Ok(_) => console_log!("Cached response: {}", req.path()),
-            Err(e) => console_error!("Failed to cache response: {:?}", e),
+/// Response telling the client that the variant it has is still valid. `Vary` is sent to clients
+/// which may have got a negotiated format, as the variant may be an animation.
+fn make_not_modified_response(
+    etag: &str,
+    accepts_webp: bool,
+    cache_policy: &CachePolicy,
+) -> ApiResult<Response> {
+    let mut headers = Headers::new();
+    let res = headers.set("ETag", etag).and_then(|_| {
+        cache_policy.apply(&mut headers)?;
+        if accepts_webp {
+            headers.append("Vary", "Accept")?;
         }
+        Ok(())
     });
+    res.map_err(|e| {
+        console_error!("Failed to set headers of not modified response: {:?}", e);
+        ApiError::no_msg(500)
+    })?;
+    Ok(Response::empty()
+        .map_err(|_| ApiError::no_msg(500))?
+        .with_status(304)
+        .with_headers(headers))
+}
 
-    Ok(resp)
+/// Scale the view of the response is counted by: that of the variant, or the one resolved for
+/// `fit` of the whole image. Views are counted by scale, for telling which scales are worth
+/// pre-generating.
+fn view_scale(parts: &ReqPathParts, resp: &Response) -> Option<u32> {
+    match parts.fit {
+        Some(_) if parts.frame.is_none() => resp
+            .headers()
+            .get(FIT_SCALE_HEADER)
+            .ok()
+            .flatten()
+            .and_then(|v| v.parse().ok()),
+        _ => parts.variant_scale(),
+    }
+}
+
+fn is_stale(resp: &Response, cache_policy: &CachePolicy) -> bool {
+    let generated_at = resp
+        .headers()
+        .get(GENERATED_AT_HEADER)
+        .ok()
+        .flatten()
+        .and_then(|v| v.parse().ok())
+        // entries cached before the header was introduced
+        .unwrap_or(0);
+    cache_policy.is_stale(generated_at, Date::now().as_millis())
+}
+
+async fn put_cache(cache: &Cache, key: &str, resp: Response) {
+    match cache.put(key, resp).await {
+        Ok(_) => console_log!("Cached response: {}", key),
+        Err(e) => console_error!("Failed to cache response: {:?}", e),
+    }
 }
 
 async fn generate_upscaled_image(
-    req_path: &str,
+    parts: &ReqPathParts,
+    src: &SourceImage,
+    limits: &Limits,
+    options: OutputOptions,
     bucket: SendWrapper<Bucket>,
-) -> ApiResult<Vec<u8>> {
-    let Some(parts) = match_req_path(req_path) else {
-        console_log!("Path doesn't match the pattern: {}", req_path);
-        return Err(ApiError::no_msg(404));
-    };
-    if parts.ext != "png" {
+    cold_bucket: Option<SendWrapper<Bucket>>,
+    index: Option<SendWrapper<KvStore>>,
+) -> ApiResult<GeneratedImage> {
+    if !SERVED_FORMATS.contains(&parts.ext.as_str()) {
         console_log!("Unsupported extension: {}", parts.ext);
         return Err(ApiError::no_msg(404));
     }
+    let started = Date::now().as_millis();
+    let stored = load_stored_variant(parts, src, options, &bucket, cold_bucket, index).await;
+    let (source_key, loaded, mut img) = match stored {
+        Some((source_key, img)) => (source_key, Date::now().as_millis(), img),
+        None => {
+            let (source_key, src_img_data) = load_source_image(&bucket, src, &parts.hash).await?;
+            let loaded = Date::now().as_millis();
+            let img = render_image(parts, &src_img_data, limits, options, &src.annotations)?;
+            (source_key, loaded, img)
+        }
+    };
+    img.trace.source_key = Some(source_key);
+    img.trace.path = trace_path(parts, options);
+    img.trace.time("load", loaded - started);
+    img.trace.time("render", Date::now().as_millis() - loaded);
+    Ok(img)
+}
+
+/// What the request asks of the source, as told by the debug headers.
+fn trace_path(parts: &ReqPathParts, options: OutputOptions) -> String {
+    let size = match parts.downscale {
+        Some(Downscale::Fraction(f)) => format!("{}x", f),
+        Some(Downscale::Width(w)) => format!("w{}", w),
+        Some(Downscale::Height(h)) => format!("h{}", h),
+        None if parts.thumb => "thumb".to_string(),
+        None => match parts.fit {
+            Some(w) => format!("fit{}", w),
+            None => format!("{}x", parts.scale),
+        },
+    };
+    // annotations are drawn last
+    let size = match options.annotations {
+        Some(digest) => format!("{}/annotations-{:016x}", size, digest),
+        None => size,
+    };
+    let size = match options.simulate {
+        Some(vision) => format!("simulate-{}/{}", vision, size),
+        None => size,
+    };
+    let size = match options.max_colors {
+        Some(n) => format!("colors{}/{}", n, size),
+        None => size,
+    };
+    let size = match options.orientation.flip {
+        Some(Flip::Horizontal) => format!("flip-h/{}", size),
+        Some(Flip::Vertical) => format!("flip-v/{}", size),
+        None => size,
+    };
+    let size = match options.orientation.rotation {
+        Some(Rotation::R90) => format!("rot90/{}", size),
+        Some(Rotation::R180) => format!("rot180/{}", size),
+        Some(Rotation::R270) => format!("rot270/{}", size),
+        None => size,
+    };
+    let size = match options.crop {
+        Some(c) => format!("crop/{},{},{},{}/{}", c.x, c.y, c.width, c.height, size),
+        None => size,
+    };
+    match parts.frame {
+        Some(n) => format!("frame/{}/{}", n, size),
+        None => size,
+    }
+}
+
+/// Everything the output of the request depends on besides the source, for entity tags.
+fn describe_variant(
+    parts: &ReqPathParts,
+    options: OutputOptions,
+    epoch: u64,
+    image_epoch: u64,
+) -> String {
+    // tags stay as they were before images had epochs of their own, until they are bumped
+    let epoch = match image_epoch {
+        0 => epoch.to_string(),
+        n => format!("{}.{}", epoch, n),
+    };
+    format!(
+        "{}.{}?speed={:?}&playback={:?}&webp={}&epoch={}",
+        trace_path(parts, options),
+        parts.ext,
+        options.speed,
+        options.playback,
+        options.accepts_webp,
+        epoch
+    )
+}
+
+/// Generate the image requested from the source image data, with the annotations outlined if
+/// requested.
+fn render_image(
+    parts: &ReqPathParts,
+    src_img_data: &[u8],
+    limits: &Limits,
+    options: OutputOptions,
+    annotations: &[Annotation],
+) -> ApiResult<GeneratedImage> {
+    // stored animations are upscaled frame by frame
+    let src_anim = decode_animation(src_img_data, image::ImageFormat::Png).map_err(|e| {
+        console_error!("Failed to decode animation from memory: {:?}", e);
+        ApiError::no_msg(500)
+    })?;
+    let src_img = match (src_anim, parts.frame) {
+        (Some(src_anim), None) if !parts.thumb => {
+            return generate_upscaled_animation(parts, src_anim, limits, options, annotations);
+        }
+        // a single frame of an animation, which is the first one for thumbnails
+        (Some(mut src_anim), frame) => {
+            let n = frame.unwrap_or(0);
+            if n >= src_anim.frames.len() {
+                console_log!("Frame out of range: {} (frame {})", parts.hash, n);
+                return Err(ApiError::no_msg(404));
+            }
+            DynamicImage::ImageRgba8(src_anim.frames.swap_remove(n).image)
+        }
+        // still images have only one frame
+        (None, Some(n)) if n > 0 => {
+            console_log!("Frame out of range: {} (frame {})", parts.hash, n);
+            return Err(ApiError::no_msg(404));
+        }
+        (None, _) => image::load_from_memory_with_format(src_img_data, image::ImageFormat::Png)
+            .map_err(|e| {
+                console_error!("Failed to decode image from memory: {:?}", e);
+                ApiError::no_msg(500)
+            })?,
+    };
+    let src_img = match check_crop(options.crop, Dimensions::of(&src_img))? {
+        Some(crop) => crop.apply(&src_img),
+        None => src_img,
+    };
+    let src_img = options.orientation.apply(src_img);
+    let src_img = match options.max_colors {
+        Some(n) => DynamicImage::ImageRgba8(quantize(&src_img.to_rgba8(), n)),
+        None => src_img,
+    };
+    let src_img = match options.simulate {
+        Some(vision) => vision.apply(src_img),
+        None => src_img,
+    };
+    let dims = Dimensions::of(&src_img);
+    let scale = resolve_scale(parts, dims, limits, |_| true)?;
+    let upscaled_img = if parts.thumb {
+        thumbnail_image(src_img, THUMB_MAX_SIDE)
+    } else if let Some(downscale) = parts.downscale {
+        let dims = downscale_dimensions(downscale, Dimensions::of(&src_img))?;
+        downscale_image(&src_img, dims)
+    } else {
+        // any integer scale can be requested, but limit it to avoid generating oversized images
+        if !limits.allows_scaled_dimensions(Dimensions::of(&src_img), scale) {
+            return Err(ApiError::new(400, "Scale too big"));
+        }
+        if scale == 1 {
+            src_img
+        } else {
+            upscale_image(&src_img, scale)
+        }
+    };
+    let upscaled_img = match options.annotations {
+        Some(_) => {
+            let mut img = upscaled_img.to_rgba8();
+            draw_annotations(&mut img, annotations, scale);
+            DynamicImage::ImageRgba8(img)
+        }
+        None => upscaled_img,
+    };
+
+    let mut upscaled_img_data = Vec::new();
+    let (res, content_type) = match parts.ext.as_str() {
+        "avif" => (
+            encode_avif(&upscaled_img, &mut upscaled_img_data),
+            "image/avif",
+        ),
+        // the encoder of the `image` crate only does lossless WebP, which is what pixel art needs
+        "webp" => (
+            encode_image(
+                &upscaled_img,
+                image::ImageFormat::WebP,
+                &mut upscaled_img_data,
+            ),
+            "image/webp",
+        ),
+        "gif" => (
+            encode_image(
+                &upscaled_img,
+                image::ImageFormat::Gif,
+                &mut upscaled_img_data,
+            ),
+            "image/gif",
+        ),
+        "bmp" => (
+            encode_image(
+                &upscaled_img,
+                image::ImageFormat::Bmp,
+                &mut upscaled_img_data,
+            ),
+            "image/bmp",
+        ),
+        // responses are not stored, so they don't have to be deterministic
+        _ => (
+            encode_png(&upscaled_img, &mut upscaled_img_data, false),
+            "image/png",
+        ),
+    };
+    res.map_err(|e| {
+        console_error!("Failed to encode image: {:?}", e);
+        ApiError::no_msg(500)
+    })?;
+    Ok(GeneratedImage {
+        data: upscaled_img_data,
+        content_type,
+        negotiated: false,
+        fit_scale: parts.fit.map(|_| scale),
+        trace: Trace {
+            mode: "still",
+            ..Trace::default()
+        },
+    })
+}
+
+/// Scale to upscale the source of the dimensions by: the one in the path, or for `fit`, the
+/// largest one at which the source fits in the width, allowed by the limits and `allows`. Sources
+/// wider than the width are rejected, as pixel art is never downscaled to fit.
+fn resolve_scale(
+    parts: &ReqPathParts,
+    dims: Dimensions,
+    limits: &Limits,
+    allows: impl Fn(u32) -> bool,
+) -> ApiResult<u32> {
+    match parts.fit {
+        Some(max_width) if dims.fit_scale(max_width) == 0 => Err(ApiError::new(
+            400,
+            "Image is wider than the width to fit in",
+        )),
+        Some(max_width) => Ok((2..=dims.fit_scale(max_width))
+            .rev()
+            .find(|&s| limits.allows_scaled_dimensions(dims, s) && allows(s))
+            .unwrap_or(1)),
+        None => Ok(parts.scale),
+    }
+}
+
+fn downscale_dimensions(downscale: Downscale, src: Dimensions) -> ApiResult<Dimensions> {
+    downscale
+        .target_dimensions(src)
+        .ok_or_else(|| ApiError::new(400, "Target size must be smaller than the image"))
+}
 
-    // get source image data from the bucket
-    let src_img_data = bucket
-        .get(format!("{}.png", parts.hash))
-        .execute()
+/// Stored image that a response is generated from.
+#[derive(Clone)]
+struct SourceImage {
+    tenant: Tenant,
+    namespace: Namespace,
+    file_name: String,
+    /// Annotations of the image to outline, loaded only if requested.
+    annotations: Vec<Annotation>,
+}
+
+/// Get source image data from the bucket, in the newest key layout it is stored in, along with its
+/// key.
+async fn load_source_image(
+    bucket: &Bucket,
+    src: &SourceImage,
+    hash: &str,
+) -> ApiResult<(String, Vec<u8>)> {
+    find_versioned(bucket, &src.tenant, &src.namespace, &src.file_name)
         .await
         .map_err(|e| {
-            console_error!("Failed to fetch image from the bucket: {:?}", e);
+            console_error!("Failed to fetch image from the bucket: {}", e);
             ApiError::no_msg(500)
         })?
         .ok_or_else(|| {
-            console_log!("Image not found: {}", parts.hash);
+            console_log!("Image not found: {}", hash);
             ApiError::no_msg(404)
-        })?
-        .body()
-        .ok_or_else(|| {
-            console_error!("Object doesn't have body");
-            ApiError::no_msg(500)
-        })?
-        .bytes()
-        .await
-        .map_err(|e| {
-            console_error!("Failed to read object body: {:?}", e);
-            ApiError::no_msg(500)
-        })?;
-
-    // upscale the image
-    let src_img = image::load_from_memory_with_format(&src_img_data, image::ImageFormat::Png)
-        .map_err(|e| {
-            console_error!("Failed to decode image from memory: {:?}", e);
-            ApiError::no_msg(500)
-        })?;
+        })
+}
 
-    // limit scale factor to avoid generating oversized images
-    let long_side = u32::max(src_img.width(), src_img.height());
-    if long_side * parts.scale > 1024 {
-        return Err(ApiError::new(400, "Scale too big"));
+/// Load the upscaled variant as stored at upload along with its key, if the request asks for it
+/// as is: from the bucket, or the cold bucket if it is kept there. Variants that are not stored
+/// (or fail to load) are generated instead, so this never fails.
+///
+/// Indexed images (see `upix_lib::upload_index`) are looked for only where the index tells, and
+/// the others in both buckets.
+async fn load_stored_variant(
+    parts: &ReqPathParts,
+    src: &SourceImage,
+    options: OutputOptions,
+    bucket: &Bucket,
+    cold_bucket: Option<SendWrapper<Bucket>>,
+    index: Option<SendWrapper<KvStore>>,
+) -> Option<(String, GeneratedImage)> {
+    let as_stored = parts.ext == "png"
+        && parts.scale > 1
+        && parts.fit.is_none()
+        && parts.frame.is_none()
+        && parts.downscale.is_none()
+        && options.speed.is_none()
+        && options.crop.is_none()
+        && options.orientation.is_identity()
+        && options.max_colors.is_none()
+        && options.simulate.is_none()
+        && options.playback.is_preserve()
+        && options.annotations.is_none()
+        && !options.accepts_webp;
+    if !as_stored {
+        return None;
+    }
+    let file_name = Variant::Upscaled(parts.scale).file_name(&parts.hash);
+    let indexed = match index.as_deref() {
+        Some(kv) => {
+            let key_prefix = KeySchema::CURRENT.key_prefix(&src.tenant, &src.namespace);
+            load_index_entry(kv, &key_prefix, &parts.hash)
+                .await
+                .unwrap_or_else(|e| {
+                    console_error!("Failed to look up the upload index: {:?}", e);
+                    None
+                })
+        }
+        None => None,
+    };
+    let buckets: Vec<_> = match indexed.as_ref().map(|entry| entry.scaled(parts.scale)) {
+        Some(None) => return None,
+        Some(Some(variant)) if variant.cold => cold_bucket.as_deref().into_iter().collect(),
+        Some(Some(_)) => vec![bucket],
+        None => std::iter::once(bucket)
+            .chain(cold_bucket.as_deref())
+            .collect(),
+    };
+    for bucket in buckets {
+        match find_versioned(bucket, &src.tenant, &src.namespace, &file_name).await {
+            Ok(Some((key, data))) => {
+                let animated = PngDecoder::new(Cursor::new(&data))
+                    .and_then(|d| d.is_apng())
+                    .unwrap_or(false);
+                let img = GeneratedImage {
+                    data,
+                    content_type: if animated { "image/apng" } else { "image/png" },
+                    // clients accepting WebP get animations in it, which aren't stored
+                    negotiated: animated,
+                    fit_scale: None,
+                    trace: Trace {
+                        mode: "stored",
+                        ..Trace::default()
+                    },
+                };
+                return Some((key, img));
+            }
+            Ok(None) => {}
+            Err(e) => {
+                console_error!("Failed to fetch stored variant from the bucket: {}", e);
+                return None;
+            }
+        }
     }
+    None
+}
 
-    let upscaled_img = if parts.scale == 1 {
-        src_img
-    } else {
-        upscale_image(&src_img, parts.scale)
+#[derive(Serialize)]
+struct Palette<'a> {
+    hash: &'a str,
+    /// distinct colors of all frames, the most used first
+    colors: Vec<PaletteEntry>,
+}
+
+/// Serve the palette of the stored image as JSON.
+async fn get_palette(
+    parts: &PalettePathParts,
+    env: &Env,
+    tenant: &Tenant,
+    cache_policy: &CachePolicy,
+) -> ApiResult<Response> {
+    let Ok(bucket) = env.bucket("IMGS_BUCKET") else {
+        console_error!("Failed to get bindings to the R2 bucket");
+        return Err(ApiError::no_msg(500));
     };
+    let Some(namespace) = find_namespace(env, parts.namespace.as_deref()) else {
+        console_log!("Unknown namespace: {:?}", parts.namespace);
+        return Err(ApiError::no_msg(404));
+    };
+    let src = SourceImage {
+        tenant: tenant.clone(),
+        namespace,
+        file_name: format!("{}.png", parts.hash),
+        annotations: Vec::new(),
+    };
+    let (_, src_img_data) = load_source_image(&bucket, &src, &parts.hash).await?;
 
-    let mut upscaled_img_data = Vec::new();
-    encode_image(
-        &upscaled_img,
-        image::ImageFormat::Png,
-        &mut upscaled_img_data,
-    )
+    let decode_error = |e| {
+        console_error!("Failed to decode image from memory: {:?}", e);
+        ApiError::no_msg(500)
+    };
+    let colors = match decode_animation(&src_img_data, image::ImageFormat::Png)
+        .map_err(decode_error)?
+    {
+        Some(anim) => extract_palette(anim.frames.iter().map(|f| &f.image)),
+        None => {
+            let img = image::load_from_memory_with_format(&src_img_data, image::ImageFormat::Png)
+                .map_err(decode_error)?;
+            extract_palette([&img.to_rgba8()])
+        }
+    };
+
+    let mut resp = Response::from_json(&Palette {
+        hash: &parts.hash,
+        colors,
+    })
     .map_err(|e| {
+        console_error!("Failed to serialize palette: {:?}", e);
+        ApiError::no_msg(500)
+    })?;
+    cache_policy.apply(resp.headers_mut()).map_err(|e| {
+        console_error!("Failed to set cache headers: {:?}", e);
+        ApiError::no_msg(500)
+    })?;
+    Ok(resp)
+}
+
+/// Serve the composite of the two stored images in the root namespace as PNG (see
+/// `upix_lib::compare`).
+async fn get_compare(
+    req: &Request,
+    parts: &ComparePathParts,
+    env: &Env,
+    ctx: &Context,
+    tenant: &Tenant,
+    cache_policy: &CachePolicy,
+) -> ApiResult<Response> {
+    let Ok(url) = req.url() else {
+        return Err(ApiError::no_msg(500));
+    };
+    let mode: CompareMode = parse_query_param(&url, "mode")?.unwrap_or_default();
+    let scale = match url.query_pairs().find(|(k, _)| k == "scale") {
+        Some((_, v)) => match v.parse::<u32>() {
+            Ok(scale) if scale > 0 => scale,
+            _ => return Err(ApiError::new(400, "'scale' must be a positive integer")),
+        },
+        None => 1,
+    };
+
+    let (epoch, image_epochs) = load_epochs(env, &[&parts.hash_a, &parts.hash_b]).await;
+    let mut cache_key = request_cache_url(req)?;
+    apply_epoch(&mut cache_key, epoch);
+    apply_image_epochs(&mut cache_key, &image_epochs);
+    let cache_key = cache_key.to_string();
+    let cache = Cache::default();
+    let cached_resp = cache.get(&cache_key, false).await.map_err(|e| {
+        console_error!("Failed to match request against cache: {:?}", e);
+        ApiError::no_msg(500)
+    })?;
+    if let Some(resp) = cached_resp {
+        console_log!("Cache hit: {}", req.path());
+        return with_cache_status(resp, "hit");
+    }
+
+    let Ok(bucket) = env.bucket("IMGS_BUCKET") else {
+        console_error!("Failed to get bindings to the R2 bucket");
+        return Err(ApiError::no_msg(500));
+    };
+    let Some(namespace) = find_namespace(env, None) else {
+        console_error!("Failed to find the root namespace");
+        return Err(ApiError::no_msg(500));
+    };
+    let limits = namespace.limits.clone();
+    let mut imgs = Vec::with_capacity(2);
+    for hash in [&parts.hash_a, &parts.hash_b] {
+        let src = SourceImage {
+            tenant: tenant.clone(),
+            namespace: namespace.clone(),
+            file_name: format!("{}.png", hash),
+            annotations: Vec::new(),
+        };
+        let (_, src_img_data) = load_source_image(&bucket, &src, hash).await?;
+        // animations are decoded to their first frames
+        let img = image::load_from_memory_with_format(&src_img_data, image::ImageFormat::Png)
+            .map_err(|e| {
+                console_error!("Failed to decode image from memory: {:?}", e);
+                ApiError::no_msg(500)
+            })?;
+        imgs.push(img.to_rgba8());
+    }
+
+    let dims = mode.dimensions(Dimensions::of(&imgs[0]), Dimensions::of(&imgs[1]));
+    if !limits.allows_scaled_dimensions(dims, scale) {
+        return Err(ApiError::new(400, "Scale too big"));
+    }
+    let composite = DynamicImage::ImageRgba8(mode.compose(&imgs[0], &imgs[1]));
+    let composite = match scale {
+        1 => composite,
+        _ => upscale_image(&composite, scale),
+    };
+    let mut data = Vec::new();
+    encode_png(&composite, &mut data, false).map_err(|e| {
         console_error!("Failed to encode image: {:?}", e);
         ApiError::no_msg(500)
     })?;
-    Ok(upscaled_img_data)
+    let img = GeneratedImage {
+        data,
+        content_type: "image/png",
+        negotiated: false,
+        fit_scale: None,
+        trace: Trace {
+            mode: "compare",
+            ..Trace::default()
+        },
+    };
+    let mut resp = make_image_response(img, None, cache_policy);
+    let resp2 = resp.cloned().unwrap();
+    ctx.wait_until(async move {
+        put_cache(&cache, &cache_key, resp2).await;
+    });
+    with_cache_status(resp, "miss")
+}
+
+/// Encoder speed of AVIF (1-10). Workers have little CPU time, so the fastest one is used.
+const AVIF_SPEED: u8 = 10;
+/// Encoder quality of AVIF (1-100). Flat areas of pixel art compress well even at high quality,
+/// and lower ones blur edges of pixels.
+const AVIF_QUALITY: u8 = 90;
+
+fn encode_avif(img: &DynamicImage, dest: &mut Vec<u8>) -> image::ImageResult<()> {
+    let encoder = AvifEncoder::new_with_speed_quality(dest, AVIF_SPEED, AVIF_QUALITY);
+    img.write_with_encoder(encoder)
+}
+
+fn generate_upscaled_animation(
+    parts: &ReqPathParts,
+    src_anim: Animation,
+    limits: &Limits,
+    options: OutputOptions,
+    annotations: &[Annotation],
+) -> ApiResult<GeneratedImage> {
+    if STILL_ONLY_FORMATS.contains(&parts.ext.as_str()) {
+        console_log!(
+            "{} is only supported for still images: {}",
+            parts.ext,
+            parts.hash
+        );
+        return Err(ApiError::no_msg(404));
+    }
+    // frames quantized one by one would flicker between palettes
+    if options.max_colors.is_some() {
+        return Err(ApiError::new(400, "Animations can't be quantized"));
+    }
+    let src_anim = match check_crop(options.crop, Dimensions::from(src_anim.dimensions()))? {
+        Some(crop) => src_anim.crop(crop),
+        None => src_anim,
+    };
+    let src_anim = match options.orientation.is_identity() {
+        true => src_anim,
+        false => src_anim.orient(options.orientation),
+    };
+    let mut src_anim = match options.simulate {
+        Some(vision) => src_anim.simulate(vision),
+        None => src_anim,
+    };
+    src_anim.playback = options.playback.apply(src_anim.playback);
+    let dims = Dimensions::from(src_anim.dimensions());
+    let scale = resolve_scale(parts, dims, limits, |s| src_anim.allows_scale(s))?;
+    let downscaled_dims = match parts.downscale {
+        Some(downscale) => Some(downscale_dimensions(downscale, dims)?),
+        None => {
+            if !limits.allows_scaled_dimensions(dims, scale) || !src_anim.allows_scale(scale) {
+                return Err(ApiError::new(400, "Scale too big"));
+            }
+            None
+        }
+    };
+    // delays of GIF are quantized even at the original speed
+    let src_anim = match (options.speed, parts.ext.as_str()) {
+        (speed, "gif") => src_anim.retime(speed.unwrap_or(1.0), FrameTiming::GIF),
+        (Some(speed), _) => src_anim.retime(speed, FrameTiming::EXACT),
+        (None, _) => src_anim,
+    };
+    let mut upscaled_anim = match downscaled_dims {
+        Some(dims) => src_anim.downscale(dims),
+        None if scale == 1 => src_anim,
+        None => src_anim.upscale(scale),
+    };
+    if options.annotations.is_some() {
+        for frame in &mut upscaled_anim.frames {
+            draw_annotations(&mut frame.image, annotations, scale);
+        }
+    }
+
+    // animations are encoded to WebP if requested explicitly or accepted, APNG otherwise
+    let mut upscaled_anim_data = Vec::new();
+    let (res, content_type) = if parts.ext == "gif" {
+        (
+            encode_gif(&upscaled_anim, &mut upscaled_anim_data),
+            "image/gif",
+        )
+    } else if parts.ext == "webp" || options.accepts_webp {
+        (
+            encode_animated_webp(&upscaled_anim, &mut upscaled_anim_data),
+            "image/webp",
+        )
+    } else {
+        (
+            encode_apng(&upscaled_anim, &mut upscaled_anim_data),
+            "image/apng",
+        )
+    };
+    res.map_err(|e| {
+        console_error!("Failed to encode animation: {:?}", e);
+        ApiError::no_msg(500)
+    })?;
+    Ok(GeneratedImage {
+        data: upscaled_anim_data,
+        content_type,
+        negotiated: parts.ext == "png",
+        fit_scale: parts.fit.map(|_| scale),
+        trace: Trace {
+            mode: "animation",
+            ..Trace::default()
+        },
+    })
 }
 
 struct ReqPathParts {
+    namespace: Option<String>,
     hash: String,
+    /// Index of the frame to extract from an animation
+    frame: Option<usize>,
     scale: u32,
+    /// Target size smaller than the original, in place of `scale`
+    downscale: Option<Downscale>,
+    /// Whether a thumbnail is requested, in place of `scale`
+    thumb: bool,
+    /// Max width to fit the largest integer scale in, in place of `scale`
+    fit: Option<u32>,
     ext: String,
 }
 
+impl ReqPathParts {
+    /// Scale of the variant, if it is a (possibly pre-generated) scaled variant of the whole
+    /// image.
+    fn variant_scale(&self) -> Option<u32> {
+        (self.frame.is_none() && self.downscale.is_none() && !self.thumb && self.fit.is_none())
+            .then_some(self.scale)
+    }
+}
+
+struct PalettePathParts {
+    namespace: Option<String>,
+    hash: String,
+}
+
+fn match_palette_path(path: &str) -> Option<PalettePathParts> {
+    let re_path =
+        Regex::new(r"^/(?:(?P<ns>[a-z0-9-]{1,32})/)?(?P<hash>[0-9a-f]{64})/palette\.json$")
+            .unwrap();
+    let caps = re_path.captures(path)?;
+    Some(PalettePathParts {
+        namespace: caps.name("ns").map(|ns| ns.as_str().to_string()),
+        hash: caps.name("hash")?.as_str().to_string(),
+    })
+}
+
+struct ComparePathParts {
+    hash_a: String,
+    hash_b: String,
+}
+
+fn match_compare_path(path: &str) -> Option<ComparePathParts> {
+    let re_path = Regex::new(r"^/compare/(?P<a>[0-9a-f]{64})/(?P<b>[0-9a-f]{64})\.png$").unwrap();
+    let caps = re_path.captures(path)?;
+    Some(ComparePathParts {
+        hash_a: caps.name("a")?.as_str().to_string(),
+        hash_b: caps.name("b")?.as_str().to_string(),
+    })
+}
+
 fn match_req_path(path: &str) -> Option<ReqPathParts> {
     let re_path =
-        Regex::new(r"^/(?P<hash>[0-9a-f]{64})(?P<sx>_(?P<scale>[1-9][0-9]*)x)?\.(?P<ext>[a-z]+)$")
+        Regex::new(r"^/(?:(?P<ns>[a-z0-9-]{1,32})/)?(?P<hash>[0-9a-f]{64})(?:/frame/(?P<frame>0|[1-9][0-9]*))?(?:(?P<sx>_(?P<scale>[1-9][0-9]*)x)|_(?P<frac>0\.[0-9]{1,3})x|/(?P<side>[wh])(?P<len>[1-9][0-9]*)|(?P<thumb>_thumb)|/fit-(?P<fit>[1-9][0-9]*))?\.(?P<ext>[a-z]+)$")
             .unwrap();
     let caps = re_path.captures(path)?;
 
+    let namespace = caps.name("ns").map(|ns| ns.as_str().to_string());
     let hash = caps.name("hash")?.as_str().to_string();
+    let frame = match caps.name("frame") {
+        Some(n) => Some(n.as_str().parse().ok()?),
+        None => None,
+    };
     let scale = match caps.name("sx") {
         Some(_) => caps.name("scale")?.as_str().parse().ok()?,
         None => 1,
     };
+    let downscale = match (caps.name("frac"), caps.name("side")) {
+        (Some(f), _) => Some(Downscale::Fraction(f.as_str().parse().ok()?)),
+        (None, Some(side)) => {
+            let len = caps.name("len")?.as_str().parse().ok()?;
+            Some(match side.as_str() {
+                "w" => Downscale::Width(len),
+                _ => Downscale::Height(len),
+            })
+        }
+        (None, None) => None,
+    };
+    let fit = match caps.name("fit") {
+        Some(w) => Some(w.as_str().parse().ok()?),
+        None => None,
+    };
     let ext = caps.name("ext")?.as_str().to_string();
-    Some(ReqPathParts { hash, scale, ext })
+    Some(ReqPathParts {
+        namespace,
+        hash,
+        frame,
+        scale,
+        downscale,
+        thumb: caps.name("thumb").is_some(),
+        fit,
+        ext,
+    })
 }
 
 #[cfg(test)]
 mod test {
-    use super::match_req_path;
+    use upix_lib::{dimensions::Dimensions, downscale::Downscale, namespace::Limits};
+
+    use super::{match_compare_path, match_palette_path, match_req_path, resolve_scale};
 
     const HASH: &str = "1ea5e9febc7265432c41cf87b41f9ca1ea084bec600509add2c04048a8fec600";
 
@@ -173,10 +1398,37 @@ mod test {
     fn test_match_req_path() {
         let path = format!("/{}_2x.png", HASH);
         let parts = match_req_path(&path).unwrap();
+        assert_eq!(parts.namespace, None);
         assert_eq!(parts.hash, HASH);
+        assert_eq!(parts.frame, None);
         assert_eq!(parts.scale, 2);
         assert_eq!(parts.ext, "png");
 
+        let path = format!("/avatars/{}_4x.png", HASH);
+        let parts = match_req_path(&path).unwrap();
+        assert_eq!(parts.namespace.as_deref(), Some("avatars"));
+        assert_eq!(parts.hash, HASH);
+        assert_eq!(parts.scale, 4);
+
+        let path = format!("/{}/frame/3_4x.png", HASH);
+        let parts = match_req_path(&path).unwrap();
+        assert_eq!(parts.hash, HASH);
+        assert_eq!(parts.frame, Some(3));
+        assert_eq!(parts.scale, 4);
+
+        let path = format!("/avatars/{}/frame/0.png", HASH);
+        let parts = match_req_path(&path).unwrap();
+        assert_eq!(parts.namespace.as_deref(), Some("avatars"));
+        assert_eq!(parts.frame, Some(0));
+        assert_eq!(parts.scale, 1);
+
+        let path = format!("/{}/frame/01.png", HASH);
+        assert!(match_req_path(&path).is_none());
+
+        let path = format!("/a/b/{}_4x.png", HASH);
+        let parts = match_req_path(&path);
+        assert!(parts.is_none());
+
         let path = format!("/{}_100x.png", HASH);
         let parts = match_req_path(&path).unwrap();
         assert_eq!(parts.scale, 100);
@@ -197,5 +1449,104 @@ mod test {
         let path = format!("/{}_0x.png", HASH);
         let parts = match_req_path(&path);
         assert!(parts.is_none());
+
+        let path = format!("/{}_0.5x.png", HASH);
+        let parts = match_req_path(&path).unwrap();
+        assert_eq!(parts.scale, 1);
+        assert_eq!(parts.downscale, Some(Downscale::Fraction(0.5)));
+
+        let path = format!("/avatars/{}/w256.webp", HASH);
+        let parts = match_req_path(&path).unwrap();
+        assert_eq!(parts.namespace.as_deref(), Some("avatars"));
+        assert_eq!(parts.downscale, Some(Downscale::Width(256)));
+        assert_eq!(parts.ext, "webp");
+
+        let path = format!("/{}/frame/2/h32.png", HASH);
+        let parts = match_req_path(&path).unwrap();
+        assert_eq!(parts.frame, Some(2));
+        assert_eq!(parts.downscale, Some(Downscale::Height(32)));
+
+        let path = format!("/{}_thumb.png", HASH);
+        let parts = match_req_path(&path).unwrap();
+        assert!(parts.thumb);
+        assert_eq!(parts.scale, 1);
+        assert_eq!(parts.downscale, None);
+        let path = format!("/avatars/{}/frame/1_thumb.webp", HASH);
+        let parts = match_req_path(&path).unwrap();
+        assert!(parts.thumb);
+        assert_eq!(parts.frame, Some(1));
+        assert!(!match_req_path(&format!("/{}_2x.png", HASH)).unwrap().thumb);
+
+        let path = format!("/{}_thumb_2x.png", HASH);
+        assert!(match_req_path(&path).is_none());
+        let path = format!("/{}/w256_2x.png", HASH);
+        assert!(match_req_path(&path).is_none());
+        let path = format!("/{}_1.5x.png", HASH);
+        assert!(match_req_path(&path).is_none());
+        let path = format!("/{}/w0.png", HASH);
+        assert!(match_req_path(&path).is_none());
+        let path = format!("/{}/palette.json", HASH);
+        assert!(match_req_path(&path).is_none());
+
+        let path = format!("/{}/fit-256.png", HASH);
+        let parts = match_req_path(&path).unwrap();
+        assert_eq!(parts.fit, Some(256));
+        assert_eq!(parts.scale, 1);
+        assert_eq!(parts.variant_scale(), None);
+        let path = format!("/avatars/{}/frame/1/fit-64.webp", HASH);
+        let parts = match_req_path(&path).unwrap();
+        assert_eq!(parts.frame, Some(1));
+        assert_eq!(parts.fit, Some(64));
+        assert_eq!(
+            match_req_path(&format!("/{}_2x.png", HASH)).unwrap().fit,
+            None
+        );
+        let path = format!("/{}/fit-0.png", HASH);
+        assert!(match_req_path(&path).is_none());
+        let path = format!("/{}/fit-256_2x.png", HASH);
+        assert!(match_req_path(&path).is_none());
+    }
+
+    #[test]
+    fn test_resolve_scale() {
+        let dims = Dimensions::from((100, 10));
+        let limits = Limits::default();
+        let resolve = |path: &str, allows: fn(u32) -> bool| {
+            let parts = match_req_path(&format!("/{}{}", HASH, path)).unwrap();
+            resolve_scale(&parts, dims, &limits, allows).map_err(|e| e.status())
+        };
+        assert_eq!(resolve("_3x.png", |_| true), Ok(3));
+        assert_eq!(resolve("/fit-256.png", |_| true), Ok(2));
+        // capped by pixels of the upscaled image (3200x320 at 32x), not by `max_scale`
+        assert_eq!(resolve("/fit-4096.png", |_| true), Ok(32));
+        assert_eq!(resolve("/fit-256.png", |s| s != 2), Ok(1));
+        assert_eq!(resolve("/fit-100.png", |_| true), Ok(1));
+        // even 1x doesn't fit
+        assert_eq!(resolve("/fit-99.png", |_| true), Err(400));
+    }
+
+    #[test]
+    fn test_match_palette_path() {
+        let parts = match_palette_path(&format!("/{}/palette.json", HASH)).unwrap();
+        assert_eq!(parts.namespace, None);
+        assert_eq!(parts.hash, HASH);
+
+        let parts = match_palette_path(&format!("/avatars/{}/palette.json", HASH)).unwrap();
+        assert_eq!(parts.namespace.as_deref(), Some("avatars"));
+
+        assert!(match_palette_path(&format!("/{}_2x/palette.json", HASH)).is_none());
+        assert!(match_palette_path(&format!("/{}/palette.png", HASH)).is_none());
+    }
+
+    #[test]
+    fn test_match_compare_path() {
+        const HASH_B: &str = "0ea5e9febc7265432c41cf87b41f9ca1ea084bec600509add2c04048a8fec601";
+        let parts = match_compare_path(&format!("/compare/{}/{}.png", HASH, HASH_B)).unwrap();
+        assert_eq!(parts.hash_a, HASH);
+        assert_eq!(parts.hash_b, HASH_B);
+
+        assert!(match_compare_path(&format!("/compare/{}/{}.webp", HASH, HASH_B)).is_none());
+        assert!(match_compare_path(&format!("/compare/{}.png", HASH)).is_none());
+        assert!(match_req_path(&format!("/compare/{}/{}.png", HASH, HASH_B)).is_none());
     }
 }
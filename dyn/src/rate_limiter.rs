@@ -0,0 +1,67 @@
+use std::time::Duration;
+
+use upix_lib::rate_limit::{RateDecision, RateLimit, RateWindows};
+use worker::*;
+
+const WINDOWS_KEY: &str = "windows";
+
+/// Storage of the client is deleted after no uploads for this long.
+const IDLE_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Durable Object that counts uploads of a client, one object per client IP address.
+///
+/// Storage layout:
+/// - `windows`: uploads in the current minute and hour, deleted an hour after the last upload
+#[durable_object]
+pub struct UploadRateLimiter {
+    state: State,
+}
+
+#[durable_object]
+impl DurableObject for UploadRateLimiter {
+    fn new(state: State, _env: Env) -> Self {
+        Self { state }
+    }
+
+    async fn fetch(&mut self, mut req: Request) -> Result<Response> {
+        match (req.method(), req.path().as_str()) {
+            (Method::Post, "/hit") => {
+                let limit: RateLimit = req.json().await?;
+                Response::from_json(&self.hit(limit).await?)
+            }
+            _ => Response::error("Not Found", 404),
+        }
+    }
+
+    async fn alarm(&mut self) -> Result<Response> {
+        // the windows have expired, so there's nothing to keep for the client
+        self.state.storage().delete_all().await?;
+        Response::empty()
+    }
+}
+
+impl UploadRateLimiter {
+    async fn hit(&mut self, limit: RateLimit) -> Result<RateDecision> {
+        let mut storage = self.state.storage();
+        let mut windows = storage
+            .get::<RateWindows>(WINDOWS_KEY)
+            .await
+            .unwrap_or_default();
+        match windows.hit(Date::now().as_millis(), limit) {
+            Ok(()) => {
+                storage.put(WINDOWS_KEY, &windows).await?;
+                storage.set_alarm(IDLE_TTL).await?;
+                Ok(RateDecision {
+                    allowed: true,
+                    retry_after: None,
+                    exhausted: windows.hour_exhausted(limit),
+                })
+            }
+            Err(secs) => Ok(RateDecision {
+                allowed: false,
+                retry_after: Some(secs),
+                exhausted: false,
+            }),
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use std::time::Duration;
+
+use upix_lib::upload_lock::{LockDecision, LockRequest, LockState, LEASE_MS};
+use worker::*;
+
+const LEASE_KEY: &str = "lease";
+
+/// Durable Object that leases the processing of uploads of the same data, one object per key
+/// prefix and hash (see lib/src/upload_lock.rs).
+///
+/// Storage layout:
+/// - `lease`: the owner of the lease and its expiry, deleted once released or expired
+#[durable_object]
+pub struct UploadLock {
+    state: State,
+}
+
+#[durable_object]
+impl DurableObject for UploadLock {
+    fn new(state: State, _env: Env) -> Self {
+        Self { state }
+    }
+
+    async fn fetch(&mut self, mut req: Request) -> Result<Response> {
+        let path = req.path();
+        if req.method() != Method::Post {
+            return Response::error("Not Found", 404);
+        }
+        let LockRequest { owner } = req.json().await?;
+        let now = Date::now().as_millis();
+        let mut storage = self.state.storage();
+        let mut lock = storage
+            .get::<LockState>(LEASE_KEY)
+            .await
+            .unwrap_or_default();
+        let acquired = match path.as_str() {
+            "/acquire" => {
+                let acquired = lock.acquire(&owner, now);
+                if acquired {
+                    storage.put(LEASE_KEY, &lock).await?;
+                    // leases of uploads dying while holding them are cleaned up once expired
+                    storage.set_alarm(Duration::from_millis(LEASE_MS)).await?;
+                }
+                acquired
+            }
+            "/release" => {
+                lock.release(&owner);
+                if !lock.is_held(now) {
+                    storage.delete_all().await?;
+                }
+                false
+            }
+            _ => return Response::error("Not Found", 404),
+        };
+        Response::from_json(&LockDecision { acquired })
+    }
+
+    async fn alarm(&mut self) -> Result<Response> {
+        let mut storage = self.state.storage();
+        let lock = storage
+            .get::<LockState>(LEASE_KEY)
+            .await
+            .unwrap_or_default();
+        // the lease may have been extended since the alarm was set
+        if !lock.is_held(Date::now().as_millis()) {
+            storage.delete_all().await?;
+        }
+        Response::empty()
+    }
+}
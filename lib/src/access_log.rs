@@ -0,0 +1,249 @@
+//! Access logs of workers, exported to the bucket as NDJSON.
+//!
+//! Both workers record each request in a buffer of the isolate, which is flushed to a new object
+//! when enough records are pending or enough time has passed since the last flush. Objects are
+//! put under the `ACCESS_LOG_PREFIX` var in dated directories, like Logpush does:
+//! `{prefix}{worker}/{YYYYMMDD}/{HH}/{unix time in ms}-{random}.ndjson`, so that any tool
+//! reading Logpush output from R2 can read them. Logging is disabled without the var.
+//!
+//! Records pending in an evicted isolate are lost, so logs are for analyzing traffic rather than
+//! for accounting of every request.
+
+use std::cell::RefCell;
+
+use serde::Serialize;
+use worker::{
+    console_error, Bucket, Context, Date, Env, HttpMetadata, Method, Response, ResponseBody,
+};
+
+use crate::config::{Config, IMGS_BUCKET_BINDING};
+
+/// Header of responses telling the cache status, which access logs record.
+pub const CACHE_STATUS_HEADER: &str = "X-Upix-Cache";
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AccessRecord {
+    /// unix time in milliseconds
+    pub timestamp: u64,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    /// size of the response body in bytes, if known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<u64>,
+    /// `hit`, `miss`, `bypass` or `revalidated` (with 304), for responses through the cache
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache: Option<String>,
+    /// time taken to respond in milliseconds
+    pub duration_ms: u64,
+}
+
+impl AccessRecord {
+    /// Record of the response to the request started at `started_at` (unix time in milliseconds).
+    pub fn of_response(method: Method, path: String, started_at: u64, resp: &Response) -> Self {
+        let headers = resp.headers();
+        let bytes = match resp.body() {
+            ResponseBody::Body(data) => Some(data.len() as u64),
+            ResponseBody::Empty => Some(0),
+            ResponseBody::Stream(_) => headers
+                .get("Content-Length")
+                .ok()
+                .flatten()
+                .and_then(|l| l.parse().ok()),
+        };
+        let now = now_ms();
+        AccessRecord {
+            timestamp: started_at,
+            method: String::from(method),
+            path,
+            status: resp.status_code(),
+            bytes,
+            cache: headers.get(CACHE_STATUS_HEADER).ok().flatten(),
+            duration_ms: now.saturating_sub(started_at),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct AccessLogBuffer {
+    lines: Vec<String>,
+    last_flush_ms: u64,
+}
+
+/// Flush the buffer if this many records are pending...
+const FLUSH_THRESHOLD: usize = 100;
+/// ...or this much time has passed since the last flush.
+const FLUSH_INTERVAL_MS: u64 = 60 * 1000;
+
+impl AccessLogBuffer {
+    pub fn record(&mut self, record: &AccessRecord) {
+        match serde_json::to_string(record) {
+            Ok(line) => self.lines.push(line),
+            Err(e) => console_error!("failed to serialize access record: {}", e),
+        }
+    }
+
+    pub fn should_flush(&self, now_ms: u64) -> bool {
+        self.lines.len() >= FLUSH_THRESHOLD
+            || (!self.lines.is_empty()
+                && now_ms.saturating_sub(self.last_flush_ms) >= FLUSH_INTERVAL_MS)
+    }
+
+    /// Take all the buffered records out as NDJSON.
+    pub fn take_ndjson(&mut self, now_ms: u64) -> String {
+        self.last_flush_ms = now_ms;
+        let mut ndjson = std::mem::take(&mut self.lines).join("\n");
+        ndjson.push('\n');
+        ndjson
+    }
+}
+
+/// Key of the object of records flushed at the time.
+pub fn log_object_key(prefix: &str, worker: &str, now_ms: u64, suffix: &str) -> String {
+    let (year, month, day) = civil_date(now_ms / (24 * 60 * 60 * 1000));
+    let hour = now_ms / (60 * 60 * 1000) % 24;
+    format!(
+        "{}{}/{:04}{:02}{:02}/{:02}/{}-{}.ndjson",
+        prefix, worker, year, month, day, hour, now_ms, suffix
+    )
+}
+
+/// Date in the Gregorian calendar of the day since the unix epoch.
+fn civil_date(days: u64) -> (u64, u64, u64) {
+    // https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+thread_local! {
+    static ACCESS_LOG_BUFFER: RefCell<AccessLogBuffer> = RefCell::new(AccessLogBuffer::default());
+}
+
+/// Record the access, flushing buffered records to the bucket after responding if it is time to.
+/// `worker` names the directory of the worker's logs.
+pub fn log_access(env: &Env, ctx: &Context, worker: &'static str, record: AccessRecord) {
+    let Some(prefix) = Config::from_env(env)
+        .ok()
+        .and_then(|c| c.access_log_prefix.clone())
+    else {
+        return;
+    };
+    let now = record.timestamp + record.duration_ms;
+    let ndjson = ACCESS_LOG_BUFFER.with(|buf| {
+        let mut buf = buf.borrow_mut();
+        buf.record(&record);
+        buf.should_flush(now).then(|| buf.take_ndjson(now))
+    });
+    let Some(ndjson) = ndjson else {
+        return;
+    };
+
+    let Ok(bucket) = env.bucket(IMGS_BUCKET_BINDING) else {
+        console_error!("failed to get bindings to the R2 bucket");
+        return;
+    };
+    ctx.wait_until(async move {
+        if let Err(e) = put_log_object(&bucket, &prefix, worker, now, ndjson).await {
+            console_error!("failed to flush access logs: {}", e);
+        }
+    });
+}
+
+async fn put_log_object(
+    bucket: &Bucket,
+    prefix: &str,
+    worker: &str,
+    now_ms: u64,
+    ndjson: String,
+) -> Result<(), String> {
+    // isolates flushing at the same millisecond must not overwrite each other
+    let mut suffix = [0u8; 4];
+    getrandom::getrandom(&mut suffix).map_err(|e| e.to_string())?;
+    let key = log_object_key(prefix, worker, now_ms, &hex::encode(suffix));
+    let meta = HttpMetadata {
+        content_type: Some("application/x-ndjson".to_string()),
+        ..HttpMetadata::default()
+    };
+    bucket
+        .put(&key, ndjson)
+        .http_metadata(meta)
+        .execute()
+        .await
+        .map(|_| ())
+        .map_err(|e| format!("{:?}", e))
+}
+
+/// Unix time in milliseconds, for `AccessRecord::timestamp` and durations.
+pub fn now_ms() -> u64 {
+    Date::now().as_millis()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn record(path: &str) -> AccessRecord {
+        AccessRecord {
+            timestamp: 0,
+            method: "GET".to_string(),
+            path: path.to_string(),
+            status: 200,
+            bytes: Some(42),
+            cache: Some("hit".to_string()),
+            duration_ms: 3,
+        }
+    }
+
+    #[test]
+    fn test_access_log_buffer() {
+        let mut buf = AccessLogBuffer::default();
+        assert!(!buf.should_flush(FLUSH_INTERVAL_MS * 2));
+
+        buf.record(&record("/a.png"));
+        assert!(!buf.should_flush(FLUSH_INTERVAL_MS - 1));
+        assert!(buf.should_flush(FLUSH_INTERVAL_MS));
+        assert_eq!(
+            buf.take_ndjson(FLUSH_INTERVAL_MS),
+            "{\"timestamp\":0,\"method\":\"GET\",\"path\":\"/a.png\",\"status\":200,\"bytes\":42,\"cache\":\"hit\",\"duration_ms\":3}\n"
+        );
+        assert!(!buf.should_flush(FLUSH_INTERVAL_MS * 2));
+
+        for _ in 0..FLUSH_THRESHOLD {
+            buf.record(&AccessRecord {
+                bytes: None,
+                cache: None,
+                ..record("/b.png")
+            });
+        }
+        assert!(buf.should_flush(FLUSH_INTERVAL_MS + 1));
+        let ndjson = buf.take_ndjson(FLUSH_INTERVAL_MS + 1);
+        assert_eq!(ndjson.lines().count(), FLUSH_THRESHOLD);
+        assert!(!ndjson.contains("cache"));
+    }
+
+    #[test]
+    fn test_log_object_key() {
+        assert_eq!(
+            log_object_key("_logs/", "dyn", 0, "ab12"),
+            "_logs/dyn/19700101/00/0-ab12.ndjson"
+        );
+        // 2024-02-29T13:45:00Z
+        assert_eq!(
+            log_object_key("_logs/", "api", 1709214300000, "00"),
+            "_logs/api/20240229/13/1709214300000-00.ndjson"
+        );
+        // 2000-12-31T23:59:59.999Z
+        assert_eq!(
+            log_object_key("_l/", "api", 978307199999, "ff"),
+            "_l/api/20001231/23/978307199999-ff.ndjson"
+        );
+    }
+}
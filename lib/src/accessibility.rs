@@ -0,0 +1,185 @@
+//! Contrast reports of palettes (`GET /images/{hash}/accessibility`), for artists making sprites
+//! for accessible UIs. They complement the simulation of color vision deficiencies by the dyn
+//! worker (see `color_vision`), which tells hues apart but not lightness.
+//!
+//! Colors are paired by adjacency: each pair of neighboring pixels of different colors counts
+//! toward the pair of their colors, so that the reported pairs are the ones which have to be told
+//! apart in the image (like outlines against fills), rather than any two colors of the palette.
+//! Contrast ratios are those of WCAG 2.x, where 3:1 is the minimum for graphical objects and UI
+//! components (success criterion 1.4.11). Fully transparent pixels are the background, which
+//! depends on where the image is placed, so they are skipped. Alpha of other pixels is ignored.
+
+use std::collections::HashMap;
+
+use image::RgbaImage;
+use serde::Serialize;
+
+use crate::color_vision::to_linear;
+
+/// Pairs of colors reported, the most frequent first.
+pub const MAX_REPORTED_PAIRS: usize = 16;
+
+/// Min contrast ratio of graphical objects against adjacent colors by WCAG.
+pub const MIN_NON_TEXT_CONTRAST: f64 = 3.0;
+
+/// Palettes are flagged as low-contrast if edges between colors of insufficient contrast make up
+/// at least this share of all the edges.
+const LOW_CONTRAST_SHARE: f64 = 0.25;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ContrastPair {
+    /// Colors in `#rrggbb` form, the darker first.
+    pub colors: [String; 2],
+    /// Number of pairs of neighboring pixels in the colors.
+    pub edges: u64,
+    /// Contrast ratio, from 1 to 21, rounded to 2 decimal places.
+    pub ratio: f64,
+    /// Whether the ratio is at least `MIN_NON_TEXT_CONTRAST`.
+    pub sufficient: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AccessibilityReport {
+    /// Number of pairs of neighboring pixels of different colors.
+    pub edges: u64,
+    /// Up to `MAX_REPORTED_PAIRS` pairs of colors, by the number of edges between them.
+    pub pairs: Vec<ContrastPair>,
+    /// Share of the edges between colors of insufficient contrast, in 0 to 1.
+    pub low_contrast_share: f64,
+    /// Whether the share is high enough for the palette to be hard to tell apart.
+    pub low_contrast: bool,
+}
+
+impl AccessibilityReport {
+    pub fn of(img: &RgbaImage) -> Self {
+        let mut counts = HashMap::<([u8; 3], [u8; 3]), u64>::new();
+        let (w, h) = img.dimensions();
+        let rgb = |x, y| {
+            let px = img.get_pixel(x, y);
+            (px[3] > 0).then_some([px[0], px[1], px[2]])
+        };
+        for (x, y, _) in img.enumerate_pixels() {
+            let Some(a) = rgb(x, y) else {
+                continue;
+            };
+            let neighbors = [
+                (x + 1 < w).then(|| (x + 1, y)),
+                (y + 1 < h).then(|| (x, y + 1)),
+            ];
+            for (nx, ny) in neighbors.into_iter().flatten() {
+                match rgb(nx, ny) {
+                    Some(b) if a != b => *counts.entry((a.min(b), a.max(b))).or_default() += 1,
+                    _ => {}
+                }
+            }
+        }
+
+        let edges = counts.values().sum();
+        let low_contrast_edges: u64 = counts
+            .iter()
+            .filter(|((a, b), _)| contrast_ratio(*a, *b) < MIN_NON_TEXT_CONTRAST)
+            .map(|(_, n)| n)
+            .sum();
+        let low_contrast_share = match edges {
+            0 => 0.0,
+            n => low_contrast_edges as f64 / n as f64,
+        };
+
+        let mut pairs: Vec<_> = counts.into_iter().collect();
+        pairs.sort_by(|(c1, n1), (c2, n2)| n2.cmp(n1).then(c1.cmp(c2)));
+        let pairs = pairs
+            .into_iter()
+            .take(MAX_REPORTED_PAIRS)
+            .map(|((a, b), edges)| {
+                let ratio = contrast_ratio(a, b);
+                let (dark, light) = match relative_luminance(a) <= relative_luminance(b) {
+                    true => (a, b),
+                    false => (b, a),
+                };
+                ContrastPair {
+                    colors: [dark, light].map(|c| format!("#{}", hex::encode(c))),
+                    edges,
+                    ratio: (ratio * 100.0).round() / 100.0,
+                    sufficient: ratio >= MIN_NON_TEXT_CONTRAST,
+                }
+            })
+            .collect();
+        AccessibilityReport {
+            edges,
+            pairs,
+            low_contrast_share,
+            low_contrast: edges > 0 && low_contrast_share >= LOW_CONTRAST_SHARE,
+        }
+    }
+}
+
+/// Relative luminance of the sRGB color by WCAG, in 0 to 1.
+pub fn relative_luminance(rgb: [u8; 3]) -> f64 {
+    let [r, g, b] = rgb.map(|c| f64::from(to_linear(c)));
+    0.2126 * r + 0.7152 * g + 0.0722 * b
+}
+
+/// Contrast ratio of the colors by WCAG, in 1 to 21 regardless of their order.
+pub fn contrast_ratio(a: [u8; 3], b: [u8; 3]) -> f64 {
+    let (la, lb) = (relative_luminance(a), relative_luminance(b));
+    (la.max(lb) + 0.05) / (la.min(lb) + 0.05)
+}
+
+#[cfg(test)]
+mod test {
+    use image::Rgba;
+
+    use super::*;
+
+    #[test]
+    fn test_contrast_ratio() {
+        let (black, white) = ([0, 0, 0], [255, 255, 255]);
+        assert_eq!(contrast_ratio(black, white), 21.0);
+        assert_eq!(contrast_ratio(white, black), 21.0);
+        assert_eq!(contrast_ratio(white, white), 1.0);
+        // #767676 is the lightest gray with 4.5:1 against white
+        assert!(contrast_ratio([0x76; 3], white) >= 4.5);
+        assert!(contrast_ratio([0x77; 3], white) < 4.5);
+    }
+
+    #[test]
+    fn test_accessibility_report() {
+        // a white fill with a black outline on a transparent background
+        let mut img = RgbaImage::from_pixel(6, 6, Rgba([255, 0, 0, 0]));
+        for (x, y, px) in img.enumerate_pixels_mut() {
+            if (1..=4).contains(&x) && (1..=4).contains(&y) {
+                *px = Rgba([0, 0, 0, 255]);
+            }
+        }
+        let fill = [(2, 2), (3, 2), (2, 3), (3, 3)];
+        for (x, y) in fill {
+            img.put_pixel(x, y, Rgba([255, 255, 255, 255]));
+        }
+        let report = AccessibilityReport::of(&img);
+        // edges against the background don't count
+        assert_eq!(report.edges, 8);
+        assert_eq!(report.pairs.len(), 1);
+        assert_eq!(report.pairs[0].colors, ["#000000", "#ffffff"]);
+        assert_eq!(report.pairs[0].ratio, 21.0);
+        assert!(!report.low_contrast);
+
+        // a gray fill barely different from the outline
+        for (x, y) in fill {
+            img.put_pixel(x, y, Rgba([0x30, 0x30, 0x30, 255]));
+        }
+        let report = AccessibilityReport::of(&img);
+        assert!(!report.pairs[0].sufficient);
+        assert_eq!(report.low_contrast_share, 1.0);
+        assert!(report.low_contrast);
+
+        assert_eq!(
+            AccessibilityReport::of(&RgbaImage::new(4, 4)),
+            AccessibilityReport {
+                edges: 0,
+                pairs: Vec::new(),
+                low_contrast_share: 0.0,
+                low_contrast: false,
+            }
+        );
+    }
+}
@@ -0,0 +1,139 @@
+//! Usage metrics written to Workers Analytics Engine.
+//!
+//! The api worker writes a data point per upload to the `UPLOAD_ANALYTICS` dataset, and the dyn
+//! worker one per served variant to `SERVE_ANALYTICS`. Fields are positional in Analytics Engine,
+//! so their order is fixed as below for queries of the SQL API:
+//!
+//! - uploads: `blob1` = format (extension, or `frame` for delta frames), `blob2` = `new` or
+//!   `dedup`, `double1` = pixels, `double2` = processing time in ms, `index1` = tenant ID
+//! - serves: `blob1` = cache status (`hit`, `miss`, `revalidated` or `bypass`), `double1` =
+//!   scale, `double2` = bytes of the body, `index1` = image hash
+//!
+//! Metrics are not written without the bindings, and failures to write them are only logged.
+
+use image::ImageFormat;
+use serde::Serialize;
+use worker::{
+    console_error,
+    js_sys::{Function, Reflect, JSON},
+    wasm_bindgen::{JsCast, JsValue},
+    Env, Result as WorkerResult,
+};
+
+pub const UPLOAD_ANALYTICS_BINDING: &str = "UPLOAD_ANALYTICS";
+pub const SERVE_ANALYTICS_BINDING: &str = "SERVE_ANALYTICS";
+
+/// A data point as taken by `writeDataPoint()` of datasets.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct DataPoint {
+    pub blobs: Vec<String>,
+    pub doubles: Vec<f64>,
+    pub indexes: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct UploadMetrics<'a> {
+    pub tenant: &'a str,
+    /// format of the uploaded data, or `None` for delta frames
+    pub format: Option<ImageFormat>,
+    pub pixels: u64,
+    pub processing_ms: u64,
+    pub deduplicated: bool,
+}
+
+impl UploadMetrics<'_> {
+    pub fn data_point(&self) -> DataPoint {
+        let format = self.format.map_or("frame", |f| {
+            f.extensions_str().first().copied().unwrap_or("unknown")
+        });
+        let kind = if self.deduplicated { "dedup" } else { "new" };
+        DataPoint {
+            blobs: vec![format.to_string(), kind.to_string()],
+            doubles: vec![self.pixels as f64, self.processing_ms as f64],
+            indexes: vec![self.tenant.to_string()],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ServeMetrics<'a> {
+    pub hash: &'a str,
+    pub scale: u32,
+    /// value of the cache status header of the response
+    pub cache: &'a str,
+    pub bytes: u64,
+}
+
+impl ServeMetrics<'_> {
+    pub fn data_point(&self) -> DataPoint {
+        DataPoint {
+            blobs: vec![self.cache.to_string()],
+            doubles: vec![f64::from(self.scale), self.bytes as f64],
+            indexes: vec![self.hash.to_string()],
+        }
+    }
+}
+
+/// Write the data point to the dataset of the binding, if it is bound.
+pub fn write_data_point(env: &Env, binding: &str, point: &DataPoint) {
+    if let Err(e) = try_write_data_point(env, binding, point) {
+        console_error!("failed to write data point to {}: {:?}", binding, e);
+    }
+}
+
+fn try_write_data_point(env: &Env, binding: &str, point: &DataPoint) -> WorkerResult<()> {
+    // workers-rs has no wrapper of datasets
+    let dataset = Reflect::get(env, &JsValue::from(binding))?;
+    if dataset.is_undefined() {
+        return Ok(());
+    }
+    let write: Function = Reflect::get(&dataset, &JsValue::from("writeDataPoint"))?.dyn_into()?;
+    let point = JSON::parse(&serde_json::to_string(point)?)?;
+    write.call1(&dataset, &point)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_data_points() {
+        let upload = UploadMetrics {
+            tenant: "t1",
+            format: Some(ImageFormat::Gif),
+            pixels: 256,
+            processing_ms: 12,
+            deduplicated: false,
+        };
+        assert_eq!(
+            upload.data_point(),
+            DataPoint {
+                blobs: vec!["gif".to_string(), "new".to_string()],
+                doubles: vec![256.0, 12.0],
+                indexes: vec!["t1".to_string()],
+            }
+        );
+        let frame = UploadMetrics {
+            format: None,
+            deduplicated: true,
+            ..upload
+        };
+        assert_eq!(frame.data_point().blobs, ["frame", "dedup"]);
+
+        let serve = ServeMetrics {
+            hash: "abc",
+            scale: 4,
+            cache: "hit",
+            bytes: 1024,
+        };
+        assert_eq!(
+            serve.data_point(),
+            DataPoint {
+                blobs: vec!["hit".to_string()],
+                doubles: vec![4.0, 1024.0],
+                indexes: vec!["abc".to_string()],
+            }
+        );
+    }
+}
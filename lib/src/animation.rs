@@ -0,0 +1,702 @@
+//! Animated images.
+//!
+//! Animations are kept as a list of fully composited frames of the same size, each with its
+//! delay. They are stored in the bucket as APNG, whose first frame doubles as the static image
+//! for clients (and code paths) unaware of animation. Animated GIFs are converted to APNG at
+//! upload, with their loop counts and comments (see `playback`), and APNG uploads are stored as
+//! re-encoded from their composited frames.
+
+use std::io::Cursor;
+
+use image::{
+    codecs::{
+        gif::{GifDecoder, GifEncoder, Repeat},
+        png::PngDecoder,
+        webp::WebPEncoder,
+    },
+    imageops, AnimationDecoder, Delay, DynamicImage, ExtendedColorType, Frame, ImageError,
+    ImageFormat, ImageResult, RgbaImage,
+};
+
+use crate::{
+    color_key::ColorKey,
+    color_vision::ColorVision,
+    crop::Crop,
+    dimensions::Dimensions,
+    downscale::downscale_rgba,
+    orientation::Orientation,
+    playback::{gif_comment_extension, read_gif_playback, Playback, PNG_COMMENT_KEYWORD},
+    set_deterministic_png_options,
+};
+
+/// Max number of frames of an animation.
+pub const MAX_FRAMES: usize = 256;
+
+/// Max number of pixels of all frames of an animation together, so that generated animations
+/// fit in the memory of workers (64 MiB as RGBA).
+pub const MAX_TOTAL_PIXELS: u64 = 1 << 24;
+
+/// Whether an animation of the dimensions and the number of frames can be upscaled by the scale
+/// factor within `MAX_TOTAL_PIXELS`.
+pub fn frames_allow_scale(dims: Dimensions, frames: usize, scale: u32) -> bool {
+    dims.pixels()
+        .saturating_mul(u64::from(scale).pow(2))
+        .saturating_mul(frames as u64)
+        <= MAX_TOTAL_PIXELS
+}
+
+/// Range of speed factors for retiming animations.
+pub const SPEED_RANGE: std::ops::RangeInclusive<f64> = 0.1..=10.0;
+
+/// Resolution of frame delays of an output format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameTiming {
+    /// Delays are multiples of this.
+    pub quantum_ms: u32,
+    /// Shortest delay played as is.
+    pub min_delay_ms: u32,
+}
+
+impl FrameTiming {
+    /// APNG and WebP can express delays in milliseconds.
+    pub const EXACT: FrameTiming = FrameTiming {
+        quantum_ms: 1,
+        min_delay_ms: 1,
+    };
+    /// GIF delays are in centiseconds, and browsers slow delays shorter than 20ms down to 100ms.
+    pub const GIF: FrameTiming = FrameTiming {
+        quantum_ms: 10,
+        min_delay_ms: 20,
+    };
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnimFrame {
+    pub image: RgbaImage,
+    pub delay_ms: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Animation {
+    /// Frames of the animation. Never empty, and all frames have the same dimensions.
+    pub frames: Vec<AnimFrame>,
+    pub playback: Playback,
+}
+
+impl Animation {
+    pub fn dimensions(&self) -> (u32, u32) {
+        self.frames[0].image.dimensions()
+    }
+
+    /// Number of pixels of all frames together, once upscaled by the scale factor.
+    pub fn total_pixels(&self, scale: u32) -> u64 {
+        let dims = Dimensions::from(self.dimensions());
+        dims.pixels()
+            .saturating_mul(u64::from(scale).pow(2))
+            .saturating_mul(self.frames.len() as u64)
+    }
+
+    /// Whether the animation can be upscaled by the scale factor within `MAX_TOTAL_PIXELS`.
+    pub fn allows_scale(&self, scale: u32) -> bool {
+        frames_allow_scale(self.dimensions().into(), self.frames.len(), scale)
+    }
+
+    pub fn first_frame(&self) -> DynamicImage {
+        DynamicImage::ImageRgba8(self.frames[0].image.clone())
+    }
+
+    /// Upscale all frames by the scale factor with nearest-neighbor.
+    pub fn upscale(&self, scale: u32) -> Animation {
+        let dims = Dimensions::from(self.dimensions()).saturating_scale(scale);
+        let frames = self
+            .frames
+            .iter()
+            .map(|f| AnimFrame {
+                image: imageops::resize(
+                    &f.image,
+                    dims.width,
+                    dims.height,
+                    imageops::FilterType::Nearest,
+                ),
+                delay_ms: f.delay_ms,
+            })
+            .collect();
+        Animation {
+            frames,
+            playback: self.playback.clone(),
+        }
+    }
+
+    /// Downscale all frames to the dimensions, like `downscale_image`.
+    pub fn downscale(&self, dims: Dimensions) -> Animation {
+        let frames = self
+            .frames
+            .iter()
+            .map(|f| AnimFrame {
+                image: downscale_rgba(&f.image, dims),
+                delay_ms: f.delay_ms,
+            })
+            .collect();
+        Animation {
+            frames,
+            playback: self.playback.clone(),
+        }
+    }
+
+    /// Crop all frames to the region, which must lie within the frames.
+    pub fn crop(&self, crop: Crop) -> Animation {
+        let frames = self
+            .frames
+            .iter()
+            .map(|f| AnimFrame {
+                image: crop.apply_rgba(&f.image),
+                delay_ms: f.delay_ms,
+            })
+            .collect();
+        Animation {
+            frames,
+            playback: self.playback.clone(),
+        }
+    }
+
+    /// Rotate and flip all frames.
+    pub fn orient(&self, orientation: Orientation) -> Animation {
+        let frames = self
+            .frames
+            .iter()
+            .map(|f| AnimFrame {
+                image: orientation.apply_rgba(&f.image),
+                delay_ms: f.delay_ms,
+            })
+            .collect();
+        Animation {
+            frames,
+            playback: self.playback.clone(),
+        }
+    }
+
+    /// Simulate the color vision deficiency on all frames.
+    pub fn simulate(&self, vision: ColorVision) -> Animation {
+        let frames = self
+            .frames
+            .iter()
+            .map(|f| AnimFrame {
+                image: vision.apply_rgba(&f.image),
+                delay_ms: f.delay_ms,
+            })
+            .collect();
+        Animation {
+            frames,
+            playback: self.playback.clone(),
+        }
+    }
+
+    /// Make pixels of the key color transparent in all frames, like `ColorKey::apply_rgba`.
+    /// Returns the number of keyed pixels of all frames.
+    pub fn key_out(&self, key: ColorKey) -> (Animation, usize) {
+        let mut keyed = 0;
+        let frames = self
+            .frames
+            .iter()
+            .map(|f| {
+                let (image, n) = key.apply_rgba(&f.image);
+                keyed += n;
+                AnimFrame {
+                    image,
+                    delay_ms: f.delay_ms,
+                }
+            })
+            .collect();
+        let anim = Animation {
+            frames,
+            playback: self.playback.clone(),
+        };
+        (anim, keyed)
+    }
+
+    /// Play the animation `speed` times as fast, with delays quantized for the timing.
+    ///
+    /// Each delay is derived from the rounded timestamps of frames rather than rounded on its own,
+    /// so that rounding errors don't accumulate over the animation.
+    pub fn retime(&self, speed: f64, timing: FrameTiming) -> Animation {
+        let quantum = f64::from(timing.quantum_ms);
+        let (mut elapsed_ms, mut prev_end) = (0.0, 0u64);
+        let frames = self
+            .frames
+            .iter()
+            .map(|f| {
+                elapsed_ms += f64::from(f.delay_ms) / speed;
+                let end = ((elapsed_ms / quantum).round() * quantum) as u64;
+                let end = end.max(prev_end + u64::from(timing.min_delay_ms));
+                let delay_ms = (end - prev_end).min(u64::from(u32::MAX)) as u32;
+                prev_end = end;
+                AnimFrame {
+                    image: f.image.clone(),
+                    delay_ms,
+                }
+            })
+            .collect();
+        Animation {
+            frames,
+            playback: self.playback.clone(),
+        }
+    }
+}
+
+/// Whether animations uploaded in the format are kept as such. Other formats (like animated WebP)
+/// are reduced to their first frames.
+pub fn keeps_animation(img_fmt: ImageFormat) -> bool {
+    matches!(img_fmt, ImageFormat::Gif | ImageFormat::Png)
+}
+
+/// Decode the image data as an animation.
+///
+/// Returns `None` if the image is not animated (including animations with a single frame).
+/// At most `MAX_FRAMES + 1` frames are decoded, so that callers can reject longer animations
+/// without decoding all of them.
+pub fn decode_animation(data: &[u8], img_fmt: ImageFormat) -> ImageResult<Option<Animation>> {
+    let (frames, playback) = match img_fmt {
+        ImageFormat::Png => {
+            let decoder = PngDecoder::new(Cursor::new(data))?;
+            if !decoder.is_apng()? {
+                return Ok(None);
+            }
+            (collect_frames(decoder.apng()?)?, read_png_playback(data)?)
+        }
+        ImageFormat::Gif => (
+            collect_frames(GifDecoder::new(Cursor::new(data))?)?,
+            read_gif_playback(data),
+        ),
+        _ => return Ok(None),
+    };
+    if frames.len() <= 1 {
+        return Ok(None);
+    }
+    Ok(Some(Animation { frames, playback }))
+}
+
+/// Loop count and comment of the APNG data.
+fn read_png_playback(data: &[u8]) -> ImageResult<Playback> {
+    let reader = png::Decoder::new(Cursor::new(data))
+        .read_info()
+        .map_err(|e| ImageError::IoError(std::io::Error::other(e)))?;
+    let info = reader.info();
+    let comment = info
+        .uncompressed_latin1_text
+        .iter()
+        .find(|t| t.keyword == PNG_COMMENT_KEYWORD)
+        .and_then(|t| Playback::truncated_comment(&t.text));
+    Ok(Playback {
+        plays: info
+            .animation_control
+            .map_or(0, |c| c.num_plays.min(u32::from(u16::MAX)) as u16),
+        comment,
+    })
+}
+
+/// Number of frames of the image data, 1 for still images. Counting stops at `MAX_FRAMES + 1`.
+pub fn count_frames(data: &[u8], img_fmt: ImageFormat) -> ImageResult<usize> {
+    let frames = match img_fmt {
+        ImageFormat::Png => {
+            let decoder = PngDecoder::new(Cursor::new(data))?;
+            if !decoder.is_apng()? {
+                return Ok(1);
+            }
+            decoder.apng()?.into_frames()
+        }
+        ImageFormat::Gif => GifDecoder::new(Cursor::new(data))?.into_frames(),
+        // the frame iterator of the WebP decoder doesn't stop at the end, so frames are counted
+        // by chunks instead
+        ImageFormat::WebP => return Ok(count_webp_frames(data).clamp(1, MAX_FRAMES + 1)),
+        _ => return Ok(1),
+    };
+    let n = frames
+        .take(MAX_FRAMES + 1)
+        .try_fold(0, |n, f| f.map(|_| n + 1))?;
+    Ok(n.max(1))
+}
+
+/// Number of `ANMF` (animation frame) chunks of the WebP data.
+fn count_webp_frames(data: &[u8]) -> usize {
+    let mut n = 0;
+    // chunks follow the 12-byte header `RIFF {size} WEBP`
+    let mut pos = 12;
+    while let Some(header) = data.get(pos..).and_then(|rest| rest.get(..8)) {
+        let len = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+        if &header[..4] == b"ANMF" {
+            n += 1;
+        }
+        // payloads are padded to even sizes. Lengths of crafted data may overflow, which ends
+        // the chunks as well as running out of data does.
+        let Some(next) = len
+            .checked_add(8 + len % 2)
+            .and_then(|n| pos.checked_add(n))
+        else {
+            break;
+        };
+        pos = next;
+    }
+    n
+}
+
+fn collect_frames<'a>(decoder: impl AnimationDecoder<'a>) -> ImageResult<Vec<AnimFrame>> {
+    decoder
+        .into_frames()
+        .take(MAX_FRAMES + 1)
+        .map(|f| {
+            let f = f?;
+            let (numer, denom) = f.delay().numer_denom_ms();
+            Ok(AnimFrame {
+                delay_ms: numer / denom.max(1),
+                image: f.into_buffer(),
+            })
+        })
+        .collect()
+}
+
+/// Encode the animation as APNG, with the comment in a `tEXt` chunk.
+pub fn encode_apng(anim: &Animation, dest: &mut Vec<u8>) -> ImageResult<()> {
+    let (w, h) = anim.dimensions();
+    let mut encoder = png::Encoder::new(dest, w, h);
+    // animations are stored as is, so they are always encoded deterministically
+    set_deterministic_png_options(&mut encoder, png::ColorType::Rgba);
+    encoder
+        .set_animated(anim.frames.len() as u32, u32::from(anim.playback.plays))
+        .map_err(png_error)?;
+    if let Some(comment) = &anim.playback.comment {
+        // tEXt is Latin-1, which comments read from GIFs are
+        let latin1 = comment.chars().map(|c| if c > '\u{ff}' { '?' } else { c });
+        encoder
+            .add_text_chunk(PNG_COMMENT_KEYWORD.to_string(), latin1.collect())
+            .map_err(png_error)?;
+    }
+
+    let mut writer = encoder.write_header().map_err(png_error)?;
+    for f in &anim.frames {
+        // delays longer than a minute are really unlikely to be intended
+        let delay = f.delay_ms.min(u32::from(u16::MAX)) as u16;
+        writer.set_frame_delay(delay, 1000).map_err(png_error)?;
+        writer
+            .write_image_data(f.image.as_raw())
+            .map_err(png_error)?;
+    }
+    writer.finish().map_err(png_error)
+}
+
+/// Encode the animation as GIF, with the comment in a comment extension.
+///
+/// GIF delays are in centiseconds, so animations should be retimed with `FrameTiming::GIF`
+/// beforehand to keep their timing.
+pub fn encode_gif(anim: &Animation, dest: &mut Vec<u8>) -> ImageResult<()> {
+    let start = dest.len();
+    {
+        let mut encoder = GifEncoder::new(&mut *dest);
+        // loop counts of GIF are of repeats after the first play, and animations played once
+        // have no loop count
+        match anim.playback.plays {
+            0 => encoder.set_repeat(Repeat::Infinite)?,
+            1 => {}
+            n => encoder.set_repeat(Repeat::Finite(n - 1))?,
+        }
+        encoder.encode_frames(anim.frames.iter().map(|f| {
+            Frame::from_parts(
+                f.image.clone(),
+                0,
+                0,
+                Delay::from_numer_denom_ms(f.delay_ms, 1),
+            )
+        }))?;
+    }
+    // the encoder doesn't write comments, so one is put before the trailer
+    if let Some(comment) = &anim.playback.comment {
+        if dest.len() > start && dest.last() == Some(&0x3b) {
+            let trailer = dest.len() - 1;
+            dest.splice(trailer..trailer, gif_comment_extension(comment));
+        }
+    }
+    Ok(())
+}
+
+fn png_error(e: png::EncodingError) -> ImageError {
+    ImageError::IoError(std::io::Error::other(e))
+}
+
+fn write_webp_chunk(dest: &mut Vec<u8>, fourcc: &[u8; 4], data: &[u8]) {
+    dest.extend_from_slice(fourcc);
+    dest.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    dest.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        dest.push(0);
+    }
+}
+
+/// Encode the frame as lossless WebP, and take the VP8L bitstream out of the container.
+fn encode_vp8l(img: &RgbaImage) -> ImageResult<Vec<u8>> {
+    let mut webp = Vec::new();
+    WebPEncoder::new_lossless(&mut webp).encode(
+        img.as_raw(),
+        img.width(),
+        img.height(),
+        ExtendedColorType::Rgba8,
+    )?;
+    // the encoder writes the simple format: "RIFF" size "WEBP" "VP8L" size data
+    let len = u32::from_le_bytes(webp[16..20].try_into().unwrap()) as usize;
+    Ok(webp[20..20 + len].to_vec())
+}
+
+/// Encode the animation as lossless animated WebP. WebP has no place for comments, which are
+/// dropped.
+///
+/// The encoder of the `image` crate only supports still images, so frames are encoded one by one
+/// and assembled into the extended container format by hand.
+pub fn encode_animated_webp(anim: &Animation, dest: &mut Vec<u8>) -> ImageResult<()> {
+    let (w, h) = anim.dimensions();
+    let u24 = |v: u32| {
+        let [b0, b1, b2, _] = v.to_le_bytes();
+        [b0, b1, b2]
+    };
+
+    let mut chunks = Vec::new();
+
+    // flags: animation | alpha
+    let mut vp8x = vec![0x02 | 0x10, 0, 0, 0];
+    vp8x.extend_from_slice(&u24(w - 1));
+    vp8x.extend_from_slice(&u24(h - 1));
+    write_webp_chunk(&mut chunks, b"VP8X", &vp8x);
+
+    // background color (transparent) and loop count (0 = forever)
+    let [lo, hi] = anim.playback.plays.to_le_bytes();
+    write_webp_chunk(&mut chunks, b"ANIM", &[0, 0, 0, 0, lo, hi]);
+
+    for f in &anim.frames {
+        let mut anmf = Vec::new();
+        anmf.extend_from_slice(&u24(0)); // x offset / 2
+        anmf.extend_from_slice(&u24(0)); // y offset / 2
+        anmf.extend_from_slice(&u24(w - 1));
+        anmf.extend_from_slice(&u24(h - 1));
+        anmf.extend_from_slice(&u24(f.delay_ms.min(0xff_ffff)));
+        // frames are fully composited, so they replace the canvas instead of being blended
+        anmf.push(0x02);
+        write_webp_chunk(&mut anmf, b"VP8L", &encode_vp8l(&f.image)?);
+        write_webp_chunk(&mut chunks, b"ANMF", &anmf);
+    }
+
+    dest.extend_from_slice(b"RIFF");
+    dest.extend_from_slice(&(chunks.len() as u32 + 4).to_le_bytes());
+    dest.extend_from_slice(b"WEBP");
+    dest.extend_from_slice(&chunks);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use image::{codecs::webp::WebPDecoder, Rgba};
+
+    use super::*;
+
+    fn test_animation() -> Animation {
+        let frames = [[255, 0, 0, 255], [0, 0, 255, 128]]
+            .into_iter()
+            .enumerate()
+            .map(|(i, color)| AnimFrame {
+                image: RgbaImage::from_pixel(3, 2, Rgba(color)),
+                delay_ms: 100 * (i as u32 + 1),
+            })
+            .collect();
+        Animation {
+            frames,
+            playback: Playback::default(),
+        }
+    }
+
+    #[test]
+    fn test_playback_roundtrip() {
+        let mut anim = test_animation().retime(1.0, FrameTiming::GIF);
+        for plays in [0, 1, 3] {
+            anim.playback = Playback {
+                plays,
+                comment: Some("by an artist".to_string()),
+            };
+            let mut apng = Vec::new();
+            encode_apng(&anim, &mut apng).unwrap();
+            let decoded = decode_animation(&apng, ImageFormat::Png).unwrap().unwrap();
+            assert_eq!(decoded.playback, anim.playback);
+
+            let mut gif = Vec::new();
+            encode_gif(&anim, &mut gif).unwrap();
+            assert_eq!(read_gif_playback(&gif), anim.playback);
+            let decoded = decode_animation(&gif, ImageFormat::Gif).unwrap().unwrap();
+            assert_eq!(decoded.frames.len(), anim.frames.len());
+        }
+
+        let mut webp = Vec::new();
+        encode_animated_webp(&anim, &mut webp).unwrap();
+        // ANIM follows the 12-byte header and the VP8X chunk (8 + 10 bytes)
+        assert_eq!(&webp[30..34], b"ANIM");
+        assert_eq!(&webp[42..44], &3u16.to_le_bytes());
+    }
+
+    #[test]
+    fn test_apng_roundtrip() {
+        let anim = test_animation();
+        let mut data = Vec::new();
+        encode_apng(&anim, &mut data).unwrap();
+
+        let decoded = decode_animation(&data, ImageFormat::Png).unwrap().unwrap();
+        assert_eq!(decoded, anim);
+
+        // the first frame is the static image
+        let img = image::load_from_memory_with_format(&data, ImageFormat::Png).unwrap();
+        assert_eq!(img.to_rgba8(), anim.frames[0].image);
+    }
+
+    #[test]
+    fn test_decode_uploaded_apng() {
+        // the second frame only updates a pixel, blended over the first one
+        let mut data = Vec::new();
+        let mut encoder = png::Encoder::new(&mut data, 2, 2);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_animated(2, 3).unwrap();
+        let mut writer = encoder.write_header().unwrap();
+        writer
+            .write_image_data(&[255, 0, 0, 255].repeat(4))
+            .unwrap();
+        writer.set_frame_dimension(1, 1).unwrap();
+        writer.set_frame_position(1, 1).unwrap();
+        writer.set_blend_op(png::BlendOp::Over).unwrap();
+        writer.write_image_data(&[0, 0, 255, 255]).unwrap();
+        writer.finish().unwrap();
+
+        assert!(keeps_animation(ImageFormat::Png));
+        assert!(!keeps_animation(ImageFormat::WebP));
+        let decoded = decode_animation(&data, ImageFormat::Png).unwrap().unwrap();
+        assert_eq!(decoded.playback.plays, 3);
+        let second = &decoded.frames[1].image;
+        assert_eq!(second.dimensions(), (2, 2));
+        assert_eq!(second.get_pixel(0, 0).0, [255, 0, 0, 255]);
+        assert_eq!(second.get_pixel(1, 1).0, [0, 0, 255, 255]);
+
+        // stored as re-encoded from the composited frames
+        let mut stored = Vec::new();
+        encode_apng(&decoded, &mut stored).unwrap();
+        let restored = decode_animation(&stored, ImageFormat::Png)
+            .unwrap()
+            .unwrap();
+        assert_eq!(restored, decoded);
+    }
+
+    #[test]
+    fn test_encode_animated_webp() {
+        let anim = test_animation();
+        let mut data = Vec::new();
+        encode_animated_webp(&anim, &mut data).unwrap();
+
+        let decoder = WebPDecoder::new(Cursor::new(&data)).unwrap();
+        assert!(decoder.has_animation());
+        // the frame iterator of the decoder doesn't stop at the end, but yields errors
+        let frames: Vec<_> = decoder
+            .into_frames()
+            .take(anim.frames.len())
+            .map(|f| {
+                let f = f.unwrap();
+                (f.delay().numer_denom_ms(), f.into_buffer())
+            })
+            .collect();
+        for ((delay, img), expected) in frames.into_iter().zip(&anim.frames) {
+            assert_eq!(delay, (expected.delay_ms, 1));
+            assert_eq!(&img, &expected.image);
+        }
+    }
+
+    #[test]
+    fn test_count_frames() {
+        let anim = test_animation();
+        let mut apng = Vec::new();
+        encode_apng(&anim, &mut apng).unwrap();
+        assert_eq!(count_frames(&apng, ImageFormat::Png).unwrap(), 2);
+        let mut webp = Vec::new();
+        encode_animated_webp(&anim, &mut webp).unwrap();
+        assert_eq!(count_frames(&webp, ImageFormat::WebP).unwrap(), 2);
+        let mut gif = Vec::new();
+        encode_gif(&anim.retime(1.0, FrameTiming::GIF), &mut gif).unwrap();
+        assert_eq!(count_frames(&gif, ImageFormat::Gif).unwrap(), 2);
+
+        let mut png = Vec::new();
+        crate::encode_png(&anim.first_frame(), &mut png, true).unwrap();
+        assert_eq!(count_frames(&png, ImageFormat::Png).unwrap(), 1);
+
+        // chunks of crafted lengths end the data
+        let mut crafted = b"RIFF\0\0\0\0WEBPANMF".to_vec();
+        crafted.extend_from_slice(&u32::MAX.to_le_bytes());
+        crafted.extend_from_slice(b"ANMF\0\0\0\0");
+        assert_eq!(count_webp_frames(&crafted), 1);
+    }
+
+    #[test]
+    fn test_gif_roundtrip() {
+        let anim = test_animation().retime(1.0, FrameTiming::GIF);
+        let mut data = Vec::new();
+        encode_gif(&anim, &mut data).unwrap();
+
+        let decoded = decode_animation(&data, ImageFormat::Gif).unwrap().unwrap();
+        assert_eq!(decoded.frames.len(), 2);
+        for (f, expected) in decoded.frames.iter().zip(&anim.frames) {
+            assert_eq!(f.delay_ms, expected.delay_ms);
+            assert_eq!(f.image.dimensions(), expected.image.dimensions());
+        }
+        // GIF has no partial transparency, but opaque colors survive
+        assert_eq!(decoded.frames[0].image, anim.frames[0].image);
+    }
+
+    #[test]
+    fn test_total_pixels() {
+        let anim = test_animation();
+        assert_eq!(anim.total_pixels(1), 3 * 2 * 2);
+        assert_eq!(anim.total_pixels(4), 3 * 2 * 16 * 2);
+        assert!(anim.allows_scale(16));
+        assert!(!anim.allows_scale(1 << 12));
+        assert_eq!(anim.total_pixels(u32::MAX), u64::MAX);
+    }
+
+    #[test]
+    fn test_decode_static_image() {
+        let mut data = Vec::new();
+        crate::encode_image(
+            &DynamicImage::ImageRgba8(RgbaImage::new(2, 2)),
+            ImageFormat::Png,
+            &mut data,
+        )
+        .unwrap();
+        assert_eq!(decode_animation(&data, ImageFormat::Png).unwrap(), None);
+    }
+
+    #[test]
+    fn test_retime_animation() {
+        let delays = |anim: &Animation| anim.frames.iter().map(|f| f.delay_ms).collect::<Vec<_>>();
+        let anim = test_animation(); // 100ms, 200ms
+
+        assert_eq!(delays(&anim.retime(2.0, FrameTiming::EXACT)), [50, 100]);
+        assert_eq!(delays(&anim.retime(0.5, FrameTiming::EXACT)), [200, 400]);
+
+        let mut anim = test_animation();
+        for f in &mut anim.frames {
+            f.delay_ms = 70;
+        }
+        // 23.33.. each; rounding each one would drift to 20ms * 2 in GIF
+        assert_eq!(delays(&anim.retime(3.0, FrameTiming::GIF)), [20, 30]);
+        // too short delays are clamped to the minimum
+        assert_eq!(delays(&anim.retime(10.0, FrameTiming::GIF)), [20, 20]);
+        assert_eq!(delays(&anim.retime(7.0, FrameTiming::EXACT)), [10, 10]);
+    }
+
+    #[test]
+    fn test_upscale_animation() {
+        let upscaled = test_animation().upscale(4);
+        assert_eq!(upscaled.dimensions(), (12, 8));
+        assert_eq!(upscaled.frames[1].delay_ms, 200);
+        assert_eq!(
+            upscaled.frames[1].image.get_pixel(11, 7),
+            &Rgba([0, 0, 255, 128])
+        );
+    }
+}
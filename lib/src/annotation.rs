@@ -0,0 +1,218 @@
+//! Named rectangular regions of images, like hitboxes and attachment points, for game tooling
+//! built on upix (`PUT /images/{hash}/annotations`).
+//!
+//! Annotations are stored in the `annotations` table of the D1 database, in pixels of the
+//! original image, and each request replaces all of them. Attachment points are regions of a
+//! single pixel. The dyn worker outlines them over served images by `?annotations=1`, in colors
+//! of `OVERLAY_COLORS` by their order, for checking them against the sprite. Outlines are drawn
+//! after scaling, so they are a pixel wide at any scale.
+
+use image::{Rgba, RgbaImage};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{crop::Crop, dimensions::Dimensions};
+
+/// Max number of annotations of an image.
+pub const MAX_ANNOTATIONS: usize = 64;
+
+/// Max length of names of annotations, in bytes.
+pub const MAX_NAME_LEN: usize = 64;
+
+/// Colors of outlines, cycled through by the order of annotations.
+const OVERLAY_COLORS: [[u8; 4]; 6] = [
+    [255, 0, 255, 255],
+    [0, 255, 255, 255],
+    [255, 255, 0, 255],
+    [0, 255, 0, 255],
+    [255, 128, 0, 255],
+    [0, 128, 255, 255],
+];
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Annotation {
+    /// Letters, digits, `-`, `_` and `.`, unique in the image.
+    pub name: String,
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Annotation {
+    pub fn region(&self) -> Crop {
+        Crop {
+            x: self.x,
+            y: self.y,
+            width: self.width,
+            height: self.height,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum AnnotationError {
+    #[error("Too many annotations (> {})", MAX_ANNOTATIONS)]
+    TooMany,
+    #[error("Invalid annotation name: '{0}'")]
+    InvalidName(String),
+    #[error("Duplicate annotation name: '{0}'")]
+    DuplicateName(String),
+    #[error("Annotation '{0}' is empty")]
+    Empty(String),
+    #[error("Annotation '{name}' must lie within the image ({width} x {height})")]
+    OutOfBounds {
+        name: String,
+        width: u32,
+        height: u32,
+    },
+}
+
+fn is_valid_name(name: &str) -> bool {
+    (1..=MAX_NAME_LEN).contains(&name.len())
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+}
+
+/// Check the annotations of an image of the dimensions.
+pub fn validate_annotations(
+    annotations: &[Annotation],
+    dims: Dimensions,
+) -> Result<(), AnnotationError> {
+    if annotations.len() > MAX_ANNOTATIONS {
+        return Err(AnnotationError::TooMany);
+    }
+    for (i, a) in annotations.iter().enumerate() {
+        if !is_valid_name(&a.name) {
+            return Err(AnnotationError::InvalidName(a.name.clone()));
+        }
+        if annotations[..i].iter().any(|b| b.name == a.name) {
+            return Err(AnnotationError::DuplicateName(a.name.clone()));
+        }
+        if a.width == 0 || a.height == 0 {
+            return Err(AnnotationError::Empty(a.name.clone()));
+        }
+        if !a.region().fits_in(dims) {
+            return Err(AnnotationError::OutOfBounds {
+                name: a.name.clone(),
+                width: dims.width,
+                height: dims.height,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Digest of the annotations, which responses with them drawn are cached and tagged by.
+pub fn annotations_digest(annotations: &[Annotation]) -> u64 {
+    let json = serde_json::to_string(annotations).unwrap_or_default();
+    let digest = Sha256::digest(json.as_bytes());
+    u64::from_be_bytes(digest[..8].try_into().unwrap())
+}
+
+/// Outline the regions of the annotations over the image, which is the original upscaled by the
+/// scale. Regions are outlined along their inner borders.
+pub fn draw_annotations(img: &mut RgbaImage, annotations: &[Annotation], scale: u32) {
+    let (w, h) = img.dimensions();
+    for (a, color) in annotations.iter().zip(OVERLAY_COLORS.iter().cycle()) {
+        let x0 = a.x.saturating_mul(scale);
+        let y0 = a.y.saturating_mul(scale);
+        let x1 = a.x.saturating_add(a.width).saturating_mul(scale).min(w);
+        let y1 = a.y.saturating_add(a.height).saturating_mul(scale).min(h);
+        if x0 >= x1 || y0 >= y1 {
+            continue;
+        }
+        for x in x0..x1 {
+            img.put_pixel(x, y0, Rgba(*color));
+            img.put_pixel(x, y1 - 1, Rgba(*color));
+        }
+        for y in y0..y1 {
+            img.put_pixel(x0, y, Rgba(*color));
+            img.put_pixel(x1 - 1, y, Rgba(*color));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn annotation(name: &str, x: u32, y: u32, width: u32, height: u32) -> Annotation {
+        Annotation {
+            name: name.to_string(),
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn test_validate_annotations() {
+        let dims = Dimensions::new(16, 8);
+        let hitbox = annotation("hitbox", 2, 0, 12, 8);
+        let hand = annotation("hand.r", 15, 7, 1, 1);
+        assert_eq!(validate_annotations(&[hitbox.clone(), hand], dims), Ok(()));
+        assert_eq!(validate_annotations(&[], dims), Ok(()));
+
+        assert_eq!(
+            validate_annotations(&[hitbox.clone(), hitbox.clone()], dims),
+            Err(AnnotationError::DuplicateName("hitbox".to_string()))
+        );
+        assert_eq!(
+            validate_annotations(&[annotation("hit box", 0, 0, 1, 1)], dims),
+            Err(AnnotationError::InvalidName("hit box".to_string()))
+        );
+        assert_eq!(
+            validate_annotations(&[annotation("foot", 0, 0, 0, 1)], dims),
+            Err(AnnotationError::Empty("foot".to_string()))
+        );
+        assert!(matches!(
+            validate_annotations(&[annotation("tail", 8, 4, 9, 1)], dims),
+            Err(AnnotationError::OutOfBounds { .. })
+        ));
+        let many: Vec<_> = (0..=MAX_ANNOTATIONS)
+            .map(|i| annotation(&format!("p{}", i), 0, 0, 1, 1))
+            .collect();
+        assert_eq!(
+            validate_annotations(&many, dims),
+            Err(AnnotationError::TooMany)
+        );
+    }
+
+    #[test]
+    fn test_draw_annotations() {
+        let bg = Rgba([0, 0, 0, 0]);
+        let mut img = RgbaImage::from_pixel(8, 8, bg);
+        let annotations = [
+            annotation("body", 1, 1, 2, 2),
+            annotation("eye", 0, 0, 1, 1),
+        ];
+        draw_annotations(&mut img, &annotations, 2);
+
+        // the region of (1, 1) to (3, 3) is (2, 2) to (6, 6) at 2x
+        let body = Rgba(OVERLAY_COLORS[0]);
+        for (x, y) in [(2, 2), (5, 2), (2, 5), (5, 5), (3, 2)] {
+            assert_eq!(*img.get_pixel(x, y), body);
+        }
+        assert_eq!(*img.get_pixel(3, 3), bg);
+        assert_eq!(*img.get_pixel(6, 6), bg);
+        // a pixel at 2x is outlined as a whole
+        let eye = Rgba(OVERLAY_COLORS[1]);
+        for (x, y) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+            assert_eq!(*img.get_pixel(x, y), eye);
+        }
+
+        assert_ne!(
+            annotations_digest(&annotations),
+            annotations_digest(&annotations[..1])
+        );
+        // colors of outlines change with the order
+        let reordered = [annotations[1].clone(), annotations[0].clone()];
+        assert_ne!(
+            annotations_digest(&annotations),
+            annotations_digest(&reordered)
+        );
+    }
+}
@@ -0,0 +1,121 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use worker::Request;
+
+/// What a credential may do. The admin scope grants the others too.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// Uploading images, and issuing upload tokens.
+    Write,
+    /// Listing images.
+    List,
+    /// Operations of operators, like deleting images.
+    Admin,
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Scope::Write => "write",
+            Scope::List => "list",
+            Scope::Admin => "admin",
+        })
+    }
+}
+
+/// Scopes of keys without explicit ones, which is what keys could do before scopes existed.
+pub const DEFAULT_SCOPES: [Scope; 2] = [Scope::Write, Scope::List];
+
+/// Whether the scopes grant the `required` one.
+pub fn grants(scopes: &[Scope], required: Scope) -> bool {
+    scopes.iter().any(|&s| s == required || s == Scope::Admin)
+}
+
+/// Scopes of an API key by its entry in KV: a JSON object with `scopes` (like
+/// `{"scopes": ["write"]}`), or anything else for the default scopes. Entries with malformed
+/// scopes are errors rather than defaults, so that typos never widen what keys may do.
+pub fn scopes_of_key_entry(entry: &str) -> Result<Vec<Scope>, String> {
+    let Ok(serde_json::Value::Object(entry)) = serde_json::from_str(entry) else {
+        return Ok(DEFAULT_SCOPES.to_vec());
+    };
+    match entry.get("scopes") {
+        Some(scopes) => {
+            Vec::deserialize(scopes).map_err(|e| format!("invalid scopes of API key: {}", e))
+        }
+        None => Ok(DEFAULT_SCOPES.to_vec()),
+    }
+}
+
+/// Extract the token from `Authorization: Bearer <token>` header of the request.
+pub fn bearer_token(req: &Request) -> Option<String> {
+    let auth = req.headers().get("Authorization").ok()??;
+    let token = auth.strip_prefix("Bearer ")?.trim();
+    (!token.is_empty()).then(|| token.to_string())
+}
+
+/// Compare two byte strings in constant time (with respect to their contents).
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Whether the token is one of the comma-separated keys in the list. Blank entries never match.
+pub fn is_listed_key(token: &str, list: &str) -> bool {
+    // no short-circuit, so the time doesn't tell the position of the key
+    list.split(',')
+        .map(str::trim)
+        .filter(|k| !k.is_empty())
+        .fold(false, |found, k| {
+            found | constant_time_eq(token.as_bytes(), k.as_bytes())
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret!"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[test]
+    fn test_is_listed_key() {
+        assert!(is_listed_key("k1", "k1"));
+        assert!(is_listed_key("k2", "k1, k2 ,k3"));
+        assert!(!is_listed_key("k", "k1,k2"));
+        assert!(!is_listed_key("", "k1,,k2"));
+        assert!(!is_listed_key("k1", ""));
+    }
+
+    #[test]
+    fn test_scopes() {
+        assert!(grants(&[Scope::Write], Scope::Write));
+        assert!(!grants(&[Scope::Write], Scope::List));
+        assert!(!grants(&DEFAULT_SCOPES, Scope::Admin));
+        assert!(grants(&[Scope::Admin], Scope::List));
+
+        assert_eq!(
+            scopes_of_key_entry("issued to kiosk"),
+            Ok(DEFAULT_SCOPES.to_vec())
+        );
+        assert_eq!(
+            scopes_of_key_entry(r#"{"owner": "me"}"#),
+            Ok(DEFAULT_SCOPES.to_vec())
+        );
+        assert_eq!(
+            scopes_of_key_entry(r#"{"owner": "kiosk", "scopes": ["write"]}"#),
+            Ok(vec![Scope::Write])
+        );
+        assert_eq!(scopes_of_key_entry(r#"{"scopes": []}"#), Ok(vec![]));
+        assert!(scopes_of_key_entry(r#"{"scopes": ["wirte"]}"#).is_err());
+        assert_eq!(Scope::Admin.to_string(), "admin");
+    }
+}
@@ -0,0 +1,106 @@
+//! Avatar mode: square sources rendered into a fixed ladder of sizes.
+
+use image::{imageops::FilterType, DynamicImage, GenericImageView};
+
+/// Sizes of avatar images generated for each upload.
+pub const AVATAR_SIZES: [u32; 4] = [32, 64, 128, 256];
+
+/// Max length of sides of avatar source images.
+pub const MAX_AVATAR_SOURCE_LEN: u32 = 128;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AvatarError {
+    NotSquare { width: u32, height: u32 },
+    TooLarge { len: u32 },
+}
+
+impl std::fmt::Display for AvatarError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AvatarError::NotSquare { width, height } => write!(
+                f,
+                "Avatar image must be square ({} x {}), or specify crop=center",
+                width, height
+            ),
+            AvatarError::TooLarge { len } => write!(
+                f,
+                "Avatar image is too large ({} > {})",
+                len, MAX_AVATAR_SOURCE_LEN
+            ),
+        }
+    }
+}
+
+/// Crop the largest possible square out of the center of the image.
+pub fn center_crop_square(img: &DynamicImage) -> DynamicImage {
+    let (w, h) = img.dimensions();
+    let side = u32::min(w, h);
+    img.crop_imm((w - side) / 2, (h - side) / 2, side, side)
+}
+
+/// Validate the image as an avatar source, center-cropping it first if `crop` is set.
+pub fn prepare_avatar_source(img: DynamicImage, crop: bool) -> Result<DynamicImage, AvatarError> {
+    let (width, height) = img.dimensions();
+    let img = if width == height {
+        img
+    } else if crop {
+        center_crop_square(&img)
+    } else {
+        return Err(AvatarError::NotSquare { width, height });
+    };
+
+    if img.width() > MAX_AVATAR_SOURCE_LEN {
+        return Err(AvatarError::TooLarge { len: img.width() });
+    }
+    Ok(img)
+}
+
+/// Render a (square) avatar source into the given size with nearest-neighbor filtering.
+pub fn render_avatar(src: &DynamicImage, size: u32) -> DynamicImage {
+    src.resize_exact(size, size, FilterType::Nearest)
+}
+
+#[cfg(test)]
+mod test {
+    use image::RgbaImage;
+
+    use super::*;
+
+    fn image(w: u32, h: u32) -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::new(w, h))
+    }
+
+    #[test]
+    fn test_prepare_avatar_source() {
+        let img = prepare_avatar_source(image(16, 16), false).unwrap();
+        assert_eq!(img.dimensions(), (16, 16));
+
+        assert_eq!(
+            prepare_avatar_source(image(16, 24), false).unwrap_err(),
+            AvatarError::NotSquare {
+                width: 16,
+                height: 24
+            }
+        );
+        let img = prepare_avatar_source(image(16, 24), true).unwrap();
+        assert_eq!(img.dimensions(), (16, 16));
+
+        // cropping can bring a wide image into the limit
+        let img = prepare_avatar_source(image(300, 100), true).unwrap();
+        assert_eq!(img.dimensions(), (100, 100));
+
+        assert_eq!(
+            prepare_avatar_source(image(129, 129), false).unwrap_err(),
+            AvatarError::TooLarge { len: 129 }
+        );
+    }
+
+    #[test]
+    fn test_center_crop_square() {
+        let mut src = RgbaImage::new(4, 2);
+        src.put_pixel(1, 0, image::Rgba([255, 0, 0, 255]));
+        let cropped = center_crop_square(&DynamicImage::ImageRgba8(src));
+        assert_eq!(cropped.dimensions(), (2, 2));
+        assert_eq!(cropped.get_pixel(0, 0), image::Rgba([255, 0, 0, 255]));
+    }
+}
@@ -0,0 +1,175 @@
+//! Storage of image objects.
+//!
+//! The pipeline reads and writes images through `BlobStore`, which is implemented by R2 buckets
+//! on workers and by a local directory natively (`FsStore`), so the CLI and native tests go
+//! through the same code paths as workers without any Cloudflare dependency.
+
+use worker::{Bucket, HttpMetadata};
+
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+pub struct BlobError(pub String);
+
+// futures of workers are not `Send` anyway, so the bound doesn't matter to callers
+#[allow(async_fn_in_trait)]
+pub trait BlobStore {
+    /// Data of the object at the key, or `None` if there is no such object.
+    async fn load(&self, key: &str) -> Result<Option<Vec<u8>>, BlobError>;
+
+    /// Store the data at the key, replacing the existing object if any.
+    async fn store(&self, key: &str, data: Vec<u8>, content_type: &str) -> Result<(), BlobError>;
+}
+
+impl BlobStore for Bucket {
+    async fn load(&self, key: &str) -> Result<Option<Vec<u8>>, BlobError> {
+        let Some(obj) = self
+            .get(key)
+            .execute()
+            .await
+            .map_err(|e| BlobError(format!("failed to get {}: {:?}", key, e)))?
+        else {
+            return Ok(None);
+        };
+        let Some(body) = obj.body() else {
+            return Err(BlobError(format!("object doesn't have body: {}", key)));
+        };
+        body.bytes()
+            .await
+            .map(Some)
+            .map_err(|e| BlobError(format!("failed to read body of {}: {:?}", key, e)))
+    }
+
+    async fn store(&self, key: &str, data: Vec<u8>, content_type: &str) -> Result<(), BlobError> {
+        let meta = HttpMetadata {
+            content_type: Some(content_type.to_string()),
+            ..HttpMetadata::default()
+        };
+        self.put(key, data)
+            .http_metadata(meta)
+            .execute()
+            .await
+            .map(|_| ())
+            .map_err(|e| BlobError(format!("failed to put {}: {:?}", key, e)))
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use fs_store::FsStore;
+#[cfg(not(target_arch = "wasm32"))]
+pub use mem_store::MemStore;
+
+#[cfg(not(target_arch = "wasm32"))]
+mod fs_store {
+    use std::{
+        fs, io,
+        path::{Component, Path, PathBuf},
+    };
+
+    use super::{BlobError, BlobStore};
+
+    /// Objects as files under a directory, with keys as relative paths. Content types are not
+    /// kept, as file extensions tell them.
+    #[derive(Debug, Clone)]
+    pub struct FsStore {
+        root: PathBuf,
+    }
+
+    impl FsStore {
+        pub fn new(root: impl Into<PathBuf>) -> Self {
+            Self { root: root.into() }
+        }
+
+        fn path(&self, key: &str) -> Result<PathBuf, BlobError> {
+            // keys must stay under the root
+            let rel = Path::new(key);
+            if key.is_empty() || !rel.components().all(|c| matches!(c, Component::Normal(_))) {
+                return Err(BlobError(format!("invalid key: {:?}", key)));
+            }
+            Ok(self.root.join(rel))
+        }
+    }
+
+    impl BlobStore for FsStore {
+        async fn load(&self, key: &str) -> Result<Option<Vec<u8>>, BlobError> {
+            let path = self.path(key)?;
+            match fs::read(&path) {
+                Ok(data) => Ok(Some(data)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(BlobError(format!("failed to read {:?}: {}", path, e))),
+            }
+        }
+
+        async fn store(&self, key: &str, data: Vec<u8>, _: &str) -> Result<(), BlobError> {
+            let path = self.path(key)?;
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)
+                    .map_err(|e| BlobError(format!("failed to create {:?}: {}", dir, e)))?;
+            }
+            fs::write(&path, data)
+                .map_err(|e| BlobError(format!("failed to write {:?}: {}", path, e)))
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use futures::executor::block_on;
+
+        use super::*;
+
+        #[test]
+        fn test_fs_store() {
+            let root = std::env::temp_dir().join(format!("upix-fs-store-{}", std::process::id()));
+            let store = FsStore::new(&root);
+
+            block_on(store.store("ns/a.png", vec![1, 2, 3], "image/png")).unwrap();
+            assert_eq!(
+                block_on(store.load("ns/a.png")).unwrap(),
+                Some(vec![1, 2, 3])
+            );
+            assert_eq!(block_on(store.load("ns/b.png")).unwrap(), None);
+
+            assert!(block_on(store.load("../a.png")).is_err());
+            assert!(block_on(store.load("/etc/passwd")).is_err());
+            assert!(block_on(store.store("", vec![], "image/png")).is_err());
+
+            fs::remove_dir_all(root).unwrap();
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+mod mem_store {
+    use std::{cell::RefCell, collections::HashMap};
+
+    use super::{BlobError, BlobStore};
+
+    /// Objects in memory, for running the pipeline without any I/O (like measuring it).
+    #[derive(Debug, Default)]
+    pub struct MemStore {
+        objects: RefCell<HashMap<String, Vec<u8>>>,
+    }
+
+    impl MemStore {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Keys of the stored objects, sorted.
+        pub fn keys(&self) -> Vec<String> {
+            let mut keys: Vec<_> = self.objects.borrow().keys().cloned().collect();
+            keys.sort();
+            keys
+        }
+    }
+
+    impl BlobStore for MemStore {
+        async fn load(&self, key: &str) -> Result<Option<Vec<u8>>, BlobError> {
+            Ok(self.objects.borrow().get(key).cloned())
+        }
+
+        async fn store(&self, key: &str, data: Vec<u8>, _: &str) -> Result<(), BlobError> {
+            self.objects.borrow_mut().insert(key.to_string(), data);
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,194 @@
+//! Bulk deletion of images by admins, like after spam waves (`POST /admin/images/delete`).
+//!
+//! Images are selected by a list of hashes, or by a filter on their records (upload time, tag
+//! and uploader). Deletion takes two passes over the same selection:
+//!
+//! 1. a dry run (`"dry_run": true`), which deletes nothing and responds with the hashes that would
+//!    be deleted, and a confirmation token of them, signed with the `BULK_DELETE_SECRET` secret
+//! 2. the execution (`"dry_run": false`), which must carry the token, and is rejected if the
+//!    selection has changed since the dry run (like by new uploads matching the filter)
+//!
+//! Executions send a `DeleteJob` per image to the queue of the api worker (see `variant_queue`),
+//! whose consumer deletes them with retries, as deleting an image takes a dozen or so subrequests
+//! (listings and deletions in each bucket and key layout, the index, the record and the cache
+//! epoch). Selections are limited to `MAX_BULK_DELETE` images, and larger selections take several
+//! rounds; a dry run of the same selection tells which images remain.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{is_valid_hash, presign::hmac_sha256, tags::is_valid_tag};
+
+/// Name of the secret which confirmation tokens are signed with.
+pub const BULK_DELETE_SECRET: &str = "BULK_DELETE_SECRET";
+
+/// Max number of images deleted by a request.
+pub const MAX_BULK_DELETE: usize = 50;
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct BulkDeleteRequest {
+    #[serde(default)]
+    pub hashes: Option<Vec<String>>,
+    #[serde(default)]
+    pub filter: Option<DeleteFilter>,
+    /// required, so that nothing is deleted by requests forgetting it
+    pub dry_run: bool,
+    /// the token responded by the dry run, required to execute
+    #[serde(default)]
+    pub confirm: Option<String>,
+}
+
+/// Criteria of images to delete, all of which must match.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct DeleteFilter {
+    /// uploaded before this unix time in milliseconds
+    pub older_than: Option<u64>,
+    pub tag: Option<String>,
+    /// `key:{SHA-256 of the API key}` or `token:{nonce of the upload token}`, as told by
+    /// `GET /admin/uploads/{hash}`
+    pub uploader: Option<String>,
+}
+
+/// Images selected by a request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Selection {
+    /// deduplicated, in the given order
+    Hashes(Vec<String>),
+    Filter(DeleteFilter),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum BulkDeleteError {
+    #[error("Either 'hashes' or 'filter' is required, but not both")]
+    NoSelection,
+    #[error("Too many hashes (> {})", MAX_BULK_DELETE)]
+    TooMany,
+    #[error("Invalid hash: '{0}'")]
+    InvalidHash(String),
+    #[error("Filters need at least one of 'older_than', 'tag' and 'uploader'")]
+    EmptyFilter,
+    #[error("Invalid tag: '{0}'")]
+    InvalidTag(String),
+    #[error("'confirm' is required to execute, get it by a dry run first")]
+    Unconfirmed,
+}
+
+impl BulkDeleteRequest {
+    /// The images selected, if the request is valid.
+    pub fn selection(&self) -> Result<Selection, BulkDeleteError> {
+        if !self.dry_run && self.confirm.is_none() {
+            return Err(BulkDeleteError::Unconfirmed);
+        }
+        match (&self.hashes, &self.filter) {
+            (Some(hashes), None) => {
+                let mut selected: Vec<String> = Vec::with_capacity(hashes.len());
+                for hash in hashes {
+                    let hash = hash.to_ascii_lowercase();
+                    if !is_valid_hash(&hash) {
+                        return Err(BulkDeleteError::InvalidHash(hash));
+                    }
+                    if !selected.contains(&hash) {
+                        selected.push(hash);
+                    }
+                }
+                if selected.len() > MAX_BULK_DELETE {
+                    return Err(BulkDeleteError::TooMany);
+                }
+                Ok(Selection::Hashes(selected))
+            }
+            (None, Some(filter)) => {
+                if filter.older_than.is_none() && filter.tag.is_none() && filter.uploader.is_none()
+                {
+                    return Err(BulkDeleteError::EmptyFilter);
+                }
+                let tag = filter.tag.as_ref().map(|t| t.trim().to_ascii_lowercase());
+                if let Some(tag) = tag.as_ref().filter(|t| !is_valid_tag(t)) {
+                    return Err(BulkDeleteError::InvalidTag(tag.clone()));
+                }
+                Ok(Selection::Filter(DeleteFilter {
+                    tag,
+                    ..filter.clone()
+                }))
+            }
+            _ => Err(BulkDeleteError::NoSelection),
+        }
+    }
+}
+
+/// Token confirming the deletion of the images of the tenant, which doesn't depend on their
+/// order. Signed with the secret, so that it can only be had from a dry run.
+pub fn confirmation_token(secret: &[u8], tenant: &str, hashes: &[String]) -> String {
+    let mut sorted: Vec<_> = hashes.iter().map(String::as_str).collect();
+    sorted.sort_unstable();
+    hex::encode(hmac_sha256(
+        secret,
+        &format!("{}:{}", tenant, sorted.join(",")),
+    ))
+}
+
+/// Deletion of an image of a bulk deletion, sent to the queue.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeleteJob {
+    pub tenant: String,
+    pub hash: String,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn request(json: &str) -> BulkDeleteRequest {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_selection() {
+        let (a, b) = ("a".repeat(64), "b".repeat(64));
+        let req = request(&format!(
+            r#"{{"hashes": ["{}", "{}", "{}"], "dry_run": true}}"#,
+            a,
+            b,
+            a.to_uppercase()
+        ));
+        assert_eq!(req.selection(), Ok(Selection::Hashes(vec![a.clone(), b])));
+
+        let req = request(r#"{"filter": {"tag": " Spam ", "older_than": 1}, "dry_run": true}"#);
+        assert_eq!(
+            req.selection(),
+            Ok(Selection::Filter(DeleteFilter {
+                older_than: Some(1),
+                tag: Some("spam".to_string()),
+                uploader: None,
+            }))
+        );
+
+        // dry_run can't be omitted
+        assert!(serde_json::from_str::<BulkDeleteRequest>(r#"{"hashes": []}"#).is_err());
+        let req = request(r#"{"filter": {}, "dry_run": true}"#);
+        assert_eq!(req.selection(), Err(BulkDeleteError::EmptyFilter));
+        let req = request(r#"{"dry_run": true}"#);
+        assert_eq!(req.selection(), Err(BulkDeleteError::NoSelection));
+        let req = request(r#"{"hashes": ["abc"], "dry_run": true}"#);
+        assert_eq!(
+            req.selection(),
+            Err(BulkDeleteError::InvalidHash("abc".to_string()))
+        );
+        let req = request(&format!(r#"{{"hashes": ["{}"], "dry_run": false}}"#, a));
+        assert_eq!(req.selection(), Err(BulkDeleteError::Unconfirmed));
+    }
+
+    #[test]
+    fn test_confirmation_token() {
+        let (a, b) = ("a".repeat(64), "b".repeat(64));
+        let token = |secret: &[u8], tenant, hashes: &[&String]| {
+            let hashes: Vec<_> = hashes.iter().map(|&h| h.clone()).collect();
+            confirmation_token(secret, tenant, &hashes)
+        };
+        let ab = token(b"secret", "", &[&a, &b]);
+        assert_eq!(ab, token(b"secret", "", &[&b, &a]));
+        assert_ne!(ab, token(b"secret", "", &[&a]));
+        assert_ne!(token(b"secret", "", &[]), token(b"secret", "", &[&b]));
+        // can't be had without the secret, nor reused by other tenants
+        assert_ne!(ab, token(b"other", "", &[&a, &b]));
+        assert_ne!(ab, token(b"secret", "acme", &[&a, &b]));
+    }
+}
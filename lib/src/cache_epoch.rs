@@ -0,0 +1,158 @@
+//! Epochs of cached variants: a global one, and one per image.
+//!
+//! The dyn worker caches generated variants by URL, and they are immutable by default. Epochs are
+//! part of cache keys, so bumping the global epoch (by `POST /admin/cache-epoch` of the api worker)
+//! invalidates all cached variants at once, e.g. after fixing an encoder bug, and bumping the epoch
+//! of an image (by `POST /admin/purge/{hash}` and deletions) invalidates all its cached variants,
+//! in every namespace, scale, format and query. Only the edge cache is invalidated: browsers keep
+//! their copies until they expire.
+//!
+//! Epochs are stored in the `CACHE_EPOCH` KV namespace, the global one under `epoch` and those of
+//! images under `image:{hash}`. Epoch 0 (also when unset or unbound) leaves cache keys as they
+//! were before epochs existed.
+
+use worker::{kv::KvStore, Env, Result as WorkerResult, Url};
+
+/// Name of the KV binding for the epoch, shared by both workers.
+pub const CACHE_EPOCH_BINDING: &str = "CACHE_EPOCH";
+
+const EPOCH_KEY: &str = "epoch";
+
+fn image_epoch_key(hash: &str) -> String {
+    format!("image:{}", hash)
+}
+
+/// Epochs are cached at the edge for this long, so bumps take up to this long to apply.
+const EPOCH_CACHE_TTL: u64 = 60;
+
+/// The current epoch.
+pub async fn load_cache_epoch(env: &Env) -> WorkerResult<u64> {
+    let Ok(kv) = env.kv(CACHE_EPOCH_BINDING) else {
+        return Ok(0);
+    };
+    read_epoch(&kv, EPOCH_KEY, Some(EPOCH_CACHE_TTL)).await
+}
+
+/// Increment the epoch, returning the new one. Concurrent bumps may be counted once, which is
+/// fine as either invalidates the cache.
+pub async fn bump_cache_epoch(kv: &KvStore) -> WorkerResult<u64> {
+    bump_epoch(kv, EPOCH_KEY).await
+}
+
+/// The current epoch of the image with the hash.
+pub async fn load_image_epoch(env: &Env, hash: &str) -> WorkerResult<u64> {
+    let Ok(kv) = env.kv(CACHE_EPOCH_BINDING) else {
+        return Ok(0);
+    };
+    read_epoch(&kv, &image_epoch_key(hash), Some(EPOCH_CACHE_TTL)).await
+}
+
+/// Increment the epoch of the image with the hash, returning the new one, like
+/// `bump_cache_epoch`.
+pub async fn bump_image_epoch(kv: &KvStore, hash: &str) -> WorkerResult<u64> {
+    bump_epoch(kv, &image_epoch_key(hash)).await
+}
+
+async fn bump_epoch(kv: &KvStore, key: &str) -> WorkerResult<u64> {
+    let epoch = read_epoch(kv, key, None).await? + 1;
+    kv.put(key, epoch.to_string())?.execute().await?;
+    Ok(epoch)
+}
+
+async fn read_epoch(kv: &KvStore, key: &str, cache_ttl: Option<u64>) -> WorkerResult<u64> {
+    let mut get = kv.get(key);
+    if let Some(ttl) = cache_ttl {
+        get = get.cache_ttl(ttl);
+    }
+    Ok(get
+        .text()
+        .await?
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(0))
+}
+
+/// Remove query params starting with `_` from the request URL, which are reserved for what cache
+/// keys are told apart by (like the epoch), so that clients can't send them to read or write
+/// entries of other keys.
+pub fn strip_reserved_params(url: &mut Url) {
+    if !url.query_pairs().any(|(k, _)| k.starts_with('_')) {
+        return;
+    }
+    let pairs: Vec<_> = url
+        .query_pairs()
+        .filter(|(k, _)| !k.starts_with('_'))
+        .map(|(k, v)| (k.into_owned(), v.into_owned()))
+        .collect();
+    if pairs.is_empty() {
+        url.set_query(None);
+    } else {
+        url.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+}
+
+/// Add the epoch to the cache key URL.
+pub fn apply_epoch(key: &mut Url, epoch: u64) {
+    if epoch != 0 {
+        key.query_pairs_mut()
+            .append_pair("_epoch", &epoch.to_string());
+    }
+}
+
+/// Add the epochs of the images the response is rendered from to the cache key URL.
+pub fn apply_image_epochs(key: &mut Url, epochs: &[u64]) {
+    if epochs.iter().any(|&e| e != 0) {
+        let epochs: Vec<_> = epochs.iter().map(u64::to_string).collect();
+        key.query_pairs_mut()
+            .append_pair("_image_epoch", &epochs.join("."));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_apply_epoch() {
+        let mut key = Url::parse("https://dyn.example/abc_2x.png?format=webp").unwrap();
+        apply_epoch(&mut key, 0);
+        assert_eq!(key.as_str(), "https://dyn.example/abc_2x.png?format=webp");
+        apply_epoch(&mut key, 3);
+        assert_eq!(
+            key.as_str(),
+            "https://dyn.example/abc_2x.png?format=webp&_epoch=3"
+        );
+
+        apply_image_epochs(&mut key, &[0]);
+        assert_eq!(
+            key.as_str(),
+            "https://dyn.example/abc_2x.png?format=webp&_epoch=3"
+        );
+        apply_image_epochs(&mut key, &[2]);
+        assert_eq!(
+            key.as_str(),
+            "https://dyn.example/abc_2x.png?format=webp&_epoch=3&_image_epoch=2"
+        );
+        let mut key = Url::parse("https://dyn.example/compare/a/b.png").unwrap();
+        apply_image_epochs(&mut key, &[0, 1]);
+        assert_eq!(
+            key.as_str(),
+            "https://dyn.example/compare/a/b.png?_image_epoch=0.1"
+        );
+    }
+
+    #[test]
+    fn test_strip_reserved_params() {
+        let mut url = Url::parse("https://dyn.example/abc_2x.png?format=webp").unwrap();
+        strip_reserved_params(&mut url);
+        assert_eq!(url.as_str(), "https://dyn.example/abc_2x.png?format=webp");
+
+        let mut url =
+            Url::parse("https://dyn.example/abc_2x.png?_epoch=9&format=webp&_accept=webp").unwrap();
+        strip_reserved_params(&mut url);
+        assert_eq!(url.as_str(), "https://dyn.example/abc_2x.png?format=webp");
+
+        let mut url = Url::parse("https://dyn.example/abc_2x.png?_annotations=0").unwrap();
+        strip_reserved_params(&mut url);
+        assert_eq!(url.as_str(), "https://dyn.example/abc_2x.png");
+    }
+}
@@ -0,0 +1,221 @@
+//! Declarative cache policies per route.
+//!
+//! Every cacheable response type has an entry in [`CachePolicy::default_for`]. Each field can be
+//! overridden by a var named `CACHE_{ROUTE}_{FIELD}` (e.g. `CACHE_VARIANT_EDGE_TTL`), where
+//! `FIELD` is one of `EDGE_TTL`, `BROWSER_TTL`, `NEGATIVE_TTL`, `SOFT_TTL` (seconds) or `VARY`
+//! (comma-separated header names).
+
+use worker::{Env, Headers, Result as WorkerResult};
+
+/// Cacheable response types.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheRoute {
+    /// Images served by the dyn worker. They are content-addressed, so cached "forever".
+    Variant,
+    /// Rankings of trending images.
+    Trending,
+}
+
+impl CacheRoute {
+    fn env_prefix(&self) -> &'static str {
+        match self {
+            CacheRoute::Variant => "CACHE_VARIANT",
+            CacheRoute::Trending => "CACHE_TRENDING",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CachePolicy {
+    /// How long shared caches (Cloudflare's edge) may keep the response, in seconds.
+    pub edge_ttl: u32,
+    /// How long browsers may keep the response, in seconds.
+    pub browser_ttl: u32,
+    /// How long "not found" responses may be cached, in seconds. 0 disables negative caching.
+    pub negative_ttl: u32,
+    /// Cached responses older than this are still served, but regenerated in the background.
+    /// 0 disables background regeneration.
+    pub soft_ttl: u32,
+    /// Request headers the response varies on.
+    pub vary: Vec<String>,
+}
+
+const ONE_YEAR: u32 = 31536000;
+
+impl CachePolicy {
+    pub fn default_for(route: CacheRoute) -> Self {
+        match route {
+            CacheRoute::Variant => Self {
+                edge_ttl: ONE_YEAR,
+                browser_ttl: ONE_YEAR,
+                negative_ttl: 60,
+                soft_ttl: 0,
+                vary: vec![],
+            },
+            CacheRoute::Trending => Self {
+                edge_ttl: 300,
+                browser_ttl: 60,
+                negative_ttl: 0,
+                soft_ttl: 0,
+                vary: vec![],
+            },
+        }
+    }
+
+    /// Policy for the route, with overrides from env vars applied.
+    pub fn from_env(route: CacheRoute, env: &Env) -> Self {
+        Self::default_for(route)
+            .with_overrides(route, |name| env.var(name).ok().map(|v| v.to_string()))
+    }
+
+    /// Apply overrides looked up by `lookup` with var names for the route.
+    /// Values that fail to parse are ignored.
+    pub fn with_overrides(
+        mut self,
+        route: CacheRoute,
+        lookup: impl Fn(&str) -> Option<String>,
+    ) -> Self {
+        let prefix = route.env_prefix();
+        let ttl = |field: &str| {
+            lookup(&format!("{}_{}", prefix, field))?
+                .trim()
+                .parse()
+                .ok()
+        };
+
+        if let Some(v) = ttl("EDGE_TTL") {
+            self.edge_ttl = v;
+        }
+        if let Some(v) = ttl("BROWSER_TTL") {
+            self.browser_ttl = v;
+        }
+        if let Some(v) = ttl("NEGATIVE_TTL") {
+            self.negative_ttl = v;
+        }
+        if let Some(v) = ttl("SOFT_TTL") {
+            self.soft_ttl = v;
+        }
+        if let Some(v) = lookup(&format!("{}_VARY", prefix)) {
+            self.vary = v
+                .split(',')
+                .map(|h| h.trim().to_string())
+                .filter(|h| !h.is_empty())
+                .collect();
+        }
+        self
+    }
+
+    /// `Cache-Control` header value for successful responses.
+    pub fn cache_control(&self) -> String {
+        if self.edge_ttl == 0 && self.browser_ttl == 0 {
+            return "no-store".to_string();
+        }
+        if self.edge_ttl == self.browser_ttl {
+            format!("public, max-age={}", self.browser_ttl)
+        } else {
+            format!(
+                "public, max-age={}, s-maxage={}",
+                self.browser_ttl, self.edge_ttl
+            )
+        }
+    }
+
+    /// `Cache-Control` header value for "not found" responses.
+    pub fn negative_cache_control(&self) -> String {
+        if self.negative_ttl == 0 {
+            "no-store".to_string()
+        } else {
+            format!("public, max-age={}", self.negative_ttl)
+        }
+    }
+
+    /// Whether a cached response generated at `generated_at_ms` should be regenerated at `now_ms`.
+    pub fn is_stale(&self, generated_at_ms: u64, now_ms: u64) -> bool {
+        self.soft_ttl != 0
+            && now_ms.saturating_sub(generated_at_ms) > u64::from(self.soft_ttl) * 1000
+    }
+
+    /// Set caching headers for a successful response.
+    pub fn apply(&self, headers: &mut Headers) -> WorkerResult<()> {
+        headers.set("Cache-Control", &self.cache_control())?;
+        if !self.vary.is_empty() {
+            headers.set("Vary", &self.vary.join(", "))?;
+        }
+        Ok(())
+    }
+
+    /// Set caching headers for a "not found" response.
+    pub fn apply_negative(&self, headers: &mut Headers) -> WorkerResult<()> {
+        headers.set("Cache-Control", &self.negative_cache_control())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn test_cache_control() {
+        let variant = CachePolicy::default_for(CacheRoute::Variant);
+        assert_eq!(variant.cache_control(), "public, max-age=31536000");
+        assert_eq!(variant.negative_cache_control(), "public, max-age=60");
+
+        let trending = CachePolicy::default_for(CacheRoute::Trending);
+        assert_eq!(trending.cache_control(), "public, max-age=60, s-maxage=300");
+        assert_eq!(trending.negative_cache_control(), "no-store");
+
+        let no_cache = CachePolicy {
+            edge_ttl: 0,
+            browser_ttl: 0,
+            negative_ttl: 0,
+            soft_ttl: 0,
+            vary: vec![],
+        };
+        assert_eq!(no_cache.cache_control(), "no-store");
+    }
+
+    #[test]
+    fn test_is_stale() {
+        let mut policy = CachePolicy::default_for(CacheRoute::Variant);
+        policy.soft_ttl = 0;
+        assert!(!policy.is_stale(0, u64::MAX));
+
+        policy.soft_ttl = 60;
+        assert!(!policy.is_stale(1000, 61_000));
+        assert!(policy.is_stale(1000, 61_001));
+        // clocks may go backwards across isolates
+        assert!(!policy.is_stale(61_001, 1000));
+    }
+
+    #[test]
+    fn test_with_overrides() {
+        let vars = HashMap::from([
+            ("CACHE_VARIANT_EDGE_TTL", "3600"),
+            ("CACHE_VARIANT_NEGATIVE_TTL", "0"),
+            ("CACHE_VARIANT_SOFT_TTL", "600"),
+            ("CACHE_VARIANT_VARY", "Accept, ,Origin"),
+            ("CACHE_VARIANT_BROWSER_TTL", "not a number"),
+            ("CACHE_TRENDING_EDGE_TTL", "1"),
+        ]);
+        let lookup = |name: &str| vars.get(name).map(|v| v.to_string());
+
+        let policy = CachePolicy::default_for(CacheRoute::Variant)
+            .with_overrides(CacheRoute::Variant, lookup);
+        assert_eq!(
+            policy,
+            CachePolicy {
+                edge_ttl: 3600,
+                browser_ttl: 31536000,
+                negative_ttl: 0,
+                soft_ttl: 600,
+                vary: vec!["Accept".to_string(), "Origin".to_string()],
+            }
+        );
+
+        let policy = CachePolicy::default_for(CacheRoute::Trending)
+            .with_overrides(CacheRoute::Trending, |_| None);
+        assert_eq!(policy, CachePolicy::default_for(CacheRoute::Trending));
+    }
+}
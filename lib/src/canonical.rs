@@ -0,0 +1,121 @@
+//! Canonical form of uploaded images, in which their originals are stored.
+//!
+//! Originals are re-encoded from their pixels rather than stored as uploaded, so that they carry
+//! nothing of their sources but the pixels: ancillary chunks (EXIF, text, timestamps, color
+//! profiles) are never written by the deterministic encoder (see `encode_png`), and samples are
+//! normalized here to 8 bits per channel, keeping the channels of the source (16-bit grayscale
+//! is stored as 8-bit grayscale rather than RGBA). Pixel art has no use for deeper samples, which
+//! double the size of stored objects.
+//!
+//! Comments of animations (see `playback`) are usually credits of their authors, so they are
+//! dropped too, unless the `KEEP_COMMENTS` var is set. Loop counts are kept, as they are how the
+//! animations are meant to be played.
+
+use image::DynamicImage;
+
+use crate::animation::Animation;
+
+/// The image with samples of 8 bits per channel, in the same channels.
+pub fn canonical_image(img: DynamicImage) -> DynamicImage {
+    match img {
+        DynamicImage::ImageLuma8(_)
+        | DynamicImage::ImageLumaA8(_)
+        | DynamicImage::ImageRgb8(_)
+        | DynamicImage::ImageRgba8(_) => img,
+        DynamicImage::ImageLuma16(_) => DynamicImage::ImageLuma8(img.to_luma8()),
+        DynamicImage::ImageLumaA16(_) => DynamicImage::ImageLumaA8(img.to_luma_alpha8()),
+        DynamicImage::ImageRgb16(_) | DynamicImage::ImageRgb32F(_) => {
+            DynamicImage::ImageRgb8(img.to_rgb8())
+        }
+        img => DynamicImage::ImageRgba8(img.to_rgba8()),
+    }
+}
+
+/// Canonical forms of the uploaded image and its animation, if any. Frames of animations are
+/// always 8-bit RGBA, so only their comments are dropped.
+pub fn canonicalize(
+    img: DynamicImage,
+    anim: Option<Animation>,
+    keep_comments: bool,
+) -> (DynamicImage, Option<Animation>) {
+    let anim = anim.map(|mut anim| {
+        if !keep_comments {
+            anim.playback.comment = None;
+        }
+        anim
+    });
+    (canonical_image(img), anim)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use image::{ImageBuffer, ImageFormat, Luma};
+
+    use super::*;
+    use crate::{
+        animation::{encode_apng, AnimFrame},
+        encode_png,
+        playback::Playback,
+    };
+
+    #[test]
+    fn test_canonical_png() {
+        // 16-bit grayscale with text and gamma
+        let mut data = Vec::new();
+        let mut encoder = png::Encoder::new(&mut data, 2, 1);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Sixteen);
+        encoder.set_source_gamma(png::ScaledFloat::new(0.45455));
+        encoder
+            .add_text_chunk("Author".to_string(), "someone".to_string())
+            .unwrap();
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&[0, 0, 0xff, 0xff]).unwrap();
+        writer.finish().unwrap();
+
+        let img = image::load_from_memory_with_format(&data, ImageFormat::Png).unwrap();
+        assert!(matches!(img, DynamicImage::ImageLuma16(_)));
+        let (img, _) = canonicalize(img, None, false);
+        let expected: ImageBuffer<Luma<u8>, _> = ImageBuffer::from_raw(2, 1, vec![0, 255]).unwrap();
+        assert_eq!(img, DynamicImage::ImageLuma8(expected));
+
+        let mut stored = Vec::new();
+        encode_png(&img, &mut stored, true).unwrap();
+        let reader = png::Decoder::new(Cursor::new(&stored)).read_info().unwrap();
+        let info = reader.info();
+        assert_eq!(info.bit_depth, png::BitDepth::Eight);
+        assert_eq!(info.color_type, png::ColorType::Grayscale);
+        assert!(info.uncompressed_latin1_text.is_empty());
+        assert!(info.source_gamma.is_none());
+    }
+
+    #[test]
+    fn test_canonicalize_animation() {
+        let frame = AnimFrame {
+            image: image::RgbaImage::new(1, 1),
+            delay_ms: 100,
+        };
+        let anim = Animation {
+            frames: vec![frame.clone(), frame],
+            playback: Playback {
+                plays: 2,
+                comment: Some("by someone".to_string()),
+            },
+        };
+        let img = anim.first_frame();
+
+        let (_, kept) = canonicalize(img.clone(), Some(anim.clone()), true);
+        assert_eq!(kept.unwrap().playback, anim.playback);
+        let (_, stripped) = canonicalize(img, Some(anim), false);
+        let stripped = stripped.unwrap();
+        assert_eq!(stripped.playback.comment, None);
+        assert_eq!(stripped.playback.plays, 2);
+
+        let mut stored = Vec::new();
+        encode_apng(&stripped, &mut stored).unwrap();
+        let reader = png::Decoder::new(Cursor::new(&stored)).read_info().unwrap();
+        assert!(reader.info().uncompressed_latin1_text.is_empty());
+    }
+}
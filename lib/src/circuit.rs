@@ -0,0 +1,186 @@
+//! Circuit breaker around the bucket, for degrading gracefully while R2 is unavailable.
+//!
+//! Processing an upload takes much CPU time before anything is put to the bucket, which is wasted
+//! if the puts fail anyway. Failures of bucket operations are counted by the
+//! `BucketCircuitBreaker` Durable Object (defined in the dyn worker, one object for the whole
+//! deployment), and once there are too many of them in a while, the circuit opens: uploads are
+//! rejected with 503 and `Retry-After` for a cool-down, without touching the bucket. After the
+//! cool-down, uploads are let through again, and a failure shortly after opens the circuit again.
+//!
+//! This module contains the breaker logic, which the object runs, and a client for the api worker.
+//! The client remembers the state in the isolate for a while, so that checks don't cost a request
+//! to the object each.
+
+use std::cell::Cell;
+
+use serde::{Deserialize, Serialize};
+use worker::{Method, ObjectNamespace, Request, RequestInit, Result as WorkerResult};
+
+/// Name of the Durable Object binding for the circuit breaker, shared by both workers.
+pub const CIRCUIT_BREAKER_BINDING: &str = "CIRCUIT_BREAKER";
+
+/// The circuit opens after this many failures...
+const FAILURE_THRESHOLD: u32 = 5;
+/// ...within this many milliseconds.
+const FAILURE_WINDOW_MS: u64 = 60 * 1000;
+/// The circuit stays open for this long.
+const COOL_DOWN_MS: u64 = 30 * 1000;
+/// Closed states are remembered in the isolate for this long.
+const CLOSED_CACHE_MS: u64 = 5 * 1000;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BreakerState {
+    /// Start of the window failures are counted in.
+    window_start: u64,
+    failures: u32,
+    /// End of the last cool-down, which is in the future while the circuit is open.
+    open_until: u64,
+}
+
+impl BreakerState {
+    /// Seconds until the circuit closes, if it is open at the time.
+    pub fn retry_after(&self, now_ms: u64) -> Option<u64> {
+        (now_ms < self.open_until).then(|| (self.open_until - now_ms).div_ceil(1000))
+    }
+
+    /// Count a failure at the time, opening the circuit if there have been too many of them.
+    pub fn record_failure(&mut self, now_ms: u64) {
+        if now_ms < self.open_until {
+            return;
+        }
+        // the backend is still failing after the cool-down
+        if self.open_until > 0 && now_ms - self.open_until < FAILURE_WINDOW_MS {
+            self.open(now_ms);
+            return;
+        }
+        if now_ms.saturating_sub(self.window_start) >= FAILURE_WINDOW_MS {
+            self.window_start = now_ms;
+            self.failures = 0;
+        }
+        self.failures += 1;
+        if self.failures >= FAILURE_THRESHOLD {
+            self.open(now_ms);
+        }
+    }
+
+    fn open(&mut self, now_ms: u64) {
+        self.open_until = now_ms + COOL_DOWN_MS;
+        self.failures = 0;
+    }
+}
+
+/// State of the circuit as told by the breaker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CircuitDecision {
+    /// seconds until the circuit closes, if it is open
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<u64>,
+}
+
+impl CircuitDecision {
+    pub fn of(state: &BreakerState, now_ms: u64) -> Self {
+        CircuitDecision {
+            retry_after: state.retry_after(now_ms),
+        }
+    }
+
+    /// Until when the decision can be remembered in the isolate.
+    fn valid_until(self, now_ms: u64) -> u64 {
+        match self.retry_after {
+            Some(secs) => now_ms + secs * 1000,
+            None => now_ms + CLOSED_CACHE_MS,
+        }
+    }
+}
+
+thread_local! {
+    static CACHED_DECISION: Cell<Option<(u64, CircuitDecision)>> = const { Cell::new(None) };
+}
+
+fn remember(decision: CircuitDecision, now_ms: u64) {
+    CACHED_DECISION.with(|c| c.set(Some((decision.valid_until(now_ms), decision))));
+}
+
+async fn send(ns: &ObjectNamespace, path: &str, method: Method) -> WorkerResult<CircuitDecision> {
+    let stub = ns.id_from_name("bucket")?.get_stub()?;
+    let mut init = RequestInit::new();
+    init.with_method(method);
+    let url = format!("https://circuit-breaker{}", path);
+    let mut resp = stub
+        .fetch_with_request(Request::new_with_init(&url, &init)?)
+        .await?;
+    resp.json().await
+}
+
+/// Ask the breaker whether the circuit is open now, or remember the last answer.
+pub async fn check_circuit(ns: &ObjectNamespace, now_ms: u64) -> WorkerResult<CircuitDecision> {
+    let cached = CACHED_DECISION.with(Cell::get);
+    if let Some((valid_until, decision)) = cached {
+        if now_ms < valid_until {
+            return Ok(decision);
+        }
+    }
+    let decision = send(ns, "/state", Method::Get).await?;
+    remember(decision, now_ms);
+    Ok(decision)
+}
+
+/// Tell the breaker that a bucket operation has failed.
+pub async fn report_failure(ns: &ObjectNamespace, now_ms: u64) -> WorkerResult<()> {
+    let decision = send(ns, "/failure", Method::Post).await?;
+    remember(decision, now_ms);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_breaker_state() {
+        let mut state = BreakerState::default();
+        let t0 = 1_000_000;
+        assert_eq!(state.retry_after(t0), None);
+
+        for i in 0..FAILURE_THRESHOLD - 1 {
+            state.record_failure(t0 + u64::from(i) * 1000);
+        }
+        assert_eq!(state.retry_after(t0 + 10_000), None);
+        // failures of an earlier window don't count
+        state.record_failure(t0 + FAILURE_WINDOW_MS);
+        assert_eq!(state.retry_after(t0 + FAILURE_WINDOW_MS), None);
+
+        let t1 = t0 + 2 * FAILURE_WINDOW_MS;
+        for i in 0..FAILURE_THRESHOLD {
+            state.record_failure(t1 + u64::from(i));
+        }
+        let opened = t1 + u64::from(FAILURE_THRESHOLD - 1);
+        assert_eq!(state.retry_after(opened), Some(COOL_DOWN_MS / 1000));
+        assert_eq!(state.retry_after(opened + 29_500), Some(1));
+        // failures while open don't extend the cool-down
+        state.record_failure(opened + 1000);
+        assert_eq!(state.retry_after(opened + COOL_DOWN_MS), None);
+
+        // a failure right after the cool-down opens the circuit again
+        state.record_failure(opened + COOL_DOWN_MS + 1000);
+        assert!(state.retry_after(opened + COOL_DOWN_MS + 1000).is_some());
+
+        // but not long after it
+        let mut state = BreakerState {
+            open_until: t0,
+            ..BreakerState::default()
+        };
+        state.record_failure(t0 + FAILURE_WINDOW_MS);
+        assert_eq!(state.retry_after(t0 + FAILURE_WINDOW_MS), None);
+    }
+
+    #[test]
+    fn test_circuit_decision_valid_until() {
+        let closed = CircuitDecision { retry_after: None };
+        assert_eq!(closed.valid_until(1000), 1000 + CLOSED_CACHE_MS);
+        let open = CircuitDecision {
+            retry_after: Some(20),
+        };
+        assert_eq!(open.valid_until(1000), 21_000);
+    }
+}
@@ -0,0 +1,272 @@
+//! Consistency of variant sets in the bucket, restored by scheduled scans of it.
+//!
+//! Uploads store the original and its variants concurrently, so uploads failing halfway leave
+//! variants without their original, or originals without some of their variants. Scans walk the
+//! bucket in key order, where objects of each image are adjacent, and check them per image:
+//!
+//! - variants (and manifests) without the original are orphans, and deleted
+//! - upscaled variants missing next to the original are regenerated from it: those listed in the
+//!   manifest, or without one (uploads failing before storing it), those of the ladder below the
+//!   largest stored scale
+//!
+//! Variants in cold storage are not scanned, as they are listed in the manifest as such. Images
+//! with objects newer than `GRACE_PERIOD_MS` are left to uploads which may still be storing them.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    is_valid_hash,
+    manifest::{manifest_file_name, Manifest},
+    namespace::SCALE_LADDER,
+    variant::Variant,
+};
+
+/// Images are checked once all their objects are at least this old.
+pub const GRACE_PERIOD_MS: u64 = 60 * 60 * 1000;
+
+/// An object in a listing of the bucket.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListedObject {
+    pub key: String,
+    /// time of the upload in ms since the epoch
+    pub uploaded_at: u64,
+}
+
+/// Objects of an image: the original, its variants and its manifest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageObjects {
+    pub key_prefix: String,
+    pub hash: String,
+    /// file names of the objects, relative to the prefix
+    pub names: Vec<String>,
+    /// time of the newest upload of the objects
+    pub uploaded_at: u64,
+}
+
+impl ImageObjects {
+    pub fn has_original(&self) -> bool {
+        let original = Variant::Original.file_name(&self.hash);
+        self.names.contains(&original)
+    }
+
+    pub fn has_manifest(&self) -> bool {
+        let manifest = manifest_file_name(&self.hash);
+        self.names.contains(&manifest)
+    }
+
+    /// Whether uploads may still be storing the objects.
+    pub fn is_recent(&self, now: u64) -> bool {
+        now.saturating_sub(self.uploaded_at) < GRACE_PERIOD_MS
+    }
+
+    /// File names of the objects to delete as orphans: all of them if the original is missing.
+    pub fn orphans(&self) -> &[String] {
+        match self.has_original() {
+            true => &[],
+            false => &self.names,
+        }
+    }
+
+    /// Scales of upscaled variants to regenerate from the original, by the manifest if any.
+    pub fn missing_scales(&self, manifest: Option<&Manifest>) -> Vec<u32> {
+        let stored = |scale| {
+            let name = Variant::Upscaled(scale).file_name(&self.hash);
+            self.names.contains(&name)
+        };
+        if let Some(manifest) = manifest {
+            return manifest
+                .objects
+                .iter()
+                .filter(|e| !e.cold)
+                .filter_map(|e| match Variant::parse(&self.hash, &e.name)? {
+                    Variant::Upscaled(scale) => Some(scale),
+                    _ => None,
+                })
+                .filter(|&scale| !stored(scale))
+                .collect();
+        }
+        // avatar uploads have no upscaled variants to begin with
+        let is_avatar =
+            |n: &String| matches!(Variant::parse(&self.hash, n), Some(Variant::Avatar(_)));
+        if self.names.iter().any(is_avatar) {
+            return Vec::new();
+        }
+        let Some(max) = SCALE_LADDER
+            .into_iter()
+            .filter(|&s| s > 1 && stored(s))
+            .max()
+        else {
+            return Vec::new();
+        };
+        SCALE_LADDER
+            .into_iter()
+            .filter(|&s| s > 1 && s < max && !stored(s))
+            .collect()
+    }
+}
+
+/// Split the key of an object of an image into the prefix of keys, the hash of the image and the
+/// file name. Other objects (like staged uploads) are `None`.
+pub fn split_image_key(key: &str) -> Option<(&str, &str, &str)> {
+    let name_start = key.rfind('/').map_or(0, |i| i + 1);
+    let (key_prefix, name) = key.split_at(name_start);
+    let hash = name.get(..64).filter(|h| is_valid_hash(h))?;
+    let is_image_object = Variant::parse(hash, name).is_some() || name == manifest_file_name(hash);
+    is_image_object.then_some((key_prefix, hash, name))
+}
+
+/// Groups objects of listings in key order by image. The last image of a listing may continue in
+/// the next one, so it is held until an object of another image (or the end) comes.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageGrouper {
+    /// objects of the image held
+    pending: Vec<ListedObject>,
+}
+
+impl ImageGrouper {
+    /// Add the object in key order, returning the objects of the previous image if it has ended.
+    pub fn push(&mut self, obj: ListedObject) -> Option<ImageObjects> {
+        let (key_prefix, hash, _) = split_image_key(&obj.key)?;
+        let ended = self.pending.first().is_some_and(|first| {
+            split_image_key(&first.key).map(|(p, h, _)| (p, h)) != Some((key_prefix, hash))
+        });
+        let ended = ended.then(|| self.take()).flatten();
+        self.pending.push(obj);
+        ended
+    }
+
+    /// The objects of the image held, at the end of listings.
+    pub fn finish(&mut self) -> Option<ImageObjects> {
+        self.take()
+    }
+
+    fn take(&mut self) -> Option<ImageObjects> {
+        let objects = std::mem::take(&mut self.pending);
+        let (key_prefix, hash, _) = split_image_key(&objects.first()?.key)?;
+        Some(ImageObjects {
+            key_prefix: key_prefix.to_string(),
+            hash: hash.to_string(),
+            names: objects
+                .iter()
+                .filter_map(|o| split_image_key(&o.key).map(|(_, _, name)| name.to_string()))
+                .collect(),
+            uploaded_at: objects.iter().map(|o| o.uploaded_at).max().unwrap_or(0),
+        })
+    }
+}
+
+/// Progress of a scan, kept between scheduled runs.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CleanupState {
+    /// cursor of the next listing, or `None` to start over
+    pub cursor: Option<String>,
+    pub grouper: ImageGrouper,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::manifest::ManifestEntry;
+
+    fn obj(key: &str, uploaded_at: u64) -> ListedObject {
+        ListedObject {
+            key: key.to_string(),
+            uploaded_at,
+        }
+    }
+
+    fn image(names: &[&str]) -> ImageObjects {
+        ImageObjects {
+            key_prefix: String::new(),
+            hash: "a".repeat(64),
+            names: names
+                .iter()
+                .map(|n| n.replace("{h}", &"a".repeat(64)))
+                .collect(),
+            uploaded_at: 0,
+        }
+    }
+
+    #[test]
+    fn test_split_image_key() {
+        let hash = "a".repeat(64);
+        let key = format!("_v1/tenants/acme/icons/{}_2x.png", hash);
+        assert_eq!(
+            split_image_key(&key),
+            Some(("_v1/tenants/acme/icons/", hash.as_str(), &key[23..]))
+        );
+        let key = format!("{}.manifest.json", hash);
+        assert_eq!(
+            split_image_key(&key),
+            Some(("", hash.as_str(), key.as_str()))
+        );
+        assert_eq!(split_image_key("_jobs/0123.json"), None);
+        assert_eq!(split_image_key(&format!("_v1/{}.webp", hash)), None);
+    }
+
+    #[test]
+    fn test_image_grouper() {
+        let (a, b) = ("a".repeat(64), "b".repeat(64));
+        let mut grouper = ImageGrouper::default();
+        assert_eq!(grouper.push(obj(&format!("_v1/{}.png", a), 1)), None);
+        assert_eq!(grouper.push(obj(&format!("_v1/{}_2x.png", a), 3)), None);
+        // objects of other images are skipped
+        assert_eq!(grouper.push(obj("_v1/readme.txt", 5)), None);
+
+        let ended = grouper.push(obj(&format!("_v1/{}.png", b), 2)).unwrap();
+        assert_eq!(ended.key_prefix, "_v1/");
+        assert_eq!(ended.hash, a);
+        assert_eq!(ended.names.len(), 2);
+        assert_eq!(ended.uploaded_at, 3);
+        // the same hash under another prefix is another image
+        let ended = grouper
+            .push(obj(&format!("_v1/icons/{}.png", b), 2))
+            .unwrap();
+        assert_eq!(ended.key_prefix, "_v1/");
+        assert_eq!(ended.hash, b);
+
+        // held images survive between runs
+        let json = serde_json::to_string(&grouper).unwrap();
+        let mut grouper: ImageGrouper = serde_json::from_str(&json).unwrap();
+        assert_eq!(grouper.finish().unwrap().key_prefix, "_v1/icons/");
+        assert_eq!(grouper.finish(), None);
+    }
+
+    #[test]
+    fn test_orphans() {
+        assert!(image(&["{h}.png", "{h}_2x.png"]).orphans().is_empty());
+        let orphaned = image(&["{h}.manifest.json", "{h}_2x.png"]);
+        assert_eq!(orphaned.orphans().len(), 2);
+        assert!(!orphaned.has_original());
+        assert!(orphaned.has_manifest());
+        assert!(orphaned.is_recent(GRACE_PERIOD_MS - 1));
+        assert!(!orphaned.is_recent(GRACE_PERIOD_MS));
+    }
+
+    #[test]
+    fn test_missing_scales() {
+        let hash = "a".repeat(64);
+        let manifest = Manifest {
+            hash: hash.clone(),
+            objects: ["{h}.png", "{h}_2x.png", "{h}_4x.png", "{h}_8x.png"]
+                .iter()
+                .enumerate()
+                .map(|(i, n)| ManifestEntry {
+                    cold: i == 3,
+                    ..ManifestEntry::new(n.replace("{h}", &hash), b"")
+                })
+                .collect(),
+        };
+        // cold variants are not in the bucket
+        let img = image(&["{h}.png", "{h}.manifest.json", "{h}_2x.png"]);
+        assert_eq!(img.missing_scales(Some(&manifest)), [4]);
+
+        // without manifests, gaps below the largest scale are filled
+        let img = image(&["{h}.png", "{h}_2x.png", "{h}_16x.png"]);
+        assert_eq!(img.missing_scales(None), [4, 8]);
+        assert!(image(&["{h}.png"]).missing_scales(None).is_empty());
+        assert!(image(&["{h}.png", "{h}_avatar_64.png", "{h}_4x.png"])
+            .missing_scales(None)
+            .is_empty());
+    }
+}
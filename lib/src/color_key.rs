@@ -0,0 +1,87 @@
+//! Color-key transparency, converted to alpha on upload.
+//!
+//! Assets of old games (and BMPs, which have no alpha in practice) mark transparent pixels with a
+//! reserved color, like magenta `FF00FF`, instead of alpha. Uploads with `key` turn the pixels of
+//! that exact color transparent, so that the assets don't need converting before import.
+
+use std::{fmt, str::FromStr};
+
+use image::{Rgba, RgbaImage};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ColorKey(pub [u8; 3]);
+
+impl FromStr for ColorKey {
+    type Err = String;
+
+    /// Parse the key color as 6 hex digits like `FF00FF`, optionally with a leading `#`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = s.strip_prefix('#').unwrap_or(s);
+        let err = || "'key' must be a color of 6 hex digits like 'FF00FF'".to_string();
+        if hex.len() != 6 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(err());
+        }
+        let mut rgb = [0; 3];
+        for (i, c) in rgb.iter_mut().enumerate() {
+            *c = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| err())?;
+        }
+        Ok(ColorKey(rgb))
+    }
+}
+
+impl fmt::Display for ColorKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [r, g, b] = self.0;
+        write!(f, "{:02X}{:02X}{:02X}", r, g, b)
+    }
+}
+
+impl ColorKey {
+    /// The image with opaque pixels of the key color made fully transparent, and the number of
+    /// them. Keyed pixels are zeroed, so that images keyed from different sources have the same
+    /// pixels. Translucent pixels are kept as is, since their alpha is meant already.
+    pub fn apply_rgba(self, img: &RgbaImage) -> (RgbaImage, usize) {
+        let mut keyed = 0;
+        let mut img = img.clone();
+        for px in img.pixels_mut() {
+            let Rgba([r, g, b, a]) = *px;
+            if a == u8::MAX && [r, g, b] == self.0 {
+                *px = Rgba([0, 0, 0, 0]);
+                keyed += 1;
+            }
+        }
+        (img, keyed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_color_key() {
+        assert_eq!("FF00FF".parse(), Ok(ColorKey([255, 0, 255])));
+        assert_eq!("#00ff80".parse(), Ok(ColorKey([0, 255, 128])));
+        assert!("FF00F".parse::<ColorKey>().is_err());
+        assert!("FF00FF00".parse::<ColorKey>().is_err());
+        assert!("GG00FF".parse::<ColorKey>().is_err());
+        assert!("+F+0FF".parse::<ColorKey>().is_err());
+        assert_eq!(ColorKey([255, 0, 128]).to_string(), "FF0080");
+    }
+
+    #[test]
+    fn test_apply_color_key() {
+        let magenta = Rgba([255, 0, 255, 255]);
+        let mut img = RgbaImage::from_pixel(2, 2, magenta);
+        img.put_pixel(0, 0, Rgba([10, 20, 30, 255]));
+        // translucent pixels of the color are kept
+        img.put_pixel(1, 1, Rgba([255, 0, 255, 128]));
+
+        let (keyed, count) = ColorKey([255, 0, 255]).apply_rgba(&img);
+        assert_eq!(count, 2);
+        assert_eq!(*keyed.get_pixel(0, 0), Rgba([10, 20, 30, 255]));
+        assert_eq!(*keyed.get_pixel(1, 0), Rgba([0, 0, 0, 0]));
+        assert_eq!(*keyed.get_pixel(0, 1), Rgba([0, 0, 0, 0]));
+        assert_eq!(*keyed.get_pixel(1, 1), Rgba([255, 0, 255, 128]));
+    }
+}
@@ -0,0 +1,157 @@
+//! Simulating color vision deficiencies on images served by the dyn worker, so that artists can
+//! check whether their palettes stay distinguishable.
+//!
+//! Requested by the `simulate` query param (`deuteranopia`, `protanopia` or `tritanopia`). Colors
+//! are converted to linear RGB, transformed by the matrices of Machado et al. (2009) at severity
+//! 1.0, and converted back to sRGB. Alpha is kept as is. Images are transformed after quantizing
+//! and before scaling, which gives the same result as the other way around for pixel art.
+
+use std::{collections::HashMap, fmt, str::FromStr};
+
+use image::{DynamicImage, Rgba, RgbaImage};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorVision {
+    /// Missing green cones.
+    Deuteranopia,
+    /// Missing red cones.
+    Protanopia,
+    /// Missing blue cones.
+    Tritanopia,
+}
+
+impl FromStr for ColorVision {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "deuteranopia" => Ok(ColorVision::Deuteranopia),
+            "protanopia" => Ok(ColorVision::Protanopia),
+            "tritanopia" => Ok(ColorVision::Tritanopia),
+            _ => Err("'simulate' must be one of deuteranopia, protanopia, tritanopia".to_string()),
+        }
+    }
+}
+
+impl fmt::Display for ColorVision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ColorVision::Deuteranopia => "deuteranopia",
+            ColorVision::Protanopia => "protanopia",
+            ColorVision::Tritanopia => "tritanopia",
+        };
+        f.write_str(name)
+    }
+}
+
+impl ColorVision {
+    /// Matrix of the deficiency over linear RGB.
+    fn matrix(self) -> [[f32; 3]; 3] {
+        match self {
+            ColorVision::Deuteranopia => [
+                [0.367322, 0.860646, -0.227968],
+                [0.280085, 0.672501, 0.047413],
+                [-0.011820, 0.042940, 0.968881],
+            ],
+            ColorVision::Protanopia => [
+                [0.152286, 1.052583, -0.204868],
+                [0.114503, 0.786281, 0.099216],
+                [-0.003882, -0.048116, 1.051998],
+            ],
+            ColorVision::Tritanopia => [
+                [1.255528, -0.076749, -0.178779],
+                [-0.078411, 0.930809, 0.147602],
+                [0.004733, 0.691367, 0.303900],
+            ],
+        }
+    }
+
+    /// The color as seen with the deficiency.
+    pub fn simulate_color(self, [r, g, b, a]: [u8; 4]) -> [u8; 4] {
+        let linear = [r, g, b].map(to_linear);
+        let [r, g, b] = self
+            .matrix()
+            .map(|row| to_srgb(row.iter().zip(linear).map(|(m, c)| m * c).sum()));
+        [r, g, b, a]
+    }
+
+    pub fn apply(self, img: DynamicImage) -> DynamicImage {
+        DynamicImage::ImageRgba8(self.apply_rgba(&img.to_rgba8()))
+    }
+
+    pub fn apply_rgba(self, img: &RgbaImage) -> RgbaImage {
+        // pixel art has few colors, each of which is converted once
+        let mut converted = HashMap::new();
+        let mut out = img.clone();
+        for px in out.pixels_mut() {
+            let color = *converted
+                .entry(px.0)
+                .or_insert_with(|| self.simulate_color(px.0));
+            *px = Rgba(color);
+        }
+        out
+    }
+}
+
+pub(crate) fn to_linear(c: u8) -> f32 {
+    let c = f32::from(c) / 255.0;
+    match c <= 0.04045 {
+        true => c / 12.92,
+        false => ((c + 0.055) / 1.055).powf(2.4),
+    }
+}
+
+fn to_srgb(c: f32) -> u8 {
+    let c = c.clamp(0.0, 1.0);
+    let c = match c <= 0.0031308 {
+        true => c * 12.92,
+        false => 1.055 * c.powf(1.0 / 2.4) - 0.055,
+    };
+    (c * 255.0).round() as u8
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_simulate_color() {
+        for vision in [
+            ColorVision::Deuteranopia,
+            ColorVision::Protanopia,
+            ColorVision::Tritanopia,
+        ] {
+            // grays are seen as they are, as rows of the matrices sum up to 1
+            for gray in [0, 128, 255] {
+                let [r, g, b, a] = vision.simulate_color([gray, gray, gray, 7]);
+                assert!(
+                    [r, g, b].iter().all(|c| c.abs_diff(gray) <= 1),
+                    "{}",
+                    vision
+                );
+                assert_eq!(a, 7);
+            }
+            assert_eq!(vision.to_string().parse(), Ok(vision));
+        }
+        // red and green are confused without red or green cones
+        let red = ColorVision::Deuteranopia.simulate_color([255, 0, 0, 255]);
+        let green = ColorVision::Deuteranopia.simulate_color([0, 255, 0, 255]);
+        assert!(red[0].abs_diff(red[1]) < 64 && green[0].abs_diff(green[1]) < 64);
+        assert!("achromatopsia".parse::<ColorVision>().is_err());
+    }
+
+    #[test]
+    fn test_apply_rgba() {
+        let img = RgbaImage::from_fn(4, 2, |x, _| match x % 2 {
+            0 => Rgba([255, 0, 0, 255]),
+            _ => Rgba([0, 0, 255, 0]),
+        });
+        let out = ColorVision::Protanopia.apply_rgba(&img);
+        assert_eq!(out.dimensions(), (4, 2));
+        assert_eq!(out.get_pixel(0, 0), out.get_pixel(2, 1));
+        assert_eq!(
+            out.get_pixel(1, 0).0,
+            ColorVision::Protanopia.simulate_color([0, 0, 255, 0])
+        );
+    }
+}
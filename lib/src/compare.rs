@@ -0,0 +1,142 @@
+//! Composites of two images for reviewing art side by side, served by the dyn worker
+//! (`/compare/{hashA}/{hashB}.png?mode=onion|side-by-side|checker`).
+//!
+//! Images are composited at their original sizes, aligned at their top-left corners, and the
+//! composite is upscaled as a whole, so that pixels of both line up at any scale. Animations are
+//! compared by their first frames.
+
+use std::str::FromStr;
+
+use image::{Rgba, RgbaImage};
+
+use crate::dimensions::Dimensions;
+
+/// Side of cells of the checkerboard, in pixels of the originals.
+pub const CHECKER_CELL: u32 = 8;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CompareMode {
+    /// B over A at half opacity, like onion skins of animation tools.
+    #[default]
+    Onion,
+    /// A on the left and B on the right.
+    SideBySide,
+    /// Cells of A and B alternating like a checkerboard, which reveals misaligned edges.
+    Checker,
+}
+
+impl FromStr for CompareMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "onion" => Ok(CompareMode::Onion),
+            "side-by-side" => Ok(CompareMode::SideBySide),
+            "checker" => Ok(CompareMode::Checker),
+            _ => Err("'mode' must be one of onion, side-by-side, checker".to_string()),
+        }
+    }
+}
+
+impl CompareMode {
+    /// Dimensions of the composite of images of the dimensions, before upscaling.
+    pub fn dimensions(self, a: Dimensions, b: Dimensions) -> Dimensions {
+        match self {
+            CompareMode::SideBySide => {
+                Dimensions::new(a.width.saturating_add(b.width), a.height.max(b.height))
+            }
+            _ => Dimensions::new(a.width.max(b.width), a.height.max(b.height)),
+        }
+    }
+
+    /// Composite of the images. Pixels outside either image are transparent.
+    pub fn compose(self, a: &RgbaImage, b: &RgbaImage) -> RgbaImage {
+        let dims = self.dimensions(Dimensions::of(a), Dimensions::of(b));
+        let pixel = |img: &RgbaImage, x, y| match x < img.width() && y < img.height() {
+            true => *img.get_pixel(x, y),
+            false => Rgba([0, 0, 0, 0]),
+        };
+        RgbaImage::from_fn(dims.width, dims.height, |x, y| match self {
+            CompareMode::Onion => blend_half(pixel(b, x, y), pixel(a, x, y)),
+            CompareMode::SideBySide if x < a.width() => pixel(a, x, y),
+            CompareMode::SideBySide => pixel(b, x - a.width(), y),
+            CompareMode::Checker => match (x / CHECKER_CELL + y / CHECKER_CELL) % 2 {
+                0 => pixel(a, x, y),
+                _ => pixel(b, x, y),
+            },
+        })
+    }
+}
+
+/// The source pixel at half of its opacity over the destination pixel, in straight alpha.
+fn blend_half(src: Rgba<u8>, dst: Rgba<u8>) -> Rgba<u8> {
+    let sa = f32::from(src[3]) / 255.0 * 0.5;
+    let da = f32::from(dst[3]) / 255.0;
+    let oa = sa + da * (1.0 - sa);
+    if oa == 0.0 {
+        return Rgba([0, 0, 0, 0]);
+    }
+    let channel = |i: usize| {
+        let c = (f32::from(src[i]) * sa + f32::from(dst[i]) * da * (1.0 - sa)) / oa;
+        c.round() as u8
+    };
+    Rgba([
+        channel(0),
+        channel(1),
+        channel(2),
+        (oa * 255.0).round() as u8,
+    ])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_compare_mode() {
+        assert_eq!("onion".parse(), Ok(CompareMode::Onion));
+        assert_eq!("side-by-side".parse(), Ok(CompareMode::SideBySide));
+        assert_eq!("checker".parse(), Ok(CompareMode::Checker));
+        assert!("diff".parse::<CompareMode>().is_err());
+
+        let (a, b) = (Dimensions::new(16, 8), Dimensions::new(4, 12));
+        assert_eq!(CompareMode::Onion.dimensions(a, b), Dimensions::new(16, 12));
+        assert_eq!(
+            CompareMode::SideBySide.dimensions(a, b),
+            Dimensions::new(20, 12)
+        );
+        assert_eq!(
+            CompareMode::Checker.dimensions(a, b),
+            Dimensions::new(16, 12)
+        );
+    }
+
+    #[test]
+    fn test_compose() {
+        let red = Rgba([255, 0, 0, 255]);
+        let blue = Rgba([0, 0, 255, 255]);
+        let clear = Rgba([0, 0, 0, 0]);
+        let a = RgbaImage::from_pixel(16, 16, red);
+        let b = RgbaImage::from_pixel(2, 20, blue);
+
+        let onion = CompareMode::Onion.compose(&a, &b);
+        assert_eq!(onion.dimensions(), (16, 20));
+        assert_eq!(*onion.get_pixel(0, 0), Rgba([128, 0, 128, 255]));
+        assert_eq!(*onion.get_pixel(8, 8), red);
+        assert_eq!(*onion.get_pixel(0, 18), Rgba([0, 0, 255, 128]));
+        assert_eq!(*onion.get_pixel(8, 18), clear);
+
+        let side_by_side = CompareMode::SideBySide.compose(&a, &b);
+        assert_eq!(side_by_side.dimensions(), (18, 20));
+        assert_eq!(*side_by_side.get_pixel(15, 0), red);
+        assert_eq!(*side_by_side.get_pixel(16, 0), blue);
+        assert_eq!(*side_by_side.get_pixel(0, 18), clear);
+
+        let b = RgbaImage::from_pixel(16, 16, blue);
+        let checker = CompareMode::Checker.compose(&a, &b);
+        assert_eq!(*checker.get_pixel(7, 7), red);
+        assert_eq!(*checker.get_pixel(8, 7), blue);
+        assert_eq!(*checker.get_pixel(7, 8), blue);
+        assert_eq!(*checker.get_pixel(15, 15), red);
+    }
+}
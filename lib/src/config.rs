@@ -0,0 +1,347 @@
+//! Configuration of workers, validated once per isolate.
+//!
+//! Bindings and vars don't change during the lifetime of an isolate, so they are checked on the
+//! first request, and a misconfigured deployment responds with what is wrong on every request
+//! instead of failing in the middle of handlers.
+
+use std::{collections::HashMap, fmt, str::FromStr, sync::OnceLock};
+
+use worker::{console_error, Env};
+
+use crate::{
+    content_hash::HashAlgorithm, cors::AllowedOrigins, namespace::parse_namespaces,
+    namespace::Limits, pipeline::MultiFramePolicy, rate_limit::RateLimit, ApiError,
+};
+
+/// Binding to the bucket of images, required by both workers.
+pub const IMGS_BUCKET_BINDING: &str = "IMGS_BUCKET";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    /// Limits of the root namespace, from the `MAX_DATA_LEN`, `MAX_PIXELS`, `MAX_LONG_SIDE_LEN`
+    /// and `MAX_ASPECT_RATIO` vars over the defaults.
+    pub root_limits: Limits,
+    /// Namespaces with their limits, from the `NAMESPACES` var.
+    pub namespaces: HashMap<String, Limits>,
+    /// Base URL of images served by the dyn worker, from the `PUBLIC_BASE_URL` var.
+    pub public_base_url: Option<String>,
+    /// Max numbers of uploads per client, from the `UPLOAD_RATE_LIMIT` var.
+    pub upload_rate_limit: RateLimit,
+    /// What to do with multi-frame images not kept as animations, from the `MULTI_FRAME_POLICY`
+    /// var.
+    pub multi_frame_policy: MultiFramePolicy,
+    /// Prefix of access log objects in the bucket, from the `ACCESS_LOG_PREFIX` var.
+    pub access_log_prefix: Option<String>,
+    /// Origins allowed to access the api worker via CORS, from the `ALLOWED_ORIGINS` var. Tenants
+    /// have their own allowlists.
+    pub allowed_origins: AllowedOrigins,
+    /// Algorithm of hashes of new uploads, from the `HASH_ALGORITHM` var.
+    pub hash_algorithm: HashAlgorithm,
+    /// Whether comments of uploaded animations are kept, from the `KEEP_COMMENTS` var (`true` or
+    /// `false`, the default).
+    pub keep_comments: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    MissingBinding(&'static str),
+    InvalidVar { name: &'static str, reason: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::MissingBinding(name) => write!(f, "Missing binding: {}", name),
+            ConfigError::InvalidVar { name, reason } => {
+                write!(f, "Invalid var {}: {}", name, reason)
+            }
+        }
+    }
+}
+
+impl From<&ConfigError> for ApiError {
+    fn from(e: &ConfigError) -> Self {
+        ApiError::new(500, format!("Server is misconfigured. {}", e))
+    }
+}
+
+static CONFIG: OnceLock<Result<Config, ConfigError>> = OnceLock::new();
+
+impl Config {
+    /// Load and validate the config from the environment. Only the first call per isolate
+    /// actually loads it, and later ones return the same result.
+    pub fn from_env(env: &Env) -> Result<&'static Config, &'static ConfigError> {
+        CONFIG
+            .get_or_init(|| {
+                let config = Config::load(
+                    |name| env.var(name).ok().map(|v| v.to_string()),
+                    |name| env.bucket(name).is_ok(),
+                );
+                if let Err(e) = &config {
+                    console_error!("invalid config: {}", e);
+                }
+                config
+            })
+            .as_ref()
+    }
+
+    fn load(
+        var: impl Fn(&str) -> Option<String>,
+        has_bucket: impl Fn(&str) -> bool,
+    ) -> Result<Config, ConfigError> {
+        if !has_bucket(IMGS_BUCKET_BINDING) {
+            return Err(ConfigError::MissingBinding(IMGS_BUCKET_BINDING));
+        }
+
+        let root_limits = load_root_limits(&var)?;
+        let namespaces = match var("NAMESPACES") {
+            Some(json) => {
+                parse_namespaces(&json, &root_limits).map_err(|e| ConfigError::InvalidVar {
+                    name: "NAMESPACES",
+                    reason: e.to_string(),
+                })?
+            }
+            None => HashMap::new(),
+        };
+
+        let public_base_url = var("PUBLIC_BASE_URL").filter(|u| !u.is_empty());
+        if let Some(url) = &public_base_url {
+            if !url.starts_with("https://") && !url.starts_with("http://") {
+                return Err(ConfigError::InvalidVar {
+                    name: "PUBLIC_BASE_URL",
+                    reason: "must be an http(s) URL".to_string(),
+                });
+            }
+        }
+
+        let upload_rate_limit = match var("UPLOAD_RATE_LIMIT").filter(|v| !v.is_empty()) {
+            Some(json) => serde_json::from_str(&json).map_err(|e| ConfigError::InvalidVar {
+                name: "UPLOAD_RATE_LIMIT",
+                reason: e.to_string(),
+            })?,
+            None => RateLimit::default(),
+        };
+
+        let multi_frame_policy = match var("MULTI_FRAME_POLICY").filter(|v| !v.is_empty()) {
+            Some(policy) => policy.parse().map_err(|reason| ConfigError::InvalidVar {
+                name: "MULTI_FRAME_POLICY",
+                reason,
+            })?,
+            None => MultiFramePolicy::default(),
+        };
+
+        // logs are kept out of the key space of images like other internal objects
+        let access_log_prefix = var("ACCESS_LOG_PREFIX").filter(|p| !p.is_empty());
+        if let Some(prefix) = &access_log_prefix {
+            if !prefix.starts_with('_') || !prefix.ends_with('/') {
+                return Err(ConfigError::InvalidVar {
+                    name: "ACCESS_LOG_PREFIX",
+                    reason: "must start with '_' and end with '/'".to_string(),
+                });
+            }
+        }
+
+        let allowed_origins = match var("ALLOWED_ORIGINS").filter(|v| !v.is_empty()) {
+            Some(origins) => {
+                AllowedOrigins::parse(&origins).map_err(|reason| ConfigError::InvalidVar {
+                    name: "ALLOWED_ORIGINS",
+                    reason,
+                })?
+            }
+            None => AllowedOrigins::default(),
+        };
+
+        let hash_algorithm = match var("HASH_ALGORITHM").filter(|v| !v.is_empty()) {
+            Some(algorithm) => algorithm
+                .parse()
+                .map_err(|reason| ConfigError::InvalidVar {
+                    name: "HASH_ALGORITHM",
+                    reason,
+                })?,
+            None => HashAlgorithm::default(),
+        };
+
+        let keep_comments =
+            parse_var(&var, "KEEP_COMMENTS", |_| true, "must be true or false")?.unwrap_or(false);
+
+        Ok(Config {
+            root_limits,
+            namespaces,
+            public_base_url,
+            upload_rate_limit,
+            multi_frame_policy,
+            access_log_prefix,
+            allowed_origins,
+            hash_algorithm,
+            keep_comments,
+        })
+    }
+}
+
+/// The defaults, with the ones given by vars overridden.
+fn load_root_limits(var: &impl Fn(&str) -> Option<String>) -> Result<Limits, ConfigError> {
+    let defaults = Limits::default();
+    let positive = "must be a positive integer";
+    Ok(Limits {
+        max_data_len: parse_var(var, "MAX_DATA_LEN", |n| *n > 0, positive)?
+            .unwrap_or(defaults.max_data_len),
+        max_pixels: parse_var(var, "MAX_PIXELS", |n| *n > 0, positive)?
+            .unwrap_or(defaults.max_pixels),
+        max_long_side_len: parse_var(var, "MAX_LONG_SIDE_LEN", |n| *n > 0, positive)?
+            .unwrap_or(defaults.max_long_side_len),
+        max_scaled_pixels: parse_var(var, "MAX_SCALED_PIXELS", |n| *n > 0, positive)?
+            .unwrap_or(defaults.max_scaled_pixels),
+        max_aspect_ratio: parse_var(
+            var,
+            "MAX_ASPECT_RATIO",
+            |r: &f64| *r >= 1.0 && r.is_finite(),
+            "must be a number of at least 1",
+        )?
+        .unwrap_or(defaults.max_aspect_ratio),
+        ..defaults
+    })
+}
+
+/// Parse the var if it is set and not empty.
+fn parse_var<T: FromStr>(
+    var: &impl Fn(&str) -> Option<String>,
+    name: &'static str,
+    is_valid: impl Fn(&T) -> bool,
+    reason: &str,
+) -> Result<Option<T>, ConfigError> {
+    let Some(v) = var(name).filter(|v| !v.is_empty()) else {
+        return Ok(None);
+    };
+    match v.trim().parse() {
+        Ok(v) if is_valid(&v) => Ok(Some(v)),
+        _ => Err(ConfigError::InvalidVar {
+            name,
+            reason: reason.to_string(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn load(vars: &[(&str, &str)], has_bucket: bool) -> Result<Config, ConfigError> {
+        Config::load(
+            |name| {
+                vars.iter()
+                    .find(|(k, _)| *k == name)
+                    .map(|(_, v)| v.to_string())
+            },
+            |_| has_bucket,
+        )
+    }
+
+    #[test]
+    fn test_load_config() {
+        let config = load(
+            &[
+                ("NAMESPACES", r#"{ "avatars": { "max_scale": 4 } }"#),
+                ("PUBLIC_BASE_URL", "https://img.upix.example"),
+                ("UPLOAD_RATE_LIMIT", r#"{ "per_hour": 20 }"#),
+                ("MULTI_FRAME_POLICY", "reject"),
+                ("ACCESS_LOG_PREFIX", "_logs/"),
+                ("ALLOWED_ORIGINS", "https://upix.example"),
+                ("MAX_PIXELS", "1048576"),
+                ("MAX_ASPECT_RATIO", "32"),
+                ("MAX_SCALED_PIXELS", "4194304"),
+                ("HASH_ALGORITHM", "blake3"),
+                ("KEEP_COMMENTS", "true"),
+            ],
+            true,
+        )
+        .unwrap();
+        assert_eq!(config.root_limits.max_pixels, 1 << 20);
+        assert_eq!(config.root_limits.max_aspect_ratio, 32.0);
+        assert_eq!(config.root_limits.max_scaled_pixels, 1 << 22);
+        assert_eq!(
+            config.root_limits.max_data_len,
+            Limits::default().max_data_len
+        );
+        assert_eq!(config.namespaces["avatars"].max_scale, 4);
+        // namespaces are over the root limits
+        assert_eq!(config.namespaces["avatars"].max_pixels, 1 << 20);
+        assert_eq!(
+            config.public_base_url.as_deref(),
+            Some("https://img.upix.example")
+        );
+        assert_eq!(config.upload_rate_limit.per_hour, 20);
+        assert_eq!(config.multi_frame_policy, MultiFramePolicy::Reject);
+        assert_eq!(config.access_log_prefix.as_deref(), Some("_logs/"));
+        assert!(config.allowed_origins.allows("https://upix.example"));
+        assert!(!config.allowed_origins.allows("https://other.example"));
+        assert_eq!(config.hash_algorithm, HashAlgorithm::Blake3);
+        assert!(config.keep_comments);
+
+        let config = load(&[("PUBLIC_BASE_URL", "")], true).unwrap();
+        assert!(config.namespaces.is_empty());
+        assert_eq!(config.public_base_url, None);
+        assert_eq!(config.upload_rate_limit, RateLimit::default());
+        assert_eq!(config.multi_frame_policy, MultiFramePolicy::FirstFrame);
+        assert_eq!(config.access_log_prefix, None);
+        assert!(config.allowed_origins.allows_any());
+        assert_eq!(config.hash_algorithm, HashAlgorithm::Sha256);
+        assert!(!config.keep_comments);
+        assert_eq!(config.root_limits, Limits::default());
+    }
+
+    #[test]
+    fn test_load_invalid_config() {
+        assert_eq!(
+            load(&[], false),
+            Err(ConfigError::MissingBinding(IMGS_BUCKET_BINDING))
+        );
+        assert!(matches!(
+            load(&[("NAMESPACES", "{")], true),
+            Err(ConfigError::InvalidVar {
+                name: "NAMESPACES",
+                ..
+            })
+        ));
+        assert!(matches!(
+            load(&[("PUBLIC_BASE_URL", "img.upix.example")], true),
+            Err(ConfigError::InvalidVar {
+                name: "PUBLIC_BASE_URL",
+                ..
+            })
+        ));
+        assert!(matches!(
+            load(&[("UPLOAD_RATE_LIMIT", r#"{ "per_hour": -1 }"#)], true),
+            Err(ConfigError::InvalidVar {
+                name: "UPLOAD_RATE_LIMIT",
+                ..
+            })
+        ));
+        assert!(matches!(
+            load(&[("ACCESS_LOG_PREFIX", "logs/")], true),
+            Err(ConfigError::InvalidVar {
+                name: "ACCESS_LOG_PREFIX",
+                ..
+            })
+        ));
+        for (name, value) in [
+            ("MAX_DATA_LEN", "0"),
+            ("MAX_PIXELS", "-1"),
+            ("MAX_LONG_SIDE_LEN", "big"),
+            ("MAX_ASPECT_RATIO", "0.5"),
+            ("MAX_SCALED_PIXELS", "0"),
+            ("KEEP_COMMENTS", "yes"),
+        ] {
+            assert!(matches!(
+                load(&[(name, value)], true),
+                Err(ConfigError::InvalidVar { name: n, .. }) if n == name
+            ));
+        }
+        assert!(matches!(
+            load(&[("ALLOWED_ORIGINS", "upix.example")], true),
+            Err(ConfigError::InvalidVar {
+                name: "ALLOWED_ORIGINS",
+                ..
+            })
+        ));
+    }
+}
@@ -0,0 +1,114 @@
+//! Measuring the content of images, to tell blank or near-empty ones and to extract palettes.
+
+use std::collections::{HashMap, HashSet};
+
+use image::RgbaImage;
+use serde::Serialize;
+
+/// Dimensions of the image with fully transparent borders trimmed. `(0, 0)` if the image is
+/// fully transparent.
+pub fn trimmed_dimensions(img: &RgbaImage) -> (u32, u32) {
+    let mut bounds: Option<(u32, u32, u32, u32)> = None;
+    for (x, y, px) in img.enumerate_pixels() {
+        if px[3] == 0 {
+            continue;
+        }
+        bounds = Some(match bounds {
+            None => (x, y, x, y),
+            Some((x0, y0, x1, y1)) => (x0.min(x), y0.min(y), x1.max(x), y1.max(y)),
+        });
+    }
+    match bounds {
+        None => (0, 0),
+        Some((x0, y0, x1, y1)) => (x1 - x0 + 1, y1 - y0 + 1),
+    }
+}
+
+/// Count distinct colors of the image, up to `limit`. Fully transparent pixels count as one
+/// color regardless of their RGB values.
+pub fn count_colors(img: &RgbaImage, limit: usize) -> usize {
+    let mut colors = HashSet::new();
+    for px in img.pixels() {
+        let color = if px[3] == 0 { [0; 4] } else { px.0 };
+        colors.insert(color);
+        if colors.len() >= limit {
+            break;
+        }
+    }
+    colors.len()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PaletteEntry {
+    /// Color in `#rrggbbaa` form.
+    pub color: String,
+    /// Number of pixels in the color.
+    pub count: u64,
+}
+
+/// Distinct colors of all the frames with their usage counts, the most used first (ties in the
+/// order of colors). Fully transparent pixels count as `#00000000` regardless of their RGB values.
+pub fn extract_palette<'a>(frames: impl IntoIterator<Item = &'a RgbaImage>) -> Vec<PaletteEntry> {
+    let mut counts = HashMap::<[u8; 4], u64>::new();
+    for px in frames.into_iter().flat_map(|f| f.pixels()) {
+        let color = if px[3] == 0 { [0; 4] } else { px.0 };
+        *counts.entry(color).or_default() += 1;
+    }
+    let mut palette: Vec<_> = counts.into_iter().collect();
+    palette.sort_by(|(c1, n1), (c2, n2)| n2.cmp(n1).then(c1.cmp(c2)));
+    palette
+        .into_iter()
+        .map(|(c, count)| PaletteEntry {
+            color: format!("#{}", hex::encode(c)),
+            count,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use image::Rgba;
+
+    use super::*;
+
+    #[test]
+    fn test_trimmed_dimensions() {
+        let mut img = RgbaImage::new(8, 8);
+        assert_eq!(trimmed_dimensions(&img), (0, 0));
+
+        img.put_pixel(2, 3, Rgba([255, 0, 0, 255]));
+        assert_eq!(trimmed_dimensions(&img), (1, 1));
+        img.put_pixel(5, 1, Rgba([0, 0, 0, 1]));
+        assert_eq!(trimmed_dimensions(&img), (4, 3));
+    }
+
+    #[test]
+    fn test_count_colors() {
+        let mut img = RgbaImage::new(4, 4);
+        img.put_pixel(0, 0, Rgba([255, 255, 255, 0]));
+        assert_eq!(count_colors(&img, 16), 1);
+
+        img.put_pixel(1, 0, Rgba([255, 0, 0, 255]));
+        img.put_pixel(2, 0, Rgba([0, 255, 0, 255]));
+        assert_eq!(count_colors(&img, 16), 3);
+        assert_eq!(count_colors(&img, 2), 2);
+    }
+
+    #[test]
+    fn test_extract_palette() {
+        let mut img = RgbaImage::from_pixel(2, 2, Rgba([255, 0, 0, 255]));
+        img.put_pixel(0, 0, Rgba([1, 2, 3, 0]));
+        let mut frame = RgbaImage::from_pixel(2, 2, Rgba([0, 0, 255, 128]));
+        frame.put_pixel(1, 1, Rgba([255, 255, 255, 0]));
+
+        let palette = extract_palette([&img, &frame]);
+        let entries: Vec<_> = palette
+            .iter()
+            .map(|e| (e.color.as_str(), e.count))
+            .collect();
+        assert_eq!(
+            entries,
+            [("#0000ff80", 3), ("#ff0000ff", 3), ("#00000000", 2)]
+        );
+    }
+}
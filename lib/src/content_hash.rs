@@ -0,0 +1,102 @@
+//! Hashes of contents identifying uploaded images.
+//!
+//! Images are identified by the hex-encoded hash of their data: SHA-256 by default, or BLAKE3
+//! (markedly faster in wasm) by setting the `HASH_ALGORITHM` var to `blake3`. Both digests are 32
+//! bytes, so hashes of either look the same in keys and URLs, and images stored before switching
+//! keep being served under their hashes. Uploads of data stored before switching are found by
+//! their SHA-256 hashes too (see `HashAlgorithm::lookup_hashes`), so that they are deduplicated
+//! instead of being stored again under new hashes.
+
+use std::str::FromStr;
+
+use sha2::{Digest, Sha256};
+
+/// Function hashing contents of images.
+pub trait ContentHasher {
+    /// Hex-encoded digest of the data.
+    fn hash_hex(&self, data: &[u8]) -> String;
+}
+
+pub struct Sha256Hasher;
+
+impl ContentHasher for Sha256Hasher {
+    fn hash_hex(&self, data: &[u8]) -> String {
+        hex::encode(Sha256::digest(data))
+    }
+}
+
+pub struct Blake3Hasher;
+
+impl ContentHasher for Blake3Hasher {
+    fn hash_hex(&self, data: &[u8]) -> String {
+        blake3::hash(data).to_hex().to_string()
+    }
+}
+
+/// Algorithm of hashes of new uploads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    pub fn hasher(self) -> &'static dyn ContentHasher {
+        match self {
+            HashAlgorithm::Sha256 => &Sha256Hasher,
+            HashAlgorithm::Blake3 => &Blake3Hasher,
+        }
+    }
+
+    pub fn hash_hex(self, data: &[u8]) -> String {
+        self.hasher().hash_hex(data)
+    }
+
+    /// Hashes the data may have been stored under, in the order to look them up: by this
+    /// algorithm, then by SHA-256 (for data stored before switching from it).
+    pub fn lookup_hashes(self, data: &[u8]) -> Vec<String> {
+        let mut hashes = vec![self.hash_hex(data)];
+        if self != HashAlgorithm::Sha256 {
+            hashes.push(HashAlgorithm::Sha256.hash_hex(data));
+        }
+        hashes
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            "blake3" => Ok(HashAlgorithm::Blake3),
+            _ => Err(format!("unknown hash algorithm: {}", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_hash_algorithm() {
+        assert_eq!(
+            HashAlgorithm::Sha256.hash_hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            HashAlgorithm::Blake3.hash_hex(b"abc"),
+            "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+        );
+
+        assert_eq!(HashAlgorithm::Sha256.lookup_hashes(b"abc").len(), 1);
+        let hashes = HashAlgorithm::Blake3.lookup_hashes(b"abc");
+        assert_eq!(hashes[0], HashAlgorithm::Blake3.hash_hex(b"abc"));
+        assert_eq!(hashes[1], HashAlgorithm::Sha256.hash_hex(b"abc"));
+
+        assert_eq!("blake3".parse(), Ok(HashAlgorithm::Blake3));
+        assert!("md5".parse::<HashAlgorithm>().is_err());
+    }
+}
@@ -0,0 +1,149 @@
+//! Origins allowed to access resources via CORS.
+//!
+//! Allowlists are given like `https://upix.example, https://*.upix.example`: `*` allows any
+//! origin, and `*.` in front of a host allows its subdomains (but not the host itself). Allowed
+//! origins are echoed back, with `Vary: Origin` so that caches keep responses of different
+//! origins apart.
+
+use worker::{Cors, Method, Response, Result as WorkerResult};
+
+/// Headers clients may send cross-origin, told to preflights.
+const ALLOWED_HEADERS: [&str; 2] = ["Authorization", "Content-Type"];
+
+/// Preflight responses are cached by browsers for this many seconds.
+const PREFLIGHT_MAX_AGE: u32 = 86400;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllowedOrigins(Vec<String>);
+
+impl Default for AllowedOrigins {
+    /// Any origin, as served before allowlists were configurable.
+    fn default() -> Self {
+        AllowedOrigins(vec!["*".to_string()])
+    }
+}
+
+impl AllowedOrigins {
+    pub fn new(origins: Vec<String>) -> Self {
+        AllowedOrigins(origins)
+    }
+
+    /// Parse the comma-separated allowlist.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let origins: Vec<_> = s
+            .split(',')
+            .map(str::trim)
+            .filter(|o| !o.is_empty())
+            .map(String::from)
+            .collect();
+        if let Some(o) = origins.iter().find(|o| !is_valid_pattern(o)) {
+            return Err(format!(
+                "invalid origin '{}' (must be '*' or an http(s) origin)",
+                o
+            ));
+        }
+        Ok(AllowedOrigins(origins))
+    }
+
+    pub fn allows_any(&self) -> bool {
+        self.0.iter().any(|o| o == "*")
+    }
+
+    pub fn allows(&self, origin: &str) -> bool {
+        self.0.iter().any(|p| matches_origin(p, origin))
+    }
+
+    /// CORS settings for a request from `origin`.
+    pub fn cors(&self, origin: Option<&str>) -> Cors {
+        if self.allows_any() {
+            return Cors::default().with_origins(["*"]);
+        }
+        match origin {
+            Some(origin) if self.allows(origin) => Cors::default().with_origins([origin]),
+            _ => Cors::default(),
+        }
+    }
+
+    /// Apply the CORS settings for a request from `origin` to the response.
+    pub fn apply(&self, resp: Response, origin: Option<&str>) -> WorkerResult<Response> {
+        let mut resp = resp.with_cors(&self.cors(origin))?;
+        if !self.allows_any() {
+            resp.headers_mut().append("Vary", "Origin")?;
+        }
+        Ok(resp)
+    }
+
+    /// CORS settings for a preflight from `origin` to a route allowing the methods.
+    pub fn preflight_cors(&self, origin: Option<&str>, methods: Vec<Method>) -> Cors {
+        self.cors(origin)
+            .with_methods(methods)
+            .with_allowed_headers(ALLOWED_HEADERS)
+            .with_max_age(PREFLIGHT_MAX_AGE)
+    }
+}
+
+fn is_valid_pattern(pattern: &str) -> bool {
+    pattern == "*"
+        || ["https://", "http://"].iter().any(|scheme| {
+            pattern.strip_prefix(scheme).is_some_and(|host| {
+                let host = host.strip_prefix("*.").unwrap_or(host);
+                !host.is_empty() && !host.contains(['/', '*'])
+            })
+        })
+}
+
+fn matches_origin(pattern: &str, origin: &str) -> bool {
+    if pattern == "*" || pattern.eq_ignore_ascii_case(origin) {
+        return true;
+    }
+    let Some((scheme, domain)) = pattern.split_once("://*.") else {
+        return false;
+    };
+    let origin = origin.to_ascii_lowercase();
+    let Some(host) = origin.strip_prefix(&format!("{}://", scheme.to_ascii_lowercase())) else {
+        return false;
+    };
+    let suffix = format!(".{}", domain.to_ascii_lowercase());
+    host.strip_suffix(&suffix)
+        .is_some_and(|sub| !sub.is_empty() && !sub.ends_with('.'))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_allowed_origins() {
+        let origins = AllowedOrigins::parse("https://a.example, https://*.b.example,").unwrap();
+        assert_eq!(
+            origins,
+            AllowedOrigins::new(vec![
+                "https://a.example".to_string(),
+                "https://*.b.example".to_string()
+            ])
+        );
+        assert!(AllowedOrigins::parse("*").unwrap().allows_any());
+        assert!(AllowedOrigins::parse("a.example").is_err());
+        assert!(AllowedOrigins::parse("https://a.example/").is_err());
+        assert!(AllowedOrigins::parse("https://a.*.example").is_err());
+    }
+
+    #[test]
+    fn test_allows() {
+        let origins = AllowedOrigins::parse("https://a.example, https://*.b.example").unwrap();
+        assert!(origins.allows("https://a.example"));
+        assert!(origins.allows("https://A.example"));
+        assert!(!origins.allows("http://a.example"));
+        assert!(!origins.allows("https://a.example.evil"));
+
+        assert!(origins.allows("https://x.b.example"));
+        assert!(origins.allows("https://x.y.b.example"));
+        assert!(!origins.allows("https://b.example"));
+        assert!(!origins.allows("https://xb.example"));
+        assert!(!origins.allows("http://x.b.example"));
+        assert!(!origins.allows("https://x.b.example.evil"));
+
+        assert!(AllowedOrigins::default().allows("https://any.example"));
+        assert!(!AllowedOrigins::new(Vec::new()).allows("https://any.example"));
+    }
+}
@@ -0,0 +1,118 @@
+//! Cropping source images to a region before scaling, for serving single sprites out of sprite
+//! sheets.
+//!
+//! Regions are given by the `crop` query param of the dyn worker as `x,y,w,h` in pixels of the
+//! source image.
+
+use std::str::FromStr;
+
+use image::{imageops, DynamicImage, RgbaImage};
+
+use crate::dimensions::Dimensions;
+
+/// Region of an image, in its pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Crop {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl FromStr for Crop {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid crop: '{}' (must be 'x,y,w,h')", s);
+        let nums = s
+            .split(',')
+            .map(|n| n.trim().parse::<u32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| invalid())?;
+        let [x, y, width, height] = nums[..] else {
+            return Err(invalid());
+        };
+        if width == 0 || height == 0 {
+            return Err("Crop must not be empty".to_string());
+        }
+        Ok(Crop {
+            x,
+            y,
+            width,
+            height,
+        })
+    }
+}
+
+impl Crop {
+    pub fn dimensions(self) -> Dimensions {
+        Dimensions::new(self.width, self.height)
+    }
+
+    /// Whether the region lies within an image of the dimensions.
+    pub fn fits_in(self, dims: Dimensions) -> bool {
+        u64::from(self.x) + u64::from(self.width) <= u64::from(dims.width)
+            && u64::from(self.y) + u64::from(self.height) <= u64::from(dims.height)
+    }
+
+    /// Crop the image to the region, which must lie within the image.
+    pub fn apply(self, img: &DynamicImage) -> DynamicImage {
+        img.crop_imm(self.x, self.y, self.width, self.height)
+    }
+
+    /// Crop the image to the region, which must lie within the image.
+    pub fn apply_rgba(self, img: &RgbaImage) -> RgbaImage {
+        imageops::crop_imm(img, self.x, self.y, self.width, self.height).to_image()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use image::Rgba;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_crop() {
+        assert_eq!(
+            "16,0,8,4".parse(),
+            Ok(Crop {
+                x: 16,
+                y: 0,
+                width: 8,
+                height: 4
+            })
+        );
+        assert!("16,0,8".parse::<Crop>().is_err());
+        assert!("16,0,8,4,1".parse::<Crop>().is_err());
+        assert!("-1,0,8,4".parse::<Crop>().is_err());
+        assert!("a,b,c,d".parse::<Crop>().is_err());
+        assert!("0,0,0,4".parse::<Crop>().is_err());
+    }
+
+    #[test]
+    fn test_crop() {
+        let crop = Crop {
+            x: 2,
+            y: 1,
+            width: 2,
+            height: 3,
+        };
+        assert!(crop.fits_in(Dimensions::new(4, 4)));
+        assert!(!crop.fits_in(Dimensions::new(3, 4)));
+        assert!(!crop.fits_in(Dimensions::new(4, 3)));
+        let far = Crop {
+            x: u32::MAX,
+            y: 0,
+            width: 1,
+            height: 1,
+        };
+        assert!(!far.fits_in(Dimensions::new(4, 4)));
+
+        let img = RgbaImage::from_fn(4, 4, |x, y| Rgba([x as u8, y as u8, 0, 255]));
+        let cropped = crop.apply_rgba(&img);
+        assert_eq!(Dimensions::of(&cropped), crop.dimensions());
+        assert_eq!(cropped.get_pixel(0, 0), &Rgba([2, 1, 0, 255]));
+        assert_eq!(cropped.get_pixel(1, 2), &Rgba([3, 3, 0, 255]));
+    }
+}
@@ -0,0 +1,109 @@
+//! Data URLs like `data:image/png;base64,...`, which browser canvas tooling exports images as.
+
+/// Data URL with base64 data. Other data URLs can't carry binary data compactly, so they are not
+/// accepted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DataUrl<'a> {
+    /// Media type without parameters, like `image/png`.
+    pub mime: &'a str,
+    base64: &'a str,
+}
+
+impl<'a> DataUrl<'a> {
+    pub fn parse(s: &'a str) -> Option<Self> {
+        let (header, base64) = s.strip_prefix("data:")?.split_once(',')?;
+        let (mime, params) = header.split_once(';')?;
+        // `base64` comes last, after any parameters like `charset`
+        if !params.rsplit(';').next()?.eq_ignore_ascii_case("base64") {
+            return None;
+        }
+        Some(DataUrl {
+            mime: mime.trim(),
+            base64,
+        })
+    }
+
+    /// Upper bound of the length of the decoded data, to reject oversized data before decoding.
+    pub fn max_decoded_len(&self) -> usize {
+        self.base64.len().div_ceil(4) * 3
+    }
+
+    /// Decode the data, or `None` if it is not valid base64.
+    pub fn decode(&self) -> Option<Vec<u8>> {
+        decode_base64(self.base64)
+    }
+}
+
+fn base64_value(b: u8) -> Option<u32> {
+    let v = match b {
+        b'A'..=b'Z' => b - b'A',
+        b'a'..=b'z' => b - b'a' + 26,
+        b'0'..=b'9' => b - b'0' + 52,
+        b'+' => 62,
+        b'/' => 63,
+        _ => return None,
+    };
+    Some(u32::from(v))
+}
+
+/// Decode standard base64, with or without padding.
+fn decode_base64(s: &str) -> Option<Vec<u8>> {
+    let s = s.trim_end_matches('=');
+    let mut out = Vec::with_capacity(s.len() / 4 * 3 + 2);
+    let (mut acc, mut bits) = (0u32, 0);
+    for b in s.bytes() {
+        acc = (acc << 6) | base64_value(b)?;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+    // a single char left over can't encode a whole byte, and left-over bits must be zero
+    if bits >= 6 || acc != 0 {
+        return None;
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_data_url() {
+        let url = DataUrl::parse("data:image/png;base64,iVBORw0KGgo=").unwrap();
+        assert_eq!(url.mime, "image/png");
+        assert_eq!(
+            url.decode().unwrap(),
+            [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a]
+        );
+        assert!(url.max_decoded_len() >= 8);
+
+        let url = DataUrl::parse("data:image/gif;foo=bar;base64,R0lGODlh").unwrap();
+        assert_eq!(url.decode().unwrap(), b"GIF89a");
+
+        assert_eq!(DataUrl::parse("data:image/png,rawdata"), None);
+        assert_eq!(DataUrl::parse("https://example.com/a.png"), None);
+        assert_eq!(
+            DataUrl::parse("data:image/png;base64,a$==")
+                .unwrap()
+                .decode(),
+            None
+        );
+        assert_eq!(
+            DataUrl::parse("data:image/png;base64,QQ").unwrap().decode(),
+            Some(b"A".to_vec())
+        );
+        // non-zero padding bits
+        assert_eq!(
+            DataUrl::parse("data:image/png;base64,QR").unwrap().decode(),
+            None
+        );
+        assert_eq!(
+            DataUrl::parse("data:image/png;base64,Q").unwrap().decode(),
+            None
+        );
+    }
+}
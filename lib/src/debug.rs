@@ -0,0 +1,120 @@
+//! Debugging of responses of the dyn worker in production.
+//!
+//! Requests with the `X-Upix-Debug: {secret}` header, or with the `nocache=1` query param and
+//! `Authorization: Bearer {secret}`, where the secret is the `DEBUG_SECRET` secret, bypass the
+//! cache both ways and get headers telling how the response was generated. Debugging is disabled
+//! without the secret, and unauthenticated requests for it are rejected rather than served from
+//! the cache, so that `nocache` can't be used to load the worker.
+
+use crate::{auth::constant_time_eq, ApiError, ApiResult};
+
+pub const DEBUG_SECRET: &str = "DEBUG_SECRET";
+pub const DEBUG_HEADER: &str = "X-Upix-Debug";
+
+/// Whether debugging is requested, checking that the request is authenticated if it is.
+///
+/// `header` is the value of the debug header, `bearer` the bearer token, and `nocache` whether the
+/// `nocache` query param is set.
+pub fn check_debug(
+    header: Option<&str>,
+    bearer: Option<&str>,
+    nocache: bool,
+    secret: Option<&str>,
+) -> ApiResult<bool> {
+    let token = match (header, nocache) {
+        (Some(header), _) => Some(header),
+        (None, true) => bearer,
+        (None, false) => return Ok(false),
+    };
+    let Some(secret) = secret.filter(|s| !s.is_empty()) else {
+        return Err(ApiError::new(403, "Debugging is disabled"));
+    };
+    let Some(token) = token else {
+        return Err(ApiError::new(401, "Missing debug secret"));
+    };
+    if !constant_time_eq(token.as_bytes(), secret.as_bytes()) {
+        return Err(ApiError::new(403, "Invalid debug secret"));
+    }
+    Ok(true)
+}
+
+/// How a response was generated, told by the debug headers.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Trace {
+    /// Key of the object the response was made of.
+    pub source_key: Option<String>,
+    /// What was requested of the source, like `4x` or `frame/2/w32`.
+    pub path: String,
+    /// How the response was made, like `stored` or `animation`.
+    pub mode: &'static str,
+    /// Durations of the steps in milliseconds, in the order they were taken.
+    pub timings: Vec<(&'static str, u64)>,
+}
+
+impl Trace {
+    pub fn time(&mut self, step: &'static str, millis: u64) {
+        self.timings.push((step, millis));
+    }
+
+    /// Debug headers, with timings in the `Server-Timing` header so that devtools show them.
+    pub fn headers(&self) -> Vec<(&'static str, String)> {
+        let mut headers = vec![
+            ("X-Upix-Debug-Path", self.path.clone()),
+            ("X-Upix-Debug-Mode", self.mode.to_string()),
+        ];
+        if let Some(key) = &self.source_key {
+            headers.push(("X-Upix-Debug-Source", key.clone()));
+        }
+        if !self.timings.is_empty() {
+            let timings: Vec<_> = self
+                .timings
+                .iter()
+                .map(|(step, millis)| format!("{};dur={}", step, millis))
+                .collect();
+            headers.push(("Server-Timing", timings.join(", ")));
+        }
+        headers
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_check_debug() {
+        let secret = Some("s3cret");
+        assert!(!check_debug(None, None, false, secret).unwrap());
+        assert!(!check_debug(None, Some("s3cret"), false, secret).unwrap());
+        assert!(check_debug(Some("s3cret"), None, false, secret).unwrap());
+        assert!(check_debug(None, Some("s3cret"), true, secret).unwrap());
+
+        let status = |res: ApiResult<bool>| res.unwrap_err().status();
+        assert_eq!(status(check_debug(None, None, true, secret)), 401);
+        assert_eq!(status(check_debug(Some("wrong"), None, false, secret)), 403);
+        assert_eq!(status(check_debug(None, Some("wrong"), true, secret)), 403);
+        assert_eq!(status(check_debug(Some(""), None, false, Some(""))), 403);
+        assert_eq!(status(check_debug(None, Some("s3cret"), true, None)), 403);
+    }
+
+    #[test]
+    fn test_trace_headers() {
+        let mut trace = Trace {
+            source_key: Some("_v1/abc.png".to_string()),
+            path: "4x".to_string(),
+            mode: "still",
+            ..Trace::default()
+        };
+        trace.time("load", 12);
+        trace.time("render", 30);
+        assert_eq!(
+            trace.headers(),
+            [
+                ("X-Upix-Debug-Path", "4x".to_string()),
+                ("X-Upix-Debug-Mode", "still".to_string()),
+                ("X-Upix-Debug-Source", "_v1/abc.png".to_string()),
+                ("Server-Timing", "load;dur=12, render;dur=30".to_string()),
+            ]
+        );
+    }
+}
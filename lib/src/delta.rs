@@ -0,0 +1,134 @@
+//! Delta-encoded frames.
+//!
+//! Frames of an animation are often near-identical, so instead of a full image, a frame can be
+//! uploaded as a patch against a base image which is already uploaded. A patch is a sequence of
+//! runs of changed pixels, each of which is:
+//!
+//! - `skip` (u32 LE): number of unchanged pixels since the end of the previous run
+//! - `len` (u32 LE): number of changed pixels in the run
+//! - `len` × RGBA pixels (4 bytes each)
+//!
+//! Pixels are indexed in row-major order. An empty patch yields the base image itself.
+
+use std::fmt;
+
+use image::RgbaImage;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DeltaError {
+    /// The patch ends in the middle of a run.
+    Truncated,
+    /// A run goes beyond the end of the image.
+    OutOfBounds,
+}
+
+impl fmt::Display for DeltaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeltaError::Truncated => write!(f, "Delta patch is truncated"),
+            DeltaError::OutOfBounds => write!(f, "Delta patch goes beyond the base image"),
+        }
+    }
+}
+
+fn read_u32(data: &[u8], pos: &mut usize) -> Result<u32, DeltaError> {
+    let bytes = data.get(*pos..*pos + 4).ok_or(DeltaError::Truncated)?;
+    *pos += 4;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// Reconstruct a frame by applying the patch to the base image.
+pub fn apply_delta(base: &RgbaImage, patch: &[u8]) -> Result<RgbaImage, DeltaError> {
+    let mut frame = base.clone();
+    let buf: &mut [u8] = &mut frame;
+    let n_pixels = buf.len() / 4;
+
+    let (mut pos, mut px) = (0, 0usize);
+    while pos < patch.len() {
+        let skip = read_u32(patch, &mut pos)? as usize;
+        let len = read_u32(patch, &mut pos)? as usize;
+        let start = px.checked_add(skip).ok_or(DeltaError::OutOfBounds)?;
+        let end = start.checked_add(len).ok_or(DeltaError::OutOfBounds)?;
+        if end > n_pixels {
+            return Err(DeltaError::OutOfBounds);
+        }
+        let pixels = patch.get(pos..pos + len * 4).ok_or(DeltaError::Truncated)?;
+        buf[start * 4..end * 4].copy_from_slice(pixels);
+        pos += len * 4;
+        px = end;
+    }
+    Ok(frame)
+}
+
+/// Encode the difference from the base image to the frame as a patch.
+/// Both images must have the same dimensions.
+pub fn encode_delta(base: &RgbaImage, frame: &RgbaImage) -> Vec<u8> {
+    assert_eq!(base.dimensions(), frame.dimensions());
+
+    let mut patch = Vec::new();
+    let mut pixels = base.pixels().zip(frame.pixels()).enumerate().peekable();
+    let mut prev_end = 0;
+    while let Some((i, (b, f))) = pixels.next() {
+        if b == f {
+            continue;
+        }
+        let mut run = vec![f];
+        while let Some((_, (_, f))) = pixels.next_if(|(_, (b, f))| b != f) {
+            run.push(f);
+        }
+        patch.extend_from_slice(&((i - prev_end) as u32).to_le_bytes());
+        patch.extend_from_slice(&(run.len() as u32).to_le_bytes());
+        for p in &run {
+            patch.extend_from_slice(&p.0);
+        }
+        prev_end = i + run.len();
+    }
+    patch
+}
+
+#[cfg(test)]
+mod test {
+    use image::Rgba;
+
+    use super::*;
+
+    #[test]
+    fn test_delta_roundtrip() {
+        let base = RgbaImage::from_pixel(4, 3, Rgba([0, 0, 0, 255]));
+        let mut frame = base.clone();
+        frame.put_pixel(1, 0, Rgba([255, 0, 0, 255]));
+        frame.put_pixel(2, 0, Rgba([255, 0, 0, 255]));
+        frame.put_pixel(3, 2, Rgba([0, 0, 0, 0]));
+
+        let patch = encode_delta(&base, &frame);
+        // 2 runs: (skip 1, len 2) and (skip 8, len 1)
+        assert_eq!(patch.len(), 8 + 2 * 4 + 8 + 4);
+        assert_eq!(apply_delta(&base, &patch).unwrap(), frame);
+
+        assert!(encode_delta(&base, &base).is_empty());
+        assert_eq!(apply_delta(&base, &[]).unwrap(), base);
+    }
+
+    #[test]
+    fn test_apply_invalid_delta() {
+        let base = RgbaImage::new(2, 2);
+
+        let mut patch = Vec::new();
+        patch.extend_from_slice(&3u32.to_le_bytes());
+        patch.extend_from_slice(&2u32.to_le_bytes());
+        patch.extend_from_slice(&[0; 8]);
+        assert_eq!(apply_delta(&base, &patch), Err(DeltaError::OutOfBounds));
+
+        let mut patch = Vec::new();
+        patch.extend_from_slice(&0u32.to_le_bytes());
+        patch.extend_from_slice(&2u32.to_le_bytes());
+        patch.extend_from_slice(&[0; 7]);
+        assert_eq!(apply_delta(&base, &patch), Err(DeltaError::Truncated));
+
+        assert_eq!(apply_delta(&base, &[0; 5]), Err(DeltaError::Truncated));
+        assert_eq!(
+            apply_delta(&base, &[0, 0, 0, 0, 255, 255, 255, 255]),
+            Err(DeltaError::OutOfBounds)
+        );
+    }
+}
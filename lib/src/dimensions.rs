@@ -0,0 +1,148 @@
+//! Dimensions of images, with arithmetic that never overflows.
+//!
+//! Dimensions may come from untrusted image headers, so naive `u32` math on them can overflow:
+//! `w * h` of a 65536×65536 image wraps to 0, passing any pixel count limit.
+
+use image::GenericImageView;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Dimensions {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Dimensions {
+    pub const fn new(width: u32, height: u32) -> Self {
+        Self { width, height }
+    }
+
+    pub fn of(img: &impl GenericImageView) -> Self {
+        let (width, height) = img.dimensions();
+        Self { width, height }
+    }
+
+    /// Number of pixels. Doesn't overflow, as `u32::MAX²` fits in `u64`.
+    pub fn pixels(self) -> u64 {
+        u64::from(self.width) * u64::from(self.height)
+    }
+
+    pub fn long_side(self) -> u32 {
+        self.width.max(self.height)
+    }
+
+    pub fn short_side(self) -> u32 {
+        self.width.min(self.height)
+    }
+
+    /// Whether the image has no pixel.
+    pub fn is_empty(self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    /// Ratio of the long side to the short side. Infinite if the image is empty.
+    pub fn aspect_ratio(self) -> f64 {
+        if self.is_empty() {
+            return f64::INFINITY;
+        }
+        f64::from(self.long_side()) / f64::from(self.short_side())
+    }
+
+    /// Dimensions scaled by the factor, or `None` on overflow.
+    pub fn checked_scale(self, scale: u32) -> Option<Self> {
+        Some(Self {
+            width: self.width.checked_mul(scale)?,
+            height: self.height.checked_mul(scale)?,
+        })
+    }
+
+    /// Dimensions scaled by the factor, saturating at `u32::MAX`.
+    pub fn saturating_scale(self, scale: u32) -> Self {
+        Self {
+            width: self.width.saturating_mul(scale),
+            height: self.height.saturating_mul(scale),
+        }
+    }
+
+    /// Largest integer scale at which the width is at most `max_width`. 0 if even the original is
+    /// wider, and `u32::MAX` for empty images.
+    pub fn fit_scale(self, max_width: u32) -> u32 {
+        max_width.checked_div(self.width).unwrap_or(u32::MAX)
+    }
+}
+
+impl From<(u32, u32)> for Dimensions {
+    fn from((width, height): (u32, u32)) -> Self {
+        Self { width, height }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MAX: u32 = u32::MAX;
+
+    #[test]
+    fn test_pixels() {
+        assert_eq!(Dimensions::new(0, 0).pixels(), 0);
+        assert_eq!(Dimensions::new(0, MAX).pixels(), 0);
+        assert_eq!(Dimensions::new(256, 256).pixels(), 65536);
+        // wraps to 0 in u32
+        assert_eq!(Dimensions::new(65536, 65536).pixels(), 1 << 32);
+        assert_eq!(Dimensions::new(MAX, MAX).pixels(), u64::from(MAX).pow(2));
+    }
+
+    #[test]
+    fn test_sides() {
+        let d = Dimensions::new(3, MAX);
+        assert_eq!((d.long_side(), d.short_side()), (MAX, 3));
+        let d = Dimensions::new(MAX, MAX);
+        assert_eq!((d.long_side(), d.short_side()), (MAX, MAX));
+        let d = Dimensions::new(0, 1);
+        assert_eq!((d.long_side(), d.short_side()), (1, 0));
+    }
+
+    #[test]
+    fn test_aspect_ratio() {
+        assert_eq!(Dimensions::new(16, 16).aspect_ratio(), 1.0);
+        assert_eq!(Dimensions::new(1, 16).aspect_ratio(), 16.0);
+        assert_eq!(Dimensions::new(16, 1).aspect_ratio(), 16.0);
+        assert_eq!(Dimensions::new(1, MAX).aspect_ratio(), f64::from(MAX));
+        assert_eq!(Dimensions::new(MAX, MAX).aspect_ratio(), 1.0);
+        // 0 / 0 would be NaN, which compares false against any limit
+        assert!(Dimensions::new(0, 0).aspect_ratio().is_infinite());
+        assert!(Dimensions::new(0, 5).aspect_ratio().is_infinite());
+        assert!(Dimensions::new(5, 0).is_empty());
+    }
+
+    #[test]
+    fn test_fit_scale() {
+        let d = Dimensions::new(24, 48);
+        assert_eq!(d.fit_scale(256), 10);
+        assert_eq!(d.fit_scale(240), 10);
+        assert_eq!(d.fit_scale(24), 1);
+        assert_eq!(d.fit_scale(16), 0);
+        assert_eq!(Dimensions::new(0, 0).fit_scale(16), MAX);
+    }
+
+    #[test]
+    fn test_scale() {
+        let d = Dimensions::new(64, 32);
+        assert_eq!(d.checked_scale(16), Some(Dimensions::new(1024, 512)));
+        assert_eq!(d.checked_scale(0), Some(Dimensions::new(0, 0)));
+        assert_eq!(d.saturating_scale(16), Dimensions::new(1024, 512));
+
+        let d = Dimensions::new(MAX / 2 + 1, 1);
+        assert_eq!(d.checked_scale(2), None);
+        assert_eq!(d.saturating_scale(2), Dimensions::new(MAX, 2));
+        assert_eq!(
+            Dimensions::new(MAX / 2, 1).checked_scale(2),
+            Some(Dimensions::new(MAX - 1, 2))
+        );
+        assert_eq!(Dimensions::new(1, MAX).checked_scale(2), None);
+        assert_eq!(
+            Dimensions::new(MAX, MAX).checked_scale(1),
+            Some(Dimensions::new(MAX, MAX))
+        );
+    }
+}
@@ -0,0 +1,207 @@
+//! Downscaling images to a target size, for thumbnails.
+//!
+//! Averaging filters blur pixel art and introduce colors out of its palette, so each pixel of the
+//! output takes the most frequent color of the block of source pixels it covers instead. Gallery
+//! previews (`{hash}_thumb.png`) don't need that fidelity, and are sampled with nearest neighbor.
+
+use std::collections::HashMap;
+
+use image::{imageops::FilterType, DynamicImage, Rgba, RgbaImage};
+
+use crate::dimensions::Dimensions;
+
+/// Target size of a downscale.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Downscale {
+    /// Both sides multiplied by the factor, which is in `(0, 1)`.
+    Fraction(f64),
+    /// The width, keeping the aspect ratio.
+    Width(u32),
+    /// The height, keeping the aspect ratio.
+    Height(u32),
+}
+
+impl Downscale {
+    /// Dimensions of the output from a source of the dimensions. Sides are at least 1.
+    ///
+    /// Returns `None` if the output would not be smaller than the source, or the source is empty.
+    pub fn target_dimensions(self, src: Dimensions) -> Option<Dimensions> {
+        if src.is_empty() {
+            return None;
+        }
+        let scaled = |len: u32, ratio: f64| ((f64::from(len) * ratio).round() as u32).max(1);
+        let dims = match self {
+            Downscale::Fraction(f) if f > 0.0 && f < 1.0 => {
+                Dimensions::new(scaled(src.width, f), scaled(src.height, f))
+            }
+            Downscale::Width(w) if w > 0 && w < src.width => {
+                let ratio = f64::from(w) / f64::from(src.width);
+                Dimensions::new(w, scaled(src.height, ratio))
+            }
+            Downscale::Height(h) if h > 0 && h < src.height => {
+                let ratio = f64::from(h) / f64::from(src.height);
+                Dimensions::new(scaled(src.width, ratio), h)
+            }
+            _ => return None,
+        };
+        Some(dims)
+    }
+}
+
+/// Max length of the sides of thumbnails.
+pub const THUMB_MAX_SIDE: u32 = 128;
+
+/// Dimensions of the thumbnail of an image of the dimensions, fitting in `max_side` with the
+/// aspect ratio kept. Images fitting already are left as is.
+pub fn thumbnail_dimensions(src: Dimensions, max_side: u32) -> Dimensions {
+    let long_side = src.long_side();
+    if long_side <= max_side {
+        return src;
+    }
+    let scaled = |len: u32| {
+        ((u64::from(len) * u64::from(max_side) + u64::from(long_side) / 2) / u64::from(long_side))
+            .max(1) as u32
+    };
+    Dimensions::new(scaled(src.width), scaled(src.height))
+}
+
+/// Thumbnail of the image, sampled with nearest neighbor.
+pub fn thumbnail_image(img: DynamicImage, max_side: u32) -> DynamicImage {
+    let dims = thumbnail_dimensions(Dimensions::of(&img), max_side);
+    if dims == Dimensions::of(&img) {
+        return img;
+    }
+    img.resize_exact(dims.width, dims.height, FilterType::Nearest)
+}
+
+/// Downscale the image to the dimensions, which must not be larger than the image.
+pub fn downscale_image(img: &DynamicImage, dims: Dimensions) -> DynamicImage {
+    DynamicImage::ImageRgba8(downscale_rgba(&img.to_rgba8(), dims))
+}
+
+/// Downscale the image to the dimensions, which must not be larger than the image.
+pub fn downscale_rgba(img: &RgbaImage, dims: Dimensions) -> RgbaImage {
+    let src = Dimensions::of(img);
+    // start of the block covered by the `i`-th output pixel out of `n`
+    let block_start =
+        |i: u32, n: u32, len: u32| (u64::from(i) * u64::from(len) / u64::from(n)) as u32;
+
+    let mut counts = HashMap::new();
+    RgbaImage::from_fn(dims.width, dims.height, |x, y| {
+        let (x0, x1) = (
+            block_start(x, dims.width, src.width),
+            block_start(x + 1, dims.width, src.width),
+        );
+        let (y0, y1) = (
+            block_start(y, dims.height, src.height),
+            block_start(y + 1, dims.height, src.height),
+        );
+        counts.clear();
+        for (i, (by, bx)) in (y0..y1.max(y0 + 1))
+            .flat_map(|by| (x0..x1.max(x0 + 1)).map(move |bx| (by, bx)))
+            .enumerate()
+        {
+            let mut px = *img.get_pixel(bx, by);
+            // fully transparent pixels are the same whatever their colors are
+            if px[3] == 0 {
+                px = Rgba([0, 0, 0, 0]);
+            }
+            counts.entry(px).or_insert((0, i)).0 += 1;
+        }
+        // ties are broken by the first pixel in the block, so the output is deterministic
+        counts
+            .iter()
+            .max_by_key(|(_, (n, first))| (*n, std::cmp::Reverse(*first)))
+            .map(|(px, _)| *px)
+            .unwrap_or(Rgba([0, 0, 0, 0]))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_target_dimensions() {
+        let src = Dimensions::new(64, 32);
+        assert_eq!(
+            Downscale::Fraction(0.5).target_dimensions(src),
+            Some(Dimensions::new(32, 16))
+        );
+        assert_eq!(
+            Downscale::Fraction(0.01).target_dimensions(src),
+            Some(Dimensions::new(1, 1))
+        );
+        assert_eq!(
+            Downscale::Width(16).target_dimensions(src),
+            Some(Dimensions::new(16, 8))
+        );
+        assert_eq!(
+            Downscale::Height(5).target_dimensions(src),
+            Some(Dimensions::new(10, 5))
+        );
+
+        // not a downscale
+        assert_eq!(Downscale::Fraction(1.0).target_dimensions(src), None);
+        assert_eq!(Downscale::Fraction(0.0).target_dimensions(src), None);
+        assert_eq!(Downscale::Width(64).target_dimensions(src), None);
+        assert_eq!(Downscale::Height(0).target_dimensions(src), None);
+        assert_eq!(
+            Downscale::Width(1).target_dimensions(Dimensions::new(0, 5)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_thumbnail_dimensions() {
+        let thumb = |w, h| thumbnail_dimensions(Dimensions::new(w, h), 128);
+        assert_eq!(thumb(512, 256), Dimensions::new(128, 64));
+        assert_eq!(thumb(100, 300), Dimensions::new(43, 128));
+        assert_eq!(thumb(4096, 1), Dimensions::new(128, 1));
+        // small enough already
+        assert_eq!(thumb(128, 32), Dimensions::new(128, 32));
+        assert_eq!(thumb(16, 16), Dimensions::new(16, 16));
+    }
+
+    #[test]
+    fn test_thumbnail_image() {
+        let red = Rgba([255, 0, 0, 255]);
+        let blue = Rgba([0, 0, 255, 255]);
+        let img = RgbaImage::from_fn(256, 128, |x, _| if x < 128 { red } else { blue });
+        let thumb = thumbnail_image(DynamicImage::ImageRgba8(img), 128).to_rgba8();
+        assert_eq!(Dimensions::of(&thumb), Dimensions::new(128, 64));
+        assert!(thumb.enumerate_pixels().all(|(x, _, px)| {
+            let expected = if x < 64 { red } else { blue };
+            *px == expected
+        }));
+    }
+
+    #[test]
+    fn test_downscale_rgba() {
+        let red = Rgba([255, 0, 0, 255]);
+        let blue = Rgba([0, 0, 255, 255]);
+
+        // 2x upscaled image goes back to the original
+        let orig = RgbaImage::from_fn(3, 2, |x, y| if (x + y) % 2 == 0 { red } else { blue });
+        let upscaled = image::imageops::resize(&orig, 6, 4, image::imageops::FilterType::Nearest);
+        assert_eq!(downscale_rgba(&upscaled, Dimensions::new(3, 2)), orig);
+
+        // the majority wins, and ties go to the top-left pixel
+        let mut img = RgbaImage::from_pixel(4, 2, red);
+        img.put_pixel(0, 0, blue);
+        img.put_pixel(0, 1, blue);
+        img.put_pixel(1, 1, blue);
+        img.put_pixel(2, 0, blue);
+        img.put_pixel(3, 1, blue);
+        let down = downscale_rgba(&img, Dimensions::new(2, 1));
+        assert_eq!(down.get_pixel(0, 0), &blue);
+        assert_eq!(down.get_pixel(1, 0), &blue);
+
+        // transparent pixels of any color count as one
+        let mut img = RgbaImage::from_pixel(3, 1, Rgba([1, 2, 3, 0]));
+        img.put_pixel(1, 0, Rgba([4, 5, 6, 0]));
+        img.put_pixel(2, 0, red);
+        let down = downscale_rgba(&img, Dimensions::new(1, 1));
+        assert_eq!(down.get_pixel(0, 0), &Rgba([0, 0, 0, 0]));
+    }
+}
@@ -0,0 +1,83 @@
+//! What the dyn worker can serve for a stored image on demand.
+//!
+//! Uploads respond with these capabilities as hints, so that clients build URLs of the dyn worker
+//! from what this deployment actually allows instead of hard-coding its policy.
+
+use serde::Serialize;
+
+use crate::{
+    animation::frames_allow_scale, dimensions::Dimensions, namespace::Limits,
+    transform::TRANSFORM_OPS,
+};
+
+/// Extensions of formats the dyn worker serves images in.
+pub const SERVED_FORMATS: [&str; 6] = ["png", "apng", "webp", "avif", "gif", "bmp"];
+
+/// Formats of `SERVED_FORMATS` which can't carry animations, so only still images are served in.
+pub const STILL_ONLY_FORMATS: [&str; 2] = ["avif", "bmp"];
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DynamicHints {
+    /// largest integer scale the image can be requested at (`{hash}_{N}x.{ext}`)
+    pub max_scale: u32,
+    /// extensions of formats the image can be requested in
+    pub formats: Vec<&'static str>,
+    /// number of frames, which can be requested one by one (`{hash}/frame/{n}.{ext}`)
+    pub frames: usize,
+    /// operations available to derive images from the image by `POST /images/{hash}/derive`
+    pub transforms: Vec<&'static str>,
+}
+
+impl DynamicHints {
+    /// Hints for an image of the dimensions and the number of frames (1 for still images),
+    /// stored with the limits. Images can only be derived from if `derivable`.
+    pub fn new(limits: &Limits, dims: Dimensions, frames: usize, derivable: bool) -> Self {
+        // scales are limited by pixels of upscaled images, which grow until they are not allowed
+        let max_scale = (1..)
+            .take_while(|&s| {
+                s == 1
+                    || (limits.allows_scaled_dimensions(dims, s)
+                        && (frames <= 1 || frames_allow_scale(dims, frames, s)))
+            })
+            .last()
+            .unwrap_or(1);
+        let formats = SERVED_FORMATS
+            .into_iter()
+            .filter(|f| frames <= 1 || !STILL_ONLY_FORMATS.contains(f))
+            .collect();
+        let transforms = match derivable {
+            true => TRANSFORM_OPS.to_vec(),
+            false => Vec::new(),
+        };
+        DynamicHints {
+            max_scale,
+            formats,
+            frames,
+            transforms,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_dynamic_hints() {
+        let limits = Limits::default();
+        // limited by pixels of the upscaled image, rather than by `max_scale`
+        let hints = DynamicHints::new(&limits, Dimensions::new(100, 50), 1, true);
+        assert_eq!(hints.max_scale, 14);
+        assert_eq!(hints.formats, SERVED_FORMATS);
+        assert_eq!(hints.transforms, TRANSFORM_OPS);
+
+        let hints = DynamicHints::new(&limits, Dimensions::new(16, 16), 1, false);
+        assert_eq!(hints.max_scale, 64);
+        assert!(hints.transforms.is_empty());
+
+        // animations are limited by pixels of all frames together
+        let hints = DynamicHints::new(&limits, Dimensions::new(64, 64), 256, true);
+        assert_eq!(hints.max_scale, 4);
+        assert!(!hints.formats.contains(&"avif"));
+    }
+}
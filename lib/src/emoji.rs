@@ -0,0 +1,62 @@
+//! Export to custom emoji for Slack/Discord.
+//!
+//! Both services accept square images up to 128×128 and 256 KB, so images are upscaled by the
+//! largest integer factor that fits (or shrunk if they are too large) and centered on a transparent
+//! square canvas.
+
+use image::{imageops, imageops::FilterType, DynamicImage, RgbaImage};
+
+use crate::dimensions::Dimensions;
+
+/// Length of the sides of emoji images.
+pub const EMOJI_SIZE: u32 = 128;
+
+/// Max size of emoji image data in bytes.
+pub const MAX_EMOJI_DATA_LEN: usize = 256 * 1024;
+
+/// Render the image as an emoji.
+pub fn render_emoji(img: &DynamicImage) -> DynamicImage {
+    let dims = Dimensions::of(img);
+
+    let fitted = if dims.long_side() <= EMOJI_SIZE {
+        let scale = EMOJI_SIZE / dims.long_side().max(1);
+        let fitted = dims.saturating_scale(scale);
+        img.resize(fitted.width, fitted.height, FilterType::Nearest)
+    } else {
+        // too large to be an emoji as is, shrink it while keeping the aspect ratio
+        img.resize(EMOJI_SIZE, EMOJI_SIZE, FilterType::Nearest)
+    };
+
+    let mut canvas = RgbaImage::new(EMOJI_SIZE, EMOJI_SIZE);
+    let x = (EMOJI_SIZE - fitted.width()) / 2;
+    let y = (EMOJI_SIZE - fitted.height()) / 2;
+    imageops::overlay(&mut canvas, &fitted.to_rgba8(), x.into(), y.into());
+    DynamicImage::ImageRgba8(canvas)
+}
+
+#[cfg(test)]
+mod test {
+    use image::{GenericImageView, Rgba};
+
+    use super::*;
+
+    #[test]
+    fn test_render_emoji() {
+        let mut src = RgbaImage::from_pixel(16, 8, Rgba([255, 0, 0, 255]));
+        src.put_pixel(0, 0, Rgba([0, 0, 255, 128]));
+        let emoji = render_emoji(&DynamicImage::ImageRgba8(src));
+        assert_eq!(emoji.dimensions(), (EMOJI_SIZE, EMOJI_SIZE));
+
+        // upscaled by 8 and centered vertically
+        assert_eq!(emoji.get_pixel(0, 31), Rgba([0, 0, 0, 0]));
+        assert_eq!(emoji.get_pixel(0, 32), Rgba([0, 0, 255, 128]));
+        assert_eq!(emoji.get_pixel(7, 39), Rgba([0, 0, 255, 128]));
+        assert_eq!(emoji.get_pixel(8, 32), Rgba([255, 0, 0, 255]));
+        assert_eq!(emoji.get_pixel(127, 95), Rgba([255, 0, 0, 255]));
+        assert_eq!(emoji.get_pixel(127, 96), Rgba([0, 0, 0, 0]));
+
+        let large = DynamicImage::ImageRgba8(RgbaImage::new(512, 256));
+        let emoji = render_emoji(&large);
+        assert_eq!(emoji.dimensions(), (EMOJI_SIZE, EMOJI_SIZE));
+    }
+}
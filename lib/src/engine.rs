@@ -0,0 +1,153 @@
+//! Import descriptors for game engines.
+//!
+//! Pixel art looks blurry in engines with their default import settings (bilinear filtering,
+//! mipmaps, lossy compression). A descriptor bundles settings that keep pixels crisp with the
+//! download URLs of the image and its pre-generated upscaled variants.
+
+use serde::Serialize;
+use serde_json::{json, Value};
+
+/// Default pixels-per-unit when not specified by the client.
+pub const DEFAULT_PIXELS_PER_UNIT: u32 = 16;
+pub const MAX_PIXELS_PER_UNIT: u32 = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Engine {
+    Unity,
+    Godot,
+}
+
+impl Engine {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "unity" => Some(Engine::Unity),
+            "godot" => Some(Engine::Godot),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Engine::Unity => "unity",
+            Engine::Godot => "godot",
+        }
+    }
+}
+
+/// A downloadable image file referred by a descriptor.
+#[derive(Debug, Clone, Serialize)]
+pub struct EngineFile {
+    pub scale: u32,
+    pub width: u32,
+    pub height: u32,
+    pub url: String,
+}
+
+/// Build the import descriptor of the files for the engine.
+///
+/// `files` must be sorted by scale; the pixels-per-unit is applied to each file relative to its
+/// scale, so that every variant has the same size in world units.
+pub fn import_descriptor(engine: Engine, pixels_per_unit: u32, files: &[EngineFile]) -> Value {
+    let files: Vec<Value> = files
+        .iter()
+        .map(|f| {
+            let ppu = pixels_per_unit * f.scale;
+            let settings = match engine {
+                // TextureImporter properties
+                Engine::Unity => json!({
+                    "textureType": "Sprite",
+                    "spriteMode": "Single",
+                    "spritePixelsPerUnit": ppu,
+                    "filterMode": "Point",
+                    "mipmapEnabled": false,
+                    "textureCompression": "Uncompressed",
+                    "wrapMode": "Clamp",
+                    "alphaIsTransparency": true,
+                }),
+                // params of the `texture` importer in `.import` files, and the matching
+                // Sprite3D properties (Godot has no pixels-per-unit in 2D)
+                Engine::Godot => json!({
+                    "importer": "texture",
+                    "params": {
+                        "compress/mode": 0,
+                        "mipmaps/generate": false,
+                        "process/fix_alpha_border": true,
+                    },
+                    "sprite_3d": {
+                        "texture_filter": 0,
+                        "pixel_size": 1.0 / f64::from(ppu),
+                    },
+                }),
+            };
+            json!({
+                "scale": f.scale,
+                "width": f.width,
+                "height": f.height,
+                "url": f.url,
+                "pixels_per_unit": ppu,
+                "import_settings": settings,
+            })
+        })
+        .collect();
+
+    let mut descriptor = json!({
+        "engine": engine.name(),
+        "filter": "point",
+        "mipmaps": false,
+        "pixels_per_unit": pixels_per_unit,
+        "files": files,
+    });
+    if engine == Engine::Godot {
+        // nearest filtering for all 2D textures is a project setting in Godot 4
+        descriptor["project_settings"] = json!({
+            "rendering/textures/canvas_textures/default_texture_filter": 0,
+        });
+    }
+    descriptor
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_import_descriptor() {
+        let files = [1, 2].map(|scale| EngineFile {
+            scale,
+            width: 16 * scale,
+            height: 8 * scale,
+            url: format!("https://example.com/abc_{}x.png", scale),
+        });
+
+        let unity = import_descriptor(Engine::Unity, 16, &files);
+        assert_eq!(unity["engine"], "unity");
+        assert_eq!(unity["files"][0]["import_settings"]["filterMode"], "Point");
+        assert_eq!(unity["files"][0]["import_settings"]["mipmapEnabled"], false);
+        assert_eq!(
+            unity["files"][1]["import_settings"]["spritePixelsPerUnit"],
+            32
+        );
+        assert!(unity.get("project_settings").is_none());
+
+        let godot = import_descriptor(Engine::Godot, 16, &files);
+        assert_eq!(
+            godot["files"][0]["import_settings"]["params"]["mipmaps/generate"],
+            false
+        );
+        assert_eq!(
+            godot["files"][1]["import_settings"]["sprite_3d"]["pixel_size"],
+            1.0 / 32.0
+        );
+        assert_eq!(
+            godot["project_settings"]["rendering/textures/canvas_textures/default_texture_filter"],
+            0
+        );
+    }
+
+    #[test]
+    fn test_engine_from_name() {
+        assert_eq!(Engine::from_name("unity"), Some(Engine::Unity));
+        assert_eq!(Engine::from_name("godot"), Some(Engine::Godot));
+        assert_eq!(Engine::from_name("Unity"), None);
+    }
+}
@@ -0,0 +1,102 @@
+//! Machine-readable codes of errors, sent along with their messages so that clients can branch on
+//! kinds of errors without parsing the messages.
+//!
+//! Errors are given specific codes where clients are likely to act on them (mostly rejections of
+//! uploaded images), and generic ones by their status otherwise. Codes are kept stable, while
+//! messages may change.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    // generic codes by status
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    MethodNotAllowed,
+    BodyTooLarge,
+    Unprocessable,
+    RateLimited,
+    Internal,
+    Unavailable,
+
+    // uploads
+    UnknownNamespace,
+    UnsupportedFormat,
+    DecodeFailed,
+    TooManyPixels,
+    LongSideTooLong,
+    AspectRatioOutOfRange,
+    ContentTooSmall,
+    TooFewColors,
+    TooManyFrames,
+    MultiFrameNotSupported,
+    ScaleTooLarge,
+    EncodeFailed,
+    StorageFailed,
+}
+
+impl ErrorCode {
+    /// The generic code of errors with the status.
+    pub fn of_status(status: u16) -> Self {
+        match status {
+            401 => ErrorCode::Unauthorized,
+            403 => ErrorCode::Forbidden,
+            404 => ErrorCode::NotFound,
+            405 => ErrorCode::MethodNotAllowed,
+            413 => ErrorCode::BodyTooLarge,
+            422 => ErrorCode::Unprocessable,
+            429 => ErrorCode::RateLimited,
+            503 => ErrorCode::Unavailable,
+            500.. => ErrorCode::Internal,
+            _ => ErrorCode::BadRequest,
+        }
+    }
+}
+
+/// Message of errors with the status which have none of their own: the reason phrase of the
+/// status.
+pub fn status_message(status: u16) -> &'static str {
+    match status {
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        422 => "Unprocessable Content",
+        429 => "Too Many Requests",
+        503 => "Service Unavailable",
+        500.. => "Internal Server Error",
+        _ => "Bad Request",
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let code = serde_json::to_value(self).map_err(|_| fmt::Error)?;
+        write!(f, "{}", code.as_str().unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_error_code() {
+        assert_eq!(ErrorCode::of_status(404), ErrorCode::NotFound);
+        assert_eq!(ErrorCode::of_status(400), ErrorCode::BadRequest);
+        assert_eq!(ErrorCode::of_status(502), ErrorCode::Internal);
+        assert_eq!(ErrorCode::TooManyPixels.to_string(), "TOO_MANY_PIXELS");
+        assert_eq!(status_message(404), "Not Found");
+        assert_eq!(status_message(502), "Internal Server Error");
+        assert_eq!(
+            serde_json::to_string(&ErrorCode::UnsupportedFormat).unwrap(),
+            "\"UNSUPPORTED_FORMAT\""
+        );
+    }
+}
@@ -0,0 +1,54 @@
+//! Validators of images served by the dyn worker, for clients to revalidate what they have with
+//! `If-None-Match` instead of downloading it again.
+//!
+//! Variants are rendered from immutable sources, so their entity tags are derived from the hash
+//! of the source and what is rendered of it, and requests can be answered with 304 before loading
+//! anything. `If-None-Match: *` matches any existing representation, so it is only answered once
+//! the source is known to exist.
+
+use crate::sha256_hex;
+
+/// Entity tag (quoted) of the variant of the source image with the hash. `variant` describes
+/// everything the output depends on besides the source, like the scale, the format and the
+/// cache epoch.
+pub fn variant_etag(hash: &str, variant: &str) -> String {
+    format!("\"{}-{}\"", hash, &sha256_hex(variant.as_bytes())[..16])
+}
+
+/// Whether the value of `If-None-Match` matches the entity tag, by the weak comparison. `*`
+/// matches only if the representation is known to exist.
+pub fn matches_if_none_match(if_none_match: &str, etag: &str, exists: bool) -> bool {
+    let strip_weak = |t: &str| t.strip_prefix("W/").unwrap_or(t).to_string();
+    let etag = strip_weak(etag);
+    if_none_match
+        .split(',')
+        .map(str::trim)
+        .any(|t| (t == "*" && exists) || strip_weak(t) == etag)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_variant_etag() {
+        let hash = "a".repeat(64);
+        let etag = variant_etag(&hash, "4x.png");
+        assert!(etag.starts_with(&format!("\"{}-", hash)));
+        assert!(etag.ends_with('"'));
+        assert_eq!(etag, variant_etag(&hash, "4x.png"));
+        assert_ne!(etag, variant_etag(&hash, "8x.png"));
+    }
+
+    #[test]
+    fn test_matches_if_none_match() {
+        let etag = "\"abc-123\"";
+        assert!(matches_if_none_match("\"abc-123\"", etag, false));
+        assert!(matches_if_none_match("W/\"abc-123\"", etag, false));
+        assert!(matches_if_none_match("\"xyz\", \"abc-123\"", etag, false));
+        assert!(matches_if_none_match("*", etag, true));
+        assert!(!matches_if_none_match("*", etag, false));
+        assert!(!matches_if_none_match("\"abc\"", etag, true));
+        assert!(!matches_if_none_match("abc-123", etag, true));
+    }
+}
@@ -0,0 +1,107 @@
+//! Typed extraction of path params and queries of requests to routes.
+//!
+//! Handlers take path params as the types parsed from them (like `Hash`) and queries as structs
+//! deserialized from them, instead of parsing strings by hand. Malformed params and
+//! queries are rejected with 400 the same way on every route. Unknown query params are ignored.
+
+use std::fmt;
+
+use serde::{de::DeserializeOwned, Deserialize, Deserializer};
+use worker::{Request, RouteContext};
+
+use crate::{is_valid_hash, ApiError, ApiResult};
+
+/// Values of path params (and of fields of queries).
+pub trait FromParam: Sized {
+    /// Parse the value, or tell what is wrong with it.
+    fn from_param(value: &str) -> Result<Self, String>;
+}
+
+/// Hash of an image (hex-encoded SHA-256 digest).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hash(pub String);
+
+impl FromParam for Hash {
+    fn from_param(value: &str) -> Result<Self, String> {
+        if !is_valid_hash(value) {
+            return Err("Invalid image hash".to_string());
+        }
+        Ok(Hash(value.to_string()))
+    }
+}
+
+impl fmt::Display for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+macro_rules! impl_deserialize_by_param {
+    ($($t:ty),*) => {$(
+        impl<'de> Deserialize<'de> for $t {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let s = String::deserialize(deserializer)?;
+                <$t>::from_param(&s).map_err(serde::de::Error::custom)
+            }
+        }
+    )*};
+}
+
+impl_deserialize_by_param!(Hash);
+
+/// The path param of the route, rejected with 400 if it is malformed.
+pub fn path_param<T: FromParam, D>(ctx: &RouteContext<D>, name: &str) -> ApiResult<T> {
+    let value = ctx
+        .param(name)
+        .ok_or_else(|| ApiError::new(400, format!("Missing '{}'", name)))?;
+    T::from_param(value).map_err(|e| ApiError::new(400, e))
+}
+
+/// The query of the request as the struct, rejected with 400 if it is malformed.
+pub fn query<T: DeserializeOwned>(req: &Request) -> ApiResult<T> {
+    let Ok(url) = req.url() else {
+        return Err(ApiError::no_msg(500));
+    };
+    parse_query(url.query().unwrap_or_default())
+}
+
+/// Parse the query string (without the leading `?`) as the struct.
+pub fn parse_query<T: DeserializeOwned>(query: &str) -> ApiResult<T> {
+    serde_urlencoded::from_str(query)
+        .map_err(|e| ApiError::new(400, format!("Invalid query: {}", e)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    struct TestQuery {
+        hash: Option<Hash>,
+        limit: Option<usize>,
+    }
+
+    #[test]
+    fn test_from_param() {
+        let hash = "a".repeat(64);
+        assert_eq!(Hash::from_param(&hash), Ok(Hash(hash.clone())));
+        assert!(Hash::from_param("abc").is_err());
+        assert!(Hash::from_param(&hash.to_uppercase()).is_err());
+    }
+
+    #[test]
+    fn test_parse_query() {
+        let hash = "a".repeat(64);
+        let q: TestQuery = parse_query(&format!("hash={}&limit=10&other=1", hash)).unwrap();
+        assert_eq!(q.hash, Some(Hash(hash)));
+        assert_eq!(q.limit, Some(10));
+
+        let q: TestQuery = parse_query("").unwrap();
+        assert!(q.hash.is_none() && q.limit.is_none());
+
+        let e = parse_query::<TestQuery>("hash=abc").unwrap_err();
+        assert_eq!(e.status(), 400);
+        assert!(e.message().unwrap().contains("Invalid image hash"));
+        assert!(parse_query::<TestQuery>("limit=many").is_err());
+    }
+}
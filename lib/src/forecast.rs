@@ -0,0 +1,262 @@
+//! Storage forecasts for operators, turning upload records and view counts into decisions.
+//!
+//! Growth of storage is projected from the sizes of variants recorded with uploads, by the
+//! average growth over the last `GROWTH_WINDOW_DAYS`, and priced at the rate of R2 Standard
+//! storage. Images uploaded before uploads were recorded are not counted, and neither are
+//! objects other than variants (manifests, for one), so forecasts are lower bounds.
+//!
+//! Large scales are pre-generated at upload but may be viewed rarely. When the scales from some
+//! scale up get far fewer of the views of variants than their share of storage, they are
+//! suggested to be generated on demand instead (see `namespace` for `cold_scale`).
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::stats::ScaleViews;
+
+pub const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+
+/// Days of a month of forecasts.
+const MONTH_DAYS: u64 = 30;
+
+/// Growth is averaged over this many last days.
+pub const GROWTH_WINDOW_DAYS: u64 = 30;
+
+/// Storage is projected this many months ahead.
+pub const PROJECTED_MONTHS: [u64; 4] = [1, 3, 6, 12];
+
+/// R2 Standard storage costs this many USD per GB-month...
+pub const STORAGE_USD_PER_GB_MONTH: f64 = 0.015;
+/// ...beyond this many GB of the free tier.
+pub const FREE_STORAGE_GB: f64 = 10.0;
+
+const BYTES_PER_GB: f64 = 1e9;
+
+/// Scales are suggested to be generated on demand when their share of views is less than this
+/// much of their share of storage...
+const MIN_VIEWS_PER_STORAGE: f64 = 0.1;
+/// ...as far as this many views of variants have been counted.
+const MIN_COUNTED_VIEWS: u64 = 1000;
+
+/// Bytes of the variants at a scale stored by the uploads of a day, aggregated from upload
+/// records.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct StoredBytes {
+    /// days since the unix epoch
+    pub day: u64,
+    /// absent for avatar images
+    pub scale: Option<u32>,
+    pub bytes: u64,
+    pub variants: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Forecast {
+    pub stored_bytes: u64,
+    pub monthly_cost_usd: f64,
+    /// average over the last `GROWTH_WINDOW_DAYS`
+    pub daily_growth_bytes: u64,
+    pub projections: Vec<Projection>,
+    /// by scale, smallest first
+    pub scales: Vec<ScaleUsage>,
+    pub suggestions: Vec<Suggestion>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Projection {
+    pub months: u64,
+    pub stored_bytes: u64,
+    pub monthly_cost_usd: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ScaleUsage {
+    pub scale: u32,
+    pub stored_bytes: u64,
+    pub variants: u64,
+    pub views: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Suggestion {
+    /// Machine-readable kind of the suggestion.
+    pub code: &'static str,
+    /// Scale from which variants are concerned.
+    pub min_scale: u32,
+    pub message: String,
+    pub monthly_saving_usd: f64,
+}
+
+/// Monthly cost of storing the bytes.
+pub fn monthly_cost_usd(bytes: u64) -> f64 {
+    let gb = bytes as f64 / BYTES_PER_GB;
+    (gb - FREE_STORAGE_GB).max(0.0) * STORAGE_USD_PER_GB_MONTH
+}
+
+/// Forecast of the storage on the day (since the unix epoch) from the stored bytes and the views
+/// of variants by scale.
+pub fn forecast(stored: &[StoredBytes], scale_views: &ScaleViews, today: u64) -> Forecast {
+    let stored_bytes = stored.iter().map(|s| s.bytes).sum();
+
+    // deployments younger than the window have grown for fewer days
+    let window_start = (today + 1).saturating_sub(GROWTH_WINDOW_DAYS);
+    let first_day = stored.iter().map(|s| s.day).min().unwrap_or(today);
+    let days = today.saturating_sub(first_day.max(window_start)) + 1;
+    let recent_bytes: u64 = stored
+        .iter()
+        .filter(|s| s.day >= window_start)
+        .map(|s| s.bytes)
+        .sum();
+    let daily_growth_bytes = recent_bytes / days;
+
+    let projections = PROJECTED_MONTHS
+        .iter()
+        .map(|&months| {
+            let bytes = stored_bytes + daily_growth_bytes * MONTH_DAYS * months;
+            Projection {
+                months,
+                stored_bytes: bytes,
+                monthly_cost_usd: monthly_cost_usd(bytes),
+            }
+        })
+        .collect();
+
+    let mut scales = BTreeMap::<u32, ScaleUsage>::new();
+    for s in stored {
+        let Some(scale) = s.scale else {
+            continue;
+        };
+        let usage = scales.entry(scale).or_insert_with(|| ScaleUsage {
+            scale,
+            stored_bytes: 0,
+            variants: 0,
+            views: scale_views.get(&scale).copied().unwrap_or_default(),
+        });
+        usage.stored_bytes += s.bytes;
+        usage.variants += s.variants;
+    }
+    let scales: Vec<_> = scales.into_values().collect();
+    let suggestions = suggest_on_demand(&scales, stored_bytes)
+        .into_iter()
+        .collect();
+
+    Forecast {
+        stored_bytes,
+        monthly_cost_usd: monthly_cost_usd(stored_bytes),
+        daily_growth_bytes,
+        projections,
+        scales,
+        suggestions,
+    }
+}
+
+/// Suggest generating the scales from the smallest one on demand, such that every scale from it
+/// up is viewed rarely for its storage. The original (1x) is always stored.
+fn suggest_on_demand(scales: &[ScaleUsage], stored_bytes: u64) -> Option<Suggestion> {
+    let total_bytes: u64 = scales.iter().map(|s| s.stored_bytes).sum();
+    let total_views: u64 = scales.iter().map(|s| s.views).sum();
+    if total_bytes == 0 || total_views < MIN_COUNTED_VIEWS {
+        return None;
+    }
+    let share = |n: u64, total: u64| n as f64 / total as f64;
+    let rarely_viewed = scales
+        .iter()
+        .rev()
+        .take_while(|s| {
+            s.scale > 1
+                && share(s.views, total_views)
+                    < share(s.stored_bytes, total_bytes) * MIN_VIEWS_PER_STORAGE
+        })
+        .collect::<Vec<_>>();
+    let min_scale = rarely_viewed.last()?.scale;
+    let bytes: u64 = rarely_viewed.iter().map(|s| s.stored_bytes).sum();
+    let views: u64 = rarely_viewed.iter().map(|s| s.views).sum();
+    Some(Suggestion {
+        code: "generate_on_demand",
+        min_scale,
+        message: format!(
+            "Variants at {}x and above take {:.1}% of the storage of variants but get {:.1}% of \
+             their views; consider generating them on demand with \"cold_scale\": {} and \
+             \"cold_storage\": \"on_demand\"",
+            min_scale,
+            share(bytes, total_bytes) * 100.0,
+            share(views, total_views) * 100.0,
+            min_scale
+        ),
+        monthly_saving_usd: monthly_cost_usd(stored_bytes)
+            - monthly_cost_usd(stored_bytes.saturating_sub(bytes)),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const GB: u64 = 1_000_000_000;
+
+    fn stored(day: u64, scale: u32, bytes: u64) -> StoredBytes {
+        StoredBytes {
+            day,
+            scale: Some(scale),
+            bytes,
+            variants: 1,
+        }
+    }
+
+    #[test]
+    fn test_monthly_cost() {
+        assert_eq!(monthly_cost_usd(0), 0.0);
+        assert_eq!(monthly_cost_usd(10 * GB), 0.0);
+        assert!((monthly_cost_usd(110 * GB) - 1.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_forecast_growth() {
+        // 60 GB before the window, and 30 GB in the window until today
+        let rows: Vec<_> = (0..30)
+            .map(|d| stored(100 + d, 1, GB))
+            .chain([stored(10, 1, 60 * GB)])
+            .collect();
+        let f = forecast(&rows, &ScaleViews::new(), 129);
+        assert_eq!(f.stored_bytes, 90 * GB);
+        assert_eq!(f.daily_growth_bytes, GB);
+        assert_eq!(f.projections[0].months, 1);
+        assert_eq!(f.projections[0].stored_bytes, 120 * GB);
+        assert_eq!(f.projections[3].stored_bytes, 450 * GB);
+        assert!((f.projections[0].monthly_cost_usd - 1.65).abs() < 1e-9);
+
+        // young deployments grow over their days so far
+        let f = forecast(&[stored(120, 1, 10 * GB)], &ScaleViews::new(), 129);
+        assert_eq!(f.daily_growth_bytes, GB);
+        assert_eq!(forecast(&[], &ScaleViews::new(), 129).daily_growth_bytes, 0);
+    }
+
+    #[test]
+    fn test_suggest_on_demand() {
+        let rows = [
+            stored(0, 1, GB),
+            stored(0, 2, 2 * GB),
+            stored(0, 4, 4 * GB),
+            stored(0, 8, 8 * GB),
+            stored(0, 16, 16 * GB),
+        ];
+        let views = ScaleViews::from([(1, 5000), (2, 3000), (4, 1950), (8, 40), (16, 10)]);
+        let f = forecast(&rows, &views, 0);
+        assert_eq!(f.scales.len(), 5);
+        assert_eq!(f.scales[3].views, 40);
+        assert_eq!(f.suggestions.len(), 1);
+        let suggestion = &f.suggestions[0];
+        assert_eq!(suggestion.min_scale, 8);
+        // 31 GB are stored and 24 GB of them are saved, of which 21 GB are beyond the free tier
+        assert!((suggestion.monthly_saving_usd - 21.0 * STORAGE_USD_PER_GB_MONTH).abs() < 1e-9);
+
+        // higher scales still viewed keep lower ones
+        let views = ScaleViews::from([(1, 5000), (2, 3000), (4, 1950), (8, 40), (16, 5000)]);
+        assert!(forecast(&rows, &views, 0).suggestions.is_empty());
+
+        // too few views to tell
+        let views = ScaleViews::from([(1, 500)]);
+        assert!(forecast(&rows, &views, 0).suggestions.is_empty());
+    }
+}
@@ -0,0 +1,169 @@
+//! Summary of background jobs for operators (`GET /admin/jobs`), so that backlogs and failures
+//! are seen at a glance.
+//!
+//! Jobs are of two sources: asynchronous uploads, whose states are stored under `_jobs/` in the
+//! bucket (see api/src/jobs.rs), and jobs of the variant queue, whose failures are recorded in
+//! the `failed_jobs` table of D1 (see api/src/variant_queue.rs). Jobs of the queue which succeed
+//! at the first attempt leave no trace, so they are not counted.
+
+use serde::Serialize;
+
+const HOUR_MS: u64 = 60 * 60 * 1000;
+
+/// Windows jobs are counted over, by when their states last changed.
+pub const SUMMARY_WINDOWS: [(&str, u64); 3] = [
+    ("1h", HOUR_MS),
+    ("24h", 24 * HOUR_MS),
+    ("7d", 7 * 24 * HOUR_MS),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobStatus {
+    /// A job of the queue failed, and waits in the queue to be retried.
+    Queued,
+    /// An upload is being processed.
+    InProgress,
+    /// An upload has been processed.
+    Done,
+    /// An upload failed, or a job of the queue was dead-lettered.
+    Failed,
+    /// A dead-lettered job of the queue was sent again by an operator.
+    Retried,
+}
+
+impl JobStatus {
+    /// Status of an upload job by the name of its state.
+    pub fn of_upload_state(state: &str) -> Option<Self> {
+        match state {
+            "processing" => Some(JobStatus::InProgress),
+            "done" => Some(JobStatus::Done),
+            "failed" => Some(JobStatus::Failed),
+            _ => None,
+        }
+    }
+
+    /// Status of a job of the queue by its status in the `failed_jobs` table.
+    pub fn of_failed_job(status: &str) -> Option<Self> {
+        match status {
+            "retrying" => Some(JobStatus::Queued),
+            "dead" => Some(JobStatus::Failed),
+            "retried" => Some(JobStatus::Retried),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct JobCounts {
+    pub queued: u64,
+    pub in_progress: u64,
+    pub done: u64,
+    pub failed: u64,
+    pub retried: u64,
+}
+
+impl JobCounts {
+    fn add(&mut self, status: JobStatus) {
+        let count = match status {
+            JobStatus::Queued => &mut self.queued,
+            JobStatus::InProgress => &mut self.in_progress,
+            JobStatus::Done => &mut self.done,
+            JobStatus::Failed => &mut self.failed,
+            JobStatus::Retried => &mut self.retried,
+        };
+        *count += 1;
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct WindowCounts {
+    pub window: &'static str,
+    #[serde(flatten)]
+    pub counts: JobCounts,
+}
+
+/// Count jobs by status in each of `SUMMARY_WINDOWS` until `now_ms`, given the statuses of jobs
+/// with when they last changed (unix time in milliseconds).
+pub fn count_jobs(jobs: &[(JobStatus, u64)], now_ms: u64) -> Vec<WindowCounts> {
+    SUMMARY_WINDOWS
+        .iter()
+        .map(|&(window, len_ms)| {
+            let since = now_ms.saturating_sub(len_ms);
+            let mut counts = JobCounts::default();
+            for &(status, _) in jobs.iter().filter(|(_, at)| *at > since) {
+                counts.add(status);
+            }
+            WindowCounts { window, counts }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_job_status() {
+        assert_eq!(
+            JobStatus::of_upload_state("processing"),
+            Some(JobStatus::InProgress)
+        );
+        assert_eq!(
+            JobStatus::of_upload_state("failed"),
+            Some(JobStatus::Failed)
+        );
+        assert_eq!(JobStatus::of_upload_state("retrying"), None);
+        assert_eq!(
+            JobStatus::of_failed_job("retrying"),
+            Some(JobStatus::Queued)
+        );
+        assert_eq!(JobStatus::of_failed_job("dead"), Some(JobStatus::Failed));
+        assert_eq!(
+            JobStatus::of_failed_job("retried"),
+            Some(JobStatus::Retried)
+        );
+        assert_eq!(JobStatus::of_failed_job("done"), None);
+    }
+
+    #[test]
+    fn test_count_jobs() {
+        let now = 10 * 24 * HOUR_MS;
+        let jobs = [
+            (JobStatus::InProgress, now - 1),
+            (JobStatus::Queued, now - HOUR_MS + 1),
+            (JobStatus::Done, now - HOUR_MS),
+            (JobStatus::Failed, now - 2 * HOUR_MS),
+            (JobStatus::Retried, now - 2 * 24 * HOUR_MS),
+            (JobStatus::Done, now - 8 * 24 * HOUR_MS),
+        ];
+        let counts = count_jobs(&jobs, now);
+        assert_eq!(
+            counts.iter().map(|c| c.window).collect::<Vec<_>>(),
+            ["1h", "24h", "7d"]
+        );
+        assert_eq!(
+            counts[0].counts,
+            JobCounts {
+                queued: 1,
+                in_progress: 1,
+                ..JobCounts::default()
+            }
+        );
+        assert_eq!(
+            counts[1].counts,
+            JobCounts {
+                queued: 1,
+                in_progress: 1,
+                done: 1,
+                failed: 1,
+                retried: 0,
+            }
+        );
+        assert_eq!(counts[2].counts.retried, 1);
+        assert_eq!(counts[2].counts.done, 1);
+
+        let json = serde_json::to_value(&counts[0]).unwrap();
+        assert_eq!(json["window"], "1h");
+        assert_eq!(json["in_progress"], 1);
+    }
+}
@@ -1,10 +1,74 @@
 use std::io::Cursor;
 
-use image::{imageops::FilterType, DynamicImage, GenericImageView, ImageError, ImageFormat};
+use image::{imageops::FilterType, DynamicImage, ImageError, ImageFormat};
 use serde_json::json;
 use sha2::{Digest, Sha256};
 use worker::{Response, Result as WorkerResult};
 
+use dimensions::Dimensions;
+use error_code::{status_message, ErrorCode};
+
+pub mod access_log;
+pub mod accessibility;
+pub mod analytics;
+pub mod animation;
+pub mod annotation;
+pub mod auth;
+pub mod avatar;
+pub mod blob;
+pub mod bulk_delete;
+pub mod cache_epoch;
+pub mod cache_policy;
+pub mod canonical;
+pub mod circuit;
+pub mod cleanup;
+pub mod color_key;
+pub mod color_vision;
+pub mod compare;
+pub mod config;
+pub mod content;
+pub mod content_hash;
+pub mod cors;
+pub mod crop;
+pub mod data_url;
+pub mod debug;
+pub mod delta;
+pub mod dimensions;
+pub mod downscale;
+pub mod dynamic;
+pub mod emoji;
+pub mod engine;
+pub mod error_code;
+pub mod etag;
+pub mod extract;
+pub mod forecast;
+pub mod job_summary;
+pub mod manifest;
+pub mod namespace;
+pub mod notify;
+pub mod orientation;
+pub mod pack;
+pub mod panic;
+pub mod pipeline;
+pub mod playback;
+pub mod presign;
+pub mod quality;
+pub mod quantize;
+pub mod rate_limit;
+pub mod route_guard;
+pub mod schema;
+pub mod stats;
+pub mod tags;
+pub mod tenant;
+pub mod tilemap;
+pub mod transform;
+pub mod upload_index;
+pub mod upload_lock;
+pub mod upload_token;
+pub mod variant;
+pub mod variant_queue;
+pub mod warning;
+
 /// Encode the `DynamicImage` into a `dest` buffer with the given format.
 pub fn encode_image(
     img: &DynamicImage,
@@ -15,46 +79,152 @@ pub fn encode_image(
     img.write_to(&mut buf, img_fmt)
 }
 
+/// Encode the `DynamicImage` as PNG into a `dest` buffer, deterministically if `deterministic`.
+///
+/// Deterministic output uses fixed encoder settings (compression level, a single filter, 8-bit
+/// color type of the image) and has only critical chunks, without timestamps, so the same pixels
+/// always give the same bytes as far as the `png` crate pinned in `Cargo.lock` is the same. Stored
+/// objects are encoded so, and thus hashes of derived images are stable and replicas can be
+/// compared byte by byte.
+pub fn encode_png(
+    img: &DynamicImage,
+    dest: &mut Vec<u8>,
+    deterministic: bool,
+) -> Result<(), ImageError> {
+    if !deterministic {
+        return encode_image(img, ImageFormat::Png, dest);
+    }
+    let (color, data) = match img {
+        DynamicImage::ImageLuma8(i) => (png::ColorType::Grayscale, i.as_raw().clone()),
+        DynamicImage::ImageLumaA8(i) => (png::ColorType::GrayscaleAlpha, i.as_raw().clone()),
+        DynamicImage::ImageRgb8(i) => (png::ColorType::Rgb, i.as_raw().clone()),
+        img => (png::ColorType::Rgba, img.to_rgba8().into_raw()),
+    };
+    let mut encoder = png::Encoder::new(dest, img.width(), img.height());
+    set_deterministic_png_options(&mut encoder, color);
+    let to_image_error = |e: png::EncodingError| ImageError::IoError(std::io::Error::other(e));
+    let mut writer = encoder.write_header().map_err(to_image_error)?;
+    writer.write_image_data(&data).map_err(to_image_error)?;
+    writer.finish().map_err(to_image_error)
+}
+
+/// Fixed settings of the PNG encoder for deterministic output.
+pub(crate) fn set_deterministic_png_options<W: std::io::Write>(
+    encoder: &mut png::Encoder<W>,
+    color: png::ColorType,
+) {
+    encoder.set_color(color);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_compression(png::Compression::Default);
+    encoder.set_filter(png::FilterType::Paeth);
+    encoder.set_adaptive_filter(png::AdaptiveFilterType::NonAdaptive);
+}
+
 /// Upscale the image by a given scale factor and return it as a brand new `DynamicImage`.
 pub fn upscale_image(img: &DynamicImage, scale: u32) -> DynamicImage {
-    let (w, h) = img.dimensions();
-    img.resize(w * scale, h * scale, FilterType::Nearest)
+    let dims = Dimensions::of(img).saturating_scale(scale);
+    img.resize(dims.width, dims.height, FilterType::Nearest)
 }
 
 #[derive(Debug)]
 pub struct ApiError {
     status: u16,
+    /// specific code of the error, instead of the generic one of the status
+    code: Option<ErrorCode>,
     message: Option<String>,
+    headers: Vec<(&'static str, String)>,
 }
 
 impl ApiError {
     pub fn new(status: u16, msg: impl Into<String>) -> Self {
         Self {
             status,
+            code: None,
             message: Some(msg.into()),
+            headers: Vec::new(),
         }
     }
     pub fn no_msg(status: u16) -> Self {
         Self {
             status,
+            code: None,
             message: None,
+            headers: Vec::new(),
         }
     }
 
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        self.code
+            .unwrap_or_else(|| ErrorCode::of_status(self.status))
+    }
+
+    /// Give the error a specific code (see `error_code`).
+    pub fn with_code(mut self, code: ErrorCode) -> Self {
+        self.code = Some(code);
+        self
+    }
+
+    pub fn message(&self) -> Option<&str> {
+        self.message.as_deref()
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Add the header to the error response, like `Retry-After` of 429s.
+    pub fn with_header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    /// Respond with the error as `{code, message}`, with the reason phrase of the status as the
+    /// message of errors without one.
     pub fn to_response(&self) -> WorkerResult<Response> {
-        let r = match &self.message {
-            None => Response::empty(),
-            Some(msg) => Response::from_json(&json!({ "message": msg })),
-        };
-        r.map(|r| r.with_status(self.status))
+        let message = self
+            .message
+            .as_deref()
+            .unwrap_or_else(|| status_message(self.status));
+        let mut r = Response::from_json(&json!({ "message": message, "code": self.code() }))?
+            .with_status(self.status);
+        for (name, value) in &self.headers {
+            r.headers_mut().set(name, value)?;
+        }
+        Ok(r)
     }
 }
 
 pub type ApiResult<T> = std::result::Result<T, ApiError>;
 
+/// Format of images of the MIME type. APNG has its own type, but is a PNG to decoders.
+pub fn image_format_from_mime_type(mime: &str) -> Option<ImageFormat> {
+    match mime {
+        "image/apng" => Some(ImageFormat::Png),
+        mime => ImageFormat::from_mime_type(mime),
+    }
+}
+
 /// Calculate the SHA-256 hash of the given data and convert it to a hex string.
 pub fn sha256_hex(data: &[u8]) -> String {
     let mut hasher = Sha256::new();
     hasher.update(data);
     hex::encode(hasher.finalize())
 }
+
+/// Calculate the HMAC-SHA256 of the given data keyed with the secret and convert it to a hex
+/// string.
+pub fn hmac_sha256_hex(secret: &[u8], data: &str) -> String {
+    hex::encode(presign::hmac_sha256(secret, data))
+}
+
+/// Check if the string is a valid image hash (hex-encoded SHA-256 digest).
+pub fn is_valid_hash(s: &str) -> bool {
+    s.len() == 64 && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
@@ -0,0 +1,116 @@
+//! Manifests of stored images.
+//!
+//! Each upload stores `{hash}.manifest.json` next to the original, listing the original and all
+//! its variants with their sizes and checksums. It is the source of truth for what should exist
+//! for an image: consistency checks compare the bucket against it, and bundle downloads read it
+//! instead of listing the bucket.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    blob::{BlobError, BlobStore},
+    sha256_hex,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub hash: String,
+    /// The original and its variants, in the order they were generated.
+    pub objects: Vec<ManifestEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// File name of the object, relative to the prefix of keys the manifest is stored under.
+    pub name: String,
+    /// Size of the object in bytes.
+    pub size: u64,
+    /// Hex-encoded SHA-256 digest of the object.
+    pub sha256: String,
+    /// Whether the object is in the cold bucket (see `namespace::ColdStorage`), under the same key.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub cold: bool,
+}
+
+impl ManifestEntry {
+    pub fn new(name: impl Into<String>, data: &[u8]) -> Self {
+        Self {
+            name: name.into(),
+            size: data.len() as u64,
+            sha256: sha256_hex(data),
+            cold: false,
+        }
+    }
+
+    /// Whether the data matches the entry.
+    pub fn matches(&self, data: &[u8]) -> bool {
+        self.size == data.len() as u64 && self.sha256 == sha256_hex(data)
+    }
+}
+
+/// File name of the manifest of the image with the hash.
+pub fn manifest_file_name(hash: &str) -> String {
+    format!("{}.manifest.json", hash)
+}
+
+/// Store the manifest under the `key_prefix`.
+pub async fn store_manifest(
+    store: &impl BlobStore,
+    key_prefix: &str,
+    manifest: &Manifest,
+) -> Result<(), BlobError> {
+    let data = serde_json::to_vec(manifest)
+        .map_err(|e| BlobError(format!("failed to serialize manifest: {}", e)))?;
+    let key = format!("{}{}", key_prefix, manifest_file_name(&manifest.hash));
+    store.store(&key, data, "application/json").await
+}
+
+/// Load the manifest of the image with the hash from the `key_prefix`, if any.
+pub async fn load_manifest(
+    store: &impl BlobStore,
+    key_prefix: &str,
+    hash: &str,
+) -> Result<Option<Manifest>, BlobError> {
+    let key = format!("{}{}", key_prefix, manifest_file_name(hash));
+    let Some(data) = store.load(&key).await? else {
+        return Ok(None);
+    };
+    serde_json::from_slice(&data)
+        .map(Some)
+        .map_err(|e| BlobError(format!("invalid manifest {}: {}", key, e)))
+}
+
+#[cfg(test)]
+mod test {
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::blob::FsStore;
+
+    #[test]
+    fn test_manifest_roundtrip() {
+        let root = std::env::temp_dir().join(format!("upix-manifest-{}", std::process::id()));
+        let store = FsStore::new(&root);
+
+        let manifest = Manifest {
+            hash: "abc".to_string(),
+            objects: vec![
+                ManifestEntry::new("abc.png", b"orig"),
+                ManifestEntry::new("abc_2x.png", b"upscaled"),
+            ],
+        };
+        block_on(store_manifest(&store, "ns/", &manifest)).unwrap();
+        assert!(root.join("ns/abc.manifest.json").exists());
+        assert_eq!(
+            block_on(load_manifest(&store, "ns/", "abc")).unwrap(),
+            Some(manifest.clone())
+        );
+        assert_eq!(block_on(load_manifest(&store, "", "abc")).unwrap(), None);
+
+        assert!(manifest.objects[0].matches(b"orig"));
+        assert!(!manifest.objects[0].matches(b"orig!"));
+        assert!(!manifest.objects[0].matches(b"ori0"));
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
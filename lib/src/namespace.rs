@@ -0,0 +1,328 @@
+//! Path-prefix based image namespaces.
+//!
+//! Namespaces let an operator host distinct asset classes (`/game/…`, `/avatars/…`) with their
+//! own limits. They are configured by the `NAMESPACES` var, a JSON object mapping namespace names
+//! to (partial) limits, like:
+//!
+//! ```json
+//! { "avatars": { "max_long_side_len": 128, "allowed_formats": ["png"], "max_scale": 4 } }
+//! ```
+//!
+//! Images uploaded to `POST /{name}` are stored under `{name}/` and served from `/{name}/…` by
+//! the dyn worker. Images outside of any namespace (in the root namespace) use the root limits:
+//! the defaults, with some of them overridden by the `MAX_DATA_LEN`, `MAX_PIXELS`,
+//! `MAX_LONG_SIDE_LEN` and `MAX_ASPECT_RATIO` vars (like for a more permissive staging
+//! deployment). Limits of namespaces not given in `NAMESPACES` are the root ones.
+//!
+//! Large variants are viewed the least but take the most space, so they can be kept apart from
+//! the original: `{ "cold_scale": 8 }` stores variants at 8x and above in the `COLD_IMGS_BUCKET`
+//! bucket (e.g. one of the Infrequent Access storage class), and `"cold_storage": "on_demand"`
+//! doesn't store them at all. The dyn worker serves them either way, generating them if needed.
+
+use std::collections::HashMap;
+
+use image::ImageFormat;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use worker::Env;
+
+use crate::{config::Config, dimensions::Dimensions};
+
+/// Scale factors of upscaled images pre-generated at upload, as far as limits allow.
+pub const SCALE_LADDER: [u32; 5] = [1, 2, 4, 8, 16];
+
+/// Binding of the bucket of variants in cold storage. Without it, they are kept with the original.
+pub const COLD_BUCKET_BINDING: &str = "COLD_IMGS_BUCKET";
+
+/// Where variants at `Limits::cold_scale` and above are kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ColdStorage {
+    /// In the cold bucket.
+    #[default]
+    Bucket,
+    /// Nowhere: they are generated on demand by the dyn worker.
+    OnDemand,
+}
+
+/// Limits applied to images.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Limits {
+    /// Max size of uploaded image data in bytes that are processed in the upload request.
+    pub max_data_len: usize,
+    /// Max size of uploaded image data in bytes that are accepted. Data larger than
+    /// `max_data_len` are processed after responding with `202 Accepted`. At most `max_data_len`
+    /// (the default) processes all uploads synchronously.
+    pub max_async_data_len: usize,
+    /// Max number of pixels of source images.
+    pub max_pixels: u32,
+    /// Max length of the long side of source images.
+    pub max_long_side_len: u32,
+    /// Max ratio of the long side to the short side of source images.
+    pub max_aspect_ratio: f64,
+    /// Extensions of accepted source image formats.
+    pub allowed_formats: Vec<String>,
+    /// Max scale factor of upscaled images pre-generated at upload.
+    pub max_scale: u32,
+    /// Max length of the long side of upscaled images pre-generated at upload.
+    pub max_scaled_side_len: u32,
+    /// Max number of pixels of upscaled images generated on demand, which may be at any scale.
+    pub max_scaled_pixels: u64,
+    /// Min length of each side of source images, after trimming transparent borders.
+    pub min_side_len: u32,
+    /// Min number of distinct colors of source images (transparency counts as a color).
+    pub min_colors: usize,
+    /// Min scale factor of variants kept in cold storage. All variants are kept with the original
+    /// if unset, and the original always is.
+    pub cold_scale: Option<u32>,
+    pub cold_storage: ColdStorage,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_data_len: 512 * 1024,
+            max_async_data_len: 0,
+            max_pixels: 65536,
+            max_long_side_len: 1024,
+            max_aspect_ratio: 16.0,
+            allowed_formats: ["png", "webp", "bmp", "gif", "jpeg"]
+                .map(String::from)
+                .to_vec(),
+            max_scale: 16,
+            max_scaled_side_len: 1024,
+            max_scaled_pixels: 1024 * 1024,
+            min_side_len: 1,
+            min_colors: 1,
+            cold_scale: None,
+            cold_storage: ColdStorage::default(),
+        }
+    }
+}
+
+impl Limits {
+    /// Max size of uploaded image data that are accepted at all, synchronously or not.
+    pub fn max_accepted_data_len(&self) -> usize {
+        self.max_data_len.max(self.max_async_data_len)
+    }
+
+    pub fn allows_format(&self, img_fmt: ImageFormat) -> bool {
+        img_fmt
+            .extensions_str()
+            .iter()
+            .any(|ext| self.allowed_formats.iter().any(|f| f == ext))
+    }
+
+    /// Whether an image whose long side is `long_side` can be upscaled by `scale` at upload.
+    pub fn allows_scale(&self, long_side: u32, scale: u32) -> bool {
+        scale <= self.max_scale
+            && long_side
+                .checked_mul(scale)
+                .is_some_and(|l| l <= self.max_scaled_side_len)
+    }
+
+    /// Whether an image of the dimensions can be upscaled by `scale` on demand. Unlike
+    /// `allows_scale`, only the number of pixels of the upscaled image is limited, so small images
+    /// can be upscaled beyond `max_scale`.
+    pub fn allows_scaled_dimensions(&self, dims: Dimensions, scale: u32) -> bool {
+        scale >= 1
+            && dims
+                .checked_scale(scale)
+                .is_some_and(|d| d.pixels() <= self.max_scaled_pixels)
+    }
+
+    /// Where the variant at the scale is kept, or `None` if it is kept with the original.
+    pub fn cold_storage_of(&self, scale: u32) -> Option<ColdStorage> {
+        self.cold_scale
+            .filter(|&min| scale > 1 && scale >= min)
+            .map(|_| self.cold_storage)
+    }
+
+    /// Scale factors pre-generated for an image whose long side is `long_side`.
+    /// The original (1x) is always included.
+    pub fn pregenerated_scales(&self, long_side: u32) -> Vec<u32> {
+        SCALE_LADDER
+            .into_iter()
+            .take_while(|&x| x == 1 || self.allows_scale(long_side, x))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Namespace {
+    /// Name of the namespace. Empty for the root namespace.
+    pub name: String,
+    pub limits: Limits,
+}
+
+impl Namespace {
+    /// Prefix of keys of images in the namespace, relative to the tenant's prefix.
+    pub fn key_prefix(&self) -> String {
+        if self.name.is_empty() {
+            String::new()
+        } else {
+            format!("{}/", self.name)
+        }
+    }
+}
+
+/// Name of the segment of keys under which objects of tenants are (see `tenant`), which a
+/// namespace can't take, or keys of its images would be taken for those of a tenant.
+const TENANTS_SEGMENT: &str = "tenants";
+
+pub fn is_valid_namespace_name(name: &str) -> bool {
+    name != TENANTS_SEGMENT
+        && !name.is_empty()
+        && name.len() <= 32
+        && name
+            .bytes()
+            .all(|b| matches!(b, b'a'..=b'z' | b'0'..=b'9' | b'-'))
+}
+
+/// Parse namespace configs from JSON. Namespaces with invalid names are skipped.
+pub fn parse_namespaces(json: &str, root: &Limits) -> serde_json::Result<HashMap<String, Limits>> {
+    let namespaces: HashMap<String, serde_json::Map<String, Value>> = serde_json::from_str(json)?;
+    let root = serde_json::to_value(root)?;
+    namespaces
+        .into_iter()
+        .filter(|(name, _)| is_valid_namespace_name(name))
+        .map(|(name, overrides)| {
+            let mut limits = root.clone();
+            if let Value::Object(fields) = &mut limits {
+                fields.extend(overrides);
+            }
+            Ok((name, serde_json::from_value(limits)?))
+        })
+        .collect()
+}
+
+/// Limits of the root namespace.
+pub fn root_limits(env: &Env) -> Limits {
+    Config::from_env(env)
+        .map(|c| c.root_limits.clone())
+        .unwrap_or_default()
+}
+
+/// Look up the namespace by name. `None` is the root namespace.
+///
+/// Returns `None` if the named namespace is not configured.
+pub fn find_namespace(env: &Env, name: Option<&str>) -> Option<Namespace> {
+    let Some(name) = name else {
+        return Some(Namespace {
+            name: String::new(),
+            limits: root_limits(env),
+        });
+    };
+    let limits = Config::from_env(env).ok()?.namespaces.get(name)?;
+    Some(Namespace {
+        name: name.to_string(),
+        limits: limits.clone(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_namespaces() {
+        let json = r#"{
+            "avatars": { "max_long_side_len": 128, "allowed_formats": ["png"], "max_scale": 4 },
+            "game": {},
+            "Invalid Name": {},
+            "tenants": {}
+        }"#;
+        let namespaces = parse_namespaces(json, &Limits::default()).unwrap();
+        assert_eq!(namespaces.len(), 2);
+        // taken by keys of tenants
+        assert!(!is_valid_namespace_name("tenants"));
+        assert!(is_valid_namespace_name("tenants-art"));
+
+        let avatars = &namespaces["avatars"];
+        assert_eq!(avatars.max_long_side_len, 128);
+        assert_eq!(avatars.max_scale, 4);
+        assert_eq!(avatars.max_pixels, Limits::default().max_pixels);
+        assert!(avatars.allows_format(ImageFormat::Png));
+        assert!(!avatars.allows_format(ImageFormat::Gif));
+
+        assert_eq!(namespaces["game"], Limits::default());
+
+        // limits not given are the root ones
+        let root = Limits {
+            max_pixels: 1 << 20,
+            ..Limits::default()
+        };
+        let namespaces = parse_namespaces(json, &root).unwrap();
+        assert_eq!(namespaces["avatars"].max_pixels, 1 << 20);
+        assert_eq!(namespaces["avatars"].max_scale, 4);
+        assert_eq!(namespaces["game"], root);
+        assert!(parse_namespaces(r#"{ "game": { "max_scale": "big" } }"#, &root).is_err());
+    }
+
+    #[test]
+    fn test_max_accepted_data_len() {
+        // async processing is disabled by default
+        let limits = Limits::default();
+        assert_eq!(limits.max_accepted_data_len(), limits.max_data_len);
+
+        let limits = Limits {
+            max_async_data_len: 2 * 1024 * 1024,
+            ..Limits::default()
+        };
+        assert_eq!(limits.max_accepted_data_len(), limits.max_async_data_len);
+    }
+
+    #[test]
+    fn test_allows_scale() {
+        let limits = Limits::default();
+        assert!(limits.allows_scale(64, 16));
+        assert!(!limits.allows_scale(64, 17));
+        assert!(limits.allows_scale(512, 2));
+        assert!(!limits.allows_scale(513, 2));
+        assert!(!limits.allows_scale(u32::MAX, 2));
+
+        assert_eq!(limits.pregenerated_scales(64), vec![1, 2, 4, 8, 16]);
+        assert_eq!(limits.pregenerated_scales(100), vec![1, 2, 4, 8]);
+        assert_eq!(limits.pregenerated_scales(1024), vec![1]);
+    }
+
+    #[test]
+    fn test_allows_scaled_dimensions() {
+        let limits = Limits::default();
+        // scales out of the ladder, and beyond `max_scale`, are allowed as far as pixels allow
+        assert!(limits.allows_scaled_dimensions(Dimensions::new(64, 8), 3));
+        assert!(limits.allows_scaled_dimensions(Dimensions::new(64, 64), 16));
+        assert!(limits.allows_scaled_dimensions(Dimensions::new(1, 1), 17));
+        assert!(limits.allows_scaled_dimensions(Dimensions::new(1, 1), 1024));
+        assert!(!limits.allows_scaled_dimensions(Dimensions::new(1, 1), 1025));
+        assert!(!limits.allows_scaled_dimensions(Dimensions::new(1, 1), 0));
+        assert!(!limits.allows_scaled_dimensions(Dimensions::new(2, 1), u32::MAX));
+
+        let limits = Limits {
+            max_scaled_pixels: 1000,
+            ..Limits::default()
+        };
+        assert!(limits.allows_scaled_dimensions(Dimensions::new(10, 10), 3));
+        assert!(!limits.allows_scaled_dimensions(Dimensions::new(10, 10), 4));
+        assert!(!limits.allows_scaled_dimensions(Dimensions::new(1, 1), 9999));
+    }
+
+    #[test]
+    fn test_cold_storage_of() {
+        let limits = Limits::default();
+        assert_eq!(limits.cold_storage_of(16), None);
+
+        let json = r#"{ "cold": { "cold_scale": 8 }, "lazy": { "cold_scale": 1, "cold_storage": "on_demand" } }"#;
+        let namespaces = parse_namespaces(json, &Limits::default()).unwrap();
+        let cold = &namespaces["cold"];
+        assert_eq!(cold.cold_storage_of(4), None);
+        assert_eq!(cold.cold_storage_of(8), Some(ColdStorage::Bucket));
+        assert_eq!(cold.cold_storage_of(16), Some(ColdStorage::Bucket));
+
+        // the original is always kept with the variants
+        let lazy = &namespaces["lazy"];
+        assert_eq!(lazy.cold_storage_of(1), None);
+        assert_eq!(lazy.cold_storage_of(2), Some(ColdStorage::OnDemand));
+    }
+}
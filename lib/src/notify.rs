@@ -0,0 +1,155 @@
+//! Email notifications to operators, sent via MailChannels.
+//!
+//! Notifications are enabled by setting `NOTIFY_EMAIL_TO` (comma-separated addresses) and
+//! `NOTIFY_EMAIL_FROM` vars. If they are missing, notifications are silently skipped.
+
+use serde_json::json;
+use worker::{
+    console_error, console_log, wasm_bindgen::JsValue, Env, Fetch, Headers, Method, Request,
+    RequestInit, Result as WorkerResult,
+};
+
+const MAILCHANNELS_SEND_URL: &str = "https://api.mailchannels.net/tx/v1/send";
+
+/// Events that operators are notified of.
+#[derive(Debug)]
+pub enum AdminEvent {
+    /// An abuse report was filed against an image.
+    AbuseReport {
+        /// ID of the tenant the image was reported in, empty for the default tenant
+        tenant: String,
+        /// namespace the image was reported in, empty for the root namespace
+        namespace: String,
+        hash: String,
+        reason: String,
+        contact: Option<String>,
+    },
+    /// A client used up its hourly quota of uploads (see `rate_limit`), once per hour.
+    QuotaExhausted { per_hour: u32 },
+    /// Variants of an upload failed to be generated from its original after responding (see
+    /// `variant_queue`). Failed messages of the queue are retried, and notified of each time.
+    ReplicationFailed { hash: String, error: String },
+}
+
+impl AdminEvent {
+    pub fn subject(&self) -> String {
+        match self {
+            AdminEvent::AbuseReport { hash, .. } => {
+                format!("[upix] New abuse report on {}", &hash[..8.min(hash.len())])
+            }
+            AdminEvent::QuotaExhausted { .. } => "[upix] Upload quota exhausted".to_string(),
+            AdminEvent::ReplicationFailed { hash, .. } => {
+                format!(
+                    "[upix] Failed to store variants of {}",
+                    &hash[..8.min(hash.len())]
+                )
+            }
+        }
+    }
+
+    pub fn body(&self) -> String {
+        match self {
+            AdminEvent::AbuseReport {
+                tenant,
+                namespace,
+                hash,
+                reason,
+                contact,
+            } => format!(
+                "A new abuse report was filed.\n\n\
+                 Tenant: {}\n\
+                 Namespace: {}\n\
+                 Image: {}\n\
+                 Reason:\n{}\n\n\
+                 Contact: {}\n",
+                if tenant.is_empty() {
+                    "(default)"
+                } else {
+                    tenant
+                },
+                if namespace.is_empty() {
+                    "(root)"
+                } else {
+                    namespace
+                },
+                hash,
+                reason,
+                contact.as_deref().unwrap_or("(not provided)"),
+            ),
+            AdminEvent::QuotaExhausted { per_hour } => format!(
+                "A client has used up its quota of {} uploads per hour. Its further uploads are \
+                 rejected with 429 until the hour ends.\n",
+                per_hour
+            ),
+            AdminEvent::ReplicationFailed { hash, error } => format!(
+                "Variants of an upload failed to be stored after responding.\n\n\
+                 Image: {}\n\
+                 Error: {}\n",
+                hash, error
+            ),
+        }
+    }
+}
+
+struct NotifyConfig {
+    to: Vec<String>,
+    from: String,
+}
+
+fn load_config(env: &Env) -> Option<NotifyConfig> {
+    let to: Vec<String> = env
+        .var("NOTIFY_EMAIL_TO")
+        .ok()?
+        .to_string()
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect();
+    let from = env.var("NOTIFY_EMAIL_FROM").ok()?.to_string();
+    (!to.is_empty() && !from.is_empty()).then_some(NotifyConfig { to, from })
+}
+
+async fn send_email(config: &NotifyConfig, subject: &str, body: &str) -> WorkerResult<()> {
+    let payload = json!({
+        "personalizations": [{
+            "to": config.to.iter().map(|addr| json!({ "email": addr })).collect::<Vec<_>>(),
+        }],
+        "from": { "email": config.from, "name": "upix" },
+        "subject": subject,
+        "content": [{ "type": "text/plain", "value": body }],
+    });
+
+    let mut headers = Headers::new();
+    headers.set("Content-Type", "application/json")?;
+    let mut init = RequestInit::new();
+    init.with_method(Method::Post)
+        .with_headers(headers)
+        .with_body(Some(JsValue::from_str(&payload.to_string())));
+
+    let req = Request::new_with_init(MAILCHANNELS_SEND_URL, &init)?;
+    let mut resp = Fetch::Request(req).send().await?;
+    if !(200..300).contains(&resp.status_code()) {
+        let msg = resp.text().await.unwrap_or_default();
+        return Err(format!(
+            "MailChannels responded with {}: {}",
+            resp.status_code(),
+            msg
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Notify operators of the event by email. Failures are only logged.
+pub async fn notify_admins(env: &Env, event: AdminEvent) {
+    let Some(config) = load_config(env) else {
+        console_log!(
+            "email notification is not configured, skipping: {:?}",
+            event
+        );
+        return;
+    };
+    if let Err(e) = send_email(&config, &event.subject(), &event.body()).await {
+        console_error!("failed to send notification email: {:?}", e);
+    }
+}
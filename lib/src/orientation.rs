@@ -0,0 +1,143 @@
+//! Rotating and flipping images served by the dyn worker, for mirrored variants of sprites.
+//!
+//! Requested by the `rot` (`90`, `180` or `270`, clockwise) and `flip` (`h` or `v`) query params.
+//! Images are rotated first, then flipped, after cropping and before scaling.
+
+use std::str::FromStr;
+
+use image::{imageops, DynamicImage, RgbaImage};
+
+use crate::dimensions::Dimensions;
+
+/// Clockwise rotation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    R90,
+    R180,
+    R270,
+}
+
+impl FromStr for Rotation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "90" => Ok(Rotation::R90),
+            "180" => Ok(Rotation::R180),
+            "270" => Ok(Rotation::R270),
+            _ => Err("'rot' must be one of 90, 180, 270".to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flip {
+    /// Mirrored left to right.
+    Horizontal,
+    /// Mirrored top to bottom.
+    Vertical,
+}
+
+impl FromStr for Flip {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "h" => Ok(Flip::Horizontal),
+            "v" => Ok(Flip::Vertical),
+            _ => Err("'flip' must be one of h, v".to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Orientation {
+    pub rotation: Option<Rotation>,
+    pub flip: Option<Flip>,
+}
+
+impl Orientation {
+    pub fn is_identity(self) -> bool {
+        self.rotation.is_none() && self.flip.is_none()
+    }
+
+    /// Dimensions of an image of the dimensions once oriented.
+    pub fn dimensions(self, dims: Dimensions) -> Dimensions {
+        match self.rotation {
+            Some(Rotation::R90 | Rotation::R270) => Dimensions::new(dims.height, dims.width),
+            _ => dims,
+        }
+    }
+
+    pub fn apply(self, img: DynamicImage) -> DynamicImage {
+        match self.is_identity() {
+            true => img,
+            false => DynamicImage::ImageRgba8(self.apply_rgba(&img.to_rgba8())),
+        }
+    }
+
+    pub fn apply_rgba(self, img: &RgbaImage) -> RgbaImage {
+        let rotated = match self.rotation {
+            Some(Rotation::R90) => imageops::rotate90(img),
+            Some(Rotation::R180) => imageops::rotate180(img),
+            Some(Rotation::R270) => imageops::rotate270(img),
+            None => img.clone(),
+        };
+        match self.flip {
+            Some(Flip::Horizontal) => imageops::flip_horizontal(&rotated),
+            Some(Flip::Vertical) => imageops::flip_vertical(&rotated),
+            None => rotated,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use image::Rgba;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_orientation() {
+        assert_eq!("90".parse(), Ok(Rotation::R90));
+        assert_eq!("270".parse(), Ok(Rotation::R270));
+        assert!("45".parse::<Rotation>().is_err());
+        assert!("-90".parse::<Rotation>().is_err());
+        assert_eq!("h".parse(), Ok(Flip::Horizontal));
+        assert_eq!("v".parse(), Ok(Flip::Vertical));
+        assert!("x".parse::<Flip>().is_err());
+    }
+
+    #[test]
+    fn test_orientation() {
+        // 3x2, with the top-left pixel marked
+        let mark = Rgba([255, 0, 0, 255]);
+        let mut img = RgbaImage::from_pixel(3, 2, Rgba([0, 0, 0, 255]));
+        img.put_pixel(0, 0, mark);
+
+        let rot90 = Orientation {
+            rotation: Some(Rotation::R90),
+            flip: None,
+        };
+        let out = rot90.apply_rgba(&img);
+        assert_eq!(Dimensions::of(&out), Dimensions::new(2, 3));
+        assert_eq!(rot90.dimensions(Dimensions::of(&img)), Dimensions::of(&out));
+        assert_eq!(out.get_pixel(1, 0), &mark);
+
+        let flip_h = Orientation {
+            rotation: None,
+            flip: Some(Flip::Horizontal),
+        };
+        assert_eq!(flip_h.apply_rgba(&img).get_pixel(2, 0), &mark);
+
+        // rotated first, then flipped
+        let both = Orientation {
+            rotation: Some(Rotation::R90),
+            flip: Some(Flip::Vertical),
+        };
+        assert_eq!(both.apply_rgba(&img).get_pixel(1, 2), &mark);
+
+        assert!(Orientation::default().is_identity());
+        assert_eq!(Orientation::default().apply_rgba(&img), img);
+    }
+}
@@ -0,0 +1,395 @@
+//! Asset packs: named references to images at scales, with transforms, which game builds pin by a
+//! single ID instead of dozens of hashes (`POST /packs`, `GET /packs/{id}/resolve`).
+//!
+//! A pack is JSON like:
+//!
+//! ```json
+//! {
+//!   "entries": {
+//!     "hero/idle": { "hash": "1ea5...c600", "scales": [1, 4], "format": "webp" },
+//!     "hero/idle-left": { "hash": "1ea5...c600", "scales": [4], "transform": { "flip": "h" } },
+//!     "items/key": { "hash": "9b1f...0a2e", "namespace": "icons", "transform": { "crop": "0,0,16,16" } }
+//!   }
+//! }
+//! ```
+//!
+//! Transforms are the query params of the dyn worker of the same names, checked the same way.
+//! Packs are identified by the digest of their canonical form, so they never change once stored,
+//! and storing the same pack again gives the same ID. Resolving a pack expands its entries into
+//! URLs of the dyn worker under the base URL of the tenant. Hashes are not checked to be uploaded,
+//! as images uploaded before the `uploads` table existed have no records.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    color_vision::ColorVision,
+    crop::Crop,
+    dynamic::SERVED_FORMATS,
+    is_valid_hash,
+    orientation::{Flip, Rotation},
+    pipeline::public_image_url,
+    quantize::parse_max_colors,
+    sha256_hex,
+};
+
+/// Max number of entries of a pack.
+pub const MAX_PACK_ENTRIES: usize = 256;
+
+/// Max length of names of entries, in bytes.
+pub const MAX_ENTRY_NAME_LEN: usize = 128;
+
+/// Length of pack IDs, in hex digits of the digest.
+const PACK_ID_LEN: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AssetPack {
+    /// Entries by names of letters, digits, `-`, `_`, `.` and `/`.
+    pub entries: BTreeMap<String, PackEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PackEntry {
+    pub hash: String,
+    /// the root namespace if absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    #[serde(default = "default_scales")]
+    pub scales: Vec<u32>,
+    /// extension of the format served in
+    #[serde(default = "default_format")]
+    pub format: String,
+    #[serde(default, skip_serializing_if = "PackTransform::is_empty")]
+    pub transform: PackTransform,
+}
+
+fn default_scales() -> Vec<u32> {
+    vec![1]
+}
+
+fn default_format() -> String {
+    "png".to_string()
+}
+
+/// Query params of the dyn worker applied to the image, in the forms of the params.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PackTransform {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crop: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rot: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flip: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_colors: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub simulate: Option<String>,
+}
+
+impl PackTransform {
+    pub fn is_empty(&self) -> bool {
+        self == &PackTransform::default()
+    }
+
+    /// The params present, in the order of the fields.
+    fn params(&self) -> Vec<(&'static str, &str)> {
+        [
+            ("crop", &self.crop),
+            ("rot", &self.rot),
+            ("flip", &self.flip),
+            ("max_colors", &self.max_colors),
+            ("simulate", &self.simulate),
+        ]
+        .into_iter()
+        .filter_map(|(name, v)| Some((name, v.as_deref()?)))
+        .collect()
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if let Some(crop) = &self.crop {
+            crop.parse::<Crop>()?;
+        }
+        if let Some(rot) = &self.rot {
+            rot.parse::<Rotation>()?;
+        }
+        if let Some(flip) = &self.flip {
+            flip.parse::<Flip>()?;
+        }
+        if let Some(n) = &self.max_colors {
+            parse_max_colors(n, "max_colors")?;
+        }
+        if let Some(vision) = &self.simulate {
+            vision.parse::<ColorVision>()?;
+        }
+        Ok(())
+    }
+
+    /// Query string of the params, with the leading `?` unless empty.
+    fn query(&self) -> String {
+        let params = self.params();
+        if params.is_empty() {
+            return String::new();
+        }
+        let pairs: Vec<_> = params
+            .into_iter()
+            .map(|(name, v)| format!("{}={}", name, v))
+            .collect();
+        format!("?{}", pairs.join("&"))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum PackError {
+    #[error("Pack has no entries")]
+    Empty,
+    #[error("Too many entries (> {})", MAX_PACK_ENTRIES)]
+    TooManyEntries,
+    #[error("Invalid entry name: '{0}'")]
+    InvalidName(String),
+    #[error("Entry '{0}' has an invalid hash")]
+    InvalidHash(String),
+    #[error("Entry '{0}' is in an unknown namespace")]
+    UnknownNamespace(String),
+    #[error("Entry '{name}' must have scales in 1..={max_scale}")]
+    InvalidScales { name: String, max_scale: u32 },
+    #[error("Entry '{name}' must have a format of {}", SERVED_FORMATS.join(", "))]
+    InvalidFormat { name: String },
+    #[error("Entry '{name}' has an invalid transform: {reason}")]
+    InvalidTransform { name: String, reason: String },
+}
+
+fn is_valid_entry_name(name: &str) -> bool {
+    (1..=MAX_ENTRY_NAME_LEN).contains(&name.len())
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b'/'))
+}
+
+/// Whether the string can be an ID of a pack.
+pub fn is_valid_pack_id(id: &str) -> bool {
+    id.len() == PACK_ID_LEN && id.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ResolvedPack {
+    pub id: String,
+    pub entries: BTreeMap<String, ResolvedEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ResolvedEntry {
+    pub hash: String,
+    /// URLs of the image by scale
+    pub urls: BTreeMap<u32, String>,
+}
+
+impl AssetPack {
+    /// Check the pack, with the max scale of each namespace (`None` for the root one), or `None`
+    /// for unknown namespaces.
+    pub fn validate(
+        &self,
+        max_scale_of: impl Fn(Option<&str>) -> Option<u32>,
+    ) -> Result<(), PackError> {
+        if self.entries.is_empty() {
+            return Err(PackError::Empty);
+        }
+        if self.entries.len() > MAX_PACK_ENTRIES {
+            return Err(PackError::TooManyEntries);
+        }
+        for (name, entry) in &self.entries {
+            if !is_valid_entry_name(name) {
+                return Err(PackError::InvalidName(name.clone()));
+            }
+            if !is_valid_hash(&entry.hash) {
+                return Err(PackError::InvalidHash(name.clone()));
+            }
+            let Some(max_scale) = max_scale_of(entry.namespace.as_deref()) else {
+                return Err(PackError::UnknownNamespace(name.clone()));
+            };
+            if entry.scales.is_empty() || entry.scales.iter().any(|s| !(1..=max_scale).contains(s))
+            {
+                return Err(PackError::InvalidScales {
+                    name: name.clone(),
+                    max_scale,
+                });
+            }
+            if !SERVED_FORMATS.contains(&entry.format.as_str()) {
+                return Err(PackError::InvalidFormat { name: name.clone() });
+            }
+            entry
+                .transform
+                .validate()
+                .map_err(|reason| PackError::InvalidTransform {
+                    name: name.clone(),
+                    reason,
+                })?;
+        }
+        Ok(())
+    }
+
+    /// The canonical form of the pack, which is what is stored, with scales sorted and deduped.
+    pub fn canonical(mut self) -> Self {
+        for entry in self.entries.values_mut() {
+            entry.scales.sort_unstable();
+            entry.scales.dedup();
+        }
+        self
+    }
+
+    /// ID of the pack, from the digest of its canonical form.
+    pub fn id(&self) -> String {
+        let json = serde_json::to_string(&self.clone().canonical()).unwrap_or_default();
+        sha256_hex(json.as_bytes())[..PACK_ID_LEN].to_string()
+    }
+
+    /// URLs of the entries served by the dyn worker under the base URL.
+    pub fn resolve(&self, base_url: &str) -> ResolvedPack {
+        let entries = self
+            .entries
+            .iter()
+            .map(|(name, entry)| {
+                let query = entry.transform.query();
+                let urls = entry
+                    .scales
+                    .iter()
+                    .map(|&scale| {
+                        let file_name = match scale {
+                            1 => format!("{}.{}", entry.hash, entry.format),
+                            s => format!("{}_{}x.{}", entry.hash, s, entry.format),
+                        };
+                        let namespace = entry.namespace.as_deref().unwrap_or_default();
+                        let url = public_image_url(base_url, namespace, &file_name);
+                        (scale, format!("{}{}", url, query))
+                    })
+                    .collect();
+                let resolved = ResolvedEntry {
+                    hash: entry.hash.clone(),
+                    urls,
+                };
+                (name.clone(), resolved)
+            })
+            .collect();
+        ResolvedPack {
+            id: self.id(),
+            entries,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const HASH: &str = "1ea5e9febc7265432c41cf87b41f9ca1ea084bec600509add2c04048a8fec600";
+
+    fn max_scale_of(namespace: Option<&str>) -> Option<u32> {
+        match namespace {
+            None => Some(16),
+            Some("icons") => Some(8),
+            Some(_) => None,
+        }
+    }
+
+    fn parse(json: &str) -> AssetPack {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_validate_pack() {
+        let pack = parse(&format!(
+            r#"{{"entries": {{
+                "hero/idle": {{"hash": "{HASH}", "scales": [1, 4], "format": "webp"}},
+                "items/key": {{"hash": "{HASH}", "namespace": "icons",
+                    "transform": {{"crop": "0,0,4,4", "flip": "h"}}}}
+            }}}}"#
+        ));
+        assert_eq!(pack.validate(max_scale_of), Ok(()));
+        assert_eq!(pack.entries["items/key"].scales, [1]);
+        assert_eq!(pack.entries["items/key"].format, "png");
+
+        let invalid = |entry: String| {
+            parse(&format!(r#"{{"entries": {{"a": {}}}}}"#, entry))
+                .validate(max_scale_of)
+                .unwrap_err()
+        };
+        assert_eq!(
+            invalid(r#"{"hash": "abc"}"#.to_string()),
+            PackError::InvalidHash("a".to_string())
+        );
+        assert_eq!(
+            invalid(format!(r#"{{"hash": "{HASH}", "namespace": "avatars"}}"#)),
+            PackError::UnknownNamespace("a".to_string())
+        );
+        assert_eq!(
+            invalid(format!(
+                r#"{{"hash": "{HASH}", "namespace": "icons", "scales": [16]}}"#
+            )),
+            PackError::InvalidScales {
+                name: "a".to_string(),
+                max_scale: 8
+            }
+        );
+        assert!(matches!(
+            invalid(format!(r#"{{"hash": "{HASH}", "format": "jpg"}}"#)),
+            PackError::InvalidFormat { .. }
+        ));
+        assert!(matches!(
+            invalid(format!(
+                r#"{{"hash": "{HASH}", "transform": {{"rot": "45"}}}}"#
+            )),
+            PackError::InvalidTransform { .. }
+        ));
+        assert_eq!(
+            parse(r#"{"entries": {}}"#).validate(max_scale_of),
+            Err(PackError::Empty)
+        );
+        // unknown transforms are rejected rather than ignored
+        assert!(serde_json::from_str::<AssetPack>(&format!(
+            r#"{{"entries": {{"a": {{"hash": "{HASH}", "transform": {{"speed": "2"}}}}}}}}"#
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn test_resolve_pack() {
+        let pack = parse(&format!(
+            r#"{{"entries": {{
+                "hero/idle": {{"hash": "{HASH}", "scales": [4, 1, 4]}},
+                "items/key": {{"hash": "{HASH}", "namespace": "icons", "format": "webp",
+                    "transform": {{"flip": "h", "crop": "0,0,4,4"}}}}
+            }}}}"#
+        ));
+        let resolved = pack
+            .clone()
+            .canonical()
+            .resolve("https://img.upix.example/");
+        assert_eq!(resolved.id, pack.id());
+        assert!(is_valid_pack_id(&resolved.id));
+        let urls: Vec<_> = resolved.entries["hero/idle"].urls.values().collect();
+        assert_eq!(
+            urls,
+            [
+                &format!("https://img.upix.example/{}.png", HASH),
+                &format!("https://img.upix.example/{}_4x.png", HASH),
+            ]
+        );
+        assert_eq!(
+            resolved.entries["items/key"].urls[&1],
+            format!(
+                "https://img.upix.example/icons/{}.webp?crop=0,0,4,4&flip=h",
+                HASH
+            )
+        );
+
+        // IDs don't depend on the order of scales
+        let mut reordered = pack.clone();
+        reordered.entries.get_mut("hero/idle").unwrap().scales = vec![1, 4];
+        assert_eq!(reordered.id(), pack.id());
+        reordered.entries.get_mut("hero/idle").unwrap().scales = vec![1, 2];
+        assert_ne!(reordered.id(), pack.id());
+    }
+}
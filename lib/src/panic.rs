@@ -0,0 +1,67 @@
+//! Accounting of panics in handlers.
+//!
+//! `console_error_panic_hook` only logs panics. Workers install the hook with `set_panic_hook` and
+//! enter each request with `enter_request`, so that a panic is also counted and logged with the
+//! request id, which can be looked up in the dashboard.
+//!
+//! Panics are not converted into responses: `wasm32-unknown-unknown` aborts on panics, so they
+//! can't be caught, and the runtime responds by itself.
+
+use std::{
+    cell::RefCell,
+    panic,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Once,
+    },
+};
+
+use worker::{console_error, Date, Request};
+
+static PANIC_COUNT: AtomicU64 = AtomicU64::new(0);
+static REQUEST_SEQ: AtomicU64 = AtomicU64::new(0);
+static SET_HOOK: Once = Once::new();
+
+thread_local! {
+    /// Id of the latest request entered, for the hook to log. Requests interleave at await
+    /// points, so this is the best effort.
+    static CURRENT_REQUEST_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+/// Install the panic hook, which counts panics and logs them with the request id in addition to
+/// what `console_error_panic_hook` does. Replaces `console_error_panic_hook::set_once`.
+pub fn set_panic_hook() {
+    SET_HOOK.call_once(|| {
+        panic::set_hook(Box::new(|info| {
+            let count = PANIC_COUNT.fetch_add(1, Ordering::Relaxed) + 1;
+            let request_id = CURRENT_REQUEST_ID.with(|id| id.borrow().clone());
+            console_error!(
+                "panic in request {} (panics in this isolate: {})",
+                request_id.as_deref().unwrap_or("-"),
+                count
+            );
+            console_error_panic_hook::hook(info);
+        }));
+    });
+}
+
+/// Number of panics in this isolate since it started.
+pub fn panic_count() -> u64 {
+    PANIC_COUNT.load(Ordering::Relaxed)
+}
+
+/// Id of the request: the Cloudflare Ray ID if any, so that it can be looked up in the dashboard.
+pub fn request_id(req: &Request) -> String {
+    match req.headers().get("cf-ray") {
+        Ok(Some(ray)) if !ray.is_empty() => ray,
+        _ => format!(
+            "{:x}-{:x}",
+            Date::now().as_millis(),
+            REQUEST_SEQ.fetch_add(1, Ordering::Relaxed)
+        ),
+    }
+}
+
+/// Record the request as the one being handled, for the panic hook to log.
+pub fn enter_request(request_id: &str) {
+    CURRENT_REQUEST_ID.with(|id| *id.borrow_mut() = Some(request_id.to_string()));
+}
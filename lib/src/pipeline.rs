@@ -0,0 +1,643 @@
+//! Steps of the upload pipeline shared by the api worker and the CLI.
+//!
+//! The steps here don't touch bindings, so they run the same on workers and natively: the CLI
+//! dry-runs uploads with them to debug user uploads and prepare batches offline.
+
+use std::str::FromStr;
+
+use futures::future;
+use image::{DynamicImage, ImageError, ImageResult};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    animation::{encode_apng, Animation},
+    blob::{BlobError, BlobStore},
+    content::{count_colors, trimmed_dimensions},
+    content_hash::HashAlgorithm,
+    dimensions::Dimensions,
+    dynamic::DynamicHints,
+    encode_png,
+    error_code::ErrorCode,
+    manifest::{store_manifest, Manifest, ManifestEntry},
+    namespace::{ColdStorage, Limits},
+    quality::Advice,
+    upscale_image,
+    variant::Variant,
+    warning::Warning,
+    ApiError, ApiResult,
+};
+
+#[derive(Debug, Serialize)]
+pub struct UploadResult {
+    pub images: Vec<UploadedImage>,
+    /// non-fatal decisions made while processing the upload
+    pub warnings: Vec<Warning>,
+    /// hints on exporting the source better, for sources not looking like pixel art
+    pub advice: Vec<Advice>,
+    /// whether the same data had been uploaded and the stored images are returned as is
+    pub deduplicated: bool,
+    /// what the dyn worker can serve for the image on demand
+    pub dynamic: DynamicHints,
+    /// scales of upscaled variants stored after responding (see `variant_queue`), which the dyn
+    /// worker renders on demand until then
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub deferred: Vec<u32>,
+}
+
+/// Media type of upload results as objects, which clients opt in to by `Accept`. Others are
+/// responded with the array of `images` only, as before warnings and the rest were reported.
+pub const UPLOAD_RESULT_MEDIA_TYPE: &str = "application/vnd.upix.upload+json";
+
+/// Whether the client accepts upload results as objects, by the `Accept` header.
+pub fn accepts_upload_result(accept: Option<&str>) -> bool {
+    accept.is_some_and(|accept| {
+        accept.split(',').any(|range| {
+            let media_type = range.split(';').next().unwrap_or_default().trim();
+            media_type.eq_ignore_ascii_case(UPLOAD_RESULT_MEDIA_TYPE)
+        })
+    })
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadedImage {
+    pub name: String,
+    /// absent for avatar images, whose sizes are fixed regardless of the source
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scale: Option<u32>,
+    pub width: u32,
+    pub height: u32,
+    /// size of the stored image in bytes
+    pub size: u64,
+    /// absolute URL of the image served by the dyn worker, set in responses if the base URL is
+    /// configured (see `UploadResult::with_public_urls`), and never recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+}
+
+/// URL of the stored image with the file name in the namespace, served by the dyn worker under
+/// the base URL.
+pub fn public_image_url(base_url: &str, namespace: &str, name: &str) -> String {
+    let base_url = base_url.trim_end_matches('/');
+    match namespace {
+        "" => format!("{}/{}", base_url, name),
+        ns => format!("{}/{}/{}", base_url, ns, name),
+    }
+}
+
+impl UploadResult {
+    /// The result with URLs of the images in the namespace, under the base URL. Avatar images
+    /// are not served by the dyn worker, so they are left without URLs.
+    pub fn with_public_urls(mut self, base_url: &str, namespace: &str) -> Self {
+        for img in self.images.iter_mut().filter(|img| img.scale.is_some()) {
+            img.url = Some(public_image_url(base_url, namespace, &img.name));
+        }
+        self
+    }
+}
+
+/// Validate dimensions and content of the image against the limits.
+pub fn validate_img(img: &DynamicImage, limits: &Limits) -> ApiResult<()> {
+    validate_dimensions(Dimensions::of(img), limits)?;
+
+    // keep blank or near-empty images out
+    if limits.min_side_len == 0 && limits.min_colors <= 1 {
+        return Ok(());
+    }
+    let rgba = img.to_rgba8();
+    let (tw, th) = trimmed_dimensions(&rgba);
+    if tw.min(th) < limits.min_side_len {
+        return Err(ApiError::new(
+            400,
+            format!(
+                "Image content is too small ({} x {} after trimming transparent borders, < {})",
+                tw, th, limits.min_side_len
+            ),
+        )
+        .with_code(ErrorCode::ContentTooSmall));
+    }
+    let n_colors = count_colors(&rgba, limits.min_colors);
+    if n_colors < limits.min_colors {
+        return Err(ApiError::new(
+            400,
+            format!(
+                "Image has too few colors ({} < {})",
+                n_colors, limits.min_colors
+            ),
+        )
+        .with_code(ErrorCode::TooFewColors));
+    }
+    Ok(())
+}
+
+/// Validate dimensions of an image against the limits, which can be done with dimensions read
+/// from headers, before decoding the image.
+pub fn validate_dimensions(dims: Dimensions, limits: &Limits) -> ApiResult<()> {
+    if dims.pixels() > u64::from(limits.max_pixels) {
+        return Err(ApiError::new(
+            400,
+            format!(
+                "Image has too many pixels ({} > {})",
+                dims.pixels(),
+                limits.max_pixels
+            ),
+        )
+        .with_code(ErrorCode::TooManyPixels));
+    }
+
+    let (long, short) = (dims.long_side(), dims.short_side());
+    if long > limits.max_long_side_len {
+        return Err(ApiError::new(
+            400,
+            format!(
+                "Long side of image is too long ({} > {})",
+                long, limits.max_long_side_len
+            ),
+        )
+        .with_code(ErrorCode::LongSideTooLong));
+    }
+    if dims.aspect_ratio() > limits.max_aspect_ratio {
+        return Err(ApiError::new(
+            400,
+            format!(
+                "Aspect retio of image is out of range ({} : {} > {} : 1)",
+                long, short, limits.max_aspect_ratio
+            ),
+        )
+        .with_code(ErrorCode::AspectRatioOutOfRange));
+    }
+    Ok(())
+}
+
+/// What to do with multi-frame images uploaded where animations are not kept: in the avatar
+/// mode, with direct uploads, and from APNG or animated WebP sources. Configured by the
+/// `MULTI_FRAME_POLICY` var, `first_frame` (default) or `reject`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MultiFramePolicy {
+    /// Keep the first frame, with the `frames_dropped` warning.
+    #[default]
+    FirstFrame,
+    /// Reject the upload with 422.
+    Reject,
+}
+
+impl FromStr for MultiFramePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "first_frame" => Ok(MultiFramePolicy::FirstFrame),
+            "reject" => Ok(MultiFramePolicy::Reject),
+            _ => Err(format!("unknown policy: {}", s)),
+        }
+    }
+}
+
+/// Check an upload of the image with the number of frames, of which only the first one is kept,
+/// against the policy.
+pub fn check_dropped_frames(policy: MultiFramePolicy, frames: usize) -> ApiResult<Option<Warning>> {
+    if frames <= 1 {
+        return Ok(None);
+    }
+    match policy {
+        MultiFramePolicy::FirstFrame => Ok(Some(Warning::frames_dropped(frames))),
+        MultiFramePolicy::Reject => Err(ApiError::new(
+            422,
+            format!(
+                "Multi-frame images are not supported here ({} frames); upload a single frame",
+                frames
+            ),
+        )
+        .with_code(ErrorCode::MultiFrameNotSupported)),
+    }
+}
+
+/// Parse the `scales` field of an upload: comma-separated scale factors (like `2,4`) to generate
+/// variants at, instead of the default ladder. The original (1x) is always stored.
+pub fn parse_scales(s: &str, limits: &Limits) -> ApiResult<Vec<u32>> {
+    let invalid = || {
+        ApiError::new(
+            400,
+            format!(
+                "'scales' must be comma-separated scale factors in 1..={}",
+                limits.max_scale
+            ),
+        )
+    };
+    let mut scales = vec![1];
+    for scale in s.split(',') {
+        let scale: u32 = scale.trim().parse().map_err(|_| invalid())?;
+        if !(1..=limits.max_scale).contains(&scale) {
+            return Err(invalid());
+        }
+        scales.push(scale);
+    }
+    scales.sort_unstable();
+    scales.dedup();
+    Ok(scales)
+}
+
+/// Check that an image whose long side is `long_side` can be upscaled by all the requested
+/// scales.
+pub fn check_requested_scales(limits: &Limits, long_side: u32, scales: &[u32]) -> ApiResult<()> {
+    match scales
+        .iter()
+        .find(|&&s| s != 1 && !limits.allows_scale(long_side, s))
+    {
+        Some(s) => Err(ApiError::new(
+            400,
+            format!(
+                "Image is too large to be upscaled by {}x ({} x {} > {})",
+                s, long_side, s, limits.max_scaled_side_len
+            ),
+        )
+        .with_code(ErrorCode::ScaleTooLarge)),
+        None => Ok(()),
+    }
+}
+
+/// Hash of the image encoded as PNG, for images without original data.
+pub fn png_hash(img: &DynamicImage, algorithm: HashAlgorithm) -> ImageResult<String> {
+    let mut img_data = Vec::new();
+    encode_png(img, &mut img_data, true)?;
+    Ok(algorithm.hash_hex(&img_data))
+}
+
+/// Scale factors of variants generated at upload for the image (or animation): the requested
+/// scales if any, or the default ladder.
+pub fn variant_scales(
+    limits: &Limits,
+    img: &DynamicImage,
+    anim: Option<&Animation>,
+    requested: Option<&[u32]>,
+) -> Vec<u32> {
+    requested
+        .map_or_else(
+            || limits.pregenerated_scales(Dimensions::of(img).long_side()),
+            <[u32]>::to_vec,
+        )
+        .into_iter()
+        .filter(|&scale| anim.is_none_or(|a| a.allows_scale(scale)))
+        .collect()
+}
+
+/// Encode the image upscaled by the scale factor as PNG, or the animation as APNG if any.
+/// They are to be stored, so encoded deterministically.
+pub fn encode_scaled(
+    img: &DynamicImage,
+    anim: Option<&Animation>,
+    scale: u32,
+) -> ImageResult<(Vec<u8>, Dimensions)> {
+    let mut img_data = Vec::new();
+    match anim {
+        Some(anim) if scale == 1 => encode_apng(anim, &mut img_data)?,
+        Some(anim) => encode_apng(&anim.upscale(scale), &mut img_data)?,
+        None if scale == 1 => encode_png(img, &mut img_data, true)?,
+        None => encode_png(&upscale_image(img, scale), &mut img_data, true)?,
+    }
+    Ok((img_data, Dimensions::of(img).saturating_scale(scale)))
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum StoreError {
+    #[error("failed to encode {scale}x variant: {source}")]
+    Encode { scale: u32, source: ImageError },
+    #[error(transparent)]
+    Blob(#[from] BlobError),
+}
+
+/// Stores variants are stored in.
+pub struct VariantStores<'a, S> {
+    pub store: &'a S,
+    /// Store of variants in cold storage. They go to `store` if there is none.
+    pub cold_store: Option<&'a S>,
+}
+
+impl<'a, S> VariantStores<'a, S> {
+    /// All variants in the store.
+    pub fn new(store: &'a S) -> Self {
+        Self {
+            store,
+            cold_store: None,
+        }
+    }
+}
+
+/// Encode the variant of the image (or animation) at the scale and store it under the
+/// `key_prefix`, in cold storage if the limits say so. Returns it along with its manifest entry.
+pub async fn store_variant<S: BlobStore>(
+    stores: &VariantStores<'_, S>,
+    key_prefix: &str,
+    hash: &str,
+    img: &DynamicImage,
+    anim: Option<&Animation>,
+    limits: &Limits,
+    scale: u32,
+) -> Result<(UploadedImage, ManifestEntry), StoreError> {
+    let (data, dims) =
+        encode_scaled(img, anim, scale).map_err(|source| StoreError::Encode { scale, source })?;
+    let variant = match scale {
+        1 => Variant::Original,
+        s => Variant::Upscaled(s),
+    };
+    let name = variant.file_name(hash);
+    let mut entry = ManifestEntry::new(&name, &data);
+    let dest = match (limits.cold_storage_of(scale), stores.cold_store) {
+        (Some(ColdStorage::Bucket), Some(cold_store)) => {
+            entry.cold = true;
+            cold_store
+        }
+        _ => stores.store,
+    };
+    dest.store(&format!("{}{}", key_prefix, name), data, "image/png")
+        .await?;
+    let uploaded = UploadedImage {
+        name,
+        scale: Some(scale),
+        width: dims.width,
+        height: dims.height,
+        size: entry.size,
+        url: None,
+    };
+    Ok((uploaded, entry))
+}
+
+/// Encode the original and upscaled variants of the image (or animation) at the requested scales
+/// (or the default ladder) and store them under the `key_prefix`, along with the manifest of them.
+/// Variants generated on demand (see `namespace::ColdStorage`) are not stored at all.
+pub async fn store_variants<S: BlobStore>(
+    stores: VariantStores<'_, S>,
+    key_prefix: &str,
+    hash: &str,
+    img: &DynamicImage,
+    anim: Option<&Animation>,
+    limits: &Limits,
+    scales: Option<&[u32]>,
+) -> Result<Vec<UploadedImage>, StoreError> {
+    let stores = &stores;
+    let tasks = variant_scales(limits, img, anim, scales)
+        .into_iter()
+        .filter(|&scale| limits.cold_storage_of(scale) != Some(ColdStorage::OnDemand))
+        .map(|scale| store_variant(stores, key_prefix, hash, img, anim, limits, scale));
+    let (uploaded, objects) = future::join_all(tasks)
+        .await
+        .into_iter()
+        .collect::<Result<(Vec<_>, Vec<_>), _>>()?;
+
+    // the manifest is stored last, so that it never lists missing objects
+    let manifest = Manifest {
+        hash: hash.to_string(),
+        objects,
+    };
+    store_manifest(stores.store, key_prefix, &manifest).await?;
+    Ok(uploaded)
+}
+
+#[cfg(test)]
+mod test {
+    use image::{ImageFormat, Rgba, RgbaImage};
+
+    use super::*;
+
+    fn checker(w: u32, h: u32) -> DynamicImage {
+        let img = RgbaImage::from_fn(w, h, |x, y| {
+            Rgba(if (x + y) % 2 == 0 {
+                [0, 0, 0, 255]
+            } else {
+                [255; 4]
+            })
+        });
+        DynamicImage::ImageRgba8(img)
+    }
+
+    #[test]
+    fn test_accepts_upload_result() {
+        assert!(!accepts_upload_result(None));
+        assert!(!accepts_upload_result(Some("application/json")));
+        assert!(!accepts_upload_result(Some("*/*")));
+        assert!(accepts_upload_result(Some(UPLOAD_RESULT_MEDIA_TYPE)));
+        assert!(accepts_upload_result(Some(
+            "application/json, application/vnd.upix.upload+json; q=0.9"
+        )));
+    }
+
+    #[test]
+    fn test_validate_img() {
+        let limits = Limits::default();
+        assert!(validate_img(&checker(16, 16), &limits).is_ok());
+
+        let e = validate_dimensions(Dimensions::new(u32::MAX, u32::MAX), &limits).unwrap_err();
+        assert_eq!(e.code(), ErrorCode::TooManyPixels);
+        let e = validate_img(&checker(2048, 1), &limits).unwrap_err();
+        assert_eq!(e.status(), 400);
+        let e = validate_img(&checker(64, 2), &limits).unwrap_err();
+        assert!(e.message().unwrap().starts_with("Aspect"));
+        assert_eq!(e.code(), ErrorCode::AspectRatioOutOfRange);
+
+        let blank = DynamicImage::ImageRgba8(RgbaImage::new(16, 16));
+        let limits = Limits {
+            min_side_len: 4,
+            ..Limits::default()
+        };
+        assert!(validate_img(&blank, &limits).is_err());
+    }
+
+    #[test]
+    fn test_check_dropped_frames() {
+        assert_eq!(
+            check_dropped_frames(MultiFramePolicy::Reject, 1).unwrap(),
+            None
+        );
+        assert_eq!(
+            check_dropped_frames(MultiFramePolicy::FirstFrame, 3).unwrap(),
+            Some(Warning::frames_dropped(3))
+        );
+        let e = check_dropped_frames(MultiFramePolicy::Reject, 3).unwrap_err();
+        assert_eq!(e.status(), 422);
+
+        assert_eq!("reject".parse(), Ok(MultiFramePolicy::Reject));
+        assert!("drop".parse::<MultiFramePolicy>().is_err());
+    }
+
+    #[test]
+    fn test_encode_scaled() {
+        let img = checker(8, 4);
+        let limits = Limits::default();
+        assert_eq!(
+            variant_scales(&limits, &img, None, None),
+            vec![1, 2, 4, 8, 16]
+        );
+        assert_eq!(
+            variant_scales(&limits, &img, None, Some(&[1, 4])),
+            vec![1, 4]
+        );
+
+        let (data, dims) = encode_scaled(&img, None, 4).unwrap();
+        assert_eq!(dims, Dimensions::new(32, 16));
+        let decoded = image::load_from_memory_with_format(&data, ImageFormat::Png).unwrap();
+        assert_eq!(Dimensions::of(&decoded), dims);
+    }
+
+    #[test]
+    fn test_parse_scales() {
+        let limits = Limits::default();
+        assert_eq!(parse_scales("4, 2,4", &limits).unwrap(), vec![1, 2, 4]);
+        assert_eq!(parse_scales("3", &limits).unwrap(), vec![1, 3]);
+        assert!(parse_scales("", &limits).is_err());
+        assert!(parse_scales("2,x", &limits).is_err());
+        assert_eq!(parse_scales("32", &limits).unwrap_err().status(), 400);
+
+        assert!(check_requested_scales(&limits, 64, &[1, 16]).is_ok());
+        let e = check_requested_scales(&limits, 128, &[1, 2, 16]).unwrap_err();
+        assert!(e.message().unwrap().contains("16x"));
+    }
+
+    #[test]
+    fn test_png_hash() {
+        // golden hash: if this changes, so do hashes of quantized and derived images
+        assert_eq!(
+            png_hash(&checker(8, 4), HashAlgorithm::Sha256).unwrap(),
+            "2ed02e3517a4814b53ea6cc9145f9c726034dea6b8d37e0bae678c5e66babc72"
+        );
+
+        // only critical chunks
+        let mut data = Vec::new();
+        encode_png(&checker(8, 4), &mut data, true).unwrap();
+        let mut chunks = Vec::new();
+        let mut pos = 8;
+        while pos < data.len() {
+            let len = u32::from_be_bytes(data[pos..pos + 4].try_into().unwrap()) as usize;
+            chunks.push(String::from_utf8_lossy(&data[pos + 4..pos + 8]).into_owned());
+            pos += 12 + len;
+        }
+        assert_eq!(chunks, ["IHDR", "IDAT", "IEND"]);
+    }
+
+    #[test]
+    fn test_store_variants() {
+        let root = std::env::temp_dir().join(format!("upix-pipeline-{}", std::process::id()));
+        let store = crate::blob::FsStore::new(&root);
+        let img = checker(64, 32);
+
+        let uploaded = futures::executor::block_on(store_variants(
+            VariantStores::new(&store),
+            "ns/",
+            "abc",
+            &img,
+            None,
+            &Limits::default(),
+            None,
+        ))
+        .unwrap();
+        let names: Vec<_> = uploaded.iter().map(|u| u.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "abc.png",
+                "abc_2x.png",
+                "abc_4x.png",
+                "abc_8x.png",
+                "abc_16x.png"
+            ]
+        );
+
+        let data = std::fs::read(root.join("ns/abc_16x.png")).unwrap();
+        let decoded = image::load_from_memory_with_format(&data, ImageFormat::Png).unwrap();
+        assert_eq!(Dimensions::of(&decoded), Dimensions::new(1024, 512));
+
+        let manifest =
+            futures::executor::block_on(crate::manifest::load_manifest(&store, "ns/", "abc"))
+                .unwrap()
+                .unwrap();
+        let names: Vec<_> = manifest.objects.iter().map(|o| o.name.as_str()).collect();
+        assert_eq!(names[..2], ["abc.png", "abc_2x.png"]);
+        assert!(manifest.objects[4].matches(&data));
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_store_variants_cold() {
+        let root = std::env::temp_dir().join(format!("upix-pipeline-cold-{}", std::process::id()));
+        let store = crate::blob::FsStore::new(root.join("hot"));
+        let cold_store = crate::blob::FsStore::new(root.join("cold"));
+        let img = checker(64, 32);
+        let store_with = |limits: Limits| {
+            let stores = VariantStores {
+                store: &store,
+                cold_store: Some(&cold_store),
+            };
+            futures::executor::block_on(store_variants(
+                stores, "", "abc", &img, None, &limits, None,
+            ))
+            .unwrap()
+        };
+
+        let uploaded = store_with(Limits {
+            cold_scale: Some(8),
+            ..Limits::default()
+        });
+        assert_eq!(uploaded.len(), 5);
+        assert!(root.join("hot/abc_4x.png").exists());
+        assert!(!root.join("hot/abc_8x.png").exists());
+        assert!(root.join("cold/abc_8x.png").exists());
+        assert!(root.join("cold/abc_16x.png").exists());
+        let manifest =
+            futures::executor::block_on(crate::manifest::load_manifest(&store, "", "abc"))
+                .unwrap()
+                .unwrap();
+        let cold: Vec<_> = manifest.objects.iter().map(|o| o.cold).collect();
+        assert_eq!(cold, [false, false, false, true, true]);
+
+        let uploaded = store_with(Limits {
+            cold_scale: Some(4),
+            cold_storage: ColdStorage::OnDemand,
+            ..Limits::default()
+        });
+        let names: Vec<_> = uploaded.iter().map(|u| u.name.as_str()).collect();
+        assert_eq!(names, ["abc.png", "abc_2x.png"]);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_with_public_urls() {
+        let uploaded = |name: &str, scale| UploadedImage {
+            name: name.to_string(),
+            scale,
+            width: 16,
+            height: 16,
+            size: 100,
+            url: None,
+        };
+        let result = UploadResult {
+            images: vec![
+                uploaded("abc.png", Some(1)),
+                uploaded("abc_2x.png", Some(2)),
+                uploaded("abc_avatar_32.png", None),
+            ],
+            warnings: Vec::new(),
+            advice: Vec::new(),
+            deduplicated: false,
+            dynamic: DynamicHints::new(&Limits::default(), Dimensions::new(16, 16), 1, true),
+            deferred: Vec::new(),
+        };
+
+        let root = result.with_public_urls("https://img.upix.example/", "");
+        let urls: Vec<_> = root.images.iter().map(|img| img.url.as_deref()).collect();
+        assert_eq!(
+            urls,
+            [
+                Some("https://img.upix.example/abc.png"),
+                Some("https://img.upix.example/abc_2x.png"),
+                None,
+            ]
+        );
+        let json = serde_json::to_value(&root.images[2]).unwrap();
+        assert!(json.get("url").is_none());
+
+        assert_eq!(
+            public_image_url("https://img.upix.example", "icons", "abc_4x.png"),
+            "https://img.upix.example/icons/abc_4x.png"
+        );
+    }
+}
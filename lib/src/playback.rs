@@ -0,0 +1,292 @@
+//! Loop counts and comments of animations, kept from uploaded GIFs and APNGs.
+//!
+//! Encoders loop animations forever by default, while artists often make ones to be played once
+//! (or a few times), so loop counts of sources are kept through storage as APNG and encoded into
+//! the served formats. Comments (of GIF comment extensions, or `Comment` text chunks of APNG) are
+//! kept likewise if the `KEEP_COMMENTS` var is set (see `canonical`), except for WebP which has no
+//! place for them.
+//!
+//! The dyn worker overrides them by the `loop` (`preserve`, `forever` or a number of plays) and
+//! `comment` (`preserve` or `strip`) query params. Comments can't be overridden, so that images
+//! carrying arbitrary text can't be served from the domain.
+
+use std::str::FromStr;
+
+/// Comments longer than this many characters are truncated.
+pub const MAX_COMMENT_LEN: usize = 1024;
+
+/// Keyword of PNG text chunks holding comments.
+pub const PNG_COMMENT_KEYWORD: &str = "Comment";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Playback {
+    /// How many times the animation is played, where 0 is forever (like `num_plays` of APNG).
+    pub plays: u16,
+    pub comment: Option<String>,
+}
+
+impl Playback {
+    /// The comment of the text, truncated to `MAX_COMMENT_LEN`. Empty comments are dropped.
+    pub fn truncated_comment(text: &str) -> Option<String> {
+        let text = text.trim_end_matches('\0');
+        (!text.is_empty()).then(|| text.chars().take(MAX_COMMENT_LEN).collect())
+    }
+}
+
+/// Loop count and comment of the GIF data. GIFs without the `NETSCAPE2.0` extension are played
+/// once, and loop counts of the extension are counts of repeats after the first play. Only the
+/// first comment extension is taken. Broken data gives what has been read so far.
+pub fn read_gif_playback(data: &[u8]) -> Playback {
+    let mut playback = Playback {
+        plays: 1,
+        comment: None,
+    };
+    // header (6 bytes) and logical screen descriptor (7 bytes), with the global color table
+    let Some(&flags) = data.get(10) else {
+        return playback;
+    };
+    let mut pos = 13 + color_table_len(flags);
+    while let Some(&introducer) = data.get(pos) {
+        match introducer {
+            // extension
+            0x21 => {
+                let Some(&label) = data.get(pos + 1) else {
+                    break;
+                };
+                let Some((blocks, next)) = read_sub_blocks(data, pos + 2) else {
+                    break;
+                };
+                match label {
+                    0xff if blocks.first().is_some_and(|b| b == b"NETSCAPE2.0") => {
+                        if let Some([1, lo, hi]) = blocks.get(1).map(|b| &b[..]) {
+                            let repeats = u16::from_le_bytes([*lo, *hi]);
+                            playback.plays = match repeats {
+                                0 => 0,
+                                n => n.saturating_add(1),
+                            };
+                        }
+                    }
+                    0xfe if playback.comment.is_none() => {
+                        // comments are meant to be 7-bit ASCII, and are read as Latin-1
+                        let text: String = blocks.concat().into_iter().map(char::from).collect();
+                        playback.comment = Playback::truncated_comment(&text);
+                    }
+                    _ => {}
+                }
+                pos = next;
+            }
+            // image descriptor (10 bytes), local color table, LZW code size and image data
+            0x2c => {
+                let Some(&flags) = data.get(pos + 9) else {
+                    break;
+                };
+                let data_start = pos + 10 + color_table_len(flags) + 1;
+                let Some((_, next)) = read_sub_blocks(data, data_start) else {
+                    break;
+                };
+                pos = next;
+            }
+            // trailer, or garbage
+            _ => break,
+        }
+    }
+    playback
+}
+
+/// Length of the color table following a descriptor with the packed flags.
+fn color_table_len(flags: u8) -> usize {
+    match flags & 0x80 {
+        0 => 0,
+        _ => 3 << ((flags & 0x07) + 1),
+    }
+}
+
+/// Data sub-blocks starting at the position, and the position after their terminator.
+fn read_sub_blocks(data: &[u8], mut pos: usize) -> Option<(Vec<Vec<u8>>, usize)> {
+    let mut blocks = Vec::new();
+    loop {
+        let len = usize::from(*data.get(pos)?);
+        if len == 0 {
+            return Some((blocks, pos + 1));
+        }
+        blocks.push(data.get(pos + 1..pos + 1 + len)?.to_vec());
+        pos += 1 + len;
+    }
+}
+
+/// Comment extension with the comment, to be inserted before the trailer of GIF data. Characters
+/// out of Latin-1 are written as `?`.
+pub fn gif_comment_extension(comment: &str) -> Vec<u8> {
+    let bytes: Vec<u8> = comment
+        .chars()
+        .map(|c| u8::try_from(c).unwrap_or(b'?'))
+        .collect();
+    let mut ext = vec![0x21, 0xfe];
+    for chunk in bytes.chunks(255) {
+        ext.push(chunk.len() as u8);
+        ext.extend_from_slice(chunk);
+    }
+    ext.push(0);
+    ext
+}
+
+/// How the loop count of served animations is decided.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LoopControl {
+    /// as the source
+    #[default]
+    Preserve,
+    /// played this many times, where 0 is forever
+    Plays(u16),
+}
+
+impl FromStr for LoopControl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "preserve" => Ok(LoopControl::Preserve),
+            "forever" => Ok(LoopControl::Plays(0)),
+            n => match n.parse() {
+                Ok(n) if n > 0 => Ok(LoopControl::Plays(n)),
+                _ => Err(format!(
+                    "'loop' must be 'preserve', 'forever' or 1..={}",
+                    u16::MAX
+                )),
+            },
+        }
+    }
+}
+
+/// How comments of served animations are decided.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CommentControl {
+    #[default]
+    Preserve,
+    Strip,
+}
+
+impl FromStr for CommentControl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "preserve" => Ok(CommentControl::Preserve),
+            "strip" => Ok(CommentControl::Strip),
+            _ => Err("'comment' must be 'preserve' or 'strip'".to_string()),
+        }
+    }
+}
+
+/// Overrides of the playback by the query params.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PlaybackControl {
+    pub loops: LoopControl,
+    pub comment: CommentControl,
+}
+
+impl PlaybackControl {
+    pub fn is_preserve(self) -> bool {
+        self == PlaybackControl::default()
+    }
+
+    pub fn apply(self, playback: Playback) -> Playback {
+        Playback {
+            plays: match self.loops {
+                LoopControl::Preserve => playback.plays,
+                LoopControl::Plays(n) => n,
+            },
+            comment: match self.comment {
+                CommentControl::Preserve => playback.comment,
+                CommentControl::Strip => None,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// 1x1 GIF with a global color table of 2 colors and the extensions.
+    fn gif_with(extensions: &[u8]) -> Vec<u8> {
+        let mut data = b"GIF89a".to_vec();
+        data.extend_from_slice(&[1, 0, 1, 0, 0x80, 0, 0]);
+        data.extend_from_slice(&[0, 0, 0, 255, 255, 255]);
+        data.extend_from_slice(extensions);
+        data.extend_from_slice(&[0x2c, 0, 0, 0, 0, 1, 0, 1, 0, 0]);
+        data.extend_from_slice(&[2, 2, 0x4c, 0x01, 0]);
+        data.push(0x3b);
+        data
+    }
+
+    fn netscape(repeats: u16) -> Vec<u8> {
+        let mut ext = vec![0x21, 0xff, 11];
+        ext.extend_from_slice(b"NETSCAPE2.0");
+        ext.extend_from_slice(&[3, 1]);
+        ext.extend_from_slice(&repeats.to_le_bytes());
+        ext.push(0);
+        ext
+    }
+
+    #[test]
+    fn test_read_gif_playback() {
+        assert_eq!(read_gif_playback(&gif_with(&[])).plays, 1);
+        assert_eq!(read_gif_playback(&gif_with(&netscape(0))).plays, 0);
+        assert_eq!(read_gif_playback(&gif_with(&netscape(2))).plays, 3);
+
+        let mut exts = gif_comment_extension("made by an artist");
+        exts.extend(netscape(0));
+        exts.extend(gif_comment_extension("second"));
+        let playback = read_gif_playback(&gif_with(&exts));
+        assert_eq!(
+            playback,
+            Playback {
+                plays: 0,
+                comment: Some("made by an artist".to_string())
+            }
+        );
+
+        // long comments span sub-blocks, and are truncated
+        let long = "a".repeat(MAX_COMMENT_LEN + 10);
+        let playback = read_gif_playback(&gif_with(&gif_comment_extension(&long)));
+        assert_eq!(playback.comment.unwrap().len(), MAX_COMMENT_LEN);
+
+        // broken data
+        assert_eq!(read_gif_playback(b"GIF89a").plays, 1);
+        let data = gif_with(&netscape(0));
+        assert_eq!(read_gif_playback(&data[..data.len() - 8]).plays, 0);
+    }
+
+    #[test]
+    fn test_parse_controls() {
+        assert_eq!("preserve".parse(), Ok(LoopControl::Preserve));
+        assert_eq!("forever".parse(), Ok(LoopControl::Plays(0)));
+        assert_eq!("3".parse(), Ok(LoopControl::Plays(3)));
+        assert!("0".parse::<LoopControl>().is_err());
+        assert!("65536".parse::<LoopControl>().is_err());
+        assert_eq!("strip".parse(), Ok(CommentControl::Strip));
+        assert!("override".parse::<CommentControl>().is_err());
+    }
+
+    #[test]
+    fn test_playback_control() {
+        let playback = Playback {
+            plays: 1,
+            comment: Some("hi".to_string()),
+        };
+        assert!(PlaybackControl::default().is_preserve());
+        assert_eq!(PlaybackControl::default().apply(playback.clone()), playback);
+        let control = PlaybackControl {
+            loops: LoopControl::Plays(0),
+            comment: CommentControl::Strip,
+        };
+        assert_eq!(
+            control.apply(playback),
+            Playback {
+                plays: 0,
+                comment: None
+            }
+        );
+    }
+}
@@ -0,0 +1,323 @@
+//! Heuristic scores of how much images look like pixel art, for curators of public instances to
+//! flag photos, blurry upscales and JPEG re-encodes among uploads.
+//!
+//! Images upscaled by whole blocks are measured at their original pixel size, so that the scale
+//! doesn't affect the other scores.
+//!
+//! Uploads are given advice on how to export their sources better from the scores, for novice
+//! uploaders who don't know what makes pixel art look bad when scaled.
+
+use image::{ImageFormat, Rgba, RgbaImage};
+use serde::Serialize;
+
+use crate::content::count_colors;
+
+/// Colors are counted up to this many.
+pub const MAX_COUNTED_COLORS: usize = 4096;
+
+/// Neighboring pixels differing by at least this much in some channel make a sharp edge.
+const SHARP_EDGE_DIFF: u8 = 32;
+
+/// JPEG compresses images by blocks of this size.
+const JPEG_BLOCK: u32 = 8;
+
+/// Images look like pixel art with at most this many colors...
+const PIXEL_ART_MAX_COLORS: usize = 256;
+/// ...and at least this crispness...
+const PIXEL_ART_MIN_CRISPNESS: f64 = 0.5;
+/// ...and at most this likelihood of JPEG artifacts.
+const PIXEL_ART_MAX_JPEG_ARTIFACTS: f64 = 0.5;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct QualityScore {
+    /// Size of the blocks of the same color the image is made of, which is how much it has been
+    /// upscaled by (1 if it hasn't).
+    pub upscale_factor: u32,
+    /// Likelihood that the image has been compressed by JPEG, in 0 to 1, from how much colors
+    /// change across the borders of 8x8 blocks compared to within them.
+    pub jpeg_artifacts: f64,
+    /// Number of distinct colors, up to `MAX_COUNTED_COLORS`.
+    pub colors: usize,
+    /// Share of sharp edges of all the edges between neighboring pixels of different colors, in 0
+    /// to 1. Pixel art has few smooth gradients.
+    pub edge_crispness: f64,
+    /// Whether the scores are within the ones of typical pixel art.
+    pub likely_pixel_art: bool,
+}
+
+impl QualityScore {
+    pub fn of(img: &RgbaImage) -> Self {
+        let upscale_factor = detect_upscale_factor(img);
+        let img = sample_blocks(img, upscale_factor);
+        let colors = count_colors(&img, MAX_COUNTED_COLORS);
+        let edges = EdgeStats::of(&img);
+        let edge_crispness = edges.crispness();
+        let jpeg_artifacts = edges.jpeg_artifacts();
+        QualityScore {
+            upscale_factor,
+            jpeg_artifacts,
+            colors,
+            edge_crispness,
+            likely_pixel_art: colors <= PIXEL_ART_MAX_COLORS
+                && edge_crispness >= PIXEL_ART_MIN_CRISPNESS
+                && jpeg_artifacts <= PIXEL_ART_MAX_JPEG_ARTIFACTS,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Advice {
+    /// Machine-readable kind of the advice.
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl Advice {
+    fn new(code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+/// Advice on the source image of an upload in the format.
+pub fn advise(img: &RgbaImage, source_format: ImageFormat) -> Vec<Advice> {
+    let score = QualityScore::of(img);
+    let mut advice = Vec::new();
+    if source_format == ImageFormat::Jpeg {
+        advice.push(Advice::new(
+            "jpeg_source",
+            "Source is a JPEG; consider re-exporting the original as PNG",
+        ));
+    } else if score.jpeg_artifacts > PIXEL_ART_MAX_JPEG_ARTIFACTS {
+        advice.push(Advice::new(
+            "jpeg_resave",
+            "Source appears to be a JPEG re-save; consider re-exporting the original as PNG",
+        ));
+    }
+    if score.colors > PIXEL_ART_MAX_COLORS {
+        let colors = match score.colors {
+            MAX_COUNTED_COLORS => format!("{}+", MAX_COUNTED_COLORS),
+            n => n.to_string(),
+        };
+        advice.push(Advice::new(
+            "many_colors",
+            format!(
+                "Palette has {} colors; consider reducing them to up to {}",
+                colors, PIXEL_ART_MAX_COLORS
+            ),
+        ));
+    }
+    if score.edge_crispness < PIXEL_ART_MIN_CRISPNESS {
+        advice.push(Advice::new(
+            "blurry",
+            "Edges appear smoothed; consider resizing with nearest neighbor, or not at all",
+        ));
+    }
+    if score.upscale_factor > 1 {
+        advice.push(Advice::new(
+            "upscaled",
+            format!(
+                "Image appears to be upscaled {}x; consider uploading it at its original size, as \
+                 scaled variants are generated",
+                score.upscale_factor
+            ),
+        ));
+    }
+    advice
+}
+
+fn gcd(a: u32, b: u32) -> u32 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+/// Size of the largest square blocks aligned to the top-left corner which are all of a single
+/// color: the GCD of the sides and of the lengths of all the runs of a color in rows and columns.
+pub fn detect_upscale_factor(img: &RgbaImage) -> u32 {
+    let (w, h) = img.dimensions();
+    let mut factor = gcd(w, h);
+    let mut add_runs = |len: u32, px: &dyn Fn(u32) -> Rgba<u8>| {
+        let mut run = 1;
+        for i in 1..len {
+            if px(i) == px(i - 1) {
+                run += 1;
+            } else {
+                factor = gcd(factor, run);
+                run = 1;
+            }
+        }
+    };
+    for y in 0..h {
+        add_runs(w, &|x| *img.get_pixel(x, y));
+    }
+    for x in 0..w {
+        add_runs(h, &|y| *img.get_pixel(x, y));
+    }
+    factor.max(1)
+}
+
+/// The image with each block of the size taken by its top-left pixel.
+fn sample_blocks(img: &RgbaImage, size: u32) -> RgbaImage {
+    if size <= 1 {
+        return img.clone();
+    }
+    let (w, h) = img.dimensions();
+    RgbaImage::from_fn(w / size, h / size, |x, y| {
+        *img.get_pixel(x * size, y * size)
+    })
+}
+
+/// Differences between neighboring pixels.
+#[derive(Debug, Default)]
+struct EdgeStats {
+    /// Pairs of neighbors of different colors.
+    edges: u64,
+    sharp_edges: u64,
+    /// Sums and counts of differences of neighbors across borders of JPEG blocks and within them.
+    border_diff: (u64, u64),
+    inner_diff: (u64, u64),
+}
+
+impl EdgeStats {
+    fn of(img: &RgbaImage) -> Self {
+        let mut stats = EdgeStats::default();
+        let (w, h) = img.dimensions();
+        for (x, y, px) in img.enumerate_pixels() {
+            // `at` is the coordinate of the second pixel along the direction
+            let neighbors = [
+                (x + 1 < w).then(|| (img.get_pixel(x + 1, y), x + 1)),
+                (y + 1 < h).then(|| (img.get_pixel(x, y + 1), y + 1)),
+            ];
+            for (other, at) in neighbors.into_iter().flatten() {
+                stats.add(px, other, at % JPEG_BLOCK == 0);
+            }
+        }
+        stats
+    }
+
+    fn add(&mut self, a: &Rgba<u8>, b: &Rgba<u8>, across_border: bool) {
+        let diff = a.0.iter().zip(b.0).map(|(&a, b)| a.abs_diff(b)).max();
+        let diff = diff.unwrap_or(0);
+        let sums = match across_border {
+            true => &mut self.border_diff,
+            false => &mut self.inner_diff,
+        };
+        sums.0 += u64::from(diff);
+        sums.1 += 1;
+        if diff > 0 {
+            self.edges += 1;
+        }
+        if diff >= SHARP_EDGE_DIFF {
+            self.sharp_edges += 1;
+        }
+    }
+
+    fn crispness(&self) -> f64 {
+        match self.edges {
+            0 => 1.0,
+            n => self.sharp_edges as f64 / n as f64,
+        }
+    }
+
+    /// JPEG blocks are compressed independently, so colors change more across their borders than
+    /// within them, and the changes are mostly small ones.
+    fn jpeg_artifacts(&self) -> f64 {
+        let mean = |(sum, n): (u64, u64)| (n > 0).then(|| sum as f64 / n as f64);
+        let (Some(border), Some(inner)) = (mean(self.border_diff), mean(self.inner_diff)) else {
+            return 0.0;
+        };
+        if border == 0.0 {
+            return 0.0;
+        }
+        let blockiness = ((border - inner) / border).clamp(0.0, 1.0);
+        blockiness * (1.0 - self.crispness())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// 4x4 checkerboard of two colors, each square `size` pixels.
+    fn checkerboard(size: u32) -> RgbaImage {
+        RgbaImage::from_fn(4 * size, 4 * size, |x, y| match (x / size + y / size) % 2 {
+            0 => Rgba([255, 0, 0, 255]),
+            _ => Rgba([0, 0, 255, 255]),
+        })
+    }
+
+    #[test]
+    fn test_detect_upscale_factor() {
+        assert_eq!(detect_upscale_factor(&checkerboard(1)), 1);
+        assert_eq!(detect_upscale_factor(&checkerboard(3)), 3);
+        assert_eq!(detect_upscale_factor(&checkerboard(8)), 8);
+
+        // a stray pixel breaks the blocks
+        let mut img = checkerboard(4);
+        img.put_pixel(5, 5, Rgba([0, 0, 0, 255]));
+        assert_eq!(detect_upscale_factor(&img), 1);
+
+        // blocks must fit in the sides
+        let img = RgbaImage::from_pixel(6, 4, Rgba([0, 0, 0, 255]));
+        assert_eq!(detect_upscale_factor(&img), 2);
+    }
+
+    #[test]
+    fn test_quality_score_of_pixel_art() {
+        let score = QualityScore::of(&checkerboard(8));
+        assert_eq!(score.upscale_factor, 8);
+        assert_eq!(score.colors, 2);
+        assert_eq!(score.edge_crispness, 1.0);
+        // edges of upscaled blocks on the borders of JPEG blocks don't count as artifacts
+        assert_eq!(score.jpeg_artifacts, 0.0);
+        assert!(score.likely_pixel_art);
+    }
+
+    #[test]
+    fn test_quality_score_of_gradient() {
+        let img = RgbaImage::from_fn(64, 64, |x, y| Rgba([(x * 4) as u8, (y * 4) as u8, 0, 255]));
+        let score = QualityScore::of(&img);
+        assert_eq!(score.upscale_factor, 1);
+        assert_eq!(score.colors, MAX_COUNTED_COLORS);
+        assert_eq!(score.edge_crispness, 0.0);
+        assert!(!score.likely_pixel_art);
+    }
+
+    #[test]
+    fn test_advise() {
+        let codes = |img: &RgbaImage, fmt| -> Vec<_> {
+            advise(img, fmt).into_iter().map(|a| a.code).collect()
+        };
+        assert!(codes(&checkerboard(1), ImageFormat::Png).is_empty());
+        assert_eq!(codes(&checkerboard(1), ImageFormat::Jpeg), ["jpeg_source"]);
+        assert_eq!(codes(&checkerboard(4), ImageFormat::Png), ["upscaled"]);
+
+        let img = RgbaImage::from_fn(64, 64, |x, y| Rgba([(x * 4) as u8, (y * 4) as u8, 0, 255]));
+        let advice = advise(&img, ImageFormat::Png);
+        assert_eq!(
+            advice.iter().map(|a| a.code).collect::<Vec<_>>(),
+            ["many_colors", "blurry"]
+        );
+        assert_eq!(
+            advice[0].message,
+            "Palette has 4096+ colors; consider reducing them to up to 256"
+        );
+    }
+
+    #[test]
+    fn test_jpeg_artifacts() {
+        // steps on the borders of 8x8 blocks, with faint noise within them
+        let img = RgbaImage::from_fn(32, 32, |x, y| {
+            let v = ((x / 8 + y / 8) * 8 + (x + y) % 2) as u8;
+            Rgba([v, v, v, 255])
+        });
+        let score = QualityScore::of(&img);
+        assert!(score.jpeg_artifacts > 0.5);
+        assert!(!score.likely_pixel_art);
+    }
+}
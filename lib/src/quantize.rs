@@ -0,0 +1,179 @@
+//! Palette quantization, reducing images to a few colors.
+//!
+//! Pixel art passed around as lossy images (JPEG screenshots, mostly) gains lots of slightly-off
+//! colors around edges. Quantizing it to the handful of colors it was drawn with cleans them up.
+//! The palette is chosen by median cut, weighted by how many pixels have each color.
+
+use std::collections::HashMap;
+
+use image::{Rgba, RgbaImage};
+
+use crate::transform::nearest_color;
+
+/// Range of the number of colors that images can be quantized to.
+pub const QUANTIZE_COLORS_RANGE: std::ops::RangeInclusive<usize> = 2..=256;
+
+/// Distinct colors of a box with the number of pixels of each.
+type ColorBox = Vec<([u8; 3], u32)>;
+
+fn channel_range(colors: &ColorBox, ch: usize) -> u8 {
+    let (min, max) = colors
+        .iter()
+        .fold((u8::MAX, u8::MIN), |(min, max), (c, _)| {
+            (min.min(c[ch]), max.max(c[ch]))
+        });
+    max.saturating_sub(min)
+}
+
+/// Channel with the widest range of the box, and the range.
+fn widest_channel(colors: &ColorBox) -> (usize, u8) {
+    (0..3)
+        .map(|ch| (ch, channel_range(colors, ch)))
+        .max_by_key(|&(_, r)| r)
+        .unwrap()
+}
+
+/// Split the box at the weighted median of its widest channel.
+fn split_box(mut colors: ColorBox) -> (ColorBox, ColorBox) {
+    let (ch, _) = widest_channel(&colors);
+    colors.sort_by_key(|(c, _)| c[ch]);
+
+    let total: u64 = colors.iter().map(|&(_, n)| u64::from(n)).sum();
+    let mut acc = 0;
+    let mut at = colors.len() - 1;
+    for (i, &(_, n)) in colors.iter().enumerate() {
+        acc += u64::from(n);
+        if acc * 2 >= total {
+            at = i + 1;
+            break;
+        }
+    }
+    // both halves must have some color
+    let at = at.clamp(1, colors.len() - 1);
+    let rest = colors.split_off(at);
+    (colors, rest)
+}
+
+fn mean_color(colors: &ColorBox) -> [u8; 3] {
+    let total: u64 = colors.iter().map(|&(_, n)| u64::from(n)).sum();
+    let mean = |ch: usize| {
+        let sum: u64 = colors
+            .iter()
+            .map(|&(c, n)| u64::from(c[ch]) * u64::from(n))
+            .sum();
+        ((sum + total / 2) / total) as u8
+    };
+    [mean(0), mean(1), mean(2)]
+}
+
+/// Choose a palette of up to `max_colors` colors representing the image.
+/// Fully transparent pixels don't count.
+pub fn median_cut_palette(img: &RgbaImage, max_colors: usize) -> Vec<[u8; 3]> {
+    let mut hist = HashMap::<[u8; 3], u32>::new();
+    for px in img.pixels().filter(|px| px[3] != 0) {
+        *hist.entry([px[0], px[1], px[2]]).or_default() += 1;
+    }
+    if hist.is_empty() {
+        return Vec::new();
+    }
+
+    let mut boxes = vec![hist.into_iter().collect::<ColorBox>()];
+    while boxes.len() < max_colors {
+        // split the box with the widest range first
+        let Some((i, _)) = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.len() > 1)
+            .max_by_key(|(_, b)| widest_channel(b).1)
+        else {
+            break;
+        };
+        let (a, b) = split_box(boxes.swap_remove(i));
+        boxes.push(a);
+        boxes.push(b);
+    }
+    boxes.iter().map(mean_color).collect()
+}
+
+/// Parse the number of colors to quantize to, given as the param of the name.
+pub fn parse_max_colors(s: &str, name: &str) -> Result<usize, String> {
+    s.parse()
+        .ok()
+        .filter(|n| QUANTIZE_COLORS_RANGE.contains(n))
+        .ok_or_else(|| {
+            format!(
+                "'{}' must be in {}..={}",
+                name,
+                QUANTIZE_COLORS_RANGE.start(),
+                QUANTIZE_COLORS_RANGE.end()
+            )
+        })
+}
+
+/// Reduce the colors of the image to up to `max_colors`. Alpha is kept as is.
+pub fn quantize(img: &RgbaImage, max_colors: usize) -> RgbaImage {
+    let palette = median_cut_palette(img, max_colors);
+    let mut mapped = HashMap::<[u8; 3], [u8; 3]>::new();
+    let mut out = img.clone();
+    for px in out.pixels_mut().filter(|px| px[3] != 0) {
+        let [r, g, b] = *mapped
+            .entry([px[0], px[1], px[2]])
+            .or_insert_with(|| nearest_color(&palette, px.0));
+        *px = Rgba([r, g, b, px[3]]);
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_quantize() {
+        // two flat colors with compression noise around their edge
+        let mut img = RgbaImage::from_fn(8, 4, |x, _| {
+            if x < 4 {
+                Rgba([200, 40, 40, 255])
+            } else {
+                Rgba([20, 20, 120, 255])
+            }
+        });
+        img.put_pixel(3, 0, Rgba([190, 44, 52, 255]));
+        img.put_pixel(4, 1, Rgba([30, 26, 118, 255]));
+        img.put_pixel(4, 2, Rgba([60, 30, 100, 255]));
+        img.put_pixel(0, 3, Rgba([0, 0, 0, 0]));
+
+        let out = quantize(&img, 2);
+        let mut colors: Vec<_> = out
+            .pixels()
+            .filter(|px| px[3] != 0)
+            .map(|px| px.0)
+            .collect();
+        colors.sort();
+        colors.dedup();
+        assert_eq!(colors.len(), 2);
+        assert_eq!(out.get_pixel(3, 0), out.get_pixel(1, 1));
+        assert_eq!(out.get_pixel(4, 2), out.get_pixel(6, 3));
+        // transparent pixels are left alone
+        assert_eq!(out.get_pixel(0, 3), &Rgba([0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn test_median_cut_palette_few_colors() {
+        let img = RgbaImage::from_pixel(2, 2, Rgba([1, 2, 3, 255]));
+        assert_eq!(median_cut_palette(&img, 16), vec![[1, 2, 3]]);
+        assert!(median_cut_palette(&RgbaImage::new(2, 2), 16).is_empty());
+    }
+
+    #[test]
+    fn test_parse_max_colors() {
+        assert_eq!(parse_max_colors("32", "max_colors"), Ok(32));
+        assert_eq!(parse_max_colors("256", "max_colors"), Ok(256));
+        assert_eq!(
+            parse_max_colors("1", "quantize"),
+            Err("'quantize' must be in 2..=256".to_string())
+        );
+        assert!(parse_max_colors("257", "max_colors").is_err());
+        assert!(parse_max_colors("many", "max_colors").is_err());
+    }
+}
@@ -0,0 +1,184 @@
+//! Per-client rate limiting of uploads.
+//!
+//! Upscaling is CPU-heavy, so a single client uploading in a loop can burn the CPU budget of the
+//! whole deployment. Uploads are counted by the `UploadRateLimiter` Durable Object (defined in the
+//! dyn worker), one object per client IP address, in fixed windows of a minute and an hour. This
+//! module contains the counting logic, which the object runs, and a client for the api worker.
+
+use std::net::IpAddr;
+
+use serde::{Deserialize, Serialize};
+use worker::{
+    wasm_bindgen::JsValue, Method, ObjectNamespace, Request, RequestInit, Result as WorkerResult,
+};
+
+/// Name of the Durable Object binding for the rate limiter, shared by both workers.
+pub const RATE_LIMITER_BINDING: &str = "RATE_LIMITER";
+
+const MINUTE_MS: u64 = 60 * 1000;
+const HOUR_MS: u64 = 60 * MINUTE_MS;
+
+/// Max numbers of uploads of a client per window. Configured by the `UPLOAD_RATE_LIMIT` var as
+/// (partial) JSON, like `{ "per_minute": 5 }`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimit {
+    pub per_minute: u32,
+    pub per_hour: u32,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            per_minute: 10,
+            per_hour: 100,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct Window {
+    /// Index of the window since the unix epoch.
+    index: u64,
+    count: u32,
+}
+
+impl Window {
+    /// Count a hit in the window of `len_ms` at the time, if the window has room for it.
+    /// Otherwise returns milliseconds until the next window.
+    fn hit(&mut self, now_ms: u64, len_ms: u64, max: u32) -> Result<(), u64> {
+        let index = now_ms / len_ms;
+        if index != self.index {
+            *self = Window { index, count: 0 };
+        }
+        if self.count >= max {
+            return Err((index + 1) * len_ms - now_ms);
+        }
+        self.count += 1;
+        Ok(())
+    }
+}
+
+/// Uploads of a client in the current windows.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RateWindows {
+    minute: Window,
+    hour: Window,
+}
+
+impl RateWindows {
+    /// Count an upload at the time if it is within the limit. Otherwise returns seconds until the
+    /// client may upload again, and the upload is not counted.
+    pub fn hit(&mut self, now_ms: u64, limit: RateLimit) -> Result<(), u64> {
+        let (mut minute, mut hour) = (self.minute, self.hour);
+        let res = minute
+            .hit(now_ms, MINUTE_MS, limit.per_minute)
+            .and_then(|_| hour.hit(now_ms, HOUR_MS, limit.per_hour));
+        match res {
+            Ok(()) => {
+                (self.minute, self.hour) = (minute, hour);
+                Ok(())
+            }
+            Err(wait_ms) => Err(wait_ms.div_ceil(1000)),
+        }
+    }
+
+    /// Whether the uploads counted so far have used up the quota of the hour.
+    pub fn hour_exhausted(&self, limit: RateLimit) -> bool {
+        self.hour.count >= limit.per_hour
+    }
+}
+
+/// Name of the rate limiter object for the client IP address. IPv6 clients usually have a whole
+/// /64 to pick addresses from, so they are limited by the prefix.
+pub fn client_key(ip: &str) -> String {
+    match ip.parse::<IpAddr>() {
+        Ok(IpAddr::V6(v6)) => {
+            let segments = v6.segments();
+            format!(
+                "{:x}:{:x}:{:x}:{:x}::/64",
+                segments[0], segments[1], segments[2], segments[3]
+            )
+        }
+        Ok(IpAddr::V4(v4)) => v4.to_string(),
+        Err(_) => ip.to_string(),
+    }
+}
+
+/// Decision of the rate limiter on an upload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RateDecision {
+    pub allowed: bool,
+    /// seconds until the client may upload again, if not allowed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<u64>,
+    /// whether the upload used up the quota of the hour, which happens once per hour
+    #[serde(default)]
+    pub exhausted: bool,
+}
+
+/// Ask the rate limiter whether the client may upload now, counting the upload if so.
+pub async fn check_rate_limit(
+    ns: &ObjectNamespace,
+    client: &str,
+    limit: RateLimit,
+) -> WorkerResult<RateDecision> {
+    let stub = ns.id_from_name(client)?.get_stub()?;
+    let mut init = RequestInit::new();
+    init.with_method(Method::Post)
+        .with_body(Some(JsValue::from_str(&serde_json::to_string(&limit)?)));
+    let mut resp = stub
+        .fetch_with_request(Request::new_with_init("https://rate-limiter/hit", &init)?)
+        .await?;
+    resp.json().await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rate_windows() {
+        let limit = RateLimit {
+            per_minute: 2,
+            per_hour: 3,
+        };
+        let mut windows = RateWindows::default();
+        let t0 = 10 * HOUR_MS;
+
+        assert_eq!(windows.hit(t0, limit), Ok(()));
+        assert_eq!(windows.hit(t0 + 1000, limit), Ok(()));
+        // the minute is full until its end
+        assert_eq!(windows.hit(t0 + 30_500, limit), Err(30));
+        // rejected uploads are not counted
+        assert!(!windows.hour_exhausted(limit));
+        assert_eq!(windows.hit(t0 + MINUTE_MS, limit), Ok(()));
+        assert!(windows.hour_exhausted(limit));
+        // the hour is full
+        assert_eq!(windows.hit(t0 + 2 * MINUTE_MS, limit), Err(58 * 60));
+        assert_eq!(windows.hit(t0 + HOUR_MS, limit), Ok(()));
+    }
+
+    #[test]
+    fn test_client_key() {
+        assert_eq!(client_key("203.0.113.5"), "203.0.113.5");
+        assert_eq!(
+            client_key("2001:db8:1:2:3:4:5:6"),
+            client_key("2001:db8:1:2:ffff::1")
+        );
+        assert_eq!(client_key("2001:db8:1:2:3:4:5:6"), "2001:db8:1:2::/64");
+        assert_ne!(client_key("2001:db8:1:2::1"), client_key("2001:db8:1:3::1"));
+    }
+
+    #[test]
+    fn test_parse_rate_limit() {
+        let limit: RateLimit = serde_json::from_str(r#"{ "per_minute": 5 }"#).unwrap();
+        assert_eq!(
+            limit,
+            RateLimit {
+                per_minute: 5,
+                ..RateLimit::default()
+            }
+        );
+    }
+}
@@ -0,0 +1,252 @@
+//! Methods and body sizes allowed per route, declared in a table by each worker.
+//!
+//! Requests are checked against the table before they are routed, so that routes accept nothing
+//! but what their entries allow: requests to paths not in the table are rejected with 404, with
+//! methods not allowed with 405, and with bodies over the limit (or any body, for routes without
+//! one) with 413. Sizes are checked by `Content-Length`; handlers reading bodies of unknown size
+//! still have to limit what they read.
+//!
+//! Paths matched by several entries of the method are checked against the most specific one, where
+//! literal segments win over `:name` ones and those over `*`, like the router picks routes: a
+//! `POST /tilemap` entry applies to `/tilemap` even with `POST /:namespace` listed before it.
+//! Entries of `GET` also allow `HEAD`.
+//!
+//! Entries also tell the scope (see `auth::Scope`) credentials of requests need, which the worker
+//! checks by `required_scope` along with the table.
+
+use std::cmp::Reverse;
+
+use worker::{Method, Request};
+
+use crate::{auth::Scope, ApiError, ApiResult};
+
+/// How large the body of requests to a route may be.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyLimit {
+    /// No body is allowed.
+    Empty,
+    /// Up to the bytes.
+    Max(usize),
+    /// Checked by the handler, for bodies whose limits depend on the request (like uploads, which
+    /// are limited per namespace).
+    Handler,
+}
+
+/// An entry of the table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteRule {
+    pub method: Method,
+    /// Path pattern like the router's: `:name` matches a segment, and a trailing `*` matches the
+    /// rest of the path.
+    pub pattern: &'static str,
+    pub body: BodyLimit,
+    /// Scope required of credentials, or `None` for public routes.
+    pub scope: Option<Scope>,
+    /// Whether upload tokens (see `upload_token`) stand for credentials of the scope. Handlers of
+    /// such routes verify the tokens themselves.
+    pub upload_tokens: bool,
+}
+
+impl RouteRule {
+    pub const fn new(method: Method, pattern: &'static str, body: BodyLimit) -> Self {
+        RouteRule {
+            method,
+            pattern,
+            body,
+            scope: None,
+            upload_tokens: false,
+        }
+    }
+
+    /// Require the scope of credentials.
+    pub const fn scoped(self, scope: Scope) -> Self {
+        RouteRule {
+            scope: Some(scope),
+            ..self
+        }
+    }
+
+    /// Let upload tokens stand for credentials of the scope.
+    pub const fn with_upload_tokens(self) -> Self {
+        RouteRule {
+            upload_tokens: true,
+            ..self
+        }
+    }
+
+    /// Whether requests of the method are allowed by the entry, where `GET` allows `HEAD` too.
+    fn allows_method(&self, method: &Method) -> bool {
+        self.method == *method || (self.method == Method::Get && *method == Method::Head)
+    }
+
+    /// Ranks of the segments of the pattern, higher for more specific ones.
+    fn specificity(&self) -> Vec<u8> {
+        self.pattern
+            .split('/')
+            .map(|pat| match pat {
+                "*" => 0,
+                _ if pat.starts_with(':') => 1,
+                _ => 2,
+            })
+            .collect()
+    }
+
+    fn matches_path(&self, path: &str) -> bool {
+        let mut segments = path.split('/');
+        for pat in self.pattern.split('/') {
+            if pat == "*" {
+                return segments.next().is_some();
+            }
+            let Some(seg) = segments.next() else {
+                return false;
+            };
+            let matched = match pat.strip_prefix(':') {
+                Some(_) => !seg.is_empty(),
+                None => pat == seg,
+            };
+            if !matched {
+                return false;
+            }
+        }
+        segments.next().is_none()
+    }
+}
+
+/// The most specific entry of the table for the method and the path, if any.
+pub fn find_rule<'a>(rules: &'a [RouteRule], method: &Method, path: &str) -> Option<&'a RouteRule> {
+    rules
+        .iter()
+        .filter(|r| r.allows_method(method) && r.matches_path(path))
+        .min_by_key(|r| Reverse(r.specificity()))
+}
+
+/// Check the request with the method, the path and the length of the body (if known) against the
+/// table.
+pub fn check_route(
+    rules: &[RouteRule],
+    method: &Method,
+    path: &str,
+    content_length: Option<usize>,
+) -> ApiResult<()> {
+    if !rules.iter().any(|r| r.matches_path(path)) {
+        return Err(ApiError::no_msg(404));
+    }
+    let Some(rule) = find_rule(rules, method, path) else {
+        let allowed: Vec<_> = allowed_methods(rules, path)
+            .into_iter()
+            .map(String::from)
+            .collect();
+        return Err(ApiError::no_msg(405).with_header("Allow", allowed.join(", ")));
+    };
+    let len = content_length.unwrap_or(0);
+    match rule.body {
+        BodyLimit::Empty if len > 0 => Err(ApiError::new(413, "Request body is not allowed")),
+        BodyLimit::Max(max) if len > max => Err(ApiError::new(
+            413,
+            format!("Request body is too large (> {} bytes)", max),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Methods allowed for the path, for `Allow` of 405s and answering preflights.
+pub fn allowed_methods(rules: &[RouteRule], path: &str) -> Vec<Method> {
+    let mut methods = Vec::new();
+    for rule in rules.iter().filter(|r| r.matches_path(path)) {
+        let implied = match rule.method {
+            Method::Get => vec![Method::Get, Method::Head],
+            _ => vec![rule.method.clone()],
+        };
+        for method in implied {
+            if !methods.contains(&method) {
+                methods.push(method);
+            }
+        }
+    }
+    methods
+}
+
+/// Scope required for the method and the path by the table, if any.
+pub fn required_scope(rules: &[RouteRule], method: &Method, path: &str) -> Option<Scope> {
+    find_rule(rules, method, path).and_then(|r| r.scope)
+}
+
+/// Check the request against the table.
+pub fn guard_request(rules: &[RouteRule], req: &Request) -> ApiResult<()> {
+    let content_length = req
+        .headers()
+        .get("Content-Length")
+        .ok()
+        .flatten()
+        .and_then(|l| l.parse().ok());
+    check_route(rules, &req.method(), &req.path(), content_length)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error_code::ErrorCode;
+
+    const RULES: &[RouteRule] = &[
+        RouteRule::new(Method::Get, "/", BodyLimit::Empty),
+        RouteRule::new(Method::Post, "/:namespace", BodyLimit::Handler).scoped(Scope::Write),
+        RouteRule::new(Method::Get, "/images/:hash", BodyLimit::Empty),
+        RouteRule::new(Method::Delete, "/images/:hash", BodyLimit::Empty).scoped(Scope::Admin),
+        RouteRule::new(Method::Post, "/images/:hash/report", BodyLimit::Max(1024)),
+        RouteRule::new(Method::Get, "/files/*", BodyLimit::Empty),
+        RouteRule::new(Method::Post, "/tilemap", BodyLimit::Max(512)),
+    ];
+
+    #[test]
+    fn test_check_route() {
+        let status = |method: Method, path: &str, len: Option<usize>| match check_route(
+            RULES, &method, path, len,
+        ) {
+            Ok(()) => 200,
+            Err(e) => e.status(),
+        };
+        assert_eq!(status(Method::Get, "/", None), 200);
+        assert_eq!(status(Method::Post, "/avatars", Some(1 << 20)), 200);
+        assert_eq!(status(Method::Delete, "/images/abc", None), 200);
+        assert_eq!(status(Method::Post, "/images/abc/report", Some(1024)), 200);
+        assert_eq!(status(Method::Get, "/files/a/b.png", Some(0)), 200);
+
+        assert_eq!(status(Method::Head, "/images/abc", None), 200);
+
+        // paths matched only by entries of other methods
+        assert_eq!(status(Method::Get, "/images", None), 405);
+        assert_eq!(status(Method::Get, "/images/", None), 404);
+        assert_eq!(status(Method::Get, "/images/abc/stats", None), 404);
+
+        assert_eq!(status(Method::Put, "/images/abc", None), 405);
+        assert_eq!(status(Method::Post, "/images/abc/report", Some(1025)), 413);
+        let e = check_route(RULES, &Method::Get, "/images/abc", Some(1)).unwrap_err();
+        assert_eq!(e.code(), ErrorCode::BodyTooLarge);
+        assert_eq!(status(Method::Get, "/images/abc", Some(1)), 413);
+
+        let e = check_route(RULES, &Method::Post, "/images/abc", None).unwrap_err();
+        assert_eq!(e.status(), 405);
+        assert_eq!(e.header("Allow"), Some("GET, HEAD, DELETE"));
+
+        // literal segments win over `:namespace` listed before them
+        assert_eq!(status(Method::Post, "/tilemap", Some(512)), 200);
+        assert_eq!(status(Method::Post, "/tilemap", Some(513)), 413);
+        assert_eq!(status(Method::Post, "/avatars", Some(513)), 200);
+    }
+
+    #[test]
+    fn test_required_scope() {
+        assert_eq!(
+            required_scope(RULES, &Method::Delete, "/images/abc"),
+            Some(Scope::Admin)
+        );
+        assert_eq!(required_scope(RULES, &Method::Get, "/images/abc"), None);
+        assert_eq!(required_scope(RULES, &Method::Head, "/images/abc"), None);
+        assert_eq!(
+            required_scope(RULES, &Method::Post, "/avatars"),
+            Some(Scope::Write)
+        );
+        assert_eq!(required_scope(RULES, &Method::Post, "/tilemap"), None);
+        assert_eq!(required_scope(RULES, &Method::Get, "/unknown"), None);
+    }
+}
@@ -0,0 +1,162 @@
+//! Versions of the layout of keys in the bucket.
+//!
+//! Objects are written in the current layout, under `_v1/[tenants/{id}/][{namespace}/]`. Objects
+//! from before layouts were versioned (v0) live at the same paths without the version prefix.
+//! Readers look objects up in all the versions, newest first, so a change of the layout is a new
+//! version, and objects stored in older ones stay readable instead of being orphaned.
+//!
+//! Version prefixes start with `_`, which names of namespaces can't contain, so they never clash.
+
+use crate::{
+    blob::{BlobError, BlobStore},
+    namespace::Namespace,
+    tenant::Tenant,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeySchema {
+    /// Keys without the version prefix.
+    V0,
+    V1,
+}
+
+impl KeySchema {
+    /// The version new objects are written in.
+    pub const CURRENT: KeySchema = KeySchema::V1;
+
+    /// Versions objects are read from, newest first.
+    pub const READABLE: [KeySchema; 2] = [KeySchema::V1, KeySchema::V0];
+
+    /// Prefix of all keys in the version.
+    pub fn root(self) -> &'static str {
+        match self {
+            KeySchema::V0 => "",
+            KeySchema::V1 => "_v1/",
+        }
+    }
+
+    /// Prefix of keys of the tenant's objects in the version.
+    pub fn tenant_prefix(self, tenant: &Tenant) -> String {
+        format!("{}{}", self.root(), tenant.key_prefix())
+    }
+
+    /// Prefix of keys of images in the tenant's namespace in the version.
+    pub fn key_prefix(self, tenant: &Tenant, namespace: &Namespace) -> String {
+        format!("{}{}", self.tenant_prefix(tenant), namespace.key_prefix())
+    }
+}
+
+/// ID of the tenant and name of the namespace of images under the prefix of keys (as made by
+/// `KeySchema::key_prefix`) in any version. Empty for the default tenant and the root namespace.
+pub fn parse_key_prefix(key_prefix: &str) -> Option<(&str, &str)> {
+    let path = key_prefix
+        .strip_prefix(KeySchema::V1.root())
+        .unwrap_or(key_prefix);
+    if path.is_empty() {
+        return Some(("", ""));
+    }
+    let segments: Vec<_> = path.strip_suffix('/')?.split('/').collect();
+    match segments[..] {
+        [namespace] => Some(("", namespace)),
+        ["tenants", tenant] => Some((tenant, "")),
+        ["tenants", tenant, namespace] => Some((tenant, namespace)),
+        _ => None,
+    }
+}
+
+/// Load the object with the file name in the tenant's namespace, from the newest version it is
+/// stored in.
+pub async fn load_versioned(
+    store: &impl BlobStore,
+    tenant: &Tenant,
+    namespace: &Namespace,
+    file_name: &str,
+) -> Result<Option<Vec<u8>>, BlobError> {
+    Ok(find_versioned(store, tenant, namespace, file_name)
+        .await?
+        .map(|(_, data)| data))
+}
+
+/// Like `load_versioned`, but along with the key the object was found at.
+pub async fn find_versioned(
+    store: &impl BlobStore,
+    tenant: &Tenant,
+    namespace: &Namespace,
+    file_name: &str,
+) -> Result<Option<(String, Vec<u8>)>, BlobError> {
+    for schema in KeySchema::READABLE {
+        let key = format!("{}{}", schema.key_prefix(tenant, namespace), file_name);
+        if let Some(data) = store.load(&key).await? {
+            return Ok(Some((key, data)));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod test {
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::blob::FsStore;
+
+    #[test]
+    fn test_key_prefix() {
+        let acme = Tenant {
+            id: "acme".to_string(),
+            ..Tenant::default()
+        };
+        let ns = Namespace {
+            name: "emoji".to_string(),
+            ..Namespace::default()
+        };
+        assert_eq!(
+            KeySchema::V1.key_prefix(&Tenant::default(), &Namespace::default()),
+            "_v1/"
+        );
+        assert_eq!(
+            KeySchema::V1.key_prefix(&acme, &ns),
+            "_v1/tenants/acme/emoji/"
+        );
+        assert_eq!(KeySchema::V0.key_prefix(&acme, &ns), "tenants/acme/emoji/");
+        assert_eq!(KeySchema::READABLE[0], KeySchema::CURRENT);
+    }
+
+    #[test]
+    fn test_parse_key_prefix() {
+        assert_eq!(parse_key_prefix("_v1/"), Some(("", "")));
+        assert_eq!(parse_key_prefix(""), Some(("", "")));
+        assert_eq!(parse_key_prefix("_v1/emoji/"), Some(("", "emoji")));
+        assert_eq!(
+            parse_key_prefix("_v1/tenants/acme/emoji/"),
+            Some(("acme", "emoji"))
+        );
+        assert_eq!(parse_key_prefix("tenants/acme/"), Some(("acme", "")));
+        assert_eq!(parse_key_prefix("_staging/a/b/c/"), None);
+    }
+
+    #[test]
+    fn test_load_versioned() {
+        let root = std::env::temp_dir().join(format!("upix-schema-{}", std::process::id()));
+        let store = FsStore::new(&root);
+        let (tenant, ns) = (Tenant::default(), Namespace::default());
+
+        block_on(store.store("abc.png", vec![0], "image/png")).unwrap();
+        assert_eq!(
+            block_on(load_versioned(&store, &tenant, &ns, "abc.png")).unwrap(),
+            Some(vec![0])
+        );
+        // the newer version wins
+        block_on(store.store("_v1/abc.png", vec![1], "image/png")).unwrap();
+        assert_eq!(
+            block_on(load_versioned(&store, &tenant, &ns, "abc.png")).unwrap(),
+            Some(vec![1])
+        );
+        assert_eq!(
+            block_on(load_versioned(&store, &tenant, &ns, "def.png")).unwrap(),
+            None
+        );
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,407 @@
+//! Per-image view/upload counters.
+//!
+//! Counts are kept by the `ImageCounter` Durable Object (defined in the dyn worker). This module
+//! contains the wire types exchanged with it, the in-isolate buffer used to batch increments and
+//! a tiny client for talking to the object from either worker.
+//!
+//! Images are counted by their counter key (see [`counter_key`]), so that views of the same image
+//! in different tenants and namespaces are counted apart.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use worker::{
+    wasm_bindgen::JsValue, Method, ObjectNamespace, Request, RequestInit, Result as WorkerResult,
+};
+
+use crate::schema::parse_key_prefix;
+
+/// Name of the Durable Object binding for the counter, shared by both workers.
+pub const COUNTER_BINDING: &str = "COUNTER";
+
+const HOUR_MS: u64 = 60 * 60 * 1000;
+
+/// Key of the image in the tenant's namespace in the counter: the hash under the prefix of keys of
+/// the namespace in the unversioned layout (see `schema`). Images in the root namespace of the
+/// default tenant are counted by the bare hash, as they were before tenants and namespaces.
+pub fn counter_key(tenant_id: &str, namespace: &str, hash: &str) -> String {
+    let mut key = String::new();
+    if !tenant_id.is_empty() {
+        key.push_str(&format!("tenants/{}/", tenant_id));
+    }
+    if !namespace.is_empty() {
+        key.push_str(&format!("{}/", namespace));
+    }
+    key + hash
+}
+
+/// ID of the tenant, name of the namespace and hash of the image counted under the key.
+pub fn parse_counter_key(key: &str) -> Option<(&str, &str, &str)> {
+    let (prefix, hash) = key.split_at(key.rfind('/').map_or(0, |i| i + 1));
+    let (tenant_id, namespace) = parse_key_prefix(prefix)?;
+    Some((tenant_id, namespace, hash))
+}
+
+/// Index of the hour that contains the given unix time (in milliseconds).
+pub fn hour_index(now_ms: u64) -> u64 {
+    now_ms / HOUR_MS
+}
+
+/// A batch of counter increments, keyed by counter key.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct CounterBatch {
+    #[serde(default)]
+    pub views: HashMap<String, u64>,
+    #[serde(default)]
+    pub uploads: HashMap<String, u64>,
+    /// Views of variants by scale factor, for telling which pre-generated scales are in use.
+    #[serde(default)]
+    pub scale_views: ScaleViews,
+}
+
+/// All-time views of variants by scale factor.
+pub type ScaleViews = HashMap<u32, u64>;
+
+/// Statistics of a single image.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ImageStats {
+    pub hash: String,
+    pub views: ViewStats,
+    pub uploads: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ViewStats {
+    pub total: u64,
+    pub last_24h: u64,
+    pub last_7d: u64,
+}
+
+/// Views of all images within an hour, by counter key.
+pub type HourlyViews = HashMap<String, u64>;
+
+/// Sum up views of the image under the counter `key` in hourly buckets which are within `window_hours` until `now_hour`.
+pub fn views_within(
+    buckets: &[(u64, HourlyViews)],
+    key: &str,
+    now_hour: u64,
+    window_hours: u64,
+) -> u64 {
+    buckets
+        .iter()
+        .filter(|(h, _)| h + window_hours > now_hour)
+        .filter_map(|(_, views)| views.get(key))
+        .sum()
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct TrendingEntry {
+    /// Namespace the image was viewed in. Empty for the root namespace.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub namespace: String,
+    pub hash: String,
+    pub views: u64,
+}
+
+/// Rank images of the tenant by views in hourly buckets which are within `window_hours` until
+/// `now_hour`. Views in other tenants are not counted.
+pub fn rank_trending(
+    buckets: &[(u64, HourlyViews)],
+    tenant_id: &str,
+    now_hour: u64,
+    window_hours: u64,
+    limit: usize,
+) -> Vec<TrendingEntry> {
+    let mut totals = HashMap::<(&str, &str), u64>::new();
+    for (_, views) in buckets.iter().filter(|(h, _)| h + window_hours > now_hour) {
+        for (key, n) in views {
+            match parse_counter_key(key) {
+                Some((t, namespace, hash)) if t == tenant_id => {
+                    *totals.entry((namespace, hash)).or_default() += n;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let mut ranking: Vec<_> = totals
+        .into_iter()
+        .map(|((namespace, hash), views)| TrendingEntry {
+            namespace: namespace.to_string(),
+            hash: hash.to_string(),
+            views,
+        })
+        .collect();
+    // ties are broken by namespace and hash so that the ranking is stable
+    ranking.sort_by(|a, b| {
+        b.views
+            .cmp(&a.views)
+            .then_with(|| a.namespace.cmp(&b.namespace))
+            .then_with(|| a.hash.cmp(&b.hash))
+    });
+    ranking.truncate(limit);
+    ranking
+}
+
+/// Longest window for trending rankings. Hourly views are not kept longer than this.
+pub const MAX_TRENDING_WINDOW_HOURS: u64 = 7 * 24;
+
+/// Parse a trending window spec like `24h` or `7d` into a number of hours.
+pub fn parse_trending_window(s: &str) -> Option<u64> {
+    let hours = match (s.strip_suffix('h'), s.strip_suffix('d')) {
+        (Some(n), _) => n.parse().ok()?,
+        (_, Some(n)) => n.parse::<u64>().ok()?.checked_mul(24)?,
+        _ => return None,
+    };
+    (1..=MAX_TRENDING_WINDOW_HOURS)
+        .contains(&hours)
+        .then_some(hours)
+}
+
+/// Buffers view increments in memory so that they are sent to the counter in batches,
+/// instead of a round trip per served image.
+#[derive(Debug, Default)]
+pub struct CounterBuffer {
+    views: HashMap<String, u64>,
+    scale_views: ScaleViews,
+    pending: u64,
+    last_flush_ms: u64,
+}
+
+/// Flush the buffer if this many views are pending...
+const FLUSH_THRESHOLD: u64 = 32;
+/// ...or this much time has passed since the last flush.
+const FLUSH_INTERVAL_MS: u64 = 10 * 1000;
+
+impl CounterBuffer {
+    /// Record a view of the image, at the scale if it is a scaled variant (rather than a
+    /// downscaled one or a thumbnail).
+    pub fn record_view(&mut self, hash: &str, scale: Option<u32>) {
+        *self.views.entry(hash.to_string()).or_default() += 1;
+        if let Some(scale) = scale {
+            *self.scale_views.entry(scale).or_default() += 1;
+        }
+        self.pending += 1;
+    }
+
+    pub fn should_flush(&self, now_ms: u64) -> bool {
+        self.pending >= FLUSH_THRESHOLD
+            || (self.pending > 0 && now_ms.saturating_sub(self.last_flush_ms) >= FLUSH_INTERVAL_MS)
+    }
+
+    /// Take all the buffered increments out as a batch.
+    pub fn take_batch(&mut self, now_ms: u64) -> CounterBatch {
+        self.pending = 0;
+        self.last_flush_ms = now_ms;
+        CounterBatch {
+            views: std::mem::take(&mut self.views),
+            uploads: HashMap::new(),
+            scale_views: std::mem::take(&mut self.scale_views),
+        }
+    }
+}
+
+fn counter_request(method: Method, path: &str, body: Option<String>) -> WorkerResult<Request> {
+    let mut init = RequestInit::new();
+    init.with_method(method)
+        .with_body(body.map(|b| JsValue::from_str(&b)));
+    Request::new_with_init(&format!("https://counter{}", path), &init)
+}
+
+/// Send a batch of increments to the counter.
+pub async fn send_counter_batch(ns: &ObjectNamespace, batch: &CounterBatch) -> WorkerResult<()> {
+    let stub = ns.id_from_name("global")?.get_stub()?;
+    let body = serde_json::to_string(batch)?;
+    let resp = stub
+        .fetch_with_request(counter_request(Method::Post, "/batch", Some(body))?)
+        .await?;
+    if resp.status_code() != 204 {
+        return Err(format!("counter responded with status {}", resp.status_code()).into());
+    }
+    Ok(())
+}
+
+/// Fetch the statistics of the image from the counter.
+pub async fn fetch_image_stats(ns: &ObjectNamespace, key: &str) -> WorkerResult<ImageStats> {
+    let stub = ns.id_from_name("global")?.get_stub()?;
+    let mut resp = stub
+        .fetch_with_request(counter_request(
+            Method::Get,
+            &format!("/stats/{}", key),
+            None,
+        )?)
+        .await?;
+    resp.json().await
+}
+
+/// Fetch the ranking of the tenant's most viewed images within the window from the counter.
+pub async fn fetch_trending(
+    ns: &ObjectNamespace,
+    tenant_id: &str,
+    window_hours: u64,
+    limit: usize,
+) -> WorkerResult<Vec<TrendingEntry>> {
+    let stub = ns.id_from_name("global")?.get_stub()?;
+    let mut resp = stub
+        .fetch_with_request(counter_request(
+            Method::Get,
+            &format!(
+                "/trending?tenant={}&window_hours={}&limit={}",
+                tenant_id, window_hours, limit
+            ),
+            None,
+        )?)
+        .await?;
+    resp.json().await
+}
+
+/// Fetch the views of variants by scale from the counter.
+pub async fn fetch_scale_views(ns: &ObjectNamespace) -> WorkerResult<ScaleViews> {
+    let stub = ns.id_from_name("global")?.get_stub()?;
+    let mut resp = stub
+        .fetch_with_request(counter_request(Method::Get, "/scales", None)?)
+        .await?;
+    resp.json().await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_views_within() {
+        let buckets = vec![
+            (90, HourlyViews::from([("a".to_string(), 1)])),
+            (
+                99,
+                HourlyViews::from([("a".to_string(), 2), ("b".to_string(), 5)]),
+            ),
+            (100, HourlyViews::from([("a".to_string(), 4)])),
+        ];
+        assert_eq!(views_within(&buckets, "a", 100, 1), 4);
+        assert_eq!(views_within(&buckets, "a", 100, 2), 6);
+        assert_eq!(views_within(&buckets, "a", 100, 24), 7);
+        assert_eq!(views_within(&buckets, "b", 100, 24), 5);
+        assert_eq!(views_within(&buckets, "c", 100, 24), 0);
+    }
+
+    #[test]
+    fn test_rank_trending() {
+        let buckets = vec![
+            (90, HourlyViews::from([("a".to_string(), 10)])),
+            (
+                99,
+                HourlyViews::from([("a".to_string(), 2), ("b".to_string(), 3)]),
+            ),
+            (
+                100,
+                HourlyViews::from([("b".to_string(), 1), ("c".to_string(), 3)]),
+            ),
+        ];
+        let entry = |hash: &str, views| TrendingEntry {
+            namespace: String::new(),
+            hash: hash.to_string(),
+            views,
+        };
+        assert_eq!(
+            rank_trending(&buckets, "", 100, 24, 10),
+            vec![entry("a", 12), entry("b", 4), entry("c", 3)]
+        );
+        assert_eq!(
+            rank_trending(&buckets, "", 100, 2, 10),
+            vec![entry("b", 4), entry("c", 3), entry("a", 2)]
+        );
+        assert_eq!(
+            rank_trending(&buckets, "", 100, 1, 10),
+            vec![entry("c", 3), entry("b", 1)]
+        );
+        assert_eq!(
+            rank_trending(&buckets, "", 100, 24, 1),
+            vec![entry("a", 12)]
+        );
+    }
+
+    #[test]
+    fn test_rank_trending_within_tenant() {
+        let buckets = vec![(
+            100,
+            HourlyViews::from([
+                ("a".to_string(), 1),
+                (counter_key("acme", "", "a"), 5),
+                (counter_key("acme", "pixel", "a"), 2),
+                (counter_key("acme", "pixel", "b"), 3),
+                (counter_key("evil", "", "c"), 10),
+            ]),
+        )];
+        let entry = |namespace: &str, hash: &str, views| TrendingEntry {
+            namespace: namespace.to_string(),
+            hash: hash.to_string(),
+            views,
+        };
+        assert_eq!(
+            rank_trending(&buckets, "acme", 100, 24, 10),
+            vec![
+                entry("", "a", 5),
+                entry("pixel", "b", 3),
+                entry("pixel", "a", 2)
+            ]
+        );
+        assert_eq!(
+            rank_trending(&buckets, "", 100, 24, 10),
+            vec![entry("", "a", 1)]
+        );
+    }
+
+    #[test]
+    fn test_counter_key() {
+        assert_eq!(counter_key("", "", "abc"), "abc");
+        assert_eq!(counter_key("", "pixel", "abc"), "pixel/abc");
+        assert_eq!(counter_key("acme", "", "abc"), "tenants/acme/abc");
+        assert_eq!(
+            counter_key("acme", "pixel", "abc"),
+            "tenants/acme/pixel/abc"
+        );
+
+        for (tenant_id, namespace) in [("", ""), ("", "pixel"), ("acme", ""), ("acme", "pixel")] {
+            let key = counter_key(tenant_id, namespace, "abc");
+            assert_eq!(parse_counter_key(&key), Some((tenant_id, namespace, "abc")));
+        }
+        assert_eq!(parse_counter_key("a/b/c/abc"), None);
+    }
+
+    #[test]
+    fn test_parse_trending_window() {
+        assert_eq!(parse_trending_window("1h"), Some(1));
+        assert_eq!(parse_trending_window("24h"), Some(24));
+        assert_eq!(parse_trending_window("7d"), Some(168));
+        assert_eq!(parse_trending_window("8d"), None);
+        assert_eq!(parse_trending_window("0h"), None);
+        assert_eq!(parse_trending_window("24"), None);
+        assert_eq!(parse_trending_window("h"), None);
+        assert_eq!(parse_trending_window(""), None);
+        assert_eq!(parse_trending_window("7日"), None);
+    }
+
+    #[test]
+    fn test_counter_buffer() {
+        let mut buf = CounterBuffer::default();
+        assert!(!buf.should_flush(FLUSH_INTERVAL_MS * 2));
+
+        buf.record_view("a", Some(2));
+        assert!(!buf.should_flush(FLUSH_INTERVAL_MS - 1));
+        assert!(buf.should_flush(FLUSH_INTERVAL_MS));
+
+        let batch = buf.take_batch(FLUSH_INTERVAL_MS);
+        assert_eq!(batch.views.get("a"), Some(&1));
+        assert_eq!(batch.scale_views.get(&2), Some(&1));
+        assert!(!buf.should_flush(FLUSH_INTERVAL_MS * 2));
+
+        for _ in 0..FLUSH_THRESHOLD {
+            buf.record_view("b", None);
+        }
+        assert!(buf.should_flush(FLUSH_INTERVAL_MS + 1));
+        let batch = buf.take_batch(FLUSH_INTERVAL_MS + 1);
+        assert_eq!(batch.views.get("b"), Some(&FLUSH_THRESHOLD));
+        assert!(batch.scale_views.is_empty());
+    }
+}
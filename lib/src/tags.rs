@@ -0,0 +1,89 @@
+//! Tags of uploaded images, which images are searched by.
+//!
+//! Tags are given at upload, as the comma-separated `tags` field (or query param) or as an array
+//! in JSON bodies. They are case-insensitive, and stored in lowercase.
+
+use std::fmt;
+
+/// Max number of tags of an upload.
+pub const MAX_TAGS: usize = 16;
+/// Max length of a tag in bytes.
+pub const MAX_TAG_LEN: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TagError {
+    TooMany,
+    Invalid(String),
+}
+
+impl fmt::Display for TagError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TagError::TooMany => write!(f, "Too many tags (> {})", MAX_TAGS),
+            TagError::Invalid(tag) => write!(
+                f,
+                "Invalid tag: '{}' (tags are up to {} letters, digits, '-' and '_')",
+                tag, MAX_TAG_LEN
+            ),
+        }
+    }
+}
+
+/// Whether the tag (in lowercase) is valid.
+pub fn is_valid_tag(tag: &str) -> bool {
+    !tag.is_empty()
+        && tag.len() <= MAX_TAG_LEN
+        && tag
+            .bytes()
+            .all(|b| matches!(b, b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_'))
+}
+
+/// Split comma-separated tags. Blank ones are skipped.
+pub fn split_tags(s: &str) -> Vec<String> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Tags in lowercase, sorted and deduplicated, if all of them are valid.
+pub fn normalize_tags(tags: Vec<String>) -> Result<Vec<String>, TagError> {
+    let mut tags = tags
+        .into_iter()
+        .map(|t| t.trim().to_ascii_lowercase())
+        .map(|t| match is_valid_tag(&t) {
+            true => Ok(t),
+            false => Err(TagError::Invalid(t)),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    tags.sort();
+    tags.dedup();
+    if tags.len() > MAX_TAGS {
+        return Err(TagError::TooMany);
+    }
+    Ok(tags)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_normalize_tags() {
+        let tags = normalize_tags(split_tags("Sprite, tile, ,sprite,8-bit_art")).unwrap();
+        assert_eq!(tags, ["8-bit_art", "sprite", "tile"]);
+        assert_eq!(normalize_tags(Vec::new()), Ok(Vec::new()));
+
+        assert_eq!(
+            normalize_tags(vec!["pixel art".to_string()]),
+            Err(TagError::Invalid("pixel art".to_string()))
+        );
+        assert!(normalize_tags(vec!["a".repeat(MAX_TAG_LEN + 1)]).is_err());
+        assert!(normalize_tags(vec!["ドット".to_string()]).is_err());
+
+        let many: Vec<_> = (0..=MAX_TAGS).map(|i| format!("t{}", i)).collect();
+        assert_eq!(normalize_tags(many.clone()), Err(TagError::TooMany));
+        assert_eq!(normalize_tags(many[1..].to_vec()).unwrap().len(), MAX_TAGS);
+    }
+}
@@ -0,0 +1,155 @@
+//! Tenants for white-label hosting.
+//!
+//! A tenant is mapped from a custom hostname via the `TENANTS` KV namespace: the key
+//! `host:{hostname}` holds the tenant config as JSON, like:
+//!
+//! ```json
+//! { "id": "acme", "allowed_origins": ["https://acme.example"], "cache": { "CACHE_VARIANT_EDGE_TTL": "86400" } }
+//! ```
+//!
+//! Images of a tenant live under `tenants/{id}/` in the bucket (below the version prefix, see
+//! `schema`), so a tenant's domain can only serve (and upload to) its own namespace. Requests to
+//! hosts without a mapping, or deployments without the KV binding, are served as the default
+//! tenant whose namespace is the bucket root.
+
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use worker::{console_error, Cors, Env, Result as WorkerResult};
+
+use crate::{
+    cache_policy::{CachePolicy, CacheRoute},
+    config::Config,
+    cors::AllowedOrigins,
+};
+
+pub const TENANTS_BINDING: &str = "TENANTS";
+
+/// Tenant configs are cached by KV at the edge for this many seconds.
+const TENANT_CACHE_TTL: u64 = 300;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Tenant {
+    /// ID of the tenant. Empty for the default tenant.
+    pub id: String,
+    /// Origins allowed to access the tenant's resources via CORS, in the same form as the
+    /// `ALLOWED_ORIGINS` var (see `cors`).
+    #[serde(default = "default_allowed_origins")]
+    pub allowed_origins: Vec<String>,
+    /// Cache policy overrides, in the same form as `CACHE_*` vars.
+    #[serde(default)]
+    pub cache: HashMap<String, String>,
+    /// Base URL of the tenant's images served by the dyn worker. Defaults to `PUBLIC_BASE_URL`.
+    #[serde(default)]
+    pub public_base_url: Option<String>,
+}
+
+fn default_allowed_origins() -> Vec<String> {
+    vec!["*".to_string()]
+}
+
+impl Default for Tenant {
+    fn default() -> Self {
+        Self {
+            id: String::new(),
+            allowed_origins: default_allowed_origins(),
+            cache: HashMap::new(),
+            public_base_url: None,
+        }
+    }
+}
+
+pub(crate) fn is_valid_tenant_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 64
+        && id
+            .bytes()
+            .all(|b| matches!(b, b'a'..=b'z' | b'0'..=b'9' | b'-'))
+}
+
+impl Tenant {
+    /// Prefix of keys of the tenant's objects, relative to the root of the key layout.
+    pub fn key_prefix(&self) -> String {
+        if self.id.is_empty() {
+            String::new()
+        } else {
+            format!("tenants/{}/", self.id)
+        }
+    }
+
+    /// Key of the tenant's object with the given file name, relative to the root of the key
+    /// layout.
+    pub fn object_key(&self, file_name: &str) -> String {
+        format!("{}{}", self.key_prefix(), file_name)
+    }
+
+    /// Base URL of the tenant's images: the tenant's own one, or the `PUBLIC_BASE_URL` var.
+    pub fn public_base_url(&self, env: &Env) -> Option<String> {
+        self.public_base_url
+            .clone()
+            .filter(|u| !u.is_empty())
+            .or_else(|| Config::from_env(env).ok()?.public_base_url.clone())
+    }
+
+    /// Cache policy for the route: defaults, then env overrides, then tenant overrides.
+    pub fn cache_policy(&self, route: CacheRoute, env: &Env) -> CachePolicy {
+        CachePolicy::from_env(route, env)
+            .with_overrides(route, |name| self.cache.get(name).cloned())
+    }
+
+    /// CORS settings for a request from `origin`.
+    pub fn cors(&self, origin: Option<&str>) -> Cors {
+        self.allowed_origins().cors(origin)
+    }
+
+    pub fn allowed_origins(&self) -> AllowedOrigins {
+        AllowedOrigins::new(self.allowed_origins.clone())
+    }
+}
+
+/// Resolve the tenant that the host is mapped to.
+pub async fn resolve_tenant(env: &Env, host: Option<&str>) -> WorkerResult<Tenant> {
+    let (Ok(kv), Some(host)) = (env.kv(TENANTS_BINDING), host) else {
+        return Ok(Tenant::default());
+    };
+    let tenant = kv
+        .get(&format!("host:{}", host.to_ascii_lowercase()))
+        .cache_ttl(TENANT_CACHE_TTL)
+        .json::<Tenant>()
+        .await?;
+    match tenant {
+        None => Ok(Tenant::default()),
+        Some(t) if is_valid_tenant_id(&t.id) => Ok(t),
+        Some(t) => {
+            console_error!("invalid tenant ID for host {}: {:?}", host, t.id);
+            Err("invalid tenant config".into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_object_key() {
+        let default = Tenant::default();
+        assert_eq!(default.object_key("abc.png"), "abc.png");
+
+        let acme = Tenant {
+            id: "acme".to_string(),
+            ..Tenant::default()
+        };
+        assert_eq!(acme.object_key("abc_2x.png"), "tenants/acme/abc_2x.png");
+    }
+
+    #[test]
+    fn test_is_valid_tenant_id() {
+        assert!(is_valid_tenant_id("acme"));
+        assert!(is_valid_tenant_id("acme-games-2"));
+        assert!(!is_valid_tenant_id(""));
+        assert!(!is_valid_tenant_id("../acme"));
+        assert!(!is_valid_tenant_id("Acme"));
+        assert!(!is_valid_tenant_id(&"a".repeat(65)));
+    }
+}
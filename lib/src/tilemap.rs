@@ -0,0 +1,181 @@
+//! Tilemaps stitched from uploaded tiles.
+
+use std::{collections::HashSet, fmt};
+
+use image::{imageops, DynamicImage, RgbaImage};
+use serde::Deserialize;
+
+/// Max number of rows/columns of a tilemap.
+pub const MAX_TILEMAP_GRID_LEN: usize = 64;
+/// Max length of each side of a stitched tilemap.
+pub const MAX_TILEMAP_SIDE_LEN: u32 = 2048;
+/// Max number of distinct tiles in a tilemap. Each one is a fetch from the bucket.
+pub const MAX_TILEMAP_DISTINCT_TILES: usize = 64;
+
+/// A grid of image hashes, row by row. `null` leaves the cell transparent.
+#[derive(Debug, Deserialize)]
+pub struct TilemapManifest {
+    pub tiles: Vec<Vec<Option<String>>>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TilemapError {
+    Empty,
+    TooManyTiles,
+    TooManyDistinctTiles,
+    NonUniformTileSize,
+    TooLarge,
+}
+
+impl fmt::Display for TilemapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TilemapError::Empty => write!(f, "Tilemap has no tiles"),
+            TilemapError::TooManyTiles => write!(
+                f,
+                "Tilemap can have up to {} rows and columns",
+                MAX_TILEMAP_GRID_LEN
+            ),
+            TilemapError::TooManyDistinctTiles => write!(
+                f,
+                "Tilemap can have up to {} distinct tiles",
+                MAX_TILEMAP_DISTINCT_TILES
+            ),
+            TilemapError::NonUniformTileSize => write!(f, "All tiles must have the same size"),
+            TilemapError::TooLarge => write!(
+                f,
+                "Tilemap can be up to {0}x{0} pixels",
+                MAX_TILEMAP_SIDE_LEN
+            ),
+        }
+    }
+}
+
+impl TilemapManifest {
+    /// Check the shape of the grid. Rows may be shorter than the longest one.
+    pub fn validate(&self) -> Result<(), TilemapError> {
+        let cols = self.tiles.iter().map(Vec::len).max().unwrap_or(0);
+        if self.tiles.len() > MAX_TILEMAP_GRID_LEN || cols > MAX_TILEMAP_GRID_LEN {
+            return Err(TilemapError::TooManyTiles);
+        }
+        let distinct = self.distinct_hashes();
+        if distinct.is_empty() {
+            return Err(TilemapError::Empty);
+        }
+        if distinct.len() > MAX_TILEMAP_DISTINCT_TILES {
+            return Err(TilemapError::TooManyDistinctTiles);
+        }
+        Ok(())
+    }
+
+    /// Hashes of tiles appearing in the grid, without duplicates.
+    pub fn distinct_hashes(&self) -> HashSet<&str> {
+        self.tiles
+            .iter()
+            .flatten()
+            .flatten()
+            .map(String::as_str)
+            .collect()
+    }
+}
+
+/// Stitch tiles in the grid into a tilemap. All tiles must have the same size.
+pub fn assemble_tilemap(grid: &[Vec<Option<&DynamicImage>>]) -> Result<RgbaImage, TilemapError> {
+    let mut tiles = grid.iter().flatten().flatten();
+    let (tile_w, tile_h) = match tiles.next() {
+        Some(t) => (t.width(), t.height()),
+        None => return Err(TilemapError::Empty),
+    };
+    if tiles.any(|t| (t.width(), t.height()) != (tile_w, tile_h)) {
+        return Err(TilemapError::NonUniformTileSize);
+    }
+
+    let rows = grid.len() as u32;
+    let cols = grid.iter().map(Vec::len).max().unwrap_or(0) as u32;
+    let (Some(width), Some(height)) = (tile_w.checked_mul(cols), tile_h.checked_mul(rows)) else {
+        return Err(TilemapError::TooLarge);
+    };
+    if width > MAX_TILEMAP_SIDE_LEN || height > MAX_TILEMAP_SIDE_LEN {
+        return Err(TilemapError::TooLarge);
+    }
+
+    let mut tilemap = RgbaImage::new(width, height);
+    for (y, row) in grid.iter().enumerate() {
+        for (x, tile) in row.iter().enumerate() {
+            if let Some(tile) = tile {
+                imageops::replace(
+                    &mut tilemap,
+                    &tile.to_rgba8(),
+                    i64::from(x as u32 * tile_w),
+                    i64::from(y as u32 * tile_h),
+                );
+            }
+        }
+    }
+    Ok(tilemap)
+}
+
+#[cfg(test)]
+mod test {
+    use image::Rgba;
+
+    use super::*;
+
+    fn tile(w: u32, h: u32, color: [u8; 4]) -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_pixel(w, h, Rgba(color)))
+    }
+
+    #[test]
+    fn test_assemble_tilemap() {
+        let red = tile(2, 3, [255, 0, 0, 255]);
+        let blue = tile(2, 3, [0, 0, 255, 255]);
+        let grid = vec![vec![Some(&red), None], vec![None, Some(&blue)], vec![]];
+
+        let tilemap = assemble_tilemap(&grid).unwrap();
+        assert_eq!(tilemap.dimensions(), (4, 9));
+        assert_eq!(tilemap.get_pixel(1, 2), &Rgba([255, 0, 0, 255]));
+        assert_eq!(tilemap.get_pixel(2, 0), &Rgba([0, 0, 0, 0]));
+        assert_eq!(tilemap.get_pixel(3, 5), &Rgba([0, 0, 255, 255]));
+        assert_eq!(tilemap.get_pixel(0, 8), &Rgba([0, 0, 0, 0]));
+
+        let other = tile(3, 3, [0, 255, 0, 255]);
+        let grid = vec![vec![Some(&red), Some(&other)]];
+        assert_eq!(
+            assemble_tilemap(&grid),
+            Err(TilemapError::NonUniformTileSize)
+        );
+
+        let big = tile(1024, 1024, [0, 0, 0, 255]);
+        let grid = vec![vec![Some(&big); 3]];
+        assert_eq!(assemble_tilemap(&grid), Err(TilemapError::TooLarge));
+
+        assert_eq!(assemble_tilemap(&[vec![None]]), Err(TilemapError::Empty));
+    }
+
+    #[test]
+    fn test_validate_manifest() {
+        let manifest: TilemapManifest =
+            serde_json::from_str(r#"{ "tiles": [["a", null], [null, "a"]] }"#).unwrap();
+        assert_eq!(manifest.validate(), Ok(()));
+        assert_eq!(manifest.distinct_hashes(), HashSet::from(["a"]));
+
+        let manifest: TilemapManifest =
+            serde_json::from_str(r#"{ "tiles": [[null], []] }"#).unwrap();
+        assert_eq!(manifest.validate(), Err(TilemapError::Empty));
+
+        let manifest = TilemapManifest {
+            tiles: vec![vec![Some("a".to_string())]; MAX_TILEMAP_GRID_LEN + 1],
+        };
+        assert_eq!(manifest.validate(), Err(TilemapError::TooManyTiles));
+
+        let manifest = TilemapManifest {
+            tiles: (0..=MAX_TILEMAP_DISTINCT_TILES)
+                .map(|i| Some(i.to_string()))
+                .collect::<Vec<_>>()
+                .chunks(8)
+                .map(<[_]>::to_vec)
+                .collect(),
+        };
+        assert_eq!(manifest.validate(), Err(TilemapError::TooManyDistinctTiles));
+    }
+}
@@ -0,0 +1,268 @@
+//! Transforms deriving new images from stored ones.
+//!
+//! A transform spec is a list of operations applied in order, like:
+//!
+//! ```json
+//! [{ "op": "palette", "name": "gameboy" }, { "op": "outline", "color": "#000000" }, { "op": "scale", "factor": 4 }]
+//! ```
+
+use std::fmt;
+
+use image::{DynamicImage, Rgba, RgbaImage};
+use serde::{Deserialize, Serialize};
+
+use crate::{namespace::Limits, upscale_image};
+
+/// Names of the operations, as in `op` of specs.
+pub const TRANSFORM_OPS: [&str; 3] = ["palette", "outline", "scale"];
+
+/// Max number of operations in a transform spec.
+pub const MAX_TRANSFORM_OPS: usize = 8;
+
+/// Max number of colors of a palette given by `colors`.
+pub const MAX_PALETTE_COLORS: usize = 256;
+
+/// The classic 4-shade green palette of Game Boy.
+const GAMEBOY_PALETTE: [[u8; 3]; 4] = [
+    [0x0f, 0x38, 0x0f],
+    [0x30, 0x62, 0x30],
+    [0x8b, 0xac, 0x0f],
+    [0x9b, 0xbc, 0x0f],
+];
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum TransformOp {
+    /// Map each color to the nearest one in a palette, given by `name` or by `colors`.
+    Palette {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        colors: Option<Vec<String>>,
+    },
+    /// Draw a 1px outline around opaque pixels. The canvas grows by 1px on each side.
+    Outline { color: String },
+    /// Upscale by the factor with nearest-neighbor.
+    Scale { factor: u32 },
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TransformError {
+    TooManyOps,
+    TooManyColors,
+    UnknownPalette(String),
+    InvalidColor(String),
+    InvalidScale,
+}
+
+impl fmt::Display for TransformError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransformError::TooManyOps => write!(
+                f,
+                "Transform can have up to {} operations",
+                MAX_TRANSFORM_OPS
+            ),
+            TransformError::TooManyColors => {
+                write!(f, "Palette can have up to {} colors", MAX_PALETTE_COLORS)
+            }
+            TransformError::UnknownPalette(name) => write!(f, "Unknown palette: {}", name),
+            TransformError::InvalidColor(c) => write!(f, "Invalid color: {}", c),
+            TransformError::InvalidScale => write!(f, "Scale factor is out of range"),
+        }
+    }
+}
+
+/// Parse a color in `#rrggbb` form.
+pub fn parse_hex_color(s: &str) -> Option<[u8; 3]> {
+    let hex = s.strip_prefix('#')?;
+    if hex.len() != 6 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let c = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    Some([c(0)?, c(2)?, c(4)?])
+}
+
+fn palette_colors(
+    name: Option<&str>,
+    colors: Option<&[String]>,
+) -> Result<Vec<[u8; 3]>, TransformError> {
+    match (name, colors) {
+        (Some("gameboy"), None) => Ok(GAMEBOY_PALETTE.to_vec()),
+        (None, Some(colors)) if colors.len() > MAX_PALETTE_COLORS => {
+            Err(TransformError::TooManyColors)
+        }
+        (None, Some(colors)) if !colors.is_empty() => colors
+            .iter()
+            .map(|c| parse_hex_color(c).ok_or_else(|| TransformError::InvalidColor(c.clone())))
+            .collect(),
+        (name, _) => Err(TransformError::UnknownPalette(
+            name.unwrap_or_default().to_string(),
+        )),
+    }
+}
+
+pub(crate) fn nearest_color(palette: &[[u8; 3]], [r, g, b, _]: [u8; 4]) -> [u8; 3] {
+    let dist = |c: &[u8; 3]| {
+        let d = |x: u8, y: u8| (i32::from(x) - i32::from(y)).pow(2);
+        d(r, c[0]) + d(g, c[1]) + d(b, c[2])
+    };
+    *palette.iter().min_by_key(|c| dist(c)).unwrap()
+}
+
+fn apply_palette(img: &RgbaImage, palette: &[[u8; 3]]) -> RgbaImage {
+    let mut out = img.clone();
+    for px in out.pixels_mut() {
+        if px[3] == 0 {
+            continue;
+        }
+        let [r, g, b] = nearest_color(palette, px.0);
+        *px = Rgba([r, g, b, px[3]]);
+    }
+    out
+}
+
+fn apply_outline(img: &RgbaImage, [r, g, b]: [u8; 3]) -> RgbaImage {
+    let (w, h) = img.dimensions();
+    let mut out = RgbaImage::new(w + 2, h + 2);
+    image::imageops::replace(&mut out, img, 1, 1);
+
+    let is_opaque = |x: i64, y: i64| {
+        x >= 0
+            && y >= 0
+            && x < i64::from(w)
+            && y < i64::from(h)
+            && img.get_pixel(x as u32, y as u32)[3] != 0
+    };
+    for y in 0..h + 2 {
+        for x in 0..w + 2 {
+            // coordinates in the source image
+            let (sx, sy) = (i64::from(x) - 1, i64::from(y) - 1);
+            if is_opaque(sx, sy) {
+                continue;
+            }
+            let neighbors = [(sx - 1, sy), (sx + 1, sy), (sx, sy - 1), (sx, sy + 1)];
+            if neighbors.iter().any(|&(nx, ny)| is_opaque(nx, ny)) {
+                out.put_pixel(x, y, Rgba([r, g, b, 255]));
+            }
+        }
+    }
+    out
+}
+
+/// Apply the operations to the image in order.
+pub fn apply_transform(
+    img: &DynamicImage,
+    ops: &[TransformOp],
+    limits: &Limits,
+) -> Result<DynamicImage, TransformError> {
+    if ops.len() > MAX_TRANSFORM_OPS {
+        return Err(TransformError::TooManyOps);
+    }
+
+    let mut img = img.clone();
+    for op in ops {
+        img = match op {
+            TransformOp::Palette { name, colors } => {
+                let palette = palette_colors(name.as_deref(), colors.as_deref())?;
+                DynamicImage::ImageRgba8(apply_palette(&img.to_rgba8(), &palette))
+            }
+            TransformOp::Outline { color } => {
+                let color = parse_hex_color(color)
+                    .ok_or_else(|| TransformError::InvalidColor(color.clone()))?;
+                DynamicImage::ImageRgba8(apply_outline(&img.to_rgba8(), color))
+            }
+            TransformOp::Scale { factor } => {
+                let long = img.width().max(img.height());
+                if *factor == 0 || !limits.allows_scale(long, *factor) {
+                    return Err(TransformError::InvalidScale);
+                }
+                upscale_image(&img, *factor)
+            }
+        };
+    }
+    Ok(img)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_transform() {
+        let json = r##"[
+            { "op": "palette", "name": "gameboy" },
+            { "op": "outline", "color": "#000000" },
+            { "op": "scale", "factor": 4 }
+        ]"##;
+        let ops: Vec<TransformOp> = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            ops,
+            vec![
+                TransformOp::Palette {
+                    name: Some("gameboy".to_string()),
+                    colors: None
+                },
+                TransformOp::Outline {
+                    color: "#000000".to_string()
+                },
+                TransformOp::Scale { factor: 4 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_apply_transform() {
+        let mut src = RgbaImage::new(3, 3);
+        src.put_pixel(1, 1, Rgba([255, 255, 255, 255]));
+        let src = DynamicImage::ImageRgba8(src);
+        let limits = Limits::default();
+
+        let ops = [
+            TransformOp::Palette {
+                name: Some("gameboy".to_string()),
+                colors: None,
+            },
+            TransformOp::Outline {
+                color: "#ff0000".to_string(),
+            },
+            TransformOp::Scale { factor: 2 },
+        ];
+        let out = apply_transform(&src, &ops, &limits).unwrap().to_rgba8();
+        assert_eq!(out.dimensions(), (10, 10));
+        // the lightest green, at (2, 2) before scaling
+        assert_eq!(out.get_pixel(4, 4), &Rgba([0x9b, 0xbc, 0x0f, 255]));
+        // outline next to it, but not at diagonals
+        assert_eq!(out.get_pixel(2, 4), &Rgba([255, 0, 0, 255]));
+        assert_eq!(out.get_pixel(2, 2), &Rgba([0, 0, 0, 0]));
+
+        let bad_color = [TransformOp::Outline {
+            color: "red".to_string(),
+        }];
+        assert_eq!(
+            apply_transform(&src, &bad_color, &limits),
+            Err(TransformError::InvalidColor("red".to_string()))
+        );
+        let too_many_colors = [TransformOp::Palette {
+            name: None,
+            colors: Some(vec!["#000000".to_string(); MAX_PALETTE_COLORS + 1]),
+        }];
+        assert_eq!(
+            apply_transform(&src, &too_many_colors, &limits),
+            Err(TransformError::TooManyColors)
+        );
+        let too_big = [TransformOp::Scale { factor: 1000 }];
+        assert_eq!(
+            apply_transform(&src, &too_big, &limits),
+            Err(TransformError::InvalidScale)
+        );
+    }
+
+    #[test]
+    fn test_parse_hex_color() {
+        assert_eq!(parse_hex_color("#0f380F"), Some([0x0f, 0x38, 0x0f]));
+        assert_eq!(parse_hex_color("0f380f"), None);
+        assert_eq!(parse_hex_color("#0f38"), None);
+        assert_eq!(parse_hex_color("#+f380f"), None);
+    }
+}
@@ -0,0 +1,146 @@
+//! Index of uploaded images in KV, for telling whether images exist without asking the bucket.
+//!
+//! The api worker puts an entry for each uploaded image into the `UPLOAD_INDEX` KV namespace,
+//! keyed by the prefix of the image's keys and its hash (like `_v1/{hash}`), listing the variants
+//! stored for it. The entry is deleted along with the image. Both workers look entries up before
+//! heading or listing the bucket, which is slower and billed by operation.
+//!
+//! The index is not exhaustive: images uploaded before it existed have no entries (until they are
+//! uploaded again), and writes take up to a minute to reach other locations. So only hits are
+//! trusted, and misses fall back to the bucket. Without the binding, the bucket is always asked.
+
+use serde::{Deserialize, Serialize};
+use worker::{kv::KvStore, Env, Result as WorkerResult};
+
+use crate::{
+    namespace::{ColdStorage, Limits},
+    pipeline::UploadedImage,
+};
+
+/// Name of the KV binding for the index, shared by both workers.
+pub const UPLOAD_INDEX_BINDING: &str = "UPLOAD_INDEX";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexEntry {
+    /// The original and its variants stored at upload.
+    pub variants: Vec<IndexedVariant>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexedVariant {
+    pub name: String,
+    /// absent for avatar images
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scale: Option<u32>,
+    /// size of the stored image in bytes
+    pub size: u64,
+    /// whether the variant is in the cold bucket, under the same key
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub cold: bool,
+}
+
+impl IndexEntry {
+    /// Entry of the images stored by an upload with the limits. Variants in cold storage are in
+    /// the cold bucket if there is one, and kept with the original otherwise.
+    pub fn of_upload(images: &[UploadedImage], limits: &Limits, has_cold_bucket: bool) -> Self {
+        let variants = images
+            .iter()
+            .map(|img| IndexedVariant {
+                name: img.name.clone(),
+                scale: img.scale,
+                size: img.size,
+                cold: has_cold_bucket
+                    && img.scale.and_then(|s| limits.cold_storage_of(s))
+                        == Some(ColdStorage::Bucket),
+            })
+            .collect();
+        Self { variants }
+    }
+
+    /// The variant at the scale, if it is stored.
+    pub fn scaled(&self, scale: u32) -> Option<&IndexedVariant> {
+        self.variants.iter().find(|v| v.scale == Some(scale))
+    }
+}
+
+/// Key of the entry of the image with the hash under the `key_prefix`.
+pub fn index_key(key_prefix: &str, hash: &str) -> String {
+    format!("{}{}", key_prefix, hash)
+}
+
+/// The index, if bound.
+pub fn upload_index(env: &Env) -> Option<KvStore> {
+    env.kv(UPLOAD_INDEX_BINDING).ok()
+}
+
+/// The entry of the image with the hash under the `key_prefix`, if indexed.
+pub async fn load_index_entry(
+    kv: &KvStore,
+    key_prefix: &str,
+    hash: &str,
+) -> WorkerResult<Option<IndexEntry>> {
+    Ok(kv.get(&index_key(key_prefix, hash)).json().await?)
+}
+
+pub async fn put_index_entry(
+    kv: &KvStore,
+    key_prefix: &str,
+    hash: &str,
+    entry: &IndexEntry,
+) -> WorkerResult<()> {
+    kv.put(&index_key(key_prefix, hash), entry)?
+        .execute()
+        .await?;
+    Ok(())
+}
+
+pub async fn delete_index_entry(kv: &KvStore, key_prefix: &str, hash: &str) -> WorkerResult<()> {
+    kv.delete(&index_key(key_prefix, hash)).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn uploaded(name: &str, scale: Option<u32>) -> UploadedImage {
+        UploadedImage {
+            name: name.to_string(),
+            scale,
+            width: 16,
+            height: 16,
+            size: 100,
+            url: None,
+        }
+    }
+
+    #[test]
+    fn test_index_entry_of_upload() {
+        let images = [
+            uploaded("abc.png", Some(1)),
+            uploaded("abc_4x.png", Some(4)),
+            uploaded("abc_8x.png", Some(8)),
+            uploaded("abc_avatar_64.png", None),
+        ];
+        let limits = Limits {
+            cold_scale: Some(8),
+            ..Limits::default()
+        };
+        let entry = IndexEntry::of_upload(&images, &limits, true);
+        let cold: Vec<_> = entry.variants.iter().map(|v| v.cold).collect();
+        assert_eq!(cold, [false, false, true, false]);
+        assert_eq!(entry.scaled(4).unwrap().name, "abc_4x.png");
+        assert!(entry.scaled(2).is_none());
+
+        // cold variants are kept with the original without the cold bucket
+        let entry = IndexEntry::of_upload(&images, &limits, false);
+        assert!(entry.variants.iter().all(|v| !v.cold));
+
+        let json = serde_json::to_string(&entry.variants[3]).unwrap();
+        assert_eq!(json, r#"{"name":"abc_avatar_64.png","size":100}"#);
+        assert_eq!(
+            serde_json::from_str::<IndexedVariant>(&json).unwrap(),
+            entry.variants[3]
+        );
+    }
+}
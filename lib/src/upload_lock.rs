@@ -0,0 +1,153 @@
+//! Locks serializing concurrent uploads of the same data.
+//!
+//! Without them, clients uploading the same bytes at once (like retries racing the original
+//! request) all miss deduplication, run the whole pipeline and put the same keys concurrently.
+//! Uploads which can be deduplicated take a lease from the `UploadLock` Durable Object (defined in
+//! the dyn worker, one object per key prefix and hash) before looking for stored images. Later
+//! uploads poll the object until the lease is released, and then find the images stored by the
+//! first one, responding with them as deduplicated uploads.
+//!
+//! Leases expire, so that uploads dying while holding them (like by exceeding CPU limits) don't
+//! block the others for long. Uploads which have waited too long process the data by themselves.
+//!
+//! This module contains the lock logic, which the object runs, and a client for the api worker.
+
+use serde::{Deserialize, Serialize};
+use worker::{Method, ObjectNamespace, Request, RequestInit, Response, Result as WorkerResult};
+
+/// Name of the Durable Object binding for upload locks, shared by both workers.
+pub const UPLOAD_LOCK_BINDING: &str = "UPLOAD_LOCK";
+
+/// Leases expire after this many milliseconds.
+pub const LEASE_MS: u64 = 60 * 1000;
+/// Uploads wait for leases of others for at most this long.
+pub const MAX_WAIT_MS: u64 = 30 * 1000;
+/// Intervals of polling for leases double from the min up to the max.
+const MIN_POLL_MS: u64 = 100;
+const MAX_POLL_MS: u64 = 1000;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Lease {
+    owner: String,
+    expires_at: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LockState {
+    lease: Option<Lease>,
+}
+
+impl LockState {
+    /// Take the lease for the owner, unless another one holds it at the time. Owners taking their
+    /// own lease again extend it.
+    pub fn acquire(&mut self, owner: &str, now_ms: u64) -> bool {
+        if self
+            .lease
+            .as_ref()
+            .is_some_and(|l| l.owner != owner && now_ms < l.expires_at)
+        {
+            return false;
+        }
+        self.lease = Some(Lease {
+            owner: owner.to_string(),
+            expires_at: now_ms + LEASE_MS,
+        });
+        true
+    }
+
+    /// Give up the lease of the owner. Leases taken over by others since expiring are kept.
+    pub fn release(&mut self, owner: &str) {
+        if self.lease.as_ref().is_some_and(|l| l.owner == owner) {
+            self.lease = None;
+        }
+    }
+
+    /// Whether the lease is held at the time.
+    pub fn is_held(&self, now_ms: u64) -> bool {
+        self.lease.as_ref().is_some_and(|l| now_ms < l.expires_at)
+    }
+}
+
+/// Result of an attempt to take a lease, as told by the object.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LockDecision {
+    pub acquired: bool,
+}
+
+/// Body of requests to the object.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LockRequest {
+    pub owner: String,
+}
+
+/// Milliseconds to wait before the next attempt, after the attempts made so far.
+pub fn poll_delay_ms(attempts: u32) -> u64 {
+    (MIN_POLL_MS << attempts.min(4)).min(MAX_POLL_MS)
+}
+
+async fn send(ns: &ObjectNamespace, name: &str, path: &str, owner: &str) -> WorkerResult<Response> {
+    let stub = ns.id_from_name(name)?.get_stub()?;
+    let body = serde_json::to_string(&LockRequest {
+        owner: owner.to_string(),
+    })?;
+    let mut init = RequestInit::new();
+    init.with_method(Method::Post).with_body(Some(body.into()));
+    let url = format!("https://upload-lock{}", path);
+    stub.fetch_with_request(Request::new_with_init(&url, &init)?)
+        .await
+}
+
+/// Try to take the lease of the lock with the name for the owner.
+pub async fn try_lock(ns: &ObjectNamespace, name: &str, owner: &str) -> WorkerResult<bool> {
+    let mut resp = send(ns, name, "/acquire", owner).await?;
+    let decision: LockDecision = resp.json().await?;
+    Ok(decision.acquired)
+}
+
+/// Release the lease of the lock with the name held by the owner.
+pub async fn unlock(ns: &ObjectNamespace, name: &str, owner: &str) -> WorkerResult<()> {
+    send(ns, name, "/release", owner).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lock_state() {
+        let mut state = LockState::default();
+        let t0 = 1_000_000;
+        assert!(state.acquire("a", t0));
+        assert!(!state.acquire("b", t0 + 1000));
+        assert!(state.acquire("a", t0 + 1000));
+
+        // others can't release the lease
+        state.release("b");
+        assert!(!state.acquire("b", t0 + 2000));
+        state.release("a");
+        assert!(!state.is_held(t0 + 2000));
+        assert!(state.acquire("b", t0 + 2000));
+
+        // expired leases are taken over, and not released by their former owners
+        let expired = t0 + 2000 + LEASE_MS;
+        assert!(!state.is_held(expired));
+        assert!(state.acquire("c", expired));
+        state.release("b");
+        assert!(!state.acquire("a", expired));
+    }
+
+    #[test]
+    fn test_poll_delay_ms() {
+        let delays: Vec<_> = (0..7).map(poll_delay_ms).collect();
+        assert_eq!(delays, [100, 200, 400, 800, 1000, 1000, 1000]);
+        // polling for the max wait takes a bounded number of requests to the object
+        let mut waited = 0;
+        let mut attempts = 0;
+        while waited < MAX_WAIT_MS {
+            waited += poll_delay_ms(attempts);
+            attempts += 1;
+        }
+        assert!(attempts < 40);
+    }
+}
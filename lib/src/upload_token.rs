@@ -0,0 +1,169 @@
+//! One-time upload tokens.
+//!
+//! Tokens let end users upload without the API key: the owner's backend mints one with
+//! `POST /upload-tokens` (authorized by the API key) and hands it to the client, which sends it
+//! as the bearer token of `POST /`. A token is good for one upload of data up to its max size,
+//! into its namespace of the tenant it was minted by, until it expires.
+//!
+//! Tokens are `ut1.{nonce}.{expires_at}.{max_size}.{tenant}.{namespace}.{signature}`, where the
+//! signature is hex-encoded HMAC-SHA256 of everything before it, keyed by the `UPLOAD_TOKEN_SECRET`
+//! secret. Tokens themselves are stateless, so the api worker keeps nonces of redeemed tokens
+//! until they expire to refuse second uses.
+
+use std::fmt;
+
+use crate::{
+    auth::constant_time_eq, namespace::is_valid_namespace_name, presign::hmac_sha256,
+    tenant::is_valid_tenant_id,
+};
+
+/// Name of the secret that holds the key of token signatures.
+pub const UPLOAD_TOKEN_SECRET: &str = "UPLOAD_TOKEN_SECRET";
+
+const TOKEN_PREFIX: &str = "ut1.";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UploadToken {
+    /// Random hex string, unique to the token.
+    pub nonce: String,
+    /// Unix time in seconds.
+    pub expires_at: u64,
+    /// Max size of the uploaded data in bytes.
+    pub max_size: usize,
+    /// ID of the tenant to upload into. Empty for the default tenant.
+    pub tenant: String,
+    /// Name of the namespace to upload into. Empty for the root namespace.
+    pub namespace: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenError {
+    Malformed,
+    InvalidSignature,
+    Expired,
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TokenError::Malformed => "Malformed upload token",
+            TokenError::InvalidSignature => "Invalid upload token",
+            TokenError::Expired => "Upload token has expired",
+        })
+    }
+}
+
+/// Whether the bearer token is an upload token rather than an API key.
+pub fn is_upload_token(token: &str) -> bool {
+    token.starts_with(TOKEN_PREFIX)
+}
+
+impl UploadToken {
+    fn payload(&self) -> String {
+        format!(
+            "{}{}.{}.{}.{}.{}",
+            TOKEN_PREFIX, self.nonce, self.expires_at, self.max_size, self.tenant, self.namespace
+        )
+    }
+
+    /// Encode the token, signed with the secret.
+    pub fn sign(&self, secret: &[u8]) -> String {
+        let payload = self.payload();
+        let signature = hex::encode(hmac_sha256(secret, &payload));
+        format!("{}.{}", payload, signature)
+    }
+
+    /// Decode the token, and check that it is signed with the secret and not expired at `now`
+    /// (unix time in seconds).
+    pub fn verify(token: &str, secret: &[u8], now: u64) -> Result<UploadToken, TokenError> {
+        let (payload, signature) = token.rsplit_once('.').ok_or(TokenError::Malformed)?;
+        let fields: Vec<_> = payload
+            .strip_prefix(TOKEN_PREFIX)
+            .ok_or(TokenError::Malformed)?
+            .split('.')
+            .collect();
+        let [nonce, expires_at, max_size, tenant, namespace] = fields[..] else {
+            return Err(TokenError::Malformed);
+        };
+        let is_hex = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_hexdigit());
+        if !is_hex(nonce)
+            || !(tenant.is_empty() || is_valid_tenant_id(tenant))
+            || !(namespace.is_empty() || is_valid_namespace_name(namespace))
+        {
+            return Err(TokenError::Malformed);
+        }
+        let parsed = UploadToken {
+            nonce: nonce.to_string(),
+            expires_at: expires_at.parse().map_err(|_| TokenError::Malformed)?,
+            max_size: max_size.parse().map_err(|_| TokenError::Malformed)?,
+            tenant: tenant.to_string(),
+            namespace: namespace.to_string(),
+        };
+
+        let expected = hex::encode(hmac_sha256(secret, payload));
+        if !constant_time_eq(signature.as_bytes(), expected.as_bytes()) {
+            return Err(TokenError::InvalidSignature);
+        }
+        if now >= parsed.expires_at {
+            return Err(TokenError::Expired);
+        }
+        Ok(parsed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_upload_token() {
+        let token = UploadToken {
+            nonce: "0123abcd".to_string(),
+            expires_at: 1_700_000_600,
+            max_size: 1024,
+            tenant: "acme".to_string(),
+            namespace: "emoji".to_string(),
+        };
+        let encoded = token.sign(b"secret");
+        assert!(is_upload_token(&encoded));
+        assert!(encoded.starts_with("ut1.0123abcd.1700000600.1024.acme.emoji."));
+        assert_eq!(
+            UploadToken::verify(&encoded, b"secret", 1_700_000_000),
+            Ok(token.clone())
+        );
+
+        assert_eq!(
+            UploadToken::verify(&encoded, b"secret", 1_700_000_600),
+            Err(TokenError::Expired)
+        );
+        assert_eq!(
+            UploadToken::verify(&encoded, b"other", 1_700_000_000),
+            Err(TokenError::InvalidSignature)
+        );
+        // fields can't be changed without the secret
+        let tampered = encoded.replace(".1024.", ".9999.");
+        assert_eq!(
+            UploadToken::verify(&tampered, b"secret", 1_700_000_000),
+            Err(TokenError::InvalidSignature)
+        );
+        let other_tenant = encoded.replace(".acme.", ".evil.");
+        assert_eq!(
+            UploadToken::verify(&other_tenant, b"secret", 1_700_000_000),
+            Err(TokenError::InvalidSignature)
+        );
+        assert_eq!(
+            UploadToken::verify("ut1.abc.1", b"secret", 0),
+            Err(TokenError::Malformed)
+        );
+
+        // the root namespace of the default tenant
+        let root = UploadToken {
+            tenant: String::new(),
+            namespace: String::new(),
+            ..token
+        };
+        let encoded = root.sign(b"secret");
+        assert_eq!(UploadToken::verify(&encoded, b"secret", 0), Ok(root));
+        assert!(!is_upload_token("some-api-key"));
+    }
+}
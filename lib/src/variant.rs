@@ -0,0 +1,76 @@
+//! Naming of stored images.
+//!
+//! Each upload is stored as the original `{hash}.png` plus variants next to it: upscaled images
+//! `{hash}_{N}x.png` and avatar images `{hash}_avatar_{size}.png`.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Variant {
+    Original,
+    Upscaled(u32),
+    Avatar(u32),
+}
+
+impl Variant {
+    /// File name of the variant of the image with the hash.
+    pub fn file_name(&self, hash: &str) -> String {
+        match self {
+            Variant::Original => format!("{}.png", hash),
+            Variant::Upscaled(scale) => format!("{}_{}x.png", hash, scale),
+            Variant::Avatar(size) => format!("{}_avatar_{}.png", hash, size),
+        }
+    }
+
+    /// Parse the file name of a variant of the image with the hash.
+    pub fn parse(hash: &str, name: &str) -> Option<Self> {
+        let rest = name.strip_prefix(hash)?.strip_suffix(".png")?;
+        if rest.is_empty() {
+            return Some(Variant::Original);
+        }
+        if let Some(size) = rest.strip_prefix("_avatar_") {
+            return parse_positive(size).map(Variant::Avatar);
+        }
+        let scale = rest.strip_prefix('_')?.strip_suffix('x')?;
+        parse_positive(scale).map(Variant::Upscaled)
+    }
+
+    /// Scale factor relative to the original, if the variant is a scaled copy of it.
+    pub fn scale(&self) -> Option<u32> {
+        match self {
+            Variant::Original => Some(1),
+            Variant::Upscaled(scale) => Some(*scale),
+            Variant::Avatar(_) => None,
+        }
+    }
+}
+
+fn parse_positive(s: &str) -> Option<u32> {
+    if s.starts_with('0') || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok().filter(|&n| n > 0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_variant_names() {
+        let hash = "abc";
+        for variant in [
+            Variant::Original,
+            Variant::Upscaled(4),
+            Variant::Avatar(128),
+        ] {
+            assert_eq!(
+                Variant::parse(hash, &variant.file_name(hash)),
+                Some(variant)
+            );
+        }
+        assert_eq!(Variant::parse(hash, "abc_02x.png"), None);
+        assert_eq!(Variant::parse(hash, "abc_0x.png"), None);
+        assert_eq!(Variant::parse(hash, "abc_2x.webp"), None);
+        assert_eq!(Variant::parse(hash, "abcd.png"), None);
+        assert_eq!(Variant::parse(hash, "abc_avatar_.png"), None);
+    }
+}
@@ -0,0 +1,261 @@
+//! Generation of upscaled variants deferred from uploads to a Cloudflare Queue.
+//!
+//! Encoding the upscaled variants dominates the latency of uploads. With the `VARIANT_QUEUE`
+//! producer binding, uploads store only the original (with a manifest of it) and send a
+//! `VariantJob` to the queue, which the api worker consumes (see api/src/variant_queue.rs): it
+//! stores the variants from the original, then the manifest, record and index entry of all of
+//! them. Until then, the dyn worker renders the variants from the original on demand, as it does
+//! at scales which are not stored.
+//!
+//! The same queue carries the deletions of bulk deletions (see `bulk_delete`).
+//!
+//! Jobs failing `MAX_JOB_ATTEMPTS` times in a row are dead-lettered: recorded for operators, who
+//! can send them again (see api/src/variant_queue.rs).
+
+use image::ImageFormat;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    animation::decode_animation,
+    blob::BlobStore,
+    bulk_delete::DeleteJob,
+    manifest::{load_manifest, store_manifest},
+    namespace::Limits,
+    pipeline::{store_variant, StoreError, UploadedImage, VariantStores},
+    variant::Variant,
+};
+
+/// Name of the queue producer binding of the api worker.
+pub const VARIANT_QUEUE_BINDING: &str = "VARIANT_QUEUE";
+
+/// Variants of an upload to store after responding.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VariantJob {
+    pub tenant: String,
+    pub namespace: String,
+    pub key_prefix: String,
+    pub hash: String,
+    /// images stored at upload
+    pub stored: Vec<UploadedImage>,
+    /// scales of the upscaled variants to store
+    pub scales: Vec<u32>,
+}
+
+/// Deliveries of a message before its job is dead-lettered. Must not exceed `max_retries` of the
+/// consumer (see api/wrangler.toml) plus one, or the queue drops the message first.
+pub const MAX_JOB_ATTEMPTS: u32 = 4;
+
+/// Message of the queue, tagged by `kind` so that a message of one kind never decodes as the other.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum QueueMessage {
+    Variants(VariantJob),
+    Delete(DeleteJob),
+}
+
+impl QueueMessage {
+    /// Kind of the message, as tagged.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            QueueMessage::Variants(_) => "variants",
+            QueueMessage::Delete(_) => "delete",
+        }
+    }
+
+    /// ID of the tenant and hash of the image the job of the message is on.
+    pub fn image(&self) -> (&str, &str) {
+        match self {
+            QueueMessage::Variants(job) => (&job.tenant, &job.hash),
+            QueueMessage::Delete(job) => (&job.tenant, &job.hash),
+        }
+    }
+}
+
+/// Split scales of variants of an upload into those stored at upload and those deferred. The
+/// original is always stored at upload, as variants are generated from it. `None` if there is
+/// nothing to defer, or the original is not stored at all.
+pub fn split_deferred_scales(scales: &[u32]) -> Option<(Vec<u32>, Vec<u32>)> {
+    let (now, deferred): (Vec<_>, Vec<_>) = scales.iter().partition(|&&scale| scale == 1);
+    (!now.is_empty() && !deferred.is_empty()).then_some((now, deferred))
+}
+
+/// Add the images to the list, replacing those of the same names, ordered by scale.
+pub fn merge_uploaded(images: &mut Vec<UploadedImage>, new: Vec<UploadedImage>) {
+    images.retain(|img| !new.iter().any(|n| n.name == img.name));
+    images.extend(new);
+    images.sort_by_key(|img| img.scale);
+}
+
+/// Store the upscaled variants at the scales from the original of the image stored under the
+/// `key_prefix`, and add them to its manifest if any. Returns the stored variants, or `None` if
+/// the original is gone (deleted since uploaded).
+pub async fn store_deferred_variants<S: BlobStore>(
+    stores: VariantStores<'_, S>,
+    key_prefix: &str,
+    hash: &str,
+    scales: &[u32],
+    limits: &Limits,
+) -> Result<Option<Vec<UploadedImage>>, StoreError> {
+    let key = format!("{}{}", key_prefix, Variant::Original.file_name(hash));
+    let Some(data) = stores.store.load(&key).await? else {
+        return Ok(None);
+    };
+    let decode_error = |source| StoreError::Encode { scale: 1, source };
+    // originals of animations are stored as APNG
+    let anim = decode_animation(&data, ImageFormat::Png).map_err(decode_error)?;
+    let img = match &anim {
+        Some(anim) => anim.first_frame(),
+        None => {
+            image::load_from_memory_with_format(&data, ImageFormat::Png).map_err(decode_error)?
+        }
+    };
+
+    let mut uploaded = Vec::with_capacity(scales.len());
+    let mut entries = Vec::with_capacity(scales.len());
+    for &scale in scales {
+        let (image, entry) = store_variant(
+            &stores,
+            key_prefix,
+            hash,
+            &img,
+            anim.as_ref(),
+            limits,
+            scale,
+        )
+        .await?;
+        uploaded.push(image);
+        entries.push(entry);
+    }
+
+    // the manifest is stored last, so that it never lists missing objects. images stored without
+    // one are left so, as it would list only some of their objects
+    if let Some(mut manifest) = load_manifest(stores.store, key_prefix, hash).await? {
+        manifest
+            .objects
+            .retain(|o| !entries.iter().any(|e| e.name == o.name));
+        manifest.objects.extend(entries);
+        store_manifest(stores.store, key_prefix, &manifest).await?;
+    }
+    Ok(Some(uploaded))
+}
+
+#[cfg(test)]
+mod test {
+    use futures::executor::block_on;
+    use image::{DynamicImage, Rgba, RgbaImage};
+
+    use super::*;
+    use crate::{blob::MemStore, encode_png, pipeline::store_variants, upscale_image};
+
+    #[test]
+    fn test_split_deferred_scales() {
+        assert_eq!(
+            split_deferred_scales(&[1, 2, 4]),
+            Some((vec![1], vec![2, 4]))
+        );
+        assert_eq!(split_deferred_scales(&[1]), None);
+        assert_eq!(split_deferred_scales(&[2, 4]), None);
+    }
+
+    #[test]
+    fn test_queue_message() {
+        let hash = "a".repeat(64);
+        let job = VariantJob {
+            tenant: String::new(),
+            namespace: String::new(),
+            key_prefix: "_v1/".to_string(),
+            hash: hash.clone(),
+            stored: Vec::new(),
+            scales: vec![2],
+        };
+        let message = QueueMessage::Variants(job.clone());
+        assert_eq!(message.image(), ("", hash.as_str()));
+        let json = serde_json::to_value(&message).unwrap();
+        assert_eq!(json["kind"], message.kind());
+        assert!(matches!(
+            serde_json::from_value(json.clone()).unwrap(),
+            QueueMessage::Variants(_)
+        ));
+        // broken messages of variants are rejected rather than taken for deletions
+        let mut missing_scales = json;
+        missing_scales.as_object_mut().unwrap().remove("scales");
+        assert!(serde_json::from_value::<QueueMessage>(missing_scales).is_err());
+        let untagged = serde_json::to_value(&job).unwrap();
+        assert!(serde_json::from_value::<QueueMessage>(untagged).is_err());
+
+        let message = QueueMessage::Delete(DeleteJob {
+            tenant: "acme".to_string(),
+            hash,
+        });
+        assert_eq!(message.kind(), "delete");
+        let json = serde_json::to_string(&message).unwrap();
+        assert!(json.contains(r#""kind":"delete""#));
+        assert!(matches!(
+            serde_json::from_str(&json).unwrap(),
+            QueueMessage::Delete(DeleteJob { tenant, .. }) if tenant == "acme"
+        ));
+    }
+
+    #[test]
+    fn test_store_deferred_variants() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(4, 4, Rgba([255, 0, 0, 255])));
+        let hash = "a".repeat(64);
+        let store = MemStore::new();
+        let limits = Limits::default();
+        let stored = block_on(store_variants(
+            VariantStores::new(&store),
+            "_v1/",
+            &hash,
+            &img,
+            None,
+            &limits,
+            Some(&[1]),
+        ))
+        .unwrap();
+
+        let deferred = block_on(store_deferred_variants(
+            VariantStores::new(&store),
+            "_v1/",
+            &hash,
+            &[2, 4],
+            &limits,
+        ))
+        .unwrap()
+        .unwrap();
+        assert_eq!(deferred[1].width, 16);
+        let mut images = stored;
+        merge_uploaded(&mut images, deferred);
+        let scales: Vec<_> = images.iter().map(|img| img.scale).collect();
+        assert_eq!(scales, [Some(1), Some(2), Some(4)]);
+
+        // the manifest lists all of them, as if stored at upload
+        let manifest = block_on(load_manifest(&store, "_v1/", &hash))
+            .unwrap()
+            .unwrap();
+        let names: Vec<_> = manifest.objects.iter().map(|o| o.name.as_str()).collect();
+        assert_eq!(
+            names,
+            images.iter().map(|i| i.name.as_str()).collect::<Vec<_>>()
+        );
+        let mut data = Vec::new();
+        encode_png(&upscale_image(&img, 2), &mut data, true).unwrap();
+        assert!(manifest.objects[1].matches(&data));
+
+        // originals deleted since uploaded are skipped
+        let deferred =
+            store_deferred_variants(VariantStores::new(&store), "_v2/", &hash, &[2], &limits);
+        assert!(block_on(deferred).unwrap().is_none());
+
+        // images stored without manifests get none listing only some of their objects
+        let mut data = Vec::new();
+        encode_png(&img, &mut data, true).unwrap();
+        let original = format!("_v3/{}", Variant::Original.file_name(&hash));
+        block_on(store.store(&original, data, "image/png")).unwrap();
+        let deferred =
+            store_deferred_variants(VariantStores::new(&store), "_v3/", &hash, &[2], &limits);
+        assert_eq!(block_on(deferred).unwrap().unwrap().len(), 1);
+        assert!(block_on(load_manifest(&store, "_v3/", &hash))
+            .unwrap()
+            .is_none());
+    }
+}
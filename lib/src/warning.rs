@@ -0,0 +1,169 @@
+//! Warnings on non-fatal decisions of the upload pipeline, reported to clients in responses of
+//! uploads as objects (see `pipeline::UPLOAD_RESULT_MEDIA_TYPE`).
+
+use image::RgbaImage;
+use serde::Serialize;
+
+use crate::{
+    animation::Animation,
+    color_key::ColorKey,
+    content::count_colors,
+    namespace::{Limits, SCALE_LADDER},
+};
+
+/// Images with more colors than this are unlikely to be pixel art.
+pub const MANY_COLORS_THRESHOLD: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Warning {
+    /// Machine-readable kind of the warning.
+    pub code: &'static str,
+    pub message: String,
+    /// Frames of the source and the kept ones, for warnings of dropped frames.
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    pub frames: Option<FrameCounts>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct FrameCounts {
+    pub frames: usize,
+    pub kept: usize,
+}
+
+impl Warning {
+    fn new(code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            frames: None,
+        }
+    }
+
+    pub fn many_colors() -> Self {
+        Self::new(
+            "many_colors",
+            format!(
+                "Palette exceeds {} colors; upload with 'quantize' to reduce them",
+                MANY_COLORS_THRESHOLD
+            ),
+        )
+    }
+
+    pub fn cropped(width: u32, height: u32) -> Self {
+        Self::new(
+            "cropped",
+            format!("Image was center-cropped from {} x {}", width, height),
+        )
+    }
+
+    pub fn quantized(colors: usize) -> Self {
+        Self::new(
+            "quantized",
+            format!("Image was quantized to up to {} colors", colors),
+        )
+    }
+
+    pub fn color_keyed(key: ColorKey, pixels: usize) -> Self {
+        Self::new(
+            "color_keyed",
+            format!("{} pixels of color {} were made transparent", pixels, key),
+        )
+    }
+
+    pub fn frames_dropped(frames: usize) -> Self {
+        Self {
+            frames: Some(FrameCounts { frames, kept: 1 }),
+            ..Self::new(
+                "frames_dropped",
+                format!(
+                    "Image has {} frames, and only the first one was kept",
+                    frames
+                ),
+            )
+        }
+    }
+
+    pub fn variant_skipped(scale: u32) -> Self {
+        Self::new(
+            "variant_skipped",
+            format!("{}x variant skipped: too large", scale),
+        )
+    }
+}
+
+/// Warn if the image has too many colors for pixel art.
+pub fn color_warning(img: &RgbaImage) -> Option<Warning> {
+    (count_colors(img, MANY_COLORS_THRESHOLD + 1) > MANY_COLORS_THRESHOLD)
+        .then(Warning::many_colors)
+}
+
+/// Warn of scales of the ladder (or the requested ones) which are not pre-generated for the image
+/// (or animation).
+pub fn scale_warnings(
+    limits: &Limits,
+    anim: Option<&Animation>,
+    long_side: u32,
+    requested: Option<&[u32]>,
+) -> Vec<Warning> {
+    let scales = limits.pregenerated_scales(long_side);
+    requested
+        .unwrap_or(&SCALE_LADDER)
+        .iter()
+        .copied()
+        .filter(|s| {
+            let allowed = match requested {
+                Some(_) => *s == 1 || limits.allows_scale(long_side, *s),
+                None => scales.contains(s),
+            };
+            !allowed || anim.is_some_and(|a| !a.allows_scale(*s))
+        })
+        .map(Warning::variant_skipped)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use image::Rgba;
+
+    use super::*;
+
+    #[test]
+    fn test_color_warning() {
+        let img = RgbaImage::from_fn(8, 8, |x, y| Rgba([(x * 8 + y) as u8, 0, 0, 255]));
+        assert_eq!(color_warning(&img), None);
+        let img = RgbaImage::from_fn(8, 9, |x, y| Rgba([(x * 9 + y) as u8, 0, 0, 255]));
+        assert_eq!(color_warning(&img), Some(Warning::many_colors()));
+    }
+
+    #[test]
+    fn test_frames_dropped() {
+        let json = serde_json::to_value(Warning::frames_dropped(12)).unwrap();
+        assert_eq!(json["code"], "frames_dropped");
+        assert_eq!(json["frames"], 12);
+        assert_eq!(json["kept"], 1);
+        assert!(serde_json::to_value(Warning::many_colors())
+            .unwrap()
+            .get("frames")
+            .is_none());
+    }
+
+    #[test]
+    fn test_scale_warnings() {
+        let limits = Limits::default();
+        assert!(scale_warnings(&limits, None, 64, None).is_empty());
+        assert_eq!(
+            scale_warnings(&limits, None, 100, None),
+            vec![Warning::variant_skipped(16)]
+        );
+        let messages: Vec<_> = scale_warnings(&limits, None, 1000, None)
+            .iter()
+            .map(|w| w.message.clone())
+            .collect();
+        assert_eq!(
+            messages,
+            [2, 4, 8, 16].map(|s| format!("{}x variant skipped: too large", s))
+        );
+        // only the requested scales are warned of
+        assert!(scale_warnings(&limits, None, 100, Some(&[1, 2])).is_empty());
+    }
+}
@@ -0,0 +1,132 @@
+//! Latency budgets of the upload pipeline, to catch regressions (like accidentally quadratic
+//! steps) before deploying.
+//!
+//! Representative images are run through the steps the api worker takes (decoding, validation,
+//! advice and storing variants), with variants stored in memory, and the median time of some runs
+//! is checked against the budget of each image. Budgets are for the reference machine with
+//! release builds, so the tests are ignored by default:
+//!
+//! ```sh
+//! cargo test --release -p upix-lib --test latency -- --ignored --test-threads=1
+//! ```
+//!
+//! Scale the budgets for other machines by the `UPIX_LATENCY_BUDGET_SCALE` env var (like `2.5`).
+
+use std::time::{Duration, Instant};
+
+use futures::executor::block_on;
+use image::{DynamicImage, ImageFormat, Rgba, RgbaImage};
+
+use upix_lib::{
+    blob::MemStore,
+    encode_png,
+    namespace::Limits,
+    pipeline::{store_variants, validate_img, VariantStores},
+    quality::advise,
+    sha256_hex,
+};
+
+const RUNS: usize = 5;
+
+/// Pixel art-ish image: blocks of `block` pixels in colors of a small palette.
+fn pixel_art(w: u32, h: u32, block: u32) -> Vec<u8> {
+    const PALETTE: [[u8; 4]; 6] = [
+        [0, 0, 0, 255],
+        [255, 255, 255, 255],
+        [224, 64, 64, 255],
+        [64, 160, 64, 255],
+        [48, 96, 224, 255],
+        [0, 0, 0, 0],
+    ];
+    let img = RgbaImage::from_fn(w, h, |x, y| {
+        let (bx, by) = (x / block, y / block);
+        Rgba(PALETTE[((bx * 7 + by * 13 + bx * by) % PALETTE.len() as u32) as usize])
+    });
+    let mut data = Vec::new();
+    encode_png(&DynamicImage::ImageRgba8(img), &mut data, false).unwrap();
+    data
+}
+
+fn budget_scale() -> f64 {
+    std::env::var("UPIX_LATENCY_BUDGET_SCALE")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(1.0)
+}
+
+/// Run the upload pipeline on the PNG data, returning the number of stored objects.
+fn upload(data: &[u8], limits: &Limits, scales: Option<&[u32]>) -> usize {
+    let hash = sha256_hex(data);
+    let img = image::load_from_memory_with_format(data, ImageFormat::Png).unwrap();
+    validate_img(&img, limits).unwrap();
+    advise(&img.to_rgba8(), ImageFormat::Png);
+
+    let store = MemStore::new();
+    let stores = VariantStores::new(&store);
+    block_on(store_variants(
+        stores, "", &hash, &img, None, limits, scales,
+    ))
+    .unwrap();
+    store.keys().len()
+}
+
+/// Median time of uploads of the data.
+fn measure(data: &[u8], limits: &Limits, scales: Option<&[u32]>) -> Duration {
+    // warm up
+    assert!(upload(data, limits, scales) > 0);
+    let mut times: Vec<_> = (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            upload(data, limits, scales);
+            start.elapsed()
+        })
+        .collect();
+    times.sort();
+    times[RUNS / 2]
+}
+
+fn check_budget(name: &str, data: &[u8], budget_ms: u64) {
+    let took = measure(data, &Limits::default(), None);
+    let budget = Duration::from_millis(budget_ms).mul_f64(budget_scale());
+    assert!(took <= budget, "{} took {:?} > {:?}", name, took, budget);
+}
+
+#[test]
+#[ignore = "budgets are for release builds on the reference machine"]
+fn test_latency_small_sprite() {
+    // the full ladder up to 16x
+    check_budget("16x16 sprite", &pixel_art(16, 16, 2), 10);
+}
+
+#[test]
+#[ignore = "budgets are for release builds on the reference machine"]
+fn test_latency_character() {
+    // up to 16x, 1024 x 1024
+    check_budget("64x64 character", &pixel_art(64, 64, 4), 150);
+}
+
+#[test]
+#[ignore = "budgets are for release builds on the reference machine"]
+fn test_latency_tileset() {
+    // the largest image by default limits, up to 4x
+    check_budget("256x256 tileset", &pixel_art(256, 256, 8), 200);
+}
+
+#[test]
+#[ignore = "budgets are for release builds on the reference machine"]
+fn test_latency_scales_linearly() {
+    // uploads of images with 16x pixels at the same scales should take ~16x time, and far less
+    // than 256x if some step is quadratic
+    let limits = Limits::default();
+    let scales = [1, 2];
+    let small = measure(&pixel_art(64, 64, 4), &limits, Some(&scales));
+    let large = measure(&pixel_art(256, 256, 4), &limits, Some(&scales));
+    let ratio = large.as_secs_f64() / small.as_secs_f64();
+    assert!(
+        ratio < 16.0 * 4.0,
+        "time grew by x{:.1} for x16 pixels (64x64: {:?}, 256x256: {:?})",
+        ratio,
+        small,
+        large
+    );
+}